@@ -33,6 +33,12 @@ pub fn encoding_derive(input: TokenStream) -> TokenStream {
                                     vec.extend(&item.clone().encode());
                                 }
                             }
+                        } else if is_type(ty, "Option") {
+                            quote! {
+                                if let Some(item) = &self.#name {
+                                    vec.extend(&item.clone().encode());
+                                }
+                            }
                         } else if is_type(ty, "u8")
                             || is_type(ty, "u16")
                             || is_type(ty, "u32")
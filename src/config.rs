@@ -1,13 +1,176 @@
 use std::net::Ipv4Addr;
 
+use crate::interface::InterfaceType;
+
 pub const LS_REFRESH_TIME: u32 = 1800;
 pub const MIN_LS_INTERVAL: u32 = 5;
 pub const MIN_LS_ARRIVAL: u32 = 1;
 pub const MAX_AGE: u32 = 3600;
 pub const MAX_AGE_DIFF: u32 = 900;
 pub const LS_INFINITY: u32 = 0xFF_FFFF;
+// metric of the non-stub links of a stub router (RFC 6987)
+pub const MAX_LINK_METRIC: u16 = 0xFFFF;
 pub const INIT_SEQ_NUM_LSA: i32 = 0x8000_0001u32 as i32;
 pub const MAX_SEQ_NUM_LSA: i32 = 0x7FFF_FFFF;
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/penelospf.conf";
+pub const DEFAULT_MGMT_SOCKET_PATH: &str = "/run/penelospf.sock";
+
+// When to advertise ourselves as a stub router (RFC 6987), so traffic
+// only goes through us when there is no other way
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StubRouter {
+    Off,
+    Always,
+    // for this many seconds after startup
+    OnStartup(u32),
+    // after startup, until `max-metric off` on the management socket
+    OnStartupWait,
+}
+
+// How far to go looking for repair paths to switch to when a primary
+// next hop fails, each level also tries the ones before it
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum FastReroute {
+    Off,
+    // loop-free alternates (RFC 5286)
+    Lfa,
+    // tunnels to a PQ node (RFC 7490)
+    RemoteLfa,
+    // segment lists along the post-convergence path
+    TiLfa,
+}
+
+// A block of MPLS labels, `start` to `start + size - 1`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LabelRange {
+    pub start: u32,
+    pub size: u32,
+}
+
+impl LabelRange {
+    fn parse(lo: &str, hi: &str) -> Result<Self, String> {
+        let start: u32 = parse_num(lo)?;
+        let end: u32 = parse_num(hi)?;
+        // 0 to 15 are reserved, labels are 20 bits
+        if start < 16 || end < start || end > 0xF_FFFF {
+            return Err(format!("invalid label range {} {}", lo, hi));
+        }
+        Ok(LabelRange {
+            start,
+            size: end - start + 1,
+        })
+    }
+
+    pub fn overlaps(&self, other: &LabelRange) -> bool {
+        self.start < other.start + other.size && other.start < self.start + self.size
+    }
+}
+
+// Segment routing with the MPLS data plane (RFC 8665), prefix SIDs are
+// indexes into the SRGB and adjacency SIDs are picked from the SRLB
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SegmentRouting {
+    pub srgb: LabelRange,
+    pub srlb: LabelRange,
+}
+
+impl SegmentRouting {
+    pub fn new() -> Self {
+        SegmentRouting {
+            srgb: LabelRange {
+                start: 16000,
+                size: 8000,
+            },
+            srlb: LabelRange {
+                start: 15000,
+                size: 1000,
+            },
+        }
+    }
+}
+
+// The prefix SID of the interface address, see InterfaceConfig
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PrefixSid {
+    pub index: u32,
+    // the last hop keeps the label instead of popping it (NP flag)
+    pub no_php: bool,
+}
+
+// the most topologies an instance can have next to the default one, they
+// are kept in an array for the config to stay Copy
+pub const MAX_TOPOLOGIES: usize = 8;
+// the MT bit of the options (RFC 4915 3.3), formerly the T bit
+pub const OPTION_MT: u8 = 0x01;
+
+// An additional topology (RFC 4915), made of the interfaces that have a
+// cost in it, its routes go to their own kernel table
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Topology {
+    // MT-ID, 0 is the default topology
+    pub id: u8,
+    pub table: u32,
+}
+
+// the most flexible algorithms an instance can take part in
+pub const MAX_FLEX_ALGOS: usize = 4;
+
+// What a flexible algorithm minimizes (RFC 9350 5.1)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FlexAlgoMetric {
+    Igp = 0,
+    // minimum unidirectional link delay
+    Delay = 1,
+    Te = 2,
+}
+
+// A flexible algorithm (RFC 9350) we define and take part in, paths only
+// use links whose admin groups pass the constraints, its routes go to
+// their own kernel table
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FlexAlgo {
+    // 128 to 255
+    pub id: u8,
+    pub metric_type: FlexAlgoMetric,
+    // the definition with the highest priority in the area is the one used
+    pub priority: u8,
+    // admin groups as a bitmap, bit 0 for group 0
+    pub exclude_any: u32,
+    pub include_any: u32,
+    pub include_all: u32,
+    pub table: u32,
+}
+
+impl FlexAlgo {
+    pub fn new(id: u8) -> Self {
+        FlexAlgo {
+            id,
+            metric_type: FlexAlgoMetric::Igp,
+            priority: 128,
+            exclude_any: 0,
+            include_any: 0,
+            include_all: 0,
+            table: 0,
+        }
+    }
+
+    // whether a link in these admin groups can be used (RFC 9350 13)
+    pub fn admits(&self, groups: u32) -> bool {
+        groups & self.exclude_any == 0
+            && (self.include_any == 0 || groups & self.include_any != 0)
+            && groups & self.include_all == self.include_all
+    }
+}
+
+// Traffic engineering attributes of an interface, advertised for the
+// flexible algorithms to use
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct LinkAttributes {
+    pub admin_groups: u32,
+    // in microseconds
+    pub delay: Option<u32>,
+    pub te_metric: Option<u32>,
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct Config {
@@ -15,13 +178,21 @@ pub struct Config {
     pub dead_interval: u32,
     pub inf_transit_delay: u32,
     pub rxmt_interval: u32,
+    // the router ID in use, from the config file or picked from our addresses
     pub router_id: u32,
     pub area_id: u32,
-    pub network_mask: u32,
     pub router_priority: u8,
-    pub router_dead_interval: u32,
     pub default_mtu: u16,
     pub options: u8,
+    // set the E bit in our router LSA
+    pub as_boundary_router: bool,
+    // kernel routing table our routes go to, 0 for the main table
+    pub route_table: u32,
+    pub stub_router: StubRouter,
+    pub fast_reroute: FastReroute,
+    pub segment_routing: Option<SegmentRouting>,
+    pub topologies: [Option<Topology>; MAX_TOPOLOGIES],
+    pub flex_algos: [Option<FlexAlgo>; MAX_FLEX_ALGOS],
 }
 
 impl Config {
@@ -31,13 +202,667 @@ impl Config {
             dead_interval: 40,
             inf_transit_delay: 1,
             rxmt_interval: 5,
-            router_id: 3232240450,
+            router_id: 0,
             area_id: 0,
-            network_mask: 0xFFFFF000, // 255.255.255.0
             router_priority: 1,
-            router_dead_interval: 40,
             default_mtu: 1500,
-            options: 0x02,
+            // E, and O as we store and flood opaque LSAs (RFC 5250)
+            options: 0x42,
+            as_boundary_router: false,
+            route_table: 0,
+            stub_router: StubRouter::Off,
+            fast_reroute: FastReroute::Off,
+            segment_routing: None,
+            topologies: [None; MAX_TOPOLOGIES],
+            flex_algos: [None; MAX_FLEX_ALGOS],
+        }
+    }
+
+    pub fn flex_algos(&self) -> impl Iterator<Item = FlexAlgo> {
+        self.flex_algos.into_iter().flatten()
+    }
+
+    pub fn flex_algo(&self, id: u8) -> Option<FlexAlgo> {
+        self.flex_algos().find(|x| x.id == id)
+    }
+
+    pub fn topologies(&self) -> impl Iterator<Item = Topology> {
+        self.topologies.into_iter().flatten()
+    }
+
+    pub fn topology(&self, id: u8) -> Option<Topology> {
+        self.topologies().find(|x| x.id == id)
+    }
+}
+
+// per-interface settings, anything not set in the config file keeps its default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterfaceConfig {
+    pub name: String,
+    pub mtu_ignore: bool,
+    pub network_type: Option<InterfaceType>,
+    pub hello_interval: Option<u32>,
+    pub dead_interval: Option<u32>,
+    pub router_priority: Option<u8>,
+    pub cost: Option<u16>,
+    // advertise the subnet without running the protocol on it
+    pub passive: bool,
+    // borrow the primary address of this interface instead of having our own
+    pub unnumbered: Option<String>,
+    // OSPFv3 instance ID (RFC 5340 2.4), 0 when not set
+    pub instance_id: Option<u8>,
+    // GTSM (RFC 5082), the number of hops our packets may cross, which is
+    // only more than 0 on virtual links
+    pub ttl_security: Option<u8>,
+    // advertise the interface address with this SID, a host route on
+    // loopbacks and the subnet elsewhere
+    pub prefix_sid: Option<PrefixSid>,
+    // (MT-ID, cost) of the topologies the interface is part of, besides
+    // the default one
+    pub topology_costs: Vec<(u8, u16)>,
+    pub link_attrs: LinkAttributes,
+}
+
+impl InterfaceConfig {
+    pub fn new(name: &str) -> Self {
+        InterfaceConfig {
+            name: name.to_string(),
+            ..Default::default()
         }
     }
+
+    fn parse_option(&mut self, words: &[&str]) -> Result<(), String> {
+        match words {
+            ["mtu-ignore"] => self.mtu_ignore = true,
+            ["passive"] => self.passive = true,
+            ["network-type", t] => {
+                self.network_type = Some(match *t {
+                    "broadcast" => InterfaceType::Broadcast,
+                    "point-to-point" => InterfaceType::P2P,
+                    "nbma" => InterfaceType::NBMA,
+                    "point-to-multipoint" => InterfaceType::P2MP,
+                    _ => return Err(format!("unknown network type '{}'", t)),
+                })
+            }
+            ["hello-interval", v] => self.hello_interval = Some(parse_num(v)?),
+            ["dead-interval", v] => self.dead_interval = Some(parse_num(v)?),
+            ["priority", v] => self.router_priority = Some(parse_num(v)?),
+            ["cost", v] => self.cost = Some(parse_num(v)?),
+            ["unnumbered", lender] => self.unnumbered = Some(lender.to_string()),
+            ["instance-id", v] => self.instance_id = Some(parse_num(v)?),
+            ["ttl-security"] => self.ttl_security = Some(0),
+            ["ttl-security", "hops", v] => self.ttl_security = Some(parse_num(v)?),
+            ["topology", id, "cost", cost] => {
+                let id = parse_num(id)?;
+                let cost = parse_num(cost)?;
+                self.topology_costs.retain(|(x, _)| *x != id);
+                self.topology_costs.push((id, cost));
+            }
+            ["admin-group", groups @ ..] if !groups.is_empty() => {
+                self.link_attrs.admin_groups = parse_admin_groups(groups)?
+            }
+            ["delay", v] => self.link_attrs.delay = Some(parse_num(v)?),
+            ["te-metric", v] => self.link_attrs.te_metric = Some(parse_num(v)?),
+            ["prefix-sid", "index", v] => {
+                self.prefix_sid = Some(PrefixSid {
+                    index: parse_num(v)?,
+                    no_php: false,
+                })
+            }
+            ["prefix-sid", "index", v, "no-php"] => {
+                self.prefix_sid = Some(PrefixSid {
+                    index: parse_num(v)?,
+                    no_php: true,
+                })
+            }
+            _ => return Err(format!("unknown interface option '{}'", words.join(" "))),
+        }
+        Ok(())
+    }
+}
+
+// What the router information LSA tells about us besides our
+// capabilities (RFC 7777, RFC 5642)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NodeInfo {
+    // the system hostname when not configured
+    pub hostname: Option<String>,
+    pub tags: Vec<u32>,
+}
+
+// One OSPF instance of the config file
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    // "default" for the options before the first `instance` line
+    pub name: String,
+    pub global: Config,
+    pub interfaces: Vec<InterfaceConfig>,
+    // only run on interfaces that have a block
+    pub configured_only: bool,
+    // the router ID given with `router-id`
+    pub router_id: Option<Ipv4Addr>,
+    // also run OSPFv3 for IPv6 on the same interfaces
+    pub ospfv3: bool,
+    // run on the interfaces enslaved to this VRF device, and put the
+    // routes in its table unless `table` says otherwise
+    pub vrf: Option<String>,
+    pub table: Option<u32>,
+    pub node_info: NodeInfo,
+}
+
+impl ConfigFile {
+    pub fn new(name: &str) -> Self {
+        ConfigFile {
+            name: name.to_string(),
+            global: Config::new(),
+            interfaces: vec![],
+            configured_only: false,
+            router_id: None,
+            ospfv3: false,
+            vrf: None,
+            table: None,
+            node_info: NodeInfo::default(),
+        }
+    }
+
+    // The config file is line based, '#' starts a comment. An `interface <name>`
+    // line opens a block, and the indented lines after it belong to that interface:
+    //
+    //     interface eth0
+    //         mtu-ignore
+    //
+    // Every interface runs unless `interfaces configured` is given, then only
+    // the interfaces with a block do.
+    //
+    // An `instance <name>` line starts another OSPF instance, the lines up
+    // to the next one configure it. Instances in the same VRF have to split
+    // the interfaces between them with `interfaces configured`.
+    pub fn load(path: &str) -> Result<Vec<Self>, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) => Err(format!("cannot read {}: {}", path, e)),
+        }
+    }
+
+    // At startup we can run without a config file, a reload can't make
+    // the running config go away that way
+    pub fn load_or_default(path: &str) -> Result<Vec<Self>, String> {
+        if !std::path::Path::new(path).exists() {
+            println!("Config file {} not found, using defaults", path);
+            return Ok(vec![ConfigFile::new("default")]);
+        }
+        Self::load(path)
+    }
+
+    pub fn parse(text: &str) -> Result<Vec<Self>, String> {
+        let mut files = vec![ConfigFile::new("default")];
+        // the default instance only exists if something configures it
+        let mut default_used = false;
+        for (lineno, raw) in text.lines().enumerate() {
+            let line = raw.split('#').next().unwrap().trim_end();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            if let ["instance", name] = words.as_slice() {
+                if files.iter().any(|x| x.name == *name) {
+                    return Err(format!(
+                        "line {}: instance {} defined twice",
+                        lineno + 1,
+                        name
+                    ));
+                }
+                files.push(ConfigFile::new(name));
+                continue;
+            }
+            if files.len() == 1 {
+                default_used = true;
+            }
+            let file = files.last_mut().unwrap();
+            let indented = line.starts_with(char::is_whitespace);
+            let res = if indented {
+                match file.interfaces.last_mut() {
+                    Some(int) => int.parse_option(&words),
+                    None => Err("option outside of an interface block".to_string()),
+                }
+            } else {
+                file.parse_global(&words)
+            };
+            if let Err(e) = res {
+                return Err(format!("line {}: {}", lineno + 1, e));
+            }
+        }
+        if !default_used && files.len() > 1 {
+            files.remove(0);
+        }
+        for file in files.iter() {
+            file.check_tables()?;
+            for int in file.interfaces.iter() {
+                if let Some((id, _)) = int
+                    .topology_costs
+                    .iter()
+                    .find(|(id, _)| file.global.topology(*id).is_none())
+                {
+                    return Err(format!(
+                        "interface {}: topology {} is not defined",
+                        int.name, id
+                    ));
+                }
+            }
+        }
+        Self::check_overlap(&files)?;
+        Ok(files)
+    }
+
+    // an interface can only belong to one instance, and the label ranges
+    // of instances with segment routing can't overlap since the MPLS table
+    // is shared by the whole namespace
+    fn check_overlap(files: &[ConfigFile]) -> Result<(), String> {
+        for (i, a) in files.iter().enumerate() {
+            if let Some(sr_a) = a.global.segment_routing {
+                for b in files[i + 1..].iter() {
+                    let Some(sr_b) = b.global.segment_routing else {
+                        continue;
+                    };
+                    let ranges_a = [("srgb", sr_a.srgb), ("srlb", sr_a.srlb)];
+                    let ranges_b = [("srgb", sr_b.srgb), ("srlb", sr_b.srlb)];
+                    for (name_a, range_a) in ranges_a.iter() {
+                        if let Some((name_b, _)) =
+                            ranges_b.iter().find(|(_, x)| x.overlaps(range_a))
+                        {
+                            return Err(format!(
+                                "{} of instance {} overlaps the {} of instance {}",
+                                name_a, a.name, name_b, b.name
+                            ));
+                        }
+                    }
+                }
+            }
+            for b in files[i + 1..].iter().filter(|b| b.vrf == a.vrf) {
+                if !a.configured_only || !b.configured_only {
+                    return Err(format!(
+                        "instances {} and {} share a VRF, both need `interfaces configured`",
+                        a.name, b.name
+                    ));
+                }
+                if let Some(int) = a.interfaces.iter().find(|x| b.runs_on(&x.name)) {
+                    return Err(format!(
+                        "interface {} is in both instances {} and {}",
+                        int.name, a.name, b.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_global(&mut self, words: &[&str]) -> Result<(), String> {
+        match words {
+            ["asbr"] => self.global.as_boundary_router = true,
+            ["ospfv3"] => self.ospfv3 = true,
+            ["max-metric"] => self.global.stub_router = StubRouter::Always,
+            ["max-metric", "on-startup", "wait"] => {
+                self.global.stub_router = StubRouter::OnStartupWait
+            }
+            ["max-metric", "on-startup", secs] => {
+                self.global.stub_router = StubRouter::OnStartup(parse_num(secs)?)
+            }
+            ["fast-reroute", "lfa"] => self.global.fast_reroute = FastReroute::Lfa,
+            ["fast-reroute", "remote-lfa"] => self.global.fast_reroute = FastReroute::RemoteLfa,
+            ["fast-reroute", "ti-lfa"] => self.global.fast_reroute = FastReroute::TiLfa,
+            ["segment-routing"] => {
+                self.global
+                    .segment_routing
+                    .get_or_insert_with(SegmentRouting::new);
+            }
+            ["segment-routing", "global-block", lo, hi] => {
+                let sr = self
+                    .global
+                    .segment_routing
+                    .get_or_insert_with(SegmentRouting::new);
+                sr.srgb = LabelRange::parse(lo, hi)?;
+                if sr.srgb.overlaps(&sr.srlb) {
+                    return Err("the global block overlaps the local block".to_string());
+                }
+            }
+            ["segment-routing", "local-block", lo, hi] => {
+                let sr = self
+                    .global
+                    .segment_routing
+                    .get_or_insert_with(SegmentRouting::new);
+                sr.srlb = LabelRange::parse(lo, hi)?;
+                if sr.srgb.overlaps(&sr.srlb) {
+                    return Err("the local block overlaps the global block".to_string());
+                }
+            }
+            ["topology", id, "table", table] => {
+                let id: u8 = parse_num(id)?;
+                // 128 and above are reserved
+                if !(1..=127).contains(&id) {
+                    return Err(format!("invalid topology ID {}", id));
+                }
+                let topology = Topology {
+                    id,
+                    table: parse_num(table)?,
+                };
+                if topology.table == 0 {
+                    return Err("the main table belongs to the default topology".to_string());
+                }
+                if self.global.topology(id).is_some() {
+                    return Err(format!("topology {} defined twice", id));
+                }
+                match self.global.topologies.iter_mut().find(|x| x.is_none()) {
+                    Some(slot) => *slot = Some(topology),
+                    None => return Err(format!("at most {} topologies", MAX_TOPOLOGIES)),
+                }
+                self.global.options |= OPTION_MT;
+            }
+            ["flex-algo", id, rest @ ..] if !rest.is_empty() => self.parse_flex_algo(id, rest)?,
+            ["router-id", id] => {
+                let id: Ipv4Addr = id
+                    .parse()
+                    .map_err(|_| format!("invalid router id '{}'", id))?;
+                if id.is_unspecified() {
+                    return Err("router id 0.0.0.0 is reserved".to_string());
+                }
+                self.router_id = Some(id);
+            }
+            ["hostname", name] => {
+                if name.len() > 255 {
+                    return Err("hostnames are at most 255 characters".to_string());
+                }
+                self.node_info.hostname = Some(name.to_string());
+            }
+            ["node-tag", tags @ ..] if !tags.is_empty() => {
+                for tag in tags {
+                    self.node_info.tags.push(parse_num(tag)?);
+                }
+            }
+            ["vrf", name] => self.vrf = Some(name.to_string()),
+            ["table", id] => self.table = Some(parse_num(id)?),
+            ["interfaces", "all"] => self.configured_only = false,
+            ["interfaces", "configured"] => self.configured_only = true,
+            ["interface", name] => {
+                if self.interfaces.iter().any(|x| x.name == *name) {
+                    return Err(format!("interface {} configured twice", name));
+                }
+                self.interfaces.push(InterfaceConfig::new(name));
+            }
+            _ => return Err(format!("unknown option '{}'", words.join(" "))),
+        }
+        Ok(())
+    }
+
+    // `flex-algo <id> <option>` lines, the first one for an ID defines it
+    //
+    //     flex-algo 128 table 128
+    //     flex-algo 128 metric-type delay
+    //     flex-algo 128 exclude-any 1 2
+    fn parse_flex_algo(&mut self, id: &str, words: &[&str]) -> Result<(), String> {
+        let id: u8 = parse_num(id)?;
+        if id < 128 {
+            return Err(format!("flexible algorithms are 128 to 255, not {}", id));
+        }
+        let slot = match self
+            .global
+            .flex_algos
+            .iter()
+            .position(|x| x.is_some_and(|x| x.id == id))
+        {
+            Some(i) => i,
+            None => {
+                let i = self
+                    .global
+                    .flex_algos
+                    .iter()
+                    .position(|x| x.is_none())
+                    .ok_or(format!("at most {} flexible algorithms", MAX_FLEX_ALGOS))?;
+                self.global.flex_algos[i] = Some(FlexAlgo::new(id));
+                i
+            }
+        };
+        let algo = self.global.flex_algos[slot].as_mut().unwrap();
+        match words {
+            ["table", table] => algo.table = parse_num(table)?,
+            ["priority", v] => algo.priority = parse_num(v)?,
+            ["metric-type", "igp"] => algo.metric_type = FlexAlgoMetric::Igp,
+            ["metric-type", "delay"] => algo.metric_type = FlexAlgoMetric::Delay,
+            ["metric-type", "te"] => algo.metric_type = FlexAlgoMetric::Te,
+            ["exclude-any", groups @ ..] => algo.exclude_any = parse_admin_groups(groups)?,
+            ["include-any", groups @ ..] => algo.include_any = parse_admin_groups(groups)?,
+            ["include-all", groups @ ..] => algo.include_all = parse_admin_groups(groups)?,
+            _ => return Err(format!("unknown flex-algo option '{}'", words.join(" "))),
+        }
+        Ok(())
+    }
+
+    // every topology and algorithm has a table of its own
+    fn check_tables(&self) -> Result<(), String> {
+        let mut tables: Vec<(String, u32)> = self
+            .global
+            .topologies()
+            .map(|x| (format!("topology {}", x.id), x.table))
+            .collect();
+        for algo in self.global.flex_algos() {
+            if algo.table == 0 {
+                return Err(format!("flex-algo {} needs a table", algo.id));
+            }
+            tables.push((format!("flex-algo {}", algo.id), algo.table));
+        }
+        for (i, (a, table)) in tables.iter().enumerate() {
+            if let Some((b, _)) = tables[i + 1..].iter().find(|(_, t)| t == table) {
+                return Err(format!("{} and {} both use table {}", a, b, table));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn runs_on(&self, name: &str) -> bool {
+        !self.configured_only || self.interfaces.iter().any(|x| x.name == name)
+    }
+
+    // config of the named interface, or the defaults if it has no block
+    pub fn interface(&self, name: &str) -> InterfaceConfig {
+        self.interfaces
+            .iter()
+            .find(|x| x.name == name)
+            .cloned()
+            .unwrap_or_else(|| InterfaceConfig::new(name))
+    }
+}
+
+// admin group numbers, 0 to 31
+fn parse_admin_groups(words: &[&str]) -> Result<u32, String> {
+    let mut groups = 0u32;
+    for word in words {
+        let group: u32 = parse_num(word)?;
+        if group > 31 {
+            return Err(format!("admin groups are 0 to 31, not {}", group));
+        }
+        groups |= 1 << group;
+    }
+    Ok(groups)
+}
+
+fn parse_num<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+    word.parse::<T>()
+        .map_err(|_| format!("invalid number '{}'", word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(text: &str) -> String {
+        ConfigFile::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn empty_config_is_the_default_instance() {
+        let files = ConfigFile::parse("# nothing\n\n").unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "default");
+        assert!(!files[0].configured_only);
+    }
+
+    #[test]
+    fn global_and_interface_options() {
+        let text = "\
+router-id 1.1.1.1
+interfaces configured
+segment-routing global-block 20000 20999
+max-metric on-startup 60
+node-tag 7 9
+topology 2 table 102
+flex-algo 128 table 128
+flex-algo 128 metric-type te
+flex-algo 128 exclude-any 1 3
+interface eth0
+    network-type point-to-point
+    cost 20  # a comment
+    topology 2 cost 30
+    admin-group 1
+    prefix-sid index 5 no-php
+interface eth1
+    passive
+";
+        let files = ConfigFile::parse(text).unwrap();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.router_id, Some(Ipv4Addr::new(1, 1, 1, 1)));
+        assert!(file.runs_on("eth1") && !file.runs_on("eth2"));
+        let sr = file.global.segment_routing.unwrap();
+        assert_eq!(
+            sr.srgb,
+            LabelRange {
+                start: 20000,
+                size: 1000
+            }
+        );
+        assert_eq!(sr.srlb, SegmentRouting::new().srlb);
+        assert_eq!(file.global.stub_router, StubRouter::OnStartup(60));
+        assert_eq!(file.node_info.tags, vec![7, 9]);
+        assert_eq!(file.global.topology(2).unwrap().table, 102);
+        assert_ne!(file.global.options & OPTION_MT, 0);
+        let algo = file.global.flex_algo(128).unwrap();
+        assert_eq!(algo.metric_type, FlexAlgoMetric::Te);
+        assert_eq!(algo.exclude_any, 1 << 1 | 1 << 3);
+        let eth0 = file.interface("eth0");
+        assert_eq!(eth0.network_type, Some(InterfaceType::P2P));
+        assert_eq!(eth0.cost, Some(20));
+        assert_eq!(eth0.topology_costs, vec![(2, 30)]);
+        assert_eq!(eth0.link_attrs.admin_groups, 1 << 1);
+        assert_eq!(
+            eth0.prefix_sid,
+            Some(PrefixSid {
+                index: 5,
+                no_php: true
+            })
+        );
+        assert!(file.interface("eth1").passive);
+        assert_eq!(file.interface("eth2"), InterfaceConfig::new("eth2"));
+    }
+
+    #[test]
+    fn instances() {
+        let text = "\
+instance a
+vrf red
+instance b
+vrf blue
+ospfv3
+";
+        let files = ConfigFile::parse(text).unwrap();
+        let names: Vec<&str> = files.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert!(files[1].ospfv3 && !files[0].ospfv3);
+        // options before the first instance make a default instance
+        let files = ConfigFile::parse("asbr\ninstance a\nvrf red\n").unwrap();
+        assert_eq!(files[0].name, "default");
+        assert!(files[0].global.as_boundary_router);
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let cases = [
+            ("bogus", "line 1: unknown option 'bogus'"),
+            ("    cost 1", "line 1: option outside of an interface block"),
+            ("interface eth0\n    cost x", "line 2: invalid number 'x'"),
+            (
+                "interface eth0\n    cost 70000",
+                "line 2: invalid number '70000'",
+            ),
+            (
+                "interface eth0\ninterface eth0",
+                "line 2: interface eth0 configured twice",
+            ),
+            ("router-id 0.0.0.0", "line 1: router id 0.0.0.0 is reserved"),
+            ("router-id 1.1.1", "line 1: invalid router id '1.1.1'"),
+            ("topology 128 table 5", "line 1: invalid topology ID 128"),
+            (
+                "topology 1 table 0",
+                "line 1: the main table belongs to the default topology",
+            ),
+            (
+                "flex-algo 127 table 5",
+                "line 1: flexible algorithms are 128 to 255, not 127",
+            ),
+            ("flex-algo 128 priority 1", "flex-algo 128 needs a table"),
+            (
+                "interface eth0\n    admin-group 32",
+                "line 2: admin groups are 0 to 31, not 32",
+            ),
+            ("instance a\ninstance a", "line 2: instance a defined twice"),
+            (
+                "segment-routing global-block 15500 16000",
+                "line 1: the global block overlaps the local block",
+            ),
+            (
+                "segment-routing local-block 10 100",
+                "line 1: invalid label range 10 100",
+            ),
+            (
+                "interface eth0\n    topology 3 cost 10",
+                "interface eth0: topology 3 is not defined",
+            ),
+            (
+                "topology 1 table 100\nflex-algo 128 table 100",
+                "topology 1 and flex-algo 128 both use table 100",
+            ),
+        ];
+        for (text, err) in cases {
+            assert_eq!(parse_err(text), err, "for {:?}", text);
+        }
+    }
+
+    #[test]
+    fn instances_that_clash() {
+        assert_eq!(
+            parse_err("instance a\ninstance b\n"),
+            "instances a and b share a VRF, both need `interfaces configured`"
+        );
+        assert_eq!(
+            parse_err(
+                "instance a\ninterfaces configured\ninterface eth0\n\
+                 instance b\ninterfaces configured\ninterface eth0\n"
+            ),
+            "interface eth0 is in both instances a and b"
+        );
+        assert_eq!(
+            parse_err(
+                "instance a\nvrf red\nsegment-routing\ninstance b\nvrf blue\nsegment-routing\n"
+            ),
+            "srgb of instance a overlaps the srgb of instance b"
+        );
+        let disjoint = "\
+instance a
+vrf red
+segment-routing
+instance b
+vrf blue
+segment-routing global-block 30000 30999
+segment-routing local-block 31000 31099
+";
+        assert!(ConfigFile::parse(disjoint).is_ok());
+    }
 }
@@ -1,38 +1,89 @@
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 
+use pnet::ipnetwork::Ipv4Network;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 
-use crate::config::Config;
-use crate::interface::Interface;
+use crate::config::{Config, LinkAttributes, NodeInfo, PrefixSid, MAX_AGE, MAX_SEQ_NUM_LSA};
+use crate::interface::{InterfaceState, InterfaceType};
+use crate::kernel;
 use crate::lsa::LsaNetwork;
 use crate::lsa::LsaRouter;
-use crate::lsa::LsaRouterLink;
-use crate::lsa::LsaType;
+use crate::lsa::{LinkType, LsaType, ROUTER_FLAG_B, ROUTER_FLAG_E, ROUTER_FLAG_V};
 use crate::lsa::{Lsa, LsaHeader};
+use crate::lsa::{LsaRouterLink, LsaRouterLinkTos};
 use crate::receiver::ipv4_to_bits;
 use crate::route::RouteTable;
+use crate::router_info::RouterInfo;
+
+// this many conflicting instances of our LSAs within the window (in seconds)
+// make us warn about a duplicate router ID
+const DUP_ROUTER_ID_THRESHOLD: usize = 3;
+const DUP_ROUTER_ID_WINDOW: u64 = 60;
+
+// whether two instances of an LSA describe the same thing, headers aside
+fn same_contents(a: &Lsa, b: &Lsa) -> bool {
+    match (a, b) {
+        (Lsa::LsaRouter(a), Lsa::LsaRouter(b)) => {
+            let links = |l: &LsaRouter| -> Vec<(u32, u32, u8, u16)> {
+                l.links
+                    .iter()
+                    .map(|x| (x.link_id, x.link_data, x.link_type, x.metric))
+                    .collect()
+            };
+            // the metrics in the other topologies
+            let tos = |l: &LsaRouter| -> Vec<Vec<(u8, u16)>> {
+                l.links
+                    .iter()
+                    .map(|x| x.tos_list.iter().map(|t| (t.tos, t.metric)).collect())
+                    .collect()
+            };
+            a.flags == b.flags && links(a) == links(b) && tos(a) == tos(b)
+        }
+        (Lsa::LsaNetwork(a), Lsa::LsaNetwork(b)) => {
+            a.network_mask == b.network_mask && a.attached_routers == b.attached_routers
+        }
+        (Lsa::LsaOpaque(a), Lsa::LsaOpaque(b)) => a.body == b.body,
+        _ => false,
+    }
+}
 
 // We use tokio channels for communication, see https://rust-book.junmajinlong.com/ch100/05_task_communication.html
 #[derive(Debug)]
 pub struct LinkStateDatabase {
-    pub each_int_link: HashMap<Ipv4Addr, LsaRouterLink>,
+    // interfaces described in our router LSA, keyed by interface name
+    pub each_int_link: HashMap<String, RouterLsaIntDesc>,
     pub lsa_list: Vec<Lsa>,
     pub global_config: Config,
     pub request_channel: mpsc::Receiver<DatabaseRequest>,
     pub last_iter_instant: std::time::Instant,
     pub cur_lsa_seq_num: i32,
     pub route_table: RouteTable,
-    pub int_list: Vec<Interface>,
+    // when we had to re-originate because someone else sent a different
+    // instance of our LSAs, used to detect a duplicate router ID
+    pub self_lsa_conflicts: Vec<std::time::Instant>,
+    // advertise MaxLinkMetric on our non-stub links (RFC 6987)
+    pub stub_router: bool,
+    // our LSAs are flushed, nothing is originated or computed any more
+    pub shutting_down: bool,
+    // SRLB labels of our adjacencies, keyed by interface name and neighbor ID
+    pub adj_sids: HashMap<(String, u32), u32>,
+    // the label table size and interfaces MPLS was last enabled for
+    pub mpls_setup: Option<(u32, Vec<String>)>,
+    // link state IDs of our extended link LSAs, keyed like ExtLink
+    pub ext_link_ids: HashMap<(String, u32), u32>,
+    // route tables of the topologies besides the default one, by MT-ID
+    pub topology_tables: HashMap<u8, RouteTable>,
+    // route tables of the flexible algorithms, by algorithm
+    pub flex_algo_tables: HashMap<u8, RouteTable>,
+    pub node_info: NodeInfo,
+    // what the router information LSAs say, by advertising router
+    pub router_infos: HashMap<u32, RouterInfo>,
 }
 
 impl LinkStateDatabase {
-    pub fn from(
-        config: Config,
-        request_channel: mpsc::Receiver<DatabaseRequest>,
-        int_list: Vec<Interface>,
-    ) -> Self {
+    pub fn from(config: Config, request_channel: mpsc::Receiver<DatabaseRequest>) -> Self {
         LinkStateDatabase {
             each_int_link: HashMap::new(),
             lsa_list: vec![],
@@ -41,7 +92,16 @@ impl LinkStateDatabase {
             last_iter_instant: std::time::Instant::now(),
             cur_lsa_seq_num: crate::config::INIT_SEQ_NUM_LSA as i32,
             route_table: RouteTable::new(),
-            int_list,
+            self_lsa_conflicts: vec![],
+            stub_router: false,
+            shutting_down: false,
+            adj_sids: HashMap::new(),
+            mpls_setup: None,
+            ext_link_ids: HashMap::new(),
+            topology_tables: HashMap::new(),
+            flex_algo_tables: HashMap::new(),
+            node_info: NodeInfo::default(),
+            router_infos: HashMap::new(),
         }
     }
 }
@@ -59,13 +119,52 @@ pub enum DatabaseRequestType {
     QueryLsaByHdr(LsaHeader),
     QueryLsaByLSID(u32),
     QueryLsaByLSIDAdvRouter(u32, u32),
-    QueryMultiLsa(Vec<(u32, u32)>),
+    QueryMultiLsa(Vec<(u8, u32, u32)>),
     RemoveLsa(LsaHeader),
     AddOrUpdateLsa(Lsa),
     QueryAllLsa,
     QueryAllLsaByType(u8),
-    GenRouterLsa(LsaRouterLink, Ipv4Addr),
-    GenNetworkLsa(Ipv4Addr, Ipv4Addr, Vec<Ipv4Addr>),
+    GenRouterLsa(RouterLsaIntDesc, String),
+    GenNetworkLsa(Ipv4Addr, Ipv4Addr, Vec<u32>),
+    FlushNetworkLsa(Ipv4Addr),
+    // flush everything originated under the old router ID and switch to the new one
+    ChangeRouterId(u32),
+    // a newer instance of one of our own LSAs was received (RFC 2328 13.4)
+    SelfOriginatedLsa(Lsa),
+    // enter or leave stub router mode
+    SetStubRouter(bool),
+    // flush our LSAs and withdraw our kernel routes before exiting
+    Shutdown,
+    // our hostname or node tags changed
+    SetNodeInfo(NodeInfo),
+    // the advertised hostnames, by router ID
+    QueryHostnames,
+    ShowDatabase,
+    ShowRoutes,
+}
+
+// What the database needs to know about an interface to describe it in
+// the router LSA (RFC 2328 12.4.1)
+#[derive(Debug, Clone)]
+pub struct RouterLsaIntDesc {
+    pub int_type: InterfaceType,
+    pub state: InterfaceState,
+    pub area_id: u32,
+    pub addr: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub secondary_addrs: Vec<Ipv4Network>,
+    pub unnumbered: bool,
+    pub ifindex: u32,
+    pub cost: u16,
+    pub passive: bool,
+    pub dr: Ipv4Addr,
+    // whether the network should be described as a transit network
+    pub transit: bool,
+    // router id and interface address of every fully adjacent neighbor
+    pub full_nbrs: Vec<(u32, Ipv4Addr)>,
+    pub prefix_sid: Option<PrefixSid>,
+    pub topology_costs: Vec<(u8, u16)>,
+    pub link_attrs: LinkAttributes,
 }
 
 #[derive(Debug)]
@@ -75,6 +174,8 @@ pub enum DatabaseResponse {
     Config(Config),
     Lsa(Lsa),
     LsaList(Vec<Lsa>),
+    Hostnames(HashMap<u32, String>),
+    Text(String),
 }
 
 impl LinkStateDatabase {
@@ -105,19 +206,62 @@ impl LinkStateDatabase {
 
     async fn handle_request(&mut self, req: DatabaseRequestType) -> DatabaseResponse {
         let mut recalculate_needed = false;
+        if self.shutting_down
+            && matches!(
+                req,
+                DatabaseRequestType::ChangeConfig(_)
+                    | DatabaseRequestType::GenRouterLsa(..)
+                    | DatabaseRequestType::GenNetworkLsa(..)
+                    | DatabaseRequestType::FlushNetworkLsa(_)
+                    | DatabaseRequestType::ChangeRouterId(_)
+                    | DatabaseRequestType::SelfOriginatedLsa(_)
+                    | DatabaseRequestType::SetStubRouter(_)
+                    | DatabaseRequestType::SetNodeInfo(_)
+            )
+        {
+            return DatabaseResponse::UpdateDone;
+        }
         let response = match req {
             DatabaseRequestType::QueryConfig => {
                 DatabaseResponse::Config(self.global_config.clone())
             }
             DatabaseRequestType::ChangeConfig(new_config) => {
+                let old_flags = self.router_lsa_flags();
+                let topologies_changed = new_config.topologies != self.global_config.topologies;
+                if new_config.fast_reroute != self.global_config.fast_reroute
+                    || new_config.segment_routing != self.global_config.segment_routing
+                    || new_config.flex_algos != self.global_config.flex_algos
+                    || topologies_changed
+                {
+                    recalculate_needed = true;
+                }
                 self.global_config = new_config;
-                DatabaseResponse::UpdateDone
+                self.update_mpls();
+                let mut lsas = vec![];
+                // the router LSA only depends on the global config through its
+                // flags and the topologies its links can be in
+                if (self.router_lsa_flags() != old_flags || topologies_changed)
+                    && !self.each_int_link.is_empty()
+                {
+                    let lsa = self.make_router_lsa(self.make_router_links());
+                    self.lsa_list.retain(|x| !x.same_ids(lsa.get_hdr()));
+                    self.cur_lsa_seq_num += 1;
+                    self.lsa_list.push(lsa.clone());
+                    recalculate_needed = true;
+                    println!("Router LSA generated: {:?}", lsa);
+                    lsas.push(lsa);
+                }
+                lsas.extend(self.originate_opaque_lsas());
+                DatabaseResponse::LsaList(lsas)
+            }
+            // network LSAs named after one of our addresses are ours even
+            // when they carry another router ID, e.g. from before a router
+            // ID change (RFC 2328 13.4)
+            DatabaseRequestType::AddOrUpdateLsa(lsa) if self.is_own_network_lsa(lsa.get_hdr()) => {
+                self.handle_self_originated(lsa)
             }
             DatabaseRequestType::AddOrUpdateLsa(lsa) => {
-                let lsa_index = self
-                    .lsa_list
-                    .iter()
-                    .position(|x| x.same_ids(&lsa.get_hdr()));
+                let lsa_index = self.lsa_list.iter().position(|x| x.same_ids(lsa.get_hdr()));
                 match lsa_index {
                     Some(i) => {
                         self.lsa_list[i] = lsa;
@@ -174,8 +318,9 @@ impl LinkStateDatabase {
             DatabaseRequestType::QueryMultiLsa(queries) => {
                 let mut lsa_list = vec![];
                 for lsa in &self.lsa_list {
-                    for (lsid, adv_router) in &queries {
-                        if lsa.get_hdr().link_state_id == *lsid
+                    for (ls_type, lsid, adv_router) in &queries {
+                        if lsa.get_hdr().ls_type == *ls_type
+                            && lsa.get_hdr().link_state_id == *lsid
                             && lsa.get_hdr().advertising_router == *adv_router
                         {
                             lsa_list.push(lsa.clone());
@@ -185,6 +330,7 @@ impl LinkStateDatabase {
                 DatabaseResponse::LsaList(lsa_list)
             }
             DatabaseRequestType::RemoveLsa(hdr) => {
+                // a newer instance may have replaced the one being removed
                 let lsa_index = self.lsa_list.iter().position(|x| x.get_hdr() == &hdr);
                 recalculate_needed = true;
                 match lsa_index {
@@ -195,47 +341,391 @@ impl LinkStateDatabase {
                     None => DatabaseResponse::NotFound,
                 }
             }
-            DatabaseRequestType::GenRouterLsa(link, int_addr) => {
-                // change or add the link to the hashmap
-                self.each_int_link.insert(int_addr, link);
-                let lsa = self.make_router_lsa(self.each_int_link.values().cloned().collect());
-                let old_index = self
-                    .lsa_list
-                    .iter()
-                    .position(|x| x.same_ids(&lsa.get_hdr()));
+            DatabaseRequestType::GenRouterLsa(desc, int_name) => {
+                // change or add the interface to the hashmap, interfaces that
+                // went down are no longer described
+                if desc.state == InterfaceState::Down {
+                    self.each_int_link.remove(&int_name);
+                } else {
+                    self.each_int_link.insert(int_name, desc);
+                }
+                self.update_mpls();
+                let lsa = self.make_router_lsa(self.make_router_links());
+                let old_index = self.lsa_list.iter().position(|x| x.same_ids(lsa.get_hdr()));
                 // clear the old one
-                self.lsa_list.retain(|x| !x.same_ids(&lsa.get_hdr()));
+                self.lsa_list.retain(|x| !x.same_ids(lsa.get_hdr()));
                 self.cur_lsa_seq_num += 1;
                 self.lsa_list.push(lsa.clone());
                 recalculate_needed = true;
                 println!("Router LSA generated: {:?}", lsa);
-                match old_index {
-                    Some(_) => DatabaseResponse::Lsa(lsa),
-                    None => DatabaseResponse::UpdateDone,
+                // extended links come and go with the adjacencies
+                let mut lsas = self.originate_opaque_lsas();
+                if old_index.is_some() {
+                    lsas.insert(0, lsa);
+                }
+                DatabaseResponse::LsaList(lsas)
+            }
+            DatabaseRequestType::GenNetworkLsa(int_addr, int_mask, attached_routers) => {
+                let lsa = self.make_network_lsa(int_addr, int_mask, attached_routers);
+                let old_index = self.lsa_list.iter().position(|x| x.same_ids(lsa.get_hdr()));
+                // only re-originate when the mask or the members changed
+                let unchanged = match (old_index.map(|i| &self.lsa_list[i]), &lsa) {
+                    (Some(Lsa::LsaNetwork(old)), Lsa::LsaNetwork(new)) => {
+                        old.network_mask == new.network_mask
+                            && old.attached_routers == new.attached_routers
+                            && old.header.age < crate::config::MAX_AGE as u16
+                    }
+                    _ => false,
+                };
+                if unchanged {
+                    DatabaseResponse::UpdateDone
+                } else {
+                    // replace the previous instance
+                    match old_index {
+                        Some(i) => self.lsa_list[i] = lsa.clone(),
+                        None => self.lsa_list.push(lsa.clone()),
+                    }
+                    self.cur_lsa_seq_num += 1;
+                    recalculate_needed = true;
+                    println!("Network LSA generated: {:?}", lsa);
+                    DatabaseResponse::Lsa(lsa)
                 }
             }
-            DatabaseRequestType::GenNetworkLsa(int_addr, int_mask, neighbors) => {
-                let lsa = self.make_network_lsa(int_addr, int_mask, neighbors);
-                let old_index = self
+            DatabaseRequestType::ChangeRouterId(router_id) => {
+                let old_id = self.global_config.router_id;
+                let (mut flushed, kept): (Vec<Lsa>, Vec<Lsa>) = self
                     .lsa_list
-                    .iter()
-                    .position(|x| x.same_ids(&lsa.get_hdr()));
-                self.cur_lsa_seq_num += 1;
-                self.lsa_list.push(lsa.clone());
+                    .drain(..)
+                    .partition(|x| x.get_hdr().advertising_router == old_id);
+                self.lsa_list = kept;
+                for lsa in flushed.iter_mut() {
+                    lsa.get_mut_hdr().age = crate::config::MAX_AGE as u16;
+                }
+                self.global_config.router_id = router_id;
+                self.cur_lsa_seq_num = crate::config::INIT_SEQ_NUM_LSA;
+                self.self_lsa_conflicts.clear();
                 recalculate_needed = true;
-                match old_index {
-                    Some(_) => DatabaseResponse::Lsa(lsa),
-                    None => DatabaseResponse::UpdateDone,
+                println!(
+                    "Router ID changed from {} to {}, {} LSAs flushed",
+                    Ipv4Addr::from(old_id),
+                    Ipv4Addr::from(router_id),
+                    flushed.len()
+                );
+                DatabaseResponse::LsaList(flushed)
+            }
+            DatabaseRequestType::Shutdown => {
+                let router_id = self.global_config.router_id;
+                let mut flushed = vec![];
+                for lsa in self.lsa_list.iter_mut() {
+                    let hdr = lsa.get_mut_hdr();
+                    if hdr.advertising_router == router_id && hdr.age < MAX_AGE as u16 {
+                        hdr.age = MAX_AGE as u16;
+                        flushed.push(lsa.clone());
+                    }
+                }
+                self.shutting_down = true;
+                kernel::sync_routes(&mut self.route_table.installed, vec![]);
+                kernel::sync_mpls_routes(&mut self.route_table.installed_mpls, vec![]);
+                let tables = self.topology_tables.values_mut();
+                for table in tables.chain(self.flex_algo_tables.values_mut()) {
+                    kernel::sync_routes(&mut table.installed, vec![]);
+                }
+                println!("Shutting down, {} LSAs flushed", flushed.len());
+                DatabaseResponse::LsaList(flushed)
+            }
+            DatabaseRequestType::SetStubRouter(stub_router) => {
+                if stub_router != self.stub_router && !self.each_int_link.is_empty() {
+                    self.stub_router = stub_router;
+                    let lsa = self.make_router_lsa(self.make_router_links());
+                    self.lsa_list.retain(|x| !x.same_ids(lsa.get_hdr()));
+                    self.cur_lsa_seq_num += 1;
+                    self.lsa_list.push(lsa.clone());
+                    recalculate_needed = true;
+                    println!("Router LSA generated: {:?}", lsa);
+                    DatabaseResponse::Lsa(lsa)
+                } else {
+                    self.stub_router = stub_router;
+                    DatabaseResponse::UpdateDone
                 }
             }
+            DatabaseRequestType::SelfOriginatedLsa(lsa) => {
+                recalculate_needed = true;
+                self.handle_self_originated(lsa)
+            }
+            DatabaseRequestType::FlushNetworkLsa(int_addr) => {
+                // Premature aging, the MaxAge copy stays in the database until
+                // the interface has it acknowledged and asks for its removal
+                // (RFC 2328 14)
+                let router_id = self.global_config.router_id;
+                match self.lsa_list.iter().position(|x| {
+                    x.get_hdr().ls_type == LsaType::LsaNetwork as u8
+                        && x.get_hdr().link_state_id == ipv4_to_bits(int_addr)
+                        && x.get_hdr().advertising_router == router_id
+                        && x.get_hdr().age < crate::config::MAX_AGE as u16
+                }) {
+                    Some(i) => {
+                        self.lsa_list[i].get_mut_hdr().age = crate::config::MAX_AGE as u16;
+                        let lsa = self.lsa_list[i].clone();
+                        recalculate_needed = true;
+                        println!("Network LSA flushed: {:?}", lsa.get_hdr());
+                        DatabaseResponse::Lsa(lsa)
+                    }
+                    None => DatabaseResponse::NotFound,
+                }
+            }
+            DatabaseRequestType::SetNodeInfo(node_info) => {
+                self.node_info = node_info;
+                let lsas = self.originate_opaque_lsas();
+                recalculate_needed = !lsas.is_empty();
+                DatabaseResponse::LsaList(lsas)
+            }
+            DatabaseRequestType::QueryHostnames => DatabaseResponse::Hostnames(self.hostnames()),
+            DatabaseRequestType::ShowDatabase => DatabaseResponse::Text(self.show_database()),
+            DatabaseRequestType::ShowRoutes => DatabaseResponse::Text(self.show_routes()),
         };
         // TODO: check if any LSA change occurs, if so, recalculate routing
-        if recalculate_needed {
+        if recalculate_needed && !self.shutting_down {
+            self.update_router_infos();
             self.update_route_table();
         }
         response
     }
 
+    // Either re-originate our current instance with a higher sequence number,
+    // or flush the received one if we no longer originate it. The returned
+    // LSA has to be flooded.
+    fn is_own_network_lsa(&self, hdr: &LsaHeader) -> bool {
+        hdr.ls_type == LsaType::LsaNetwork as u8
+            && self
+                .each_int_link
+                .values()
+                .any(|x| ipv4_to_bits(x.addr) == hdr.link_state_id)
+    }
+
+    fn handle_self_originated(&mut self, lsa: Lsa) -> DatabaseResponse {
+        let hdr = lsa.get_hdr().clone();
+        // there is no newer instance than MaxSequenceNumber, the received one
+        // is flushed and we start over from InitialSequenceNumber (12.1.6)
+        if hdr.sequence_number as i32 == MAX_SEQ_NUM_LSA {
+            let mut flushed = lsa;
+            flushed.get_mut_hdr().age = MAX_AGE as u16;
+            match self.lsa_list.iter().position(|x| x.same_ids(&hdr)) {
+                Some(i) => self.lsa_list[i] = flushed.clone(),
+                None => self.lsa_list.push(flushed.clone()),
+            }
+            self.cur_lsa_seq_num = crate::config::INIT_SEQ_NUM_LSA;
+            println!(
+                "Self-originated LSA at MaxSequenceNumber flushed: {:?}",
+                hdr
+            );
+            return DatabaseResponse::Lsa(flushed);
+        }
+        // our sequence numbers continue after the received one
+        let next_seq = (hdr.sequence_number as i32).wrapping_add(1);
+        if next_seq > self.cur_lsa_seq_num {
+            self.cur_lsa_seq_num = next_seq;
+        }
+        let ours = self.lsa_list.iter().position(|x| x.same_ids(&hdr));
+        let still_originated = match lsa {
+            Lsa::LsaRouter(_) => !self.each_int_link.is_empty(),
+            Lsa::LsaNetwork(_) => {
+                hdr.advertising_router == self.global_config.router_id
+                    && self.each_int_link.values().any(|x| {
+                        x.state == InterfaceState::DR && ipv4_to_bits(x.addr) == hdr.link_state_id
+                    })
+            }
+            // the SR LSAs we no longer want are flushed when they go
+            Lsa::LsaOpaque(_) => true,
+            _ => false,
+        };
+        match ours {
+            Some(i) if still_originated => {
+                if !same_contents(&self.lsa_list[i], &lsa) {
+                    self.note_self_lsa_conflict();
+                }
+                let mut new = self.lsa_list[i].clone();
+                new.get_mut_hdr().age = 0;
+                new.get_mut_hdr().sequence_number = self.cur_lsa_seq_num as u32;
+                new.set_checksum_length();
+                self.cur_lsa_seq_num += 1;
+                self.lsa_list[i] = new.clone();
+                println!("Self-originated LSA re-originated: {:?}", new.get_hdr());
+                DatabaseResponse::Lsa(new)
+            }
+            _ => {
+                let mut flushed = lsa;
+                flushed.get_mut_hdr().age = crate::config::MAX_AGE as u16;
+                self.lsa_list.retain(|x| !x.same_ids(&hdr));
+                println!("Stale self-originated LSA flushed: {:?}", hdr);
+                DatabaseResponse::Lsa(flushed)
+            }
+        }
+    }
+
+    // Two routers with the same ID keep replacing each other's LSAs, after a
+    // restart we only see our old instance once
+    fn note_self_lsa_conflict(&mut self) {
+        let now = std::time::Instant::now();
+        self.self_lsa_conflicts
+            .retain(|t| now.duration_since(*t).as_secs() < DUP_ROUTER_ID_WINDOW);
+        self.self_lsa_conflicts.push(now);
+        if self.self_lsa_conflicts.len() >= DUP_ROUTER_ID_THRESHOLD {
+            eprintln!(
+                "Possible duplicate router ID {}: our LSAs were replaced {} times in {} seconds",
+                Ipv4Addr::from(self.global_config.router_id),
+                self.self_lsa_conflicts.len(),
+                DUP_ROUTER_ID_WINDOW
+            );
+        }
+    }
+
+    // Describe every interface of the router (RFC 2328 12.4.1)
+    fn make_router_links(&self) -> Vec<LsaRouterLink> {
+        let mut names: Vec<&String> = self.each_int_link.keys().collect();
+        names.sort();
+        let mut links = vec![];
+        for name in names {
+            let desc = &self.each_int_link[name];
+            let first = links.len();
+            let stub = |link_id: Ipv4Addr, link_data: Ipv4Addr, metric: u16| {
+                let mut link = LsaRouterLink::new(metric);
+                link.link_type = LinkType::Stub as u8;
+                link.link_id = ipv4_to_bits(link_id);
+                link.link_data = ipv4_to_bits(link_data);
+                link
+            };
+            let host_mask = Ipv4Addr::new(255, 255, 255, 255);
+            if desc.state == InterfaceState::Loopback {
+                // loopback addresses are advertised as host routes
+                links.push(stub(desc.addr, host_mask, 0));
+                for net in desc.secondary_addrs.iter() {
+                    links.push(stub(net.ip(), host_mask, 0));
+                }
+                self.add_topology_metrics(&mut links[first..], desc);
+                continue;
+            }
+            let subnet = Ipv4Addr::from(ipv4_to_bits(desc.addr) & ipv4_to_bits(desc.mask));
+            match desc.int_type {
+                _ if desc.passive => links.push(stub(subnet, desc.mask, desc.cost)),
+                // unnumbered links are identified by their ifIndex (RFC 2328 12.4.1.1),
+                // the borrowed address is advertised by the interface owning it
+                InterfaceType::P2P if desc.unnumbered => {
+                    for (nbr_id, _) in desc.full_nbrs.iter() {
+                        let mut link = LsaRouterLink::new(desc.cost);
+                        link.link_type = LinkType::P2P as u8;
+                        link.link_id = *nbr_id;
+                        link.link_data = desc.ifindex;
+                        links.push(link);
+                    }
+                }
+                InterfaceType::P2P => {
+                    for (nbr_id, nbr_ip) in desc.full_nbrs.iter() {
+                        let mut link = LsaRouterLink::new(desc.cost);
+                        link.link_type = LinkType::P2P as u8;
+                        link.link_id = *nbr_id;
+                        link.link_data = ipv4_to_bits(desc.addr);
+                        links.push(link);
+                        if desc.mask == host_mask {
+                            // no subnet to advertise, add a host route to the neighbor
+                            links.push(stub(*nbr_ip, host_mask, desc.cost));
+                        }
+                    }
+                    if desc.mask != host_mask {
+                        links.push(stub(subnet, desc.mask, desc.cost));
+                    }
+                }
+                InterfaceType::Broadcast | InterfaceType::NBMA => {
+                    if desc.transit {
+                        let mut link = LsaRouterLink::new(desc.cost);
+                        link.link_type = LinkType::Transit as u8;
+                        link.link_id = ipv4_to_bits(desc.dr);
+                        link.link_data = ipv4_to_bits(desc.addr);
+                        links.push(link);
+                    } else {
+                        links.push(stub(subnet, desc.mask, desc.cost));
+                    }
+                }
+                InterfaceType::P2MP => {
+                    for (nbr_id, _) in desc.full_nbrs.iter() {
+                        let mut link = LsaRouterLink::new(desc.cost);
+                        link.link_type = LinkType::P2P as u8;
+                        link.link_id = *nbr_id;
+                        link.link_data = ipv4_to_bits(desc.addr);
+                        links.push(link);
+                    }
+                    links.push(stub(desc.addr, host_mask, 0));
+                }
+                InterfaceType::Virtual => {
+                    for (nbr_id, _) in desc.full_nbrs.iter() {
+                        let mut link = LsaRouterLink::new(desc.cost);
+                        link.link_type = LinkType::Virtual as u8;
+                        link.link_id = *nbr_id;
+                        link.link_data = ipv4_to_bits(desc.addr);
+                        links.push(link);
+                    }
+                }
+            }
+            // secondary subnets are always stubs
+            for net in desc.secondary_addrs.iter() {
+                links.push(stub(net.network(), net.mask(), desc.cost));
+            }
+            self.add_topology_metrics(&mut links[first..], desc);
+        }
+        if self.stub_router {
+            for link in links
+                .iter_mut()
+                .filter(|x| x.link_type != LinkType::Stub as u8)
+            {
+                link.metric = crate::config::MAX_LINK_METRIC;
+                for tos in link.tos_list.iter_mut() {
+                    tos.metric = crate::config::MAX_LINK_METRIC;
+                }
+            }
+        }
+        links
+    }
+
+    // Put the links of an interface in the other topologies it has a cost
+    // in (RFC 4915 3.2), what costs nothing in the default topology costs
+    // nothing in any
+    fn add_topology_metrics(&self, links: &mut [LsaRouterLink], desc: &RouterLsaIntDesc) {
+        for link in links.iter_mut() {
+            for (id, cost) in desc.topology_costs.iter() {
+                if self.global_config.topology(*id).is_none() {
+                    continue;
+                }
+                link.tos_list.push(LsaRouterLinkTos {
+                    tos: *id,
+                    reserved: 0,
+                    metric: if link.metric == 0 { 0 } else { *cost },
+                });
+            }
+            link.tos_num = link.tos_list.len() as u8;
+        }
+    }
+
+    // the V, E and B bits of the router LSA
+    fn router_lsa_flags(&self) -> u16 {
+        let mut flags = 0;
+        let mut areas: Vec<u32> = self.each_int_link.values().map(|x| x.area_id).collect();
+        areas.sort();
+        areas.dedup();
+        if areas.len() > 1 {
+            flags |= ROUTER_FLAG_B;
+        }
+        if self.global_config.as_boundary_router {
+            flags |= ROUTER_FLAG_E;
+        }
+        if self
+            .each_int_link
+            .values()
+            .any(|x| x.int_type == InterfaceType::Virtual && !x.full_nbrs.is_empty())
+        {
+            flags |= ROUTER_FLAG_V;
+        }
+        flags
+    }
+
     fn make_router_lsa(&self, links: Vec<LsaRouterLink>) -> Lsa {
         let mut lsa = Lsa::LsaRouter(LsaRouter {
             header: LsaHeader {
@@ -248,7 +738,7 @@ impl LinkStateDatabase {
                 checksum: 0,
                 length: 0,
             },
-            flags: 0,
+            flags: self.router_lsa_flags(),
             num_links: links.len() as u16,
             links,
         });
@@ -256,12 +746,7 @@ impl LinkStateDatabase {
         lsa
     }
 
-    fn make_network_lsa(
-        &self,
-        int_addr: Ipv4Addr,
-        int_mask: Ipv4Addr,
-        neighbors: Vec<Ipv4Addr>,
-    ) -> Lsa {
+    fn make_network_lsa(&self, int_addr: Ipv4Addr, int_mask: Ipv4Addr, neighbors: Vec<u32>) -> Lsa {
         let mut lsa = Lsa::LsaNetwork(LsaNetwork {
             header: LsaHeader {
                 age: 0,
@@ -274,7 +759,7 @@ impl LinkStateDatabase {
                 length: 0,
             },
             network_mask: ipv4_to_bits(int_mask),
-            attached_routers: neighbors.iter().map(|x| ipv4_to_bits(*x)).collect(),
+            attached_routers: neighbors,
         });
         lsa.set_checksum_length();
         lsa
@@ -0,0 +1,619 @@
+// The OSPFv3 link state database. Link-LSAs have link-local flooding scope
+// and are kept per interface, everything else is kept for the whole area.
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use pnet::ipnetwork::Ipv6Network;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+
+use crate::config::{Config, INIT_SEQ_NUM_LSA, MAX_AGE, MAX_SEQ_NUM_LSA};
+use crate::interface::{InterfaceState, InterfaceType};
+use crate::kernel;
+use crate::lsa_v3::*;
+use crate::packets_v3::{OPTION_V3_E, OPTION_V3_R, OPTION_V3_V6};
+use crate::route_v3::RouteTableV3;
+
+#[derive(Debug)]
+pub struct LinkStateDatabaseV3 {
+    // interfaces described in our LSAs, keyed by interface name
+    pub each_int: HashMap<String, InterfaceDescV3>,
+    // LSAs with area or AS flooding scope
+    pub lsa_list: Vec<LsaV3>,
+    // Link-LSAs, keyed by the name of the interface they were received on
+    pub link_lsas: HashMap<String, Vec<LsaV3>>,
+    pub global_config: Config,
+    pub request_channel: mpsc::Receiver<DatabaseV3Request>,
+    pub last_iter_instant: std::time::Instant,
+    pub cur_lsa_seq_num: i32,
+    pub route_table: RouteTableV3,
+    // advertise MaxLinkMetric on our links (RFC 6987)
+    pub stub_router: bool,
+    // our LSAs are flushed, nothing is originated or computed any more
+    pub shutting_down: bool,
+}
+
+#[derive(Debug)]
+pub struct DatabaseV3Request {
+    pub request_type: DatabaseV3RequestType,
+    pub reply_channel: oneshot::Sender<DatabaseV3Response>,
+}
+
+// Requests naming an interface need it to find the Link-LSAs of that link
+#[derive(Debug)]
+pub enum DatabaseV3RequestType {
+    QueryConfig,
+    ChangeConfig(Config),
+    QueryLsaByHdr(String, LsaHeaderV3),
+    QueryMultiLsa(String, Vec<(u16, u32, u32)>),
+    AddOrUpdateLsa(String, LsaV3),
+    // every LSA to describe in the DBDs sent on the interface
+    QueryAllLsa(String),
+    // the interface changed, our LSAs are re-originated as needed
+    UpdateInterface(String, InterfaceDescV3),
+    // a newer instance of one of our own LSAs was received (RFC 2328 13.4)
+    SelfOriginatedLsa(String, LsaV3),
+    // enter or leave stub router mode
+    SetStubRouter(bool),
+    // flush our LSAs and withdraw our kernel routes before exiting
+    Shutdown,
+}
+
+#[derive(Debug)]
+pub enum DatabaseV3Response {
+    UpdateDone,
+    NotFound,
+    Config(Config),
+    Lsa(LsaV3),
+    LsaList(Vec<LsaV3>),
+}
+
+// What the database needs to know about an interface to originate the
+// router, network, Link and Intra-Area-Prefix LSAs (RFC 5340 4.4.3)
+#[derive(Debug, Clone)]
+pub struct InterfaceDescV3 {
+    pub int_type: InterfaceType,
+    pub state: InterfaceState,
+    pub area_id: u32,
+    pub interface_id: u32,
+    pub link_local: Ipv6Addr,
+    pub prefixes: Vec<Ipv6Network>,
+    pub cost: u16,
+    pub priority: u8,
+    pub passive: bool,
+    // router ID of the DR, and whether the link is a transit network
+    pub dr: Ipv4Addr,
+    pub transit: bool,
+    // router ID, interface ID and link-local address of every neighbor in
+    // state 2-Way or higher, and whether it's fully adjacent
+    pub nbrs: Vec<NeighborDescV3>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NeighborDescV3 {
+    pub router_id: u32,
+    pub interface_id: u32,
+    pub link_local: Ipv6Addr,
+    pub full: bool,
+}
+
+// the part of an LSA after the header
+fn lsa_body(lsa: &LsaV3) -> Vec<u8> {
+    lsa.encode()[LSA_V3_HDR_LEN..].to_vec()
+}
+
+fn options_hi_lo(options: u32) -> (u8, u16) {
+    ((options >> 16) as u8, options as u16)
+}
+
+impl LinkStateDatabaseV3 {
+    pub fn from(config: Config, request_channel: mpsc::Receiver<DatabaseV3Request>) -> Self {
+        LinkStateDatabaseV3 {
+            each_int: HashMap::new(),
+            lsa_list: vec![],
+            link_lsas: HashMap::new(),
+            global_config: config,
+            request_channel,
+            last_iter_instant: std::time::Instant::now(),
+            cur_lsa_seq_num: crate::config::INIT_SEQ_NUM_LSA,
+            route_table: RouteTableV3::new(),
+            stub_router: false,
+            shutting_down: false,
+        }
+    }
+
+    pub async fn database_thread(&mut self) {
+        while let Some(req) = self.request_channel.recv().await {
+            let iter_duration = self.last_iter_instant.elapsed();
+            self.last_iter_instant = std::time::Instant::now();
+            self.lsa_aging(iter_duration);
+            let reply = self.handle_request(req.request_type);
+            if let Err(e) = req.reply_channel.send(reply) {
+                eprintln!("Error sending reply: {:?}", e);
+            }
+        }
+    }
+
+    fn lsa_aging(&mut self, iter_duration: std::time::Duration) {
+        let secs = iter_duration.as_secs() as u32;
+        let lists = std::iter::once(&mut self.lsa_list).chain(self.link_lsas.values_mut());
+        for list in lists {
+            for lsa in list.iter_mut() {
+                let age = lsa.get_hdr().age as u32;
+                lsa.get_mut_hdr().age = std::cmp::min(age + secs, crate::config::MAX_AGE) as u16;
+            }
+        }
+    }
+
+    // the list an LSA received on the named interface belongs to
+    fn list_for(&mut self, name: &str, hdr: &LsaHeaderV3) -> &mut Vec<LsaV3> {
+        if hdr.link_local_scope() {
+            self.link_lsas.entry(name.to_string()).or_default()
+        } else {
+            &mut self.lsa_list
+        }
+    }
+
+    fn handle_request(&mut self, req: DatabaseV3RequestType) -> DatabaseV3Response {
+        let mut recalculate_needed = false;
+        let response = match req {
+            DatabaseV3RequestType::QueryConfig => DatabaseV3Response::Config(self.global_config),
+            DatabaseV3RequestType::ChangeConfig(new_config) => {
+                if new_config.router_id != self.global_config.router_id {
+                    // the interfaces restart and describe themselves again
+                    // under the new ID, the old LSAs age out at the neighbors
+                    let old_id = self.global_config.router_id;
+                    self.lsa_list
+                        .retain(|x| x.get_hdr().advertising_router != old_id);
+                    for list in self.link_lsas.values_mut() {
+                        list.retain(|x| x.get_hdr().advertising_router != old_id);
+                    }
+                    self.cur_lsa_seq_num = crate::config::INIT_SEQ_NUM_LSA;
+                    recalculate_needed = true;
+                }
+                self.global_config = new_config;
+                DatabaseV3Response::UpdateDone
+            }
+            DatabaseV3RequestType::QueryLsaByHdr(name, hdr) => {
+                match self.list_for(&name, &hdr).iter().find(|x| x.same_ids(&hdr)) {
+                    Some(l) => DatabaseV3Response::Lsa(l.clone()),
+                    None => DatabaseV3Response::NotFound,
+                }
+            }
+            DatabaseV3RequestType::QueryMultiLsa(name, queries) => {
+                let mut lsa_list = vec![];
+                for (ls_type, lsid, adv_router) in queries {
+                    let hdr = LsaHeaderV3 {
+                        age: 0,
+                        ls_type,
+                        link_state_id: lsid,
+                        advertising_router: adv_router,
+                        sequence_number: 0,
+                        checksum: 0,
+                        length: 0,
+                    };
+                    if let Some(l) = self.list_for(&name, &hdr).iter().find(|x| x.same_ids(&hdr)) {
+                        lsa_list.push(l.clone());
+                    }
+                }
+                DatabaseV3Response::LsaList(lsa_list)
+            }
+            DatabaseV3RequestType::AddOrUpdateLsa(name, lsa) => {
+                let hdr = lsa.get_hdr().clone();
+                let list = self.list_for(&name, &hdr);
+                match list.iter().position(|x| x.same_ids(&hdr)) {
+                    Some(i) => list[i] = lsa,
+                    None => list.push(lsa),
+                }
+                recalculate_needed = true;
+                // the prefixes of a Link-LSA go into our network's
+                // Intra-Area-Prefix LSA if we are the DR
+                DatabaseV3Response::LsaList(self.originate())
+            }
+            DatabaseV3RequestType::QueryAllLsa(name) => {
+                let mut lsa_list = self.lsa_list.clone();
+                lsa_list.extend(self.link_lsas.get(&name).cloned().unwrap_or_default());
+                DatabaseV3Response::LsaList(lsa_list)
+            }
+            DatabaseV3RequestType::UpdateInterface(name, desc) => {
+                if desc.state == InterfaceState::Down {
+                    self.each_int.remove(&name);
+                    self.link_lsas.remove(&name);
+                } else {
+                    self.each_int.insert(name, desc);
+                }
+                recalculate_needed = true;
+                DatabaseV3Response::LsaList(self.originate())
+            }
+            DatabaseV3RequestType::SelfOriginatedLsa(name, lsa) => {
+                recalculate_needed = true;
+                self.handle_self_originated(&name, lsa)
+            }
+            DatabaseV3RequestType::Shutdown => {
+                let router_id = self.global_config.router_id;
+                let mut flushed = vec![];
+                let lists = std::iter::once(&mut self.lsa_list).chain(self.link_lsas.values_mut());
+                for lsa in lists.flat_map(|x| x.iter_mut()) {
+                    let hdr = lsa.get_mut_hdr();
+                    if hdr.advertising_router == router_id && hdr.age < MAX_AGE as u16 {
+                        hdr.age = MAX_AGE as u16;
+                        flushed.push(lsa.clone());
+                    }
+                }
+                self.shutting_down = true;
+                kernel::sync_routes(&mut self.route_table.installed, vec![]);
+                DatabaseV3Response::LsaList(flushed)
+            }
+            DatabaseV3RequestType::SetStubRouter(stub_router) => {
+                self.stub_router = stub_router;
+                recalculate_needed = true;
+                DatabaseV3Response::LsaList(self.originate())
+            }
+        };
+        if recalculate_needed && !self.shutting_down {
+            self.update_route_table();
+        }
+        response
+    }
+
+    // Bump our sequence number past the received instance, and either
+    // re-originate our copy or flush the received one
+    fn handle_self_originated(&mut self, name: &str, lsa: LsaV3) -> DatabaseV3Response {
+        let hdr = lsa.get_hdr().clone();
+        // there is no newer instance than MaxSequenceNumber, the received one
+        // is flushed and we start over from InitialSequenceNumber (12.1.6)
+        if hdr.sequence_number as i32 == MAX_SEQ_NUM_LSA {
+            let mut flushed = lsa;
+            flushed.get_mut_hdr().age = MAX_AGE as u16;
+            let list = self.list_for(name, &hdr);
+            match list.iter().position(|x| x.same_ids(&hdr)) {
+                Some(i) => list[i] = flushed.clone(),
+                None => list.push(flushed.clone()),
+            }
+            self.cur_lsa_seq_num = INIT_SEQ_NUM_LSA;
+            println!(
+                "Self-originated LSA at MaxSequenceNumber flushed: {:?}",
+                hdr
+            );
+            return DatabaseV3Response::Lsa(flushed);
+        }
+        // our sequence numbers continue after the received one
+        let next_seq = (hdr.sequence_number as i32).wrapping_add(1);
+        if next_seq > self.cur_lsa_seq_num {
+            self.cur_lsa_seq_num = next_seq;
+        }
+        let seq = self.cur_lsa_seq_num;
+        let list = self.list_for(name, &hdr);
+        match list.iter().position(|x| x.same_ids(&hdr)) {
+            Some(i) => {
+                let mut new = list[i].clone();
+                new.get_mut_hdr().age = 0;
+                new.get_mut_hdr().sequence_number = seq as u32;
+                new.set_checksum_length();
+                list[i] = new.clone();
+                self.bump_seq_num();
+                println!("Self-originated LSA re-originated: {:?}", new.get_hdr());
+                DatabaseV3Response::Lsa(new)
+            }
+            None => {
+                let mut flushed = lsa;
+                flushed.get_mut_hdr().age = crate::config::MAX_AGE as u16;
+                println!("Stale self-originated LSA flushed: {:?}", hdr);
+                DatabaseV3Response::Lsa(flushed)
+            }
+        }
+    }
+
+    fn header(&self, ls_type: LsaTypeV3, link_state_id: u32) -> LsaHeaderV3 {
+        LsaHeaderV3 {
+            age: 0,
+            ls_type: ls_type as u16,
+            link_state_id,
+            advertising_router: self.global_config.router_id,
+            sequence_number: 0,
+            checksum: 0,
+            length: 0,
+        }
+    }
+
+    fn options(&self) -> u32 {
+        let mut options = (OPTION_V3_V6 | OPTION_V3_R) as u32;
+        if self.global_config.options & crate::packets::OPTION_E != 0 {
+            options |= OPTION_V3_E as u32;
+        }
+        options
+    }
+
+    fn sorted_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.each_int.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // Every LSA we should be originating right now, with the name of the
+    // interface for the Link-LSAs
+    fn wanted_lsas(&self) -> Vec<(Option<String>, LsaV3)> {
+        let (options_hi, options) = options_hi_lo(self.options());
+        let mut wanted = vec![];
+        let mut links = vec![];
+        let mut stub_prefixes = vec![];
+        for name in self.sorted_names() {
+            let desc = &self.each_int[&name];
+            if desc.state == InterfaceState::Loopback {
+                // loopback addresses are advertised as host routes
+                for net in desc.prefixes.iter() {
+                    let host = Ipv6Network::new(net.ip(), 128).unwrap();
+                    let mut prefix = PrefixV3::from_network(&host, 0);
+                    prefix.prefix_options |= PREFIX_OPTION_LA;
+                    stub_prefixes.push(prefix);
+                }
+                continue;
+            }
+            if !desc.passive {
+                // Link-LSAs tell the neighbors our link-local address and prefixes
+                wanted.push((
+                    Some(name.clone()),
+                    LsaV3::Link(LsaLink {
+                        header: self.header(LsaTypeV3::Link, desc.interface_id),
+                        router_priority: desc.priority,
+                        options_hi,
+                        options,
+                        link_local_addr: ipv6_to_words(desc.link_local),
+                        num_prefixes: desc.prefixes.len() as u32,
+                        prefixes: desc
+                            .prefixes
+                            .iter()
+                            .map(|x| PrefixV3::from_network(x, 0))
+                            .collect(),
+                    }),
+                ));
+            }
+            let link = |link_type: u8, nbr_interface_id: u32, nbr_router_id: u32| LsaRouterLinkV3 {
+                link_type,
+                reserved: 0,
+                // every link of an OSPFv3 router LSA is a non-stub link
+                metric: if self.stub_router {
+                    crate::config::MAX_LINK_METRIC
+                } else {
+                    desc.cost
+                },
+                interface_id: desc.interface_id,
+                nbr_interface_id,
+                nbr_router_id,
+            };
+            match desc.int_type {
+                _ if desc.passive => {}
+                InterfaceType::P2P | InterfaceType::P2MP | InterfaceType::Virtual => {
+                    let link_type = if desc.int_type == InterfaceType::Virtual {
+                        LinkTypeV3::Virtual as u8
+                    } else {
+                        LinkTypeV3::P2P as u8
+                    };
+                    for nbr in desc.nbrs.iter().filter(|x| x.full) {
+                        links.push(link(link_type, nbr.interface_id, nbr.router_id));
+                    }
+                }
+                InterfaceType::Broadcast | InterfaceType::NBMA => {
+                    if desc.transit {
+                        // the network is identified by the interface ID of the DR
+                        let dr_interface_id = if u32::from(desc.dr) == self.global_config.router_id
+                        {
+                            desc.interface_id
+                        } else {
+                            desc.nbrs
+                                .iter()
+                                .find(|x| x.router_id == u32::from(desc.dr))
+                                .map_or(0, |x| x.interface_id)
+                        };
+                        links.push(link(
+                            LinkTypeV3::Transit as u8,
+                            dr_interface_id,
+                            u32::from(desc.dr),
+                        ));
+                    }
+                }
+            }
+            if desc.transit && desc.int_type != InterfaceType::P2P {
+                if u32::from(desc.dr) == self.global_config.router_id {
+                    wanted.extend(self.wanted_network_lsas(&name, desc));
+                }
+            } else {
+                stub_prefixes.extend(
+                    desc.prefixes
+                        .iter()
+                        .map(|x| PrefixV3::from_network(x, desc.cost)),
+                );
+            }
+        }
+        if self.each_int.is_empty() {
+            return wanted;
+        }
+        wanted.push((
+            None,
+            LsaV3::Router(LsaRouterV3 {
+                header: self.header(LsaTypeV3::Router, 0),
+                flags: self.router_lsa_flags(),
+                options_hi,
+                options,
+                links,
+            }),
+        ));
+        if !stub_prefixes.is_empty() {
+            wanted.push((
+                None,
+                LsaV3::IntraAreaPrefix(LsaIntraAreaPrefix {
+                    header: self.header(LsaTypeV3::IntraAreaPrefix, 0),
+                    num_prefixes: stub_prefixes.len() as u16,
+                    ref_ls_type: LsaTypeV3::Router as u16,
+                    ref_link_state_id: 0,
+                    ref_advertising_router: self.global_config.router_id,
+                    prefixes: stub_prefixes,
+                }),
+            ));
+        }
+        wanted
+    }
+
+    // As the DR, the network LSA and the Intra-Area-Prefix LSA carrying the
+    // prefixes of every fully adjacent router on the link (RFC 5340 4.4.3.7)
+    fn wanted_network_lsas(
+        &self,
+        name: &str,
+        desc: &InterfaceDescV3,
+    ) -> Vec<(Option<String>, LsaV3)> {
+        let (options_hi, options) = options_hi_lo(self.options());
+        let full: Vec<&NeighborDescV3> = desc.nbrs.iter().filter(|x| x.full).collect();
+        let mut attached_routers = vec![self.global_config.router_id];
+        attached_routers.extend(full.iter().map(|x| x.router_id));
+        let mut prefixes: Vec<PrefixV3> = desc
+            .prefixes
+            .iter()
+            .map(|x| PrefixV3::from_network(x, 0))
+            .collect();
+        for lsa in self.link_lsas.get(name).into_iter().flatten() {
+            if let LsaV3::Link(l) = lsa {
+                if !full
+                    .iter()
+                    .any(|x| x.router_id == l.header.advertising_router)
+                {
+                    continue;
+                }
+                for p in l.prefixes.iter() {
+                    if p.prefix_options & (PREFIX_OPTION_NU | PREFIX_OPTION_LA) != 0 {
+                        continue;
+                    }
+                    let mut p = p.clone();
+                    p.metric = 0;
+                    if !prefixes.contains(&p) {
+                        prefixes.push(p);
+                    }
+                }
+            }
+        }
+        vec![
+            (
+                None,
+                LsaV3::Network(LsaNetworkV3 {
+                    header: self.header(LsaTypeV3::Network, desc.interface_id),
+                    reserved: 0,
+                    options_hi,
+                    options,
+                    attached_routers,
+                }),
+            ),
+            (
+                None,
+                LsaV3::IntraAreaPrefix(LsaIntraAreaPrefix {
+                    header: self.header(LsaTypeV3::IntraAreaPrefix, desc.interface_id),
+                    num_prefixes: prefixes.len() as u16,
+                    ref_ls_type: LsaTypeV3::Network as u16,
+                    ref_link_state_id: desc.interface_id,
+                    ref_advertising_router: self.global_config.router_id,
+                    prefixes,
+                }),
+            ),
+        ]
+    }
+
+    fn router_lsa_flags(&self) -> u8 {
+        let mut flags = 0;
+        let mut areas: Vec<u32> = self.each_int.values().map(|x| x.area_id).collect();
+        areas.sort();
+        areas.dedup();
+        if areas.len() > 1 {
+            flags |= ROUTER_V3_FLAG_B;
+        }
+        if self.global_config.as_boundary_router {
+            flags |= ROUTER_V3_FLAG_E;
+        }
+        if self
+            .each_int
+            .values()
+            .any(|x| x.int_type == InterfaceType::Virtual && x.nbrs.iter().any(|n| n.full))
+        {
+            flags |= ROUTER_V3_FLAG_V;
+        }
+        flags
+    }
+
+    // the counter never goes past MaxSequenceNumber, the LSA that got it is
+    // flushed before its next instance
+    fn bump_seq_num(&mut self) {
+        self.cur_lsa_seq_num = match self.cur_lsa_seq_num {
+            MAX_SEQ_NUM_LSA => INIT_SEQ_NUM_LSA,
+            seq => seq + 1,
+        };
+    }
+
+    // Bring our own LSAs in line with what the interfaces look like now.
+    // Changed LSAs get a new sequence number, the ones we no longer
+    // originate are flushed; both are returned to be flooded.
+    fn originate(&mut self) -> Vec<LsaV3> {
+        if self.shutting_down {
+            return vec![];
+        }
+        let router_id = self.global_config.router_id;
+        let wanted = self.wanted_lsas();
+        let mut changed = vec![];
+        // flush what's no longer wanted
+        let mut flushed = vec![];
+        let is_wanted = |name: Option<&String>, lsa: &LsaV3| {
+            wanted
+                .iter()
+                .any(|(n, w)| n.as_ref() == name && w.same_ids(lsa.get_hdr()))
+        };
+        self.lsa_list.retain(|x| {
+            let keep = x.get_hdr().advertising_router != router_id || is_wanted(None, x);
+            if !keep {
+                flushed.push(x.clone());
+            }
+            keep
+        });
+        for (name, list) in self.link_lsas.iter_mut() {
+            list.retain(|x| {
+                x.get_hdr().advertising_router != router_id || is_wanted(Some(name), x)
+            });
+        }
+        for mut lsa in flushed {
+            lsa.get_mut_hdr().age = crate::config::MAX_AGE as u16;
+            println!("LSA flushed: {:?}", lsa.get_hdr());
+            changed.push(lsa);
+        }
+        for (name, mut lsa) in wanted {
+            let seq = self.cur_lsa_seq_num;
+            let hdr = lsa.get_hdr().clone();
+            let list = match name {
+                Some(name) => self.link_lsas.entry(name).or_default(),
+                None => &mut self.lsa_list,
+            };
+            let old = list.iter().position(|x| x.same_ids(&hdr));
+            if let Some(i) = old {
+                let old_hdr = list[i].get_hdr();
+                if old_hdr.age < MAX_AGE as u16 {
+                    if lsa_body(&list[i]) == lsa_body(&lsa) {
+                        continue;
+                    }
+                    // nothing is newer than MaxSequenceNumber, flush it first
+                    // and originate again from InitialSequenceNumber (12.1.6)
+                    if old_hdr.sequence_number as i32 == MAX_SEQ_NUM_LSA {
+                        list[i].get_mut_hdr().age = MAX_AGE as u16;
+                        println!("LSA at MaxSequenceNumber flushed: {:?}", list[i].get_hdr());
+                        changed.push(list[i].clone());
+                        self.cur_lsa_seq_num = INIT_SEQ_NUM_LSA;
+                        continue;
+                    }
+                }
+            }
+            lsa.get_mut_hdr().sequence_number = seq as u32;
+            lsa.set_checksum_length();
+            match old {
+                Some(i) => list[i] = lsa.clone(),
+                None => list.push(lsa.clone()),
+            }
+            self.bump_seq_num();
+            println!("LSA originated: {:?}", lsa.get_hdr());
+            changed.push(lsa);
+        }
+        changed
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::Ipv4Addr;
+
+// One router taking part in the DR/BDR election, as seen in its hellos
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ElectionCandidate {
+    pub router_id: u32,
+    pub addr: Ipv4Addr,
+    pub priority: u8,
+    pub declared_dr: Ipv4Addr,
+    pub declared_bdr: Ipv4Addr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ElectionResult {
+    pub dr: Ipv4Addr,
+    pub bdr: Ipv4Addr,
+}
+
+impl ElectionCandidate {
+    fn declares_dr(&self) -> bool {
+        self.declared_dr == self.addr
+    }
+
+    fn declares_bdr(&self) -> bool {
+        self.declared_bdr == self.addr
+    }
+
+    // higher priority wins, ties are broken by the higher router id
+    fn rank(&self) -> (u8, u32) {
+        (self.priority, self.router_id)
+    }
+}
+
+fn best<'a, I>(candidates: I) -> Option<&'a ElectionCandidate>
+where
+    I: Iterator<Item = &'a ElectionCandidate>,
+{
+    candidates.max_by_key(|c| c.rank())
+}
+
+// steps 2 and 3 of RFC 2328 9.4
+fn elect_once(eligible: &[ElectionCandidate]) -> ElectionResult {
+    // 2. the BDR is chosen among the routers not declaring themselves DR,
+    // preferring those declaring themselves BDR
+    let not_dr = || eligible.iter().filter(|c| !c.declares_dr());
+    let bdr = best(not_dr().filter(|c| c.declares_bdr())).or_else(|| best(not_dr()));
+    let bdr = bdr.map_or(Ipv4Addr::from(0), |c| c.addr);
+    // 3. the DR is the best router declaring itself DR, or else the new BDR
+    let dr = best(eligible.iter().filter(|c| c.declares_dr())).map_or(bdr, |c| c.addr);
+    ElectionResult { dr, bdr }
+}
+
+// Elect the DR and BDR of a network per RFC 2328 9.4. `me` is the calculating
+// router with its current DR/BDR, `others` are the neighbors in state 2-Way or
+// higher. Routers with priority 0 are never elected.
+pub fn elect_dr_bdr(me: ElectionCandidate, others: &[ElectionCandidate]) -> ElectionResult {
+    let mut me = me;
+    let eligible = |me: &ElectionCandidate| -> Vec<ElectionCandidate> {
+        others
+            .iter()
+            .chain(std::iter::once(me))
+            .filter(|c| c.priority > 0)
+            .cloned()
+            .collect()
+    };
+    let result = elect_once(&eligible(&me));
+    // 4. if our own role changed, declare the new role and run steps 2 and 3 again
+    let was_dr = me.declares_dr();
+    let was_bdr = me.declares_bdr();
+    let is_dr = result.dr == me.addr;
+    let is_bdr = result.bdr == me.addr;
+    if was_dr != is_dr || was_bdr != is_bdr {
+        me.declared_dr = result.dr;
+        me.declared_bdr = result.bdr;
+        return elect_once(&eligible(&me));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // router N sits at 10.0.0.N, declared DR/BDR 0 means none
+    fn addr(n: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 0, n)
+    }
+
+    fn router(n: u8, priority: u8, dr: u8, bdr: u8) -> ElectionCandidate {
+        let declared = |x: u8| if x == 0 { Ipv4Addr::from(0) } else { addr(x) };
+        ElectionCandidate {
+            router_id: n as u32,
+            addr: addr(n),
+            priority,
+            declared_dr: declared(dr),
+            declared_bdr: declared(bdr),
+        }
+    }
+
+    #[test]
+    fn elect_dr_bdr_table() {
+        let none = Ipv4Addr::from(0);
+        // name, calculating router, its neighbors, expected DR, expected BDR
+        let cases = [
+            (
+                "lone router priority 0",
+                router(1, 0, 0, 0),
+                vec![],
+                none,
+                none,
+            ),
+            (
+                "priority 0 is never elected",
+                router(3, 0, 0, 0),
+                vec![router(1, 1, 0, 0), router(2, 0, 0, 0)],
+                addr(1),
+                addr(1),
+            ),
+            (
+                "lone router becomes DR after step 4",
+                router(1, 1, 0, 0),
+                vec![],
+                addr(1),
+                none,
+            ),
+            (
+                "step 4 re-run hands the BDR to the neighbor",
+                router(2, 1, 0, 0),
+                vec![router(1, 1, 0, 0)],
+                addr(2),
+                addr(1),
+            ),
+            (
+                "declaring both DR and BDR only counts as DR",
+                router(1, 1, 2, 2),
+                vec![router(2, 1, 2, 2), router(3, 1, 2, 2)],
+                addr(2),
+                addr(3),
+            ),
+            (
+                "an existing DR is not preempted",
+                router(3, 10, 0, 0),
+                vec![router(1, 1, 1, 2), router(2, 1, 1, 2)],
+                addr(1),
+                addr(2),
+            ),
+            (
+                "router ID breaks priority ties among BDR claims",
+                router(1, 1, 9, 0),
+                vec![router(9, 1, 9, 0), router(2, 1, 9, 2), router(3, 1, 9, 3)],
+                addr(9),
+                addr(3),
+            ),
+            (
+                "priority beats router ID",
+                router(1, 1, 9, 0),
+                vec![router(9, 1, 9, 0), router(2, 5, 9, 0), router(3, 1, 9, 0)],
+                addr(9),
+                addr(2),
+            ),
+            (
+                "router ID breaks ties between DR claims, the loser isn't BDR",
+                router(1, 1, 0, 0),
+                vec![router(4, 1, 4, 0), router(5, 1, 5, 0)],
+                addr(5),
+                addr(1),
+            ),
+        ];
+        for (name, me, others, dr, bdr) in cases {
+            let result = elect_dr_bdr(me, &others);
+            assert_eq!(result, ElectionResult { dr, bdr }, "{}", name);
+        }
+    }
+}
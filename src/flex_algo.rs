@@ -0,0 +1,447 @@
+// Flexible algorithms (RFC 9350). Routers advertise the definition of an
+// algorithm in their router information LSA and the one with the highest
+// priority in the area is used. The routers taking part in an algorithm
+// compute paths among themselves, over the links the definition admits and
+// with the metric it picks. Link attributes come from extended link LSAs
+// (RFC 8920), every algorithm's routes go to a kernel table of their own.
+use crate::config::{FlexAlgo, FlexAlgoMetric, LinkAttributes};
+use crate::database::LinkStateDatabase;
+use crate::lsa::{LinkType, LsaOpaque, LsaRouterLink, OpaqueTlv};
+use crate::opaque::{read_u32, OPAQUE_EXT_LINK, OPAQUE_ROUTER_INFO, RI_TLV_SR_ALGORITHM};
+use crate::route::RouteTable;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+// the flexible algorithm definition TLV and its sub-TLVs
+const RI_TLV_FAD: u16 = 16;
+const FAD_SUB_TLV_EXCLUDE_ANY: u16 = 1;
+const FAD_SUB_TLV_INCLUDE_ANY: u16 = 2;
+const FAD_SUB_TLV_INCLUDE_ALL: u16 = 3;
+const FAD_SUB_TLV_FLAGS: u16 = 4;
+const CALC_TYPE_SPF: u8 = 0;
+
+const TLV_EXT_LINK: u16 = 1;
+// application specific link attributes, for the flexible algorithms when
+// the X bit is set or no application is named
+const SUB_TLV_ASLA: u16 = 10;
+const ASLA_FLEX_ALGO: u8 = 0x10;
+// the TE link sub-TLVs it carries (RFC 3630, 7308, 7471)
+const ATTR_TE_METRIC: u16 = 5;
+const ATTR_EXT_ADMIN_GROUP: u16 = 26;
+const ATTR_MIN_MAX_DELAY: u16 = 28;
+// delays and TE metrics are capped so path costs can't overflow
+const MAX_FLEX_METRIC: u32 = 0xFF_FFFF;
+
+// A definition found in a router information LSA
+#[derive(Debug, Clone)]
+pub struct FlexAlgoDefinition {
+    pub adv_router: u32,
+    pub id: u8,
+    pub priority: u8,
+    // None when it asks for something we can't compute
+    pub algo: Option<FlexAlgo>,
+}
+
+// What the paths of an algorithm may use, found before each calculation
+#[derive(Debug)]
+pub struct FlexAlgoGraph {
+    pub definition: FlexAlgo,
+    pub participants: HashSet<u32>,
+    // by advertising router, link type, link ID and link data
+    pub links: HashMap<(u32, u8, u32, u32), LinkAttributes>,
+}
+
+fn fad_tlv(algo: &FlexAlgo) -> OpaqueTlv {
+    let mut value = vec![
+        algo.id,
+        algo.metric_type as u8,
+        CALC_TYPE_SPF,
+        algo.priority,
+    ];
+    for (sub_type, groups) in [
+        (FAD_SUB_TLV_EXCLUDE_ANY, algo.exclude_any),
+        (FAD_SUB_TLV_INCLUDE_ANY, algo.include_any),
+        (FAD_SUB_TLV_INCLUDE_ALL, algo.include_all),
+    ] {
+        if groups != 0 {
+            value.extend(OpaqueTlv::new(sub_type, groups.to_be_bytes().to_vec()).encode());
+        }
+    }
+    OpaqueTlv::new(RI_TLV_FAD, value)
+}
+
+// extended admin groups past the first 32 are not supported, a constraint
+// on them can't be met
+fn parse_admin_groups(value: &[u8]) -> Option<u32> {
+    if value.len() < 4 || !value.len().is_multiple_of(4) || value[4..].iter().any(|x| *x != 0) {
+        return None;
+    }
+    Some(read_u32(value))
+}
+
+pub fn parse_definitions(lsa: &LsaOpaque) -> Vec<FlexAlgoDefinition> {
+    let mut definitions = vec![];
+    if lsa.opaque_type() != OPAQUE_ROUTER_INFO {
+        return definitions;
+    }
+    for tlv in lsa.tlvs().iter().filter(|x| x.tlv_type == RI_TLV_FAD) {
+        let value = tlv.value();
+        if value.len() < 4 || value[0] < 128 {
+            continue;
+        }
+        let mut algo = FlexAlgo::new(value[0]);
+        algo.priority = value[3];
+        let mut supported = value[2] == CALC_TYPE_SPF;
+        algo.metric_type = match value[1] {
+            0 => FlexAlgoMetric::Igp,
+            1 => FlexAlgoMetric::Delay,
+            2 => FlexAlgoMetric::Te,
+            _ => {
+                supported = false;
+                FlexAlgoMetric::Igp
+            }
+        };
+        for sub in OpaqueTlv::parse_all(&value[4..]) {
+            let groups = parse_admin_groups(sub.value());
+            match (sub.tlv_type, groups) {
+                (FAD_SUB_TLV_EXCLUDE_ANY, Some(g)) => algo.exclude_any = g,
+                (FAD_SUB_TLV_INCLUDE_ANY, Some(g)) => algo.include_any = g,
+                (FAD_SUB_TLV_INCLUDE_ALL, Some(g)) => algo.include_all = g,
+                // the flags sub-TLV with no flags set asks for nothing
+                (FAD_SUB_TLV_FLAGS, _) if sub.value().iter().all(|x| *x == 0) => {}
+                _ => supported = false,
+            }
+        }
+        definitions.push(FlexAlgoDefinition {
+            adv_router: lsa.header.advertising_router,
+            id: algo.id,
+            priority: algo.priority,
+            algo: supported.then_some(algo),
+        });
+    }
+    definitions
+}
+
+// the algorithms a router takes part in
+pub fn parse_algorithms(lsa: &LsaOpaque) -> Vec<u8> {
+    if lsa.opaque_type() != OPAQUE_ROUTER_INFO {
+        return vec![];
+    }
+    lsa.tlvs()
+        .iter()
+        .find(|x| x.tlv_type == RI_TLV_SR_ALGORITHM)
+        .map_or(vec![], |x| x.value().to_vec())
+}
+
+fn parse_asla(value: &[u8]) -> Option<LinkAttributes> {
+    if value.len() < 4 {
+        return None;
+    }
+    let (sabm_len, udabm_len) = (value[0] as usize, value[1] as usize);
+    let start = 4 + sabm_len + udabm_len;
+    if value.len() < start || (sabm_len > 0 && value[4] & ASLA_FLEX_ALGO == 0) {
+        return None;
+    }
+    let mut attrs = LinkAttributes::default();
+    for sub in OpaqueTlv::parse_all(&value[start..]) {
+        let v = sub.value();
+        match sub.tlv_type {
+            ATTR_TE_METRIC if v.len() == 4 => attrs.te_metric = Some(read_u32(v)),
+            ATTR_EXT_ADMIN_GROUP => attrs.admin_groups = parse_admin_groups(v).unwrap_or(0),
+            ATTR_MIN_MAX_DELAY if v.len() == 8 => attrs.delay = Some(read_u32(v) & 0xFF_FFFF),
+            _ => {}
+        }
+    }
+    Some(attrs)
+}
+
+// the attributes of the links in an extended link LSA, keyed like
+// FlexAlgoGraph::links
+pub fn parse_link_attrs(lsa: &LsaOpaque) -> Vec<((u32, u8, u32, u32), LinkAttributes)> {
+    let mut links = vec![];
+    if lsa.opaque_type() != OPAQUE_EXT_LINK {
+        return links;
+    }
+    for tlv in lsa.tlvs().iter().filter(|x| x.tlv_type == TLV_EXT_LINK) {
+        let value = tlv.value();
+        if value.len() < 12 {
+            continue;
+        }
+        let key = (
+            lsa.header.advertising_router,
+            value[0],
+            read_u32(&value[4..]),
+            read_u32(&value[8..]),
+        );
+        let attrs = OpaqueTlv::parse_all(&value[12..])
+            .iter()
+            .filter(|x| x.tlv_type == SUB_TLV_ASLA)
+            .find_map(|x| parse_asla(x.value()));
+        if let Some(attrs) = attrs {
+            links.push((key, attrs));
+        }
+    }
+    links
+}
+
+impl RouteTable {
+    // whether a router is part of the graph the routes are computed on
+    pub fn includes(&self, router_id: u32) -> bool {
+        match &self.flex_algo {
+            Some(graph) => graph.participants.contains(&router_id),
+            None => true,
+        }
+    }
+}
+
+impl LinkStateDatabase {
+    pub fn flex_algo_router_info_tlvs(&self) -> Vec<OpaqueTlv> {
+        self.global_config
+            .flex_algos()
+            .map(|x| fad_tlv(&x))
+            .collect()
+    }
+
+    // the attributes of one of our links, when an algorithm may use them
+    pub fn link_attrs_sub_tlv(&self, attrs: LinkAttributes) -> Option<OpaqueTlv> {
+        if self.global_config.flex_algos().next().is_none() || attrs == LinkAttributes::default() {
+            return None;
+        }
+        let mut value = vec![4, 0, 0, 0, ASLA_FLEX_ALGO, 0, 0, 0];
+        if let Some(metric) = attrs.te_metric {
+            value.extend(OpaqueTlv::new(ATTR_TE_METRIC, metric.to_be_bytes().to_vec()).encode());
+        }
+        if attrs.admin_groups != 0 {
+            let groups = attrs.admin_groups.to_be_bytes().to_vec();
+            value.extend(OpaqueTlv::new(ATTR_EXT_ADMIN_GROUP, groups).encode());
+        }
+        if let Some(delay) = attrs.delay {
+            // the same minimum and maximum
+            let delay = delay.min(MAX_FLEX_METRIC).to_be_bytes();
+            let v = [delay, delay].concat();
+            value.extend(OpaqueTlv::new(ATTR_MIN_MAX_DELAY, v).encode());
+        }
+        Some(OpaqueTlv::new(SUB_TLV_ASLA, value))
+    }
+
+    // Elect the definition of algorithm `id` and gather the routers and
+    // links it runs on, None when there is nothing we can compute
+    pub fn flex_algo_graph(&self, id: u8) -> Option<FlexAlgoGraph> {
+        let mut definitions = vec![];
+        let mut participants = HashSet::new();
+        let mut links = HashMap::new();
+        for lsa in self.area_opaque_lsas() {
+            definitions.extend(parse_definitions(lsa).into_iter().filter(|x| x.id == id));
+            if parse_algorithms(lsa).contains(&id) {
+                participants.insert(lsa.header.advertising_router);
+            }
+            links.extend(parse_link_attrs(lsa));
+        }
+        let winner = definitions
+            .into_iter()
+            .max_by_key(|x| (x.priority, x.adv_router))?;
+        let definition = match winner.algo {
+            Some(algo) => algo,
+            None => {
+                println!(
+                    "flex-algo {}: definition of {} not supported, not taking part",
+                    id,
+                    Ipv4Addr::from(winner.adv_router)
+                );
+                return None;
+            }
+        };
+        Some(FlexAlgoGraph {
+            definition,
+            participants,
+            links,
+        })
+    }
+
+    // The cost of a link of `adv_router` in the graph being computed, None
+    // if it's not part of it. Prefixes keep their cost only when the
+    // algorithm minimizes the IGP metric.
+    pub fn link_metric(&self, adv_router: u32, link: &LsaRouterLink) -> Option<u32> {
+        let metric = link.topology_metric(self.route_table.mt_id)? as u32;
+        let graph = match &self.route_table.flex_algo {
+            Some(graph) => graph,
+            None => return Some(metric),
+        };
+        let definition = &graph.definition;
+        if link.link_type == LinkType::Stub as u8 {
+            return Some(match definition.metric_type {
+                FlexAlgoMetric::Igp => metric,
+                _ => 0,
+            });
+        }
+        if link.link_type != LinkType::Transit as u8 && !graph.participants.contains(&link.link_id)
+        {
+            return None;
+        }
+        let key = (adv_router, link.link_type, link.link_id, link.link_data);
+        let attrs = graph.links.get(&key).copied().unwrap_or_default();
+        if !definition.admits(attrs.admin_groups) {
+            return None;
+        }
+        match definition.metric_type {
+            FlexAlgoMetric::Igp => Some(metric),
+            FlexAlgoMetric::Delay => attrs.delay.map(|x| x.min(MAX_FLEX_METRIC)),
+            FlexAlgoMetric::Te => attrs.te_metric.map(|x| x.min(MAX_FLEX_METRIC)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opaque::tests::opaque_lsa;
+
+    fn algo() -> FlexAlgo {
+        FlexAlgo {
+            metric_type: FlexAlgoMetric::Delay,
+            priority: 200,
+            exclude_any: 1 << 3,
+            include_all: 1 << 5 | 1,
+            ..FlexAlgo::new(128)
+        }
+    }
+
+    // an extended link TLV with an ASLA sub-TLV carrying these attributes
+    fn ext_link_tlv(sabm: Option<u8>, attrs: &[OpaqueTlv]) -> OpaqueTlv {
+        let mut asla = match sabm {
+            Some(bits) => vec![4, 0, 0, 0, bits, 0, 0, 0],
+            None => vec![0, 0, 0, 0],
+        };
+        asla.extend(crate::opaque::encode_tlvs(attrs));
+        let mut value = vec![LinkType::P2P as u8, 0, 0, 0];
+        value.extend(0x0202_0202u32.to_be_bytes());
+        value.extend(0x0A00_0001u32.to_be_bytes());
+        value.extend(OpaqueTlv::new(SUB_TLV_ASLA, asla).encode());
+        OpaqueTlv::new(TLV_EXT_LINK, value)
+    }
+
+    #[test]
+    fn definition_round_trip() {
+        let lsa = opaque_lsa(OPAQUE_ROUTER_INFO, 1, &[fad_tlv(&algo())]);
+        let definitions = parse_definitions(&lsa);
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(definitions[0].adv_router, 1);
+        assert_eq!(definitions[0].priority, 200);
+        assert_eq!(definitions[0].algo, Some(algo()));
+    }
+
+    #[test]
+    fn unsupported_definitions() {
+        let mut tlvs = vec![];
+        // another calculation type
+        let mut value = fad_tlv(&algo()).value().to_vec();
+        value[2] = 1;
+        tlvs.push(OpaqueTlv::new(RI_TLV_FAD, value.clone()));
+        // an unknown metric type
+        value[2] = CALC_TYPE_SPF;
+        value[1] = 3;
+        tlvs.push(OpaqueTlv::new(RI_TLV_FAD, value));
+        // an admin group past the first 32
+        let mut value = fad_tlv(&FlexAlgo::new(129)).value().to_vec();
+        value
+            .extend(OpaqueTlv::new(FAD_SUB_TLV_EXCLUDE_ANY, vec![0, 0, 0, 0, 0, 0, 0, 1]).encode());
+        tlvs.push(OpaqueTlv::new(RI_TLV_FAD, value));
+        let definitions = parse_definitions(&opaque_lsa(OPAQUE_ROUTER_INFO, 1, &tlvs));
+        assert_eq!(definitions.len(), 3);
+        assert!(definitions.iter().all(|x| x.algo.is_none()));
+    }
+
+    #[test]
+    fn malformed_definitions_are_skipped() {
+        let tlvs = [
+            // too short
+            OpaqueTlv::new(RI_TLV_FAD, vec![128, 0, 0]),
+            // not a flexible algorithm
+            OpaqueTlv::new(RI_TLV_FAD, vec![1, 0, 0, 128]),
+        ];
+        assert!(parse_definitions(&opaque_lsa(OPAQUE_ROUTER_INFO, 1, &tlvs)).is_empty());
+        // a truncated sub-TLV ends the list, what is left still applies
+        let mut value = fad_tlv(&algo()).value().to_vec();
+        value.truncate(value.len() - 2);
+        let lsa = opaque_lsa(OPAQUE_ROUTER_INFO, 1, &[OpaqueTlv::new(RI_TLV_FAD, value)]);
+        let algo = parse_definitions(&lsa)[0].algo.unwrap();
+        assert_eq!(algo.exclude_any, 1 << 3);
+        assert_eq!(algo.include_all, 0);
+    }
+
+    #[test]
+    fn algorithms() {
+        let tlvs = [OpaqueTlv::new(RI_TLV_SR_ALGORITHM, vec![0, 128])];
+        assert_eq!(
+            parse_algorithms(&opaque_lsa(OPAQUE_ROUTER_INFO, 1, &tlvs)),
+            vec![0, 128]
+        );
+        assert!(parse_algorithms(&opaque_lsa(OPAQUE_EXT_LINK, 1, &tlvs)).is_empty());
+    }
+
+    #[test]
+    fn link_attrs_round_trip() {
+        let attrs = [
+            OpaqueTlv::new(ATTR_TE_METRIC, 100u32.to_be_bytes().to_vec()),
+            OpaqueTlv::new(ATTR_EXT_ADMIN_GROUP, (1u32 << 5).to_be_bytes().to_vec()),
+            OpaqueTlv::new(
+                ATTR_MIN_MAX_DELAY,
+                [0x8000_0010u32, 20].map(u32::to_be_bytes).concat(),
+            ),
+        ];
+        let lsa = opaque_lsa(
+            OPAQUE_EXT_LINK,
+            1,
+            &[ext_link_tlv(Some(ASLA_FLEX_ALGO), &attrs)],
+        );
+        let links = parse_link_attrs(&lsa);
+        assert_eq!(links.len(), 1);
+        assert_eq!(
+            links[0].0,
+            (1, LinkType::P2P as u8, 0x0202_0202, 0x0A00_0001)
+        );
+        assert_eq!(
+            links[0].1,
+            LinkAttributes {
+                admin_groups: 1 << 5,
+                // the anomalous bit is not part of the delay
+                delay: Some(0x10),
+                te_metric: Some(100),
+            }
+        );
+        // no application named applies to every application
+        let lsa = opaque_lsa(OPAQUE_EXT_LINK, 1, &[ext_link_tlv(None, &attrs[..1])]);
+        assert_eq!(parse_link_attrs(&lsa)[0].1.te_metric, Some(100));
+    }
+
+    #[test]
+    fn malformed_link_attrs() {
+        // attributes for another application
+        let te = [OpaqueTlv::new(
+            ATTR_TE_METRIC,
+            100u32.to_be_bytes().to_vec(),
+        )];
+        let lsa = opaque_lsa(OPAQUE_EXT_LINK, 1, &[ext_link_tlv(Some(0x80), &te)]);
+        assert!(parse_link_attrs(&lsa).is_empty());
+        // attributes of the wrong size are ignored
+        let bad = [
+            OpaqueTlv::new(ATTR_TE_METRIC, vec![0, 100]),
+            OpaqueTlv::new(ATTR_MIN_MAX_DELAY, vec![0, 0, 0, 10]),
+        ];
+        let lsa = opaque_lsa(
+            OPAQUE_EXT_LINK,
+            1,
+            &[ext_link_tlv(Some(ASLA_FLEX_ALGO), &bad)],
+        );
+        assert_eq!(parse_link_attrs(&lsa)[0].1, LinkAttributes::default());
+        // an ASLA shorter than its bit masks, and an extended link TLV
+        // shorter than its fixed part
+        let mut value = ext_link_tlv(None, &[]).value()[..12].to_vec();
+        value.extend(OpaqueTlv::new(SUB_TLV_ASLA, vec![4, 0, 0, 0]).encode());
+        let tlvs = [
+            OpaqueTlv::new(TLV_EXT_LINK, value),
+            OpaqueTlv::new(TLV_EXT_LINK, vec![1, 0, 0, 0]),
+        ];
+        assert!(parse_link_attrs(&opaque_lsa(OPAQUE_EXT_LINK, 1, &tlvs)).is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+// IP fast reroute: for each destination, a repair path that keeps working
+// while the area reconverges after the primary next hop failed. Loop-free
+// alternates (RFC 5286) are plain next hops and get installed behind the
+// primary routes, remote LFA (RFC 7490) and TI-LFA repairs need a tunnel
+// or a segment list to carry the packets, they are only computed and
+// reported for the destinations no LFA protects
+use crate::config::FastReroute;
+use crate::database::LinkStateDatabase;
+use crate::route::{NextHop, RouteTable};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone)]
+pub enum Backup {
+    // send to another neighbor, which won't loop the packets back to us
+    Lfa {
+        hop: NextHop,
+        protection: Protection,
+    },
+    // tunnel to a node that reaches the primary next hop without the
+    // failed link
+    RemoteLfa {
+        hop: NextHop,
+        pq_node: Ipv4Addr,
+    },
+    // steer the packets along the post-convergence path
+    TiLfa {
+        hop: NextHop,
+        segments: Vec<Segment>,
+    },
+}
+
+// what an LFA keeps working through (RFC 5286 3)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protection {
+    Link,
+    Node,
+    LinkAndNode,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Node(Ipv4Addr),
+    Adjacency(Ipv4Addr, Ipv4Addr),
+}
+
+impl std::fmt::Display for Backup {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Backup::Lfa { hop, protection } => write!(
+                f,
+                "LFA via {} dev {}, {} protecting",
+                hop.addr,
+                hop.int_name,
+                match protection {
+                    Protection::Link => "link",
+                    Protection::Node => "node",
+                    Protection::LinkAndNode => "link and node",
+                }
+            ),
+            Backup::RemoteLfa { hop, pq_node } => write!(
+                f,
+                "remote LFA to PQ node {} via {} dev {}",
+                pq_node, hop.addr, hop.int_name
+            ),
+            Backup::TiLfa { hop, segments } => write!(
+                f,
+                "TI-LFA {:?} via {} dev {}",
+                segments, hop.addr, hop.int_name
+            ),
+        }
+    }
+}
+
+type Distances = HashMap<Ipv4Addr, u32>;
+
+// The SPFs around the failure of the link to one primary next hop
+struct PrimarySpf {
+    // from us without the link (P-space), with the parents in the tree
+    without: Distances,
+    prevs: HashMap<Ipv4Addr, Ipv4Addr>,
+    // towards the primary next hop with and without the link (Q-space)
+    to_primary: Distances,
+    to_primary_without: Distances,
+}
+
+// The SPF results the repair computations of one route table update share
+pub struct FrrContext {
+    root: Ipv4Addr,
+    from_root: Distances,
+    // from each neighboring router, sorted by router id
+    from_nbrs: Vec<(Ipv4Addr, Distances)>,
+    // computed when a destination behind the primary first needs them
+    primaries: HashMap<Ipv4Addr, PrimarySpf>,
+}
+
+impl RouteTable {
+    // Shortest distances from `root`, or towards it when `reverse` is set,
+    // without the links between the two routers of `skip`. The parents in
+    // the shortest path tree come along.
+    pub fn spf(
+        &self,
+        root: Ipv4Addr,
+        reverse: bool,
+        skip: Option<(Ipv4Addr, Ipv4Addr)>,
+    ) -> (Distances, HashMap<Ipv4Addr, Ipv4Addr>) {
+        let mut adj: HashMap<Ipv4Addr, Vec<(Ipv4Addr, u32)>> = HashMap::new();
+        for (src, edges) in self.edges.iter() {
+            for edge in edges {
+                let skipped = skip
+                    .is_some_and(|(a, b)| (*src, edge.dst) == (a, b) || (*src, edge.dst) == (b, a));
+                // the far end may not have its router LSA in yet
+                if skipped || !self.nodes.contains_key(src) || !self.nodes.contains_key(&edge.dst) {
+                    continue;
+                }
+                let (from, to) = if reverse {
+                    (edge.dst, *src)
+                } else {
+                    (*src, edge.dst)
+                };
+                adj.entry(from).or_default().push((to, edge.dis));
+            }
+        }
+        let mut dist = HashMap::new();
+        let mut prevs = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        dist.insert(root, 0);
+        heap.push(Reverse((0u32, root)));
+        while let Some(Reverse((d, u))) = heap.pop() {
+            if dist.get(&u).is_some_and(|x| *x < d) {
+                continue;
+            }
+            for (v, cost) in adj.get(&u).into_iter().flatten() {
+                let alt = d.saturating_add(*cost);
+                if dist.get(v).is_none_or(|x| alt < *x) {
+                    dist.insert(*v, alt);
+                    prevs.insert(*v, u);
+                    heap.push(Reverse((alt, *v)));
+                }
+            }
+        }
+        (dist, prevs)
+    }
+}
+
+// `node` is as far away once the link failed as it was before, so the
+// shortest paths don't need it
+fn unchanged(after: &Distances, before: &Distances, node: &Ipv4Addr) -> bool {
+    after.contains_key(node) && after.get(node) == before.get(node)
+}
+
+// the node after `root` on the tree path to `dest`
+fn first_hop(
+    prevs: &HashMap<Ipv4Addr, Ipv4Addr>,
+    root: Ipv4Addr,
+    dest: Ipv4Addr,
+) -> Option<Ipv4Addr> {
+    let mut cur = dest;
+    loop {
+        let prev = *prevs.get(&cur)?;
+        if prev == root {
+            return Some(cur);
+        }
+        cur = prev;
+    }
+}
+
+impl LinkStateDatabase {
+    pub fn frr_context(&self) -> FrrContext {
+        let root = Ipv4Addr::from(self.global_config.router_id);
+        let table = &self.route_table;
+        let mut nbrs: Vec<Ipv4Addr> = table
+            .edges
+            .get(&root)
+            .into_iter()
+            .flatten()
+            .map(|x| x.dst)
+            .filter(|x| table.nodes.get(x).is_some_and(|n| n.router))
+            .collect();
+        nbrs.sort();
+        nbrs.dedup();
+        FrrContext {
+            root,
+            from_root: table.spf(root, false, None).0,
+            from_nbrs: nbrs
+                .into_iter()
+                .map(|n| (n, table.spf(n, false, None).0))
+                .collect(),
+            primaries: HashMap::new(),
+        }
+    }
+
+    fn cache_primary_spf(&self, ctx: &mut FrrContext, primary: Ipv4Addr) {
+        if ctx.primaries.contains_key(&primary) {
+            return;
+        }
+        let table = &self.route_table;
+        let link = Some((ctx.root, primary));
+        let (without, prevs) = table.spf(ctx.root, false, link);
+        let spf = PrimarySpf {
+            without,
+            prevs,
+            to_primary: table.spf(primary, true, None).0,
+            to_primary_without: table.spf(primary, true, link).0,
+        };
+        ctx.primaries.insert(primary, spf);
+    }
+
+    // The best repair for `dest` when the link to the router `primary`, our
+    // primary next hop out of `primary_int`, fails. Any LFA beats the repairs
+    // we can't install, those are only tried when configured.
+    pub fn find_backup(
+        &self,
+        ctx: &mut FrrContext,
+        dest: Ipv4Addr,
+        primary: Ipv4Addr,
+        primary_int: &str,
+    ) -> Option<Backup> {
+        let mode = self.global_config.fast_reroute;
+        let lfa = self.find_lfa(ctx, dest, primary, primary_int);
+        if lfa.is_some() {
+            return lfa;
+        }
+        self.cache_primary_spf(ctx, primary);
+        if mode >= FastReroute::RemoteLfa {
+            if let Some(backup) = self.find_remote_lfa(ctx, primary) {
+                return Some(backup);
+            }
+        }
+        if mode >= FastReroute::TiLfa {
+            if let Some(backup) = self.find_ti_lfa(ctx, dest, primary) {
+                return Some(backup);
+            }
+        }
+        None
+    }
+
+    fn find_lfa(
+        &self,
+        ctx: &FrrContext,
+        dest: Ipv4Addr,
+        primary: Ipv4Addr,
+        primary_int: &str,
+    ) -> Option<Backup> {
+        let d_sd = *ctx.from_root.get(&dest)?;
+        let from_primary = ctx
+            .from_nbrs
+            .iter()
+            .find(|(id, _)| *id == primary)
+            .map(|(_, dist)| dist);
+        // (protects the link, cost through the alternate, the alternate)
+        let mut best: Option<(bool, u32, Backup)> = None;
+        for (nbr, dist) in ctx.from_nbrs.iter() {
+            if *nbr == primary {
+                continue;
+            }
+            let (Some(d_nd), Some(d_ns)) = (dist.get(&dest), dist.get(&ctx.root)) else {
+                continue;
+            };
+            // the neighbor's shortest path doesn't come back through us
+            if *d_nd >= d_ns.saturating_add(d_sd) {
+                continue;
+            }
+            let Some(hop) = self.resolve_next_hop(*nbr) else {
+                continue;
+            };
+            // on a shared network the alternate goes over the failed link too
+            let link = hop.int_name != primary_int;
+            // nor through the primary next hop
+            let node = dest != primary
+                && match (dist.get(&primary), from_primary.and_then(|x| x.get(&dest))) {
+                    (Some(d_ne), Some(d_ed)) => *d_nd < d_ne.saturating_add(*d_ed),
+                    _ => false,
+                };
+            let protection = match (link, node) {
+                (true, true) => Protection::LinkAndNode,
+                (true, false) => Protection::Link,
+                (false, true) => Protection::Node,
+                (false, false) => continue,
+            };
+            let cost = ctx
+                .from_root
+                .get(nbr)
+                .map_or(u32::MAX, |x| x.saturating_add(*d_nd));
+            let better = match &best {
+                None => true,
+                Some((best_link, best_cost, Backup::Lfa { protection: p, .. })) => {
+                    let rank =
+                        |prot: Protection, link: bool| (prot == Protection::LinkAndNode, link);
+                    rank(protection, link) > rank(*p, *best_link)
+                        || (rank(protection, link) == rank(*p, *best_link) && cost < *best_cost)
+                }
+                Some(_) => false,
+            };
+            if better {
+                best = Some((link, cost, Backup::Lfa { hop, protection }));
+            }
+        }
+        best.map(|(_, _, backup)| backup)
+    }
+
+    // RFC 7490: the closest router that we reach without the link to
+    // `primary` (P-space) and that reaches `primary` without it (Q-space)
+    fn find_remote_lfa(&self, ctx: &FrrContext, primary: Ipv4Addr) -> Option<Backup> {
+        let spf = &ctx.primaries[&primary];
+        let mut pq: Vec<(u32, Ipv4Addr)> = self
+            .route_table
+            .nodes
+            .values()
+            .filter(|n| n.router && n.id != ctx.root && n.id != primary)
+            .filter(|n| unchanged(&spf.without, &ctx.from_root, &n.id))
+            .filter(|n| unchanged(&spf.to_primary_without, &spf.to_primary, &n.id))
+            .map(|n| (spf.without[&n.id], n.id))
+            .collect();
+        pq.sort();
+        let (_, pq_node) = *pq.first()?;
+        let hop = self.resolve_next_hop(first_hop(&spf.prevs, ctx.root, pq_node)?)?;
+        Some(Backup::RemoteLfa { hop, pq_node })
+    }
+
+    // Follow the path `dest` will be reached on once the link to `primary`
+    // is gone: a node segment to the last router we already reach the same
+    // way (P), then an adjacency segment when that one still sends through
+    // the failed link. Longer segment lists are not looked for.
+    fn find_ti_lfa(&self, ctx: &FrrContext, dest: Ipv4Addr, primary: Ipv4Addr) -> Option<Backup> {
+        let table = &self.route_table;
+        let link = Some((ctx.root, primary));
+        let spf = &ctx.primaries[&primary];
+        let (post, post_prevs) = (&spf.without, &spf.prevs);
+        let to_dest = table.spf(dest, true, None).0;
+        let to_dest_without = table.spf(dest, true, link).0;
+        let mut path = vec![dest];
+        while let Some(prev) = post_prevs.get(path.last().unwrap()) {
+            if *prev == ctx.root {
+                break;
+            }
+            path.push(*prev);
+        }
+        path.reverse();
+        if post_prevs.get(&path[0]) != Some(&ctx.root) {
+            // unreachable without the link
+            return None;
+        }
+        let in_p = |x: &Ipv4Addr| unchanged(post, &ctx.from_root, x);
+        let in_q = |x: &Ipv4Addr| unchanged(&to_dest_without, &to_dest, x);
+        let is_router = |x: &Ipv4Addr| table.nodes.get(x).is_some_and(|n| n.router);
+        let last_p = path.iter().take_while(|x| in_p(x) && is_router(x)).count();
+        if last_p == 0 {
+            return None;
+        }
+        let p = path[last_p - 1];
+        let segments = if in_q(&p) {
+            vec![Segment::Node(p)]
+        } else {
+            let q = *path.get(last_p)?;
+            if !is_router(&q) || !in_q(&q) {
+                return None;
+            }
+            vec![Segment::Node(p), Segment::Adjacency(p, q)]
+        };
+        let hop = self.resolve_next_hop(path[0])?;
+        Some(Backup::TiLfa { hop, segments })
+    }
+}
@@ -1,44 +1,78 @@
-use crate::config::Config;
+use crate::config::{Config, InterfaceConfig, LinkAttributes, PrefixSid};
 use crate::database::DatabaseRequest;
-use crate::neighbor::{NbrState, Neighbor};
-use crate::packets::DBDFlag;
-use crate::receiver::ipv4_to_bits;
+use crate::interface_fsm::{InterfaceFsm, OspfInterface};
+use crate::lsa::{Lsa, LsaHeader};
+use crate::lsa_v3::LsaV3;
+use crate::neighbor::{NbrEvent, NbrState};
+use crate::packets::DBDescription;
 use crate::sender::SenderRequest;
 use crate::timer::Timer;
 use pnet::datalink::NetworkInterface;
-use pnet::ipnetwork::IpNetwork;
+use pnet::ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Debug, Clone)]
 pub struct Interface {
     pub name: String,
-    pub int_type: InterfaceType,
-    pub state: InterfaceState,
+    pub fsm: InterfaceFsm,
     pub addr: Ipv4Addr,
     pub mask: Ipv4Addr,
+    // every other ipv4 address configured on the interface
+    pub secondary_addrs: Vec<Ipv4Network>,
+    // the address is borrowed from another interface
+    pub unnumbered: bool,
+    pub ifindex: u32,
     pub area_id: u32,
     pub hello_interval: u32,
     pub dead_interval: u32,
     pub inf_transit_delay: u32,
-    pub router_priority: u8,
-    pub hello_timer: Timer,
-    pub wait_timer: Timer,
-    pub designated_router: Ipv4Addr,
-    pub backup_designated_router: Ipv4Addr,
-    pub neighbor_list: Vec<Neighbor>,
     pub int_output_cost: u32,
     pub rxmt_interval: u32,
+    pub mtu: u16,
+    pub mtu_ignore: bool,
+    // GTSM hops, see InterfaceConfig
+    pub ttl_security: Option<u8>,
+    pub prefix_sid: Option<PrefixSid>,
+    pub topology_costs: Vec<(u8, u16)>,
+    pub link_attrs: LinkAttributes,
     // AuType
     // AuKey
     pub pnet_interface: NetworkInterface,
     pub db_request_sender: mpsc::Sender<DatabaseRequest>,
     pub sdr_request_sender: mpsc::Sender<crate::sender::SenderRequest>,
     pub last_nbr_state: HashMap<Ipv4Addr, NbrState>,
+    // ip fragments waiting for reassembly, keyed by (source, identification)
+    pub frag_buffer: HashMap<(Ipv4Addr, u16), Vec<IpFragment>>,
+    pub hello_drop_counts: HashMap<HelloMismatch, u64>,
+    pub drain: Option<Drain<Lsa>>,
+    // our flushed network LSA, retransmitted until the adjacent neighbors
+    // acknowledge it
+    pub flushed_network_lsa: Option<(Lsa, Timer)>,
+}
+
+// why a received hello was dropped (RFC 2328 10.5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HelloMismatch {
+    AreaId,
+    NetworkMask,
+    HelloInterval,
+    DeadInterval,
+    ExternalRoutingCapability,
+    NssaCapability,
+    DuplicateRouterId,
 }
 
 #[derive(Debug, Clone)]
+pub struct IpFragment {
+    pub offset: usize,
+    pub more: bool,
+    pub data: Vec<u8>,
+    pub arrived: std::time::Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterfaceType {
     P2P,
     Broadcast,
@@ -47,7 +81,7 @@ pub enum InterfaceType {
     Virtual,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum InterfaceState {
     Down,
     Loopback,
@@ -69,88 +103,167 @@ pub enum InterfaceEvent {
     NeighborChange,
 }
 
+// A neighbor as `show neighbors` lists it
+#[derive(Debug)]
+pub struct NeighborSummary {
+    pub nbr_id: u32,
+    pub addr: Ipv4Addr,
+    pub priority: u8,
+    pub state: NbrState,
+}
+
+// What the interface manager tells a running interface task when the
+// kernel reports a change on its link
+#[derive(Debug)]
+pub enum InterfaceCommand {
+    LinkUp,
+    LinkDown,
+    SetMtu(u16),
+    SetAddrs(Vec<Ipv4Network>),
+    // ipv6 prefixes, only used by OSPFv3 interfaces
+    SetPrefixes(Vec<Ipv6Network>),
+    // the config file was reloaded
+    Reconfigure(Box<Config>, InterfaceConfig),
+    // LSAs originated outside of this interface
+    Flood(Vec<Lsa>),
+    FloodV3(Vec<LsaV3>),
+    // flood our flushed LSAs, wait for them to be acknowledged, then send
+    // an empty hello and stop without telling the database
+    Shutdown(Vec<Lsa>),
+    ShutdownV3(Vec<LsaV3>),
+    // resync the database with every Full neighbor that supports it,
+    // without dropping the adjacencies (RFC 4811)
+    Resync,
+    // go through Down and back up, dropping every adjacency
+    Restart,
+    // list the neighbors for `show neighbors`
+    QueryNeighbors(oneshot::Sender<Vec<NeighborSummary>>),
+    Stop,
+}
+
+// how long a graceful shutdown waits for our flushed LSAs to be acknowledged
+pub const SHUTDOWN_ACK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// A graceful shutdown in progress, the neighbors' retransmission lists
+// hold what they haven't acknowledged yet
+#[derive(Debug, Clone)]
+pub struct Drain<L> {
+    pub lsas: Vec<L>,
+    pub deadline: std::time::Instant,
+    pub rxmt_timer: Timer,
+}
+
+// the mtu the kernel has configured for this interface
+pub fn read_kernel_mtu(name: &str) -> Option<u16> {
+    let path = format!("/sys/class/net/{}/mtu", name);
+    let mtu = std::fs::read_to_string(path)
+        .ok()?
+        .trim()
+        .parse::<u32>()
+        .ok()?;
+    Some(std::cmp::min(mtu, u16::MAX as u32) as u16)
+}
+
+// the device this interface is enslaved to, its VRF if it is in one
+pub fn read_master(name: &str) -> Option<String> {
+    let path = format!("/sys/class/net/{}/master", name);
+    let target = std::fs::read_link(path).ok()?;
+    Some(target.file_name()?.to_string_lossy().to_string())
+}
+
+// ipv4 addresses worth advertising, the first one is the primary address
+pub fn usable_ipv4_addrs(nint: &NetworkInterface) -> Vec<Ipv4Network> {
+    nint.ips
+        .iter()
+        .filter_map(|ip| match ip {
+            IpNetwork::V4(ipv4) if !ipv4.ip().is_loopback() => Some(*ipv4),
+            _ => None,
+        })
+        .collect()
+}
+
 impl Interface {
     pub fn from(
         nint: NetworkInterface,
+        mut addrs: Vec<Ipv4Network>,
         config: &Config,
+        int_config: &InterfaceConfig,
         db_req_sender: mpsc::Sender<DatabaseRequest>,
         sdr_req_sender: mpsc::Sender<SenderRequest>,
     ) -> Self {
-        let mut addr = Ipv4Addr::from(0);
-        let mut mask = Ipv4Addr::from(0);
-        for ip in nint.ips.as_slice() {
-            if let IpNetwork::V4(ipv4) = ip {
-                addr = ipv4.ip();
-                mask = ipv4.mask();
-                break;
-            }
-        }
+        let (addr, mask) = if addrs.is_empty() {
+            (Ipv4Addr::from(0), Ipv4Addr::from(0))
+        } else {
+            let primary = addrs.remove(0);
+            (primary.ip(), primary.mask())
+        };
+        // broadcast and NBMA networks have to be asked for with network-type
+        let int_type = int_config.network_type.unwrap_or(InterfaceType::P2P);
+        let hello_interval = int_config.hello_interval.unwrap_or(config.hello_interval);
+        let dead_interval = int_config.dead_interval.unwrap_or(config.dead_interval);
         Interface {
             name: nint.name.clone(),
-            int_type: InterfaceType::P2P,
-            state: InterfaceState::Down,
+            fsm: InterfaceFsm::new(
+                int_type,
+                nint.is_loopback(),
+                int_config.passive,
+                int_config.router_priority.unwrap_or(config.router_priority),
+                hello_interval,
+                dead_interval,
+            ),
             addr,
             mask,
+            secondary_addrs: addrs,
+            unnumbered: int_config.unnumbered.is_some(),
+            ifindex: nint.index,
             area_id: config.area_id,
-            hello_interval: config.hello_interval,
-            dead_interval: config.dead_interval,
+            hello_interval,
+            dead_interval,
             inf_transit_delay: config.inf_transit_delay,
-            router_priority: config.router_priority,
-            hello_timer: Timer::new(config.hello_interval),
-            wait_timer: Timer::new(config.dead_interval),
-            designated_router: Ipv4Addr::from(0),
-            backup_designated_router: Ipv4Addr::from(0),
-            neighbor_list: vec![],
-            int_output_cost: 1,
+            int_output_cost: int_config.cost.unwrap_or(1) as u32,
             rxmt_interval: config.rxmt_interval,
+            mtu: read_kernel_mtu(&nint.name).unwrap_or(config.default_mtu),
+            mtu_ignore: int_config.mtu_ignore,
+            ttl_security: int_config.ttl_security,
+            prefix_sid: int_config.prefix_sid,
+            topology_costs: int_config.topology_costs.clone(),
+            link_attrs: int_config.link_attrs,
             pnet_interface: nint,
             db_request_sender: db_req_sender,
             sdr_request_sender: sdr_req_sender,
             last_nbr_state: HashMap::new(),
-        }
-    }
-
-    pub fn reset_and_close(&mut self) {
-        // reset all variables, close timer, send KellNbr event to neighbors
-        self.hello_timer.stop();
-        self.wait_timer.stop();
-        for nbr in self.neighbor_list.iter_mut() {
-            nbr.handle_event(crate::neighbor::NbrEvent::KillNbr);
-        }
-        self.neighbor_list.clear();
-        self.last_nbr_state.clear();
-    }
-
-    pub async fn check_timers(&mut self) {
-        if self.hello_timer.is_expired() {
-            self.send_hello().await;
-            self.hello_timer.start();
-        }
-        if self.wait_timer.is_up() && self.wait_timer.is_expired() {
-            self.handle_event(InterfaceEvent::WaitTimer).await;
-            self.wait_timer.stop();
-        }
-        for neighbor in self.neighbor_list.iter_mut() {
-            neighbor.check_timers().await;
+            frag_buffer: HashMap::new(),
+            hello_drop_counts: HashMap::new(),
+            drain: None,
+            flushed_network_lsa: None,
         }
     }
 
     pub async fn check_nbr_change(&mut self) -> bool {
-        // first, check if need to gen router lsa
+        // first, check if need to gen router lsa: some neighbor got into or out of Full
         let mut need_gen_router_lsa = false;
         for (ip, state) in self.last_nbr_state.iter() {
-            if let Some(nbr) = self.get_neighbor_index(*ip) {
-                if self.neighbor_list[nbr].state == NbrState::Full && state != &NbrState::Full {
-                    need_gen_router_lsa = true;
-                    break;
-                }
+            let now_full = self
+                .get_neighbor_index(*ip)
+                .is_some_and(|i| self.fsm.neighbor_list[i].state == NbrState::Full);
+            if now_full != (*state == NbrState::Full) {
+                need_gen_router_lsa = true;
+                break;
             }
         }
+        if self
+            .fsm
+            .neighbor_list
+            .iter()
+            .any(|x| x.state == NbrState::Full && !self.last_nbr_state.contains_key(&x.nbr_ip))
+        {
+            need_gen_router_lsa = true;
+        }
         if need_gen_router_lsa {
             self.query_gen_router_lsa().await;
             // if need to gen Router LSA && i am dr, gen Network LSA
-            if self.state == InterfaceState::DR {
-                assert!(self.designated_router == self.addr);
+            if self.fsm.state == InterfaceState::DR {
+                assert!(self.fsm.designated_router == self.addr);
                 self.query_gen_network_lsa().await;
             }
         }
@@ -158,12 +271,12 @@ impl Interface {
         let mut old_nbr_live_num = 0;
         // look in self.last_nbr_state
         for (ip, state) in self.last_nbr_state.iter() {
-            // if ip in self.neighbor_list
+            // if ip in self.fsm.neighbor_list
             if let Some(nbr) = self.get_neighbor_index(*ip) {
                 old_nbr_live_num += 1;
-                if state.two_way_comm_status_changed(self.neighbor_list[nbr].state) {
+                if state.two_way_comm_status_changed(self.fsm.neighbor_list[nbr].state) {
                     // println!("Neighbor {} state changed from {:?} to {:?}",
-                    //     ip, state, self.neighbor_list[nbr].state);
+                    //     ip, state, self.fsm.neighbor_list[nbr].state);
                     return true;
                 }
             } else {
@@ -172,19 +285,32 @@ impl Interface {
             }
         }
         // println!("Old neighbor live number: {}", old_nbr_live_num);
-        // println!("Current neighbor live number: {}", self.neighbor_list.len());
-        old_nbr_live_num != self.neighbor_list.len()
+        // println!("Current neighbor live number: {}", self.fsm.neighbor_list.len());
+        old_nbr_live_num != self.fsm.neighbor_list.len()
     }
 
     pub async fn update_nbr_states(&mut self) {
         self.last_nbr_state.clear();
-        for nbr in self.neighbor_list.iter() {
+        for nbr in self.fsm.neighbor_list.iter() {
             self.last_nbr_state.insert(nbr.nbr_ip, nbr.state);
         }
     }
 
+    // Neighbors are identified by their source address on multi-access
+    // networks, and by their router ID on P2P and virtual links
+    pub fn find_neighbor(&self, src_ip: Ipv4Addr, router_id: u32) -> Option<usize> {
+        match self.fsm.int_type {
+            InterfaceType::P2P | InterfaceType::Virtual => self
+                .fsm
+                .neighbor_list
+                .iter()
+                .position(|n| n.nbr_id == router_id),
+            _ => self.get_neighbor_index(src_ip),
+        }
+    }
+
     pub fn get_neighbor_index(&self, ip: Ipv4Addr) -> Option<usize> {
-        for (i, n) in self.neighbor_list.iter().enumerate() {
+        for (i, n) in self.fsm.neighbor_list.iter().enumerate() {
             if n.nbr_ip == ip {
                 return Some(i);
             }
@@ -192,199 +318,229 @@ impl Interface {
         None
     }
 
-    pub async fn handle_event(&mut self, event: InterfaceEvent) {
-        let before = self.state.clone();
-        println!("Interface {} received event {:?}", self.name, event);
-        match event {
-            InterfaceEvent::InterfaceUp => {
-                assert!(self.state == InterfaceState::Down);
-                self.state = InterfaceState::Waiting;
-                self.wait_timer.start();
-                // start hello timer, send hello packets
-                self.hello_timer.start_imm();
-                // if connect to P2P, P2MP, or virtual link, change to PointToPoint
-                // else, if can't become DR or BDR, change to DROther
-                // if can be DR && into Broadcast or NBMA, change to Waiting
-                self.query_gen_router_lsa().await;
+    // Flood the flushed LSAs and wait for the adjacent neighbors to
+    // acknowledge them, returns false when there is nobody to wait for
+    async fn start_drain(&mut self, lsas: Vec<Lsa>) -> bool {
+        if self.fsm.passive || self.fsm.state == InterfaceState::Down {
+            return false;
+        }
+        self.flood_lsu(lsas.clone()).await;
+        let hdrs: Vec<LsaHeader> = lsas.iter().map(|x| x.get_hdr().clone()).collect();
+        for nbr in self.fsm.neighbor_list.iter_mut() {
+            if matches!(
+                nbr.state,
+                NbrState::Exchange | NbrState::Loading | NbrState::Full
+            ) {
+                nbr.lsa_retransmission_list = hdrs.clone();
             }
-            InterfaceEvent::InterfaceDown => {
-                self.state = InterfaceState::Down;
-                self.hello_timer.stop();
-                self.wait_timer.stop();
-                // reset all variables, close timer, send KellNbr event to neighbors
-                self.reset_and_close();
-                self.query_gen_router_lsa().await;
+        }
+        let mut rxmt_timer = Timer::new(self.rxmt_interval);
+        rxmt_timer.start();
+        self.drain = Some(Drain {
+            lsas,
+            deadline: std::time::Instant::now() + SHUTDOWN_ACK_TIMEOUT,
+            rxmt_timer,
+        });
+        true
+    }
+
+    // Retransmit what is still unacknowledged, returns true once the
+    // shutdown can complete
+    pub async fn check_drain(&mut self) -> bool {
+        let drain = match self.drain.as_mut() {
+            Some(drain) => drain,
+            None => return false,
+        };
+        let pending: Vec<(Ipv4Addr, Vec<Lsa>)> = self
+            .fsm
+            .neighbor_list
+            .iter()
+            .filter(|x| !x.lsa_retransmission_list.is_empty())
+            .map(|nbr| {
+                let lsas = drain
+                    .lsas
+                    .iter()
+                    .filter(|l| nbr.lsa_retransmission_list.iter().any(|h| l.same_ids(h)))
+                    .cloned()
+                    .collect();
+                (nbr.nbr_ip, lsas)
+            })
+            .collect();
+        if pending.is_empty() || std::time::Instant::now() >= drain.deadline {
+            return true;
+        }
+        if !drain.rxmt_timer.is_expired() {
+            return false;
+        }
+        drain.rxmt_timer.start();
+        for (nbr_ip, lsas) in pending {
+            self.send_lsu(nbr_ip, lsas).await;
+        }
+        false
+    }
+
+    // an empty hello drops us to Init at the neighbors right away
+    pub async fn say_goodbye(&mut self) {
+        self.fsm.neighbor_list.clear();
+        self.fsm.designated_router = Ipv4Addr::from(0);
+        self.fsm.backup_designated_router = Ipv4Addr::from(0);
+        self.send_hello().await;
+        println!("Interface {} shut down", self.name);
+    }
+
+    // the commands that are not about the interface state machine
+    async fn apply_command(&mut self, cmd: InterfaceCommand) -> bool {
+        match cmd {
+            InterfaceCommand::SetMtu(mtu) => {
+                // neighbors still in ExStart will see the new mtu in our next DBD
+                self.mtu = mtu;
             }
-            InterfaceEvent::UnloopInd => {
-                assert!(self.state == InterfaceState::Loopback);
-                self.state = InterfaceState::Down;
+            InterfaceCommand::SetAddrs(mut addrs) => {
+                let (addr, mask) = if addrs.is_empty() {
+                    (Ipv4Addr::from(0), Ipv4Addr::from(0))
+                } else {
+                    let primary = addrs.remove(0);
+                    (primary.ip(), primary.mask())
+                };
+                if addr != self.addr || mask != self.mask {
+                    // neighbors and DR/BDR are tied to the primary address,
+                    // start over from Down with the new one
+                    let was_up = self.fsm.state != InterfaceState::Down;
+                    self.bring_down().await;
+                    self.addr = addr;
+                    self.mask = mask;
+                    self.secondary_addrs = addrs;
+                    if was_up {
+                        self.bring_up().await;
+                    }
+                } else if addrs != self.secondary_addrs {
+                    self.secondary_addrs = addrs;
+                    if self.fsm.state != InterfaceState::Down {
+                        self.query_gen_router_lsa().await;
+                    }
+                }
             }
-            InterfaceEvent::LoopInd => {
-                self.state = InterfaceState::Loopback;
-                // reset all variables, close timer, send KellNbr event to neighbors
-                self.reset_and_close();
+            InterfaceCommand::SetPrefixes(_)
+            | InterfaceCommand::FloodV3(_)
+            | InterfaceCommand::ShutdownV3(_) => {}
+            InterfaceCommand::Shutdown(lsas) => return self.start_drain(lsas).await,
+            InterfaceCommand::Reconfigure(config, int_config) => {
+                self.reconfigure(&config, &int_config).await;
             }
-            InterfaceEvent::WaitTimer => {
-                // the wait state before electing DR/BDR is done
-                // elect DR/BDR && change to DR/BDR/DRother
-                self.elect_dr_bdr().await;
-                self.query_gen_router_lsa().await;
+            InterfaceCommand::Flood(lsas) => {
+                if !self.fsm.passive && self.fsm.state != InterfaceState::Down {
+                    self.flood_lsu(lsas).await;
+                }
             }
-            InterfaceEvent::BackupSeen => {
-                // Found a backup designated router
-                // elect DR/BDR && change to DR/BDR/DRother
-                self.elect_dr_bdr().await;
-                self.query_gen_router_lsa().await;
+            InterfaceCommand::Resync => {
+                for nbr in self.fsm.neighbor_list.iter_mut() {
+                    if nbr.lr_capable() {
+                        nbr.handle_event(NbrEvent::OobResync);
+                    }
+                }
             }
-            InterfaceEvent::NeighborChange => {
-                // A neighbor has changed state, need to re-elect DR/BDR
-                // elect DR/BDR && change to DR/BDR/DRother
-                self.elect_dr_bdr().await;
-                self.query_gen_router_lsa().await;
+            InterfaceCommand::QueryNeighbors(reply) => {
+                let nbrs = self
+                    .fsm
+                    .neighbor_list
+                    .iter()
+                    .map(|nbr| NeighborSummary {
+                        nbr_id: nbr.nbr_id,
+                        addr: nbr.nbr_ip,
+                        priority: nbr.nbr_pri,
+                        state: nbr.state,
+                    })
+                    .collect();
+                let _ = reply.send(nbrs);
+            }
+            InterfaceCommand::LinkUp
+            | InterfaceCommand::LinkDown
+            | InterfaceCommand::Restart
+            | InterfaceCommand::Stop => {
+                // handled by the shared state machine
             }
         }
-        // if a state change occurred, send out a RouterLSA
-        if before != self.state {
-            println!(
-                "Interface {} state changed from {:?} to {:?}",
-                self.name, before, self.state
-            );
-            // send out a RouterLSA
-        }
+        true
     }
 
-    fn elect_once_bdr<'a>(
-        &'a self,
-        candidates: &'a Vec<Neighbor>,
-        need_declare: bool,
-    ) -> Option<Ipv4Addr> {
-        let mut bdr: Option<&Neighbor> = None;
-        for nbr in candidates.iter() {
-            // only those who not declare as DR can be BDR
-            if nbr.nbr_ip == nbr.nbr_dr {
-                continue;
-            }
-            // if someone declare to be bdr, choose the highest pri
-            // or if no need for declare, then just choose one
-            if nbr.nbr_ip == nbr.nbr_bdr || !need_declare {
-                if bdr.is_none() {
-                    bdr = Some(nbr);
-                } else if nbr.nbr_pri > bdr.unwrap().nbr_pri
-                    || (nbr.nbr_pri == bdr.unwrap().nbr_pri && nbr.nbr_id > bdr.unwrap().nbr_id)
-                {
-                    bdr = Some(nbr);
-                }
+    // Apply the settings that can change without bringing the interface
+    // down, neighbors and adjacencies are kept
+    async fn reconfigure(&mut self, config: &Config, int_config: &InterfaceConfig) {
+        let hello_interval = int_config.hello_interval.unwrap_or(config.hello_interval);
+        let dead_interval = int_config.dead_interval.unwrap_or(config.dead_interval);
+        let router_priority = int_config.router_priority.unwrap_or(config.router_priority);
+        let cost = int_config.cost.unwrap_or(1) as u32;
+        self.hello_interval = hello_interval;
+        self.dead_interval = dead_interval;
+        self.rxmt_interval = config.rxmt_interval;
+        self.fsm
+            .set_intervals(hello_interval, dead_interval, config.rxmt_interval);
+        self.inf_transit_delay = config.inf_transit_delay;
+        self.mtu_ignore = int_config.mtu_ignore;
+        self.ttl_security = int_config.ttl_security;
+        self.set_priority(router_priority).await;
+        if cost != self.int_output_cost
+            || int_config.prefix_sid != self.prefix_sid
+            || int_config.topology_costs != self.topology_costs
+            || int_config.link_attrs != self.link_attrs
+        {
+            self.int_output_cost = cost;
+            self.prefix_sid = int_config.prefix_sid;
+            self.topology_costs = int_config.topology_costs.clone();
+            self.link_attrs = int_config.link_attrs;
+            if self.fsm.state != InterfaceState::Down {
+                self.query_gen_router_lsa().await;
             }
         }
-        if bdr.is_some() {
-            Some(bdr.unwrap().nbr_ip)
-        } else {
-            None
-        }
     }
+}
 
-    fn elect_once_dr<'a>(&'a self, candidates: &'a Vec<Neighbor>) -> Option<Ipv4Addr> {
-        let mut dr: Option<&Neighbor> = None;
-        for nbr in candidates.iter() {
-            // if someone declare to be dr, choose the highest pri
-            // or if no need for declare, then just choose one
-            if nbr.nbr_ip == nbr.nbr_dr {
-                if dr.is_none() {
-                    dr = Some(nbr);
-                } else if nbr.nbr_pri > dr.unwrap().nbr_pri
-                    || (nbr.nbr_pri == dr.unwrap().nbr_pri && nbr.nbr_id > dr.unwrap().nbr_id)
-                {
-                    dr = Some(nbr);
-                }
-            }
-        }
-        if dr.is_some() {
-            Some(dr.unwrap().nbr_ip)
-        } else {
-            None
-        }
+impl OspfInterface for Interface {
+    type Hdr = LsaHeader;
+    type Dbd = DBDescription;
+    type Addr = Ipv4Addr;
+
+    fn fsm(&self) -> &InterfaceFsm {
+        &self.fsm
     }
 
-    async fn elect_dr_bdr(&mut self) {
-        let mut candidates = vec![];
-        for nbr in self.neighbor_list.iter() {
-            // only those whose state not lower than 2-way can be candidates
-            if nbr.state.higher_than_two_way() || nbr.state == NbrState::TwoWay {
-                candidates.push(nbr.clone());
-            }
-        }
-        let mut self_as_candidate = Neighbor::default();
-        self_as_candidate.nbr_ip = self.addr;
-        self_as_candidate.nbr_id = ipv4_to_bits(self.addr);
-        self_as_candidate.nbr_pri = self.router_priority;
-        self_as_candidate.nbr_dr = self.designated_router;
-        self_as_candidate.nbr_bdr = self.backup_designated_router;
-        candidates.push(self_as_candidate);
-        let self_index = candidates.len() - 1;
-        // 1. set the prev dr & bdr
-        let prev_dr = self.designated_router;
-        let prev_bdr = self.backup_designated_router;
-        // 2. elect BDR
-        let mut bdr;
-        if let Some(tmp_bdr) = self.elect_once_bdr(&candidates, true) {
-            bdr = Some(tmp_bdr);
-        } else {
-            // if no one declare to be bdr, choose one with no declaring dr
-            bdr = self.elect_once_bdr(&candidates, false);
-        }
-        // 3. elect DR
-        let mut dr;
-        if let Some(tmp_dr) = self.elect_once_dr(&candidates) {
-            dr = Some(tmp_dr);
-        } else {
-            // if no one declare to be dr, dr = bdr
-            dr = bdr;
-        }
-        // 4. check some conditions
-        let mut tmp_candidates = candidates.clone();
-        // if no bdr, self try to be bdr
-        if bdr.is_none() {
-            if dr.unwrap() != self.addr {
-                bdr = Some(self.addr);
-            } else {
-                tmp_candidates[self_index].nbr_dr = Ipv4Addr::from(0);
-                let tmp_dr = self.elect_once_dr(&tmp_candidates);
-                if tmp_dr.is_some() {
-                    bdr = Some(self.addr);
-                    dr = tmp_dr;
-                }
-            }
-        }
-        // avoid self be both dr and bdr
-        if prev_dr != dr.unwrap() && self.addr == dr.unwrap() {
-            // newly become dr, then recauculate bdr
-            tmp_candidates[self_index].nbr_dr = self.addr;
-            bdr = self.elect_once_bdr(&tmp_candidates, true);
-            // else if newly become bdr, no need to recauculate dr
-        }
-        // 5. set interface state
-        if dr.is_some() && self.addr == dr.unwrap() {
-            self.state = InterfaceState::DR;
-        } else if bdr.is_some() && self.addr == bdr.unwrap() {
-            self.state = InterfaceState::BDR;
-        } else {
-            self.state = InterfaceState::DROther;
+    fn fsm_mut(&mut self) -> &mut InterfaceFsm {
+        &mut self.fsm
+    }
+
+    fn log_name(&self) -> String {
+        format!("Interface {}", self.name)
+    }
+
+    async fn election_ids(&self) -> (u32, Ipv4Addr) {
+        (self.query_config().await.router_id, self.addr)
+    }
+
+    async fn hello(&self) {
+        self.send_hello().await;
+    }
+
+    fn clear_nbr_state(&mut self) {
+        self.last_nbr_state.clear();
+        self.frag_buffer.clear();
+    }
+
+    async fn event_done(&mut self, before: InterfaceState, elected: Option<bool>) {
+        let Some(dr_changed) = elected else {
+            return;
+        };
+        // if i am the new dr, gen Network LSA
+        if dr_changed && self.fsm.state == InterfaceState::DR {
+            self.query_gen_network_lsa().await;
         }
-        // 6. if dr or bdr changed, send AdjOK event to neighbors
-        if dr.unwrap() != prev_dr || (bdr.is_some() && bdr.unwrap() != prev_bdr) {
-            self.designated_router = dr.unwrap();
-            // if i am dr, gen Network LSA
-            if self.addr == dr.unwrap() {
-                self.query_gen_network_lsa().await;
-            }
-            self.backup_designated_router = bdr.unwrap_or(Ipv4Addr::from(0));
-            println!(
-                "Interface {} re-elected DR: {:?}, BDR: {:?}",
-                self.name, dr, bdr
-            );
-            for nbr in self.neighbor_list.iter_mut() {
-                nbr.handle_event(crate::neighbor::NbrEvent::AdjOK);
-            }
+        self.query_gen_router_lsa().await;
+        // no longer DR, withdraw the network LSA we originated
+        if before == InterfaceState::DR && self.fsm.state != InterfaceState::DR {
+            self.query_flush_network_lsa().await;
         }
     }
+
+    async fn handle_family_command(&mut self, cmd: InterfaceCommand) -> bool {
+        self.apply_command(cmd).await
+    }
 }
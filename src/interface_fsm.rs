@@ -0,0 +1,349 @@
+// The interface state machine of RFC 2328 9.3, shared by OSPFv2 and
+// OSPFv3 the same way the neighbor state machine is. The state lives in
+// InterfaceFsm, and each family plugs its packets and LSAs in through
+// OspfInterface.
+use crate::election::{self, ElectionCandidate};
+use crate::interface::{InterfaceCommand, InterfaceEvent, InterfaceState, InterfaceType};
+use crate::lsa::LsaHeader;
+use crate::neighbor::{IntView, NbrAddr, NbrEvent, Neighbor};
+use crate::packets::DBDescription;
+use crate::timer::Timer;
+use std::net::Ipv4Addr;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+#[derive(Debug, Clone)]
+pub struct InterfaceFsm<H = LsaHeader, D = DBDescription, A = Ipv4Addr> {
+    pub int_type: InterfaceType,
+    pub state: InterfaceState,
+    pub loopback: bool,
+    pub passive: bool,
+    pub router_priority: u8,
+    pub hello_timer: Timer,
+    pub wait_timer: Timer,
+    pub designated_router: Ipv4Addr,
+    pub backup_designated_router: Ipv4Addr,
+    pub neighbor_list: Vec<Neighbor<H, D, A>>,
+}
+
+impl<H, D, A: NbrAddr> InterfaceFsm<H, D, A> {
+    pub fn new(
+        int_type: InterfaceType,
+        loopback: bool,
+        passive: bool,
+        router_priority: u8,
+        hello_interval: u32,
+        dead_interval: u32,
+    ) -> Self {
+        InterfaceFsm {
+            int_type,
+            state: InterfaceState::Down,
+            loopback,
+            passive,
+            router_priority,
+            hello_timer: Timer::new(hello_interval),
+            wait_timer: Timer::new(dead_interval),
+            designated_router: Ipv4Addr::from(0),
+            backup_designated_router: Ipv4Addr::from(0),
+            neighbor_list: vec![],
+        }
+    }
+
+    pub fn int_view(&self) -> IntView {
+        IntView {
+            int_type: self.int_type,
+            int_state: self.state,
+            dr: self.designated_router,
+            bdr: self.backup_designated_router,
+        }
+    }
+
+    // let every neighbor see the current interface state and DR/BDR
+    pub fn sync_nbr_views(&mut self) {
+        let view = self.int_view();
+        for nbr in self.neighbor_list.iter_mut() {
+            nbr.int_view = view;
+        }
+    }
+
+    // reset all variables, close timers, send KillNbr event to neighbors
+    pub fn reset(&mut self) {
+        self.hello_timer.stop();
+        self.wait_timer.stop();
+        for nbr in self.neighbor_list.iter_mut() {
+            nbr.handle_event(NbrEvent::KillNbr);
+        }
+        self.neighbor_list.clear();
+        self.designated_router = Ipv4Addr::from(0);
+        self.backup_designated_router = Ipv4Addr::from(0);
+    }
+
+    fn interface_up(&mut self) {
+        if !self.passive {
+            // start hello timer, send hello packets
+            self.hello_timer.start_imm();
+        }
+        self.state = match self.int_type {
+            // no hellos and no neighbors, the subnet is only advertised as a stub
+            _ if self.passive => InterfaceState::DROther,
+            InterfaceType::P2P | InterfaceType::P2MP | InterfaceType::Virtual => {
+                InterfaceState::PointToPoint
+            }
+            // can't become DR or BDR
+            _ if self.router_priority == 0 => InterfaceState::DROther,
+            _ => {
+                self.wait_timer.start();
+                InterfaceState::Waiting
+            }
+        };
+        self.sync_nbr_views();
+    }
+
+    // The timers keep running, the new intervals apply from their next expiry
+    pub fn set_intervals(&mut self, hello_interval: u32, dead_interval: u32, rxmt_interval: u32) {
+        self.hello_timer.set_interval(hello_interval);
+        self.wait_timer.set_interval(dead_interval);
+        for nbr in self.neighbor_list.iter_mut() {
+            nbr.inactivity_timer.set_interval(dead_interval);
+            nbr.exstart_rxmt_timer.set_interval(rxmt_interval);
+            nbr.mst_exch_timer.set_interval(rxmt_interval);
+            nbr.lsr_rxmt_timer.set_interval(rxmt_interval);
+        }
+    }
+
+    // Elect the DR and BDR and change to DR/BDR/DROther, `me` is how we show
+    // up in the DR and BDR fields. Returns whether the DR or BDR changed.
+    fn elect_dr_bdr(&mut self, router_id: u32, me: Ipv4Addr) -> bool {
+        let me = ElectionCandidate {
+            router_id,
+            addr: me,
+            priority: self.router_priority,
+            declared_dr: self.designated_router,
+            declared_bdr: self.backup_designated_router,
+        };
+        // only those whose state not lower than 2-way can be candidates
+        let others: Vec<ElectionCandidate> = self
+            .neighbor_list
+            .iter()
+            .filter(|nbr| nbr.state.have_two_way_comm())
+            .map(|nbr| ElectionCandidate {
+                router_id: nbr.nbr_id,
+                addr: nbr.dr_id(),
+                priority: nbr.nbr_pri,
+                declared_dr: nbr.nbr_dr,
+                declared_bdr: nbr.nbr_bdr,
+            })
+            .collect();
+        let prev = (self.designated_router, self.backup_designated_router);
+        let result = election::elect_dr_bdr(me, &others);
+        self.state = if result.dr == me.addr {
+            InterfaceState::DR
+        } else if result.bdr == me.addr {
+            InterfaceState::BDR
+        } else {
+            InterfaceState::DROther
+        };
+        self.designated_router = result.dr;
+        self.backup_designated_router = result.bdr;
+        self.sync_nbr_views();
+        if prev == (result.dr, result.bdr) {
+            return false;
+        }
+        // the DR or BDR changed, adjacencies may have to be formed or torn down
+        for nbr in self.neighbor_list.iter_mut() {
+            if nbr.state.have_two_way_comm() {
+                nbr.handle_event(NbrEvent::AdjOK);
+            }
+        }
+        true
+    }
+}
+
+// What an OSPFv2 or OSPFv3 interface adds to the shared state machine
+#[allow(async_fn_in_trait)]
+pub trait OspfInterface {
+    type Hdr;
+    type Dbd;
+    type Addr: NbrAddr;
+
+    fn fsm(&self) -> &InterfaceFsm<Self::Hdr, Self::Dbd, Self::Addr>;
+    fn fsm_mut(&mut self) -> &mut InterfaceFsm<Self::Hdr, Self::Dbd, Self::Addr>;
+    // how the interface shows up in the log
+    fn log_name(&self) -> String;
+    // our router ID, and how we show up in the DR and BDR fields
+    async fn election_ids(&self) -> (u32, Ipv4Addr);
+    async fn hello(&self);
+    // forget whatever the family keeps about neighbors once they are killed
+    fn clear_nbr_state(&mut self);
+    // Called after every event, `elected` is None when the event was
+    // ignored and whether the DR or BDR changed otherwise
+    async fn event_done(&mut self, before: InterfaceState, elected: Option<bool>);
+    // the commands the shared state machine leaves to the family, returns
+    // false once the interface task should stop
+    async fn handle_family_command(&mut self, cmd: InterfaceCommand) -> bool;
+
+    async fn handle_event(&mut self, event: InterfaceEvent) {
+        let name = self.log_name();
+        let before = self.fsm().state;
+        println!("{} received event {:?}", name, event);
+        let fsm = self.fsm_mut();
+        let elected = match event {
+            InterfaceEvent::InterfaceUp => {
+                assert!(fsm.state == InterfaceState::Down);
+                fsm.interface_up();
+                Some(false)
+            }
+            InterfaceEvent::InterfaceDown => {
+                fsm.state = InterfaceState::Down;
+                fsm.reset();
+                self.clear_nbr_state();
+                Some(false)
+            }
+            InterfaceEvent::UnloopInd => {
+                assert!(fsm.state == InterfaceState::Loopback);
+                fsm.state = InterfaceState::Down;
+                Some(false)
+            }
+            InterfaceEvent::LoopInd => {
+                fsm.state = InterfaceState::Loopback;
+                fsm.reset();
+                self.clear_nbr_state();
+                Some(false)
+            }
+            InterfaceEvent::WaitTimer | InterfaceEvent::BackupSeen
+                if fsm.state != InterfaceState::Waiting =>
+            {
+                // only meaningful while waiting for the election
+                None
+            }
+            InterfaceEvent::NeighborChange
+                if !matches!(
+                    fsm.state,
+                    InterfaceState::DR | InterfaceState::BDR | InterfaceState::DROther
+                ) =>
+            {
+                // no DR/BDR on this network (or not elected yet)
+                None
+            }
+            InterfaceEvent::WaitTimer
+            | InterfaceEvent::BackupSeen
+            | InterfaceEvent::NeighborChange => {
+                let (router_id, me) = self.election_ids().await;
+                let fsm = self.fsm_mut();
+                let changed = fsm.elect_dr_bdr(router_id, me);
+                if changed {
+                    println!(
+                        "{} re-elected DR: {}, BDR: {}",
+                        name, fsm.designated_router, fsm.backup_designated_router
+                    );
+                }
+                Some(changed)
+            }
+        };
+        if before != self.fsm().state {
+            println!(
+                "{} state changed from {:?} to {:?}",
+                name,
+                before,
+                self.fsm().state
+            );
+        }
+        self.event_done(before, elected).await;
+    }
+
+    async fn bring_up(&mut self) {
+        if self.fsm().state != InterfaceState::Down {
+            return;
+        }
+        if self.fsm().loopback {
+            self.handle_event(InterfaceEvent::LoopInd).await;
+        } else {
+            self.handle_event(InterfaceEvent::InterfaceUp).await;
+        }
+    }
+
+    async fn bring_down(&mut self) {
+        match self.fsm().state {
+            InterfaceState::Down => {}
+            InterfaceState::Loopback => self.handle_event(InterfaceEvent::UnloopInd).await,
+            _ => self.handle_event(InterfaceEvent::InterfaceDown).await,
+        }
+    }
+
+    // the new priority takes part in the next election
+    async fn set_priority(&mut self, router_priority: u8) -> bool {
+        if router_priority == self.fsm().router_priority {
+            return false;
+        }
+        self.fsm_mut().router_priority = router_priority;
+        self.handle_event(InterfaceEvent::NeighborChange).await;
+        true
+    }
+
+    async fn check_timers(&mut self) {
+        let fsm = self.fsm_mut();
+        if fsm.hello_timer.is_up() && fsm.hello_timer.is_expired() {
+            self.hello().await;
+            self.fsm_mut().hello_timer.start();
+        }
+        let fsm = self.fsm_mut();
+        if fsm.wait_timer.is_up() && fsm.wait_timer.is_expired() {
+            self.handle_event(InterfaceEvent::WaitTimer).await;
+            self.fsm_mut().wait_timer.stop();
+        }
+        for neighbor in self.fsm_mut().neighbor_list.iter_mut() {
+            neighbor.check_timers().await;
+        }
+    }
+
+    // Apply a command from the interface manager, returns false once the
+    // interface task should stop
+    async fn handle_command(&mut self, cmd: InterfaceCommand) -> bool {
+        println!("{} received command {:?}", self.log_name(), cmd);
+        match cmd {
+            InterfaceCommand::LinkUp => self.bring_up().await,
+            InterfaceCommand::LinkDown => self.bring_down().await,
+            InterfaceCommand::Restart => {
+                if self.fsm().state != InterfaceState::Down {
+                    self.bring_down().await;
+                    self.bring_up().await;
+                }
+            }
+            InterfaceCommand::Stop => {
+                self.bring_down().await;
+                return false;
+            }
+            cmd => return self.handle_family_command(cmd).await,
+        }
+        true
+    }
+
+    // A passive or loopback interface only needs its stub links in the
+    // router LSA, so it never sends or receives packets
+    async fn run_passive(&mut self, mut commands: mpsc::Receiver<InterfaceCommand>) {
+        while let Some(cmd) = commands.recv().await {
+            if !self.handle_command(cmd).await {
+                return;
+            }
+        }
+        // the manager is gone, withdraw what we advertised
+        self.handle_command(InterfaceCommand::Stop).await;
+    }
+
+    // apply pending commands, returns false once the task should stop
+    async fn poll_commands(&mut self, commands: &mut mpsc::Receiver<InterfaceCommand>) -> bool {
+        loop {
+            match commands.try_recv() {
+                Ok(cmd) => {
+                    if !self.handle_command(cmd).await {
+                        return false;
+                    }
+                }
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {
+                    return self.handle_command(InterfaceCommand::Stop).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,904 @@
+// Keeps one interface task per kernel interface, starting and stopping
+// them as links and addresses come and go
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use pnet::datalink::{self, NetworkInterface};
+use pnet::ipnetwork::{Ipv4Network, Ipv6Network};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::config::{Config, ConfigFile, InterfaceConfig, StubRouter};
+use crate::database::{DatabaseRequest, DatabaseRequestType, DatabaseResponse};
+use crate::database_v3::{DatabaseV3Request, DatabaseV3RequestType, DatabaseV3Response};
+use crate::interface::{self, Interface, InterfaceCommand};
+use crate::interface_fsm::OspfInterface;
+use crate::interface_v3::{self, InterfaceV3};
+use crate::kernel;
+use crate::mgmt::{MgmtCommand, MgmtRequest, Show};
+use crate::netlink::LinkEvent;
+use crate::sender::SenderRequest;
+
+// what we last told a running interface task
+pub struct InterfaceHandle {
+    pub index: u32,
+    pub up: bool,
+    pub mtu: u16,
+    pub addrs: Vec<Ipv4Network>,
+    pub int_config: InterfaceConfig,
+    pub cmd_sender: mpsc::Sender<InterfaceCommand>,
+    pub task: JoinHandle<()>,
+}
+
+// same for the OSPFv3 task of an interface
+pub struct InterfaceHandleV3 {
+    pub index: u32,
+    pub up: bool,
+    pub mtu: u16,
+    pub link_local: Ipv6Addr,
+    pub prefixes: Vec<Ipv6Network>,
+    pub int_config: InterfaceConfig,
+    pub cmd_sender: mpsc::Sender<InterfaceCommand>,
+    pub task: JoinHandle<()>,
+}
+
+pub struct InterfaceManager {
+    pub config_path: String,
+    pub config: Config,
+    pub config_file: ConfigFile,
+    pub handles: HashMap<String, InterfaceHandle>,
+    pub v3_handles: HashMap<String, InterfaceHandleV3>,
+    pub db_request_sender: mpsc::Sender<DatabaseRequest>,
+    pub db_v3_request_sender: mpsc::Sender<DatabaseV3Request>,
+    pub sdr_request_sender: mpsc::Sender<SenderRequest>,
+    // stub router mode (RFC 6987): whether the databases have it on, the
+    // startup period and its end if it is timed, and `max-metric on`
+    pub stub_router_active: bool,
+    pub startup_stub: bool,
+    pub startup_deadline: Option<tokio::time::Instant>,
+    pub forced_stub: bool,
+}
+
+fn link_is_up(nint: &NetworkInterface) -> bool {
+    nint.is_up() && nint.is_running()
+}
+
+// An instance runs on the interfaces of its VRF, or on the ones in no VRF
+fn in_instance(config_file: &ConfigFile, name: &str) -> bool {
+    config_file.runs_on(name) && interface::read_master(name) == config_file.vrf
+}
+
+// The configured router ID, or else the highest loopback address, or else
+// the highest address of any interface of the instance
+pub fn select_router_id(config_file: &ConfigFile, ints: &[NetworkInterface]) -> Option<u32> {
+    if let Some(id) = config_file.router_id {
+        return Some(u32::from(id));
+    }
+    let highest = |loopback: bool| {
+        ints.iter()
+            .filter(|i| i.is_loopback() == loopback && in_instance(config_file, &i.name))
+            .flat_map(interface::usable_ipv4_addrs)
+            .map(|net| u32::from(net.ip()))
+            .max()
+    };
+    highest(true).or_else(|| highest(false))
+}
+
+// Fill in what the config file leaves to the kernel: the router ID and
+// the routing table of the VRF
+pub fn resolve_instance(config_file: &mut ConfigFile) -> Result<(), String> {
+    config_file.global.router_id = select_router_id(config_file, &datalink::interfaces())
+        .ok_or("no address to choose a router ID from, set router-id in the config")?;
+    config_file.global.route_table = match (config_file.table, &config_file.vrf) {
+        (Some(table), _) => table,
+        (None, Some(vrf)) => kernel::vrf_table(vrf)?,
+        (None, None) => 0,
+    };
+    let table = config_file.global.route_table;
+    if let Some(topology) = config_file.global.topologies().find(|x| x.table == table) {
+        return Err(format!(
+            "topology {} uses table {} of the default topology",
+            topology.id, table
+        ));
+    }
+    if let Some(algo) = config_file.global.flex_algos().find(|x| x.table == table) {
+        return Err(format!(
+            "flex-algo {} uses table {} of the default topology",
+            algo.id, table
+        ));
+    }
+    Ok(())
+}
+
+impl InterfaceManager {
+    pub fn new(
+        config_path: &str,
+        config_file: ConfigFile,
+        db_request_sender: mpsc::Sender<DatabaseRequest>,
+        db_v3_request_sender: mpsc::Sender<DatabaseV3Request>,
+        sdr_request_sender: mpsc::Sender<SenderRequest>,
+    ) -> Self {
+        InterfaceManager {
+            config_path: config_path.to_string(),
+            config: config_file.global,
+            config_file,
+            handles: HashMap::new(),
+            v3_handles: HashMap::new(),
+            db_request_sender,
+            db_v3_request_sender,
+            sdr_request_sender,
+            stub_router_active: false,
+            startup_stub: false,
+            startup_deadline: None,
+            forced_stub: false,
+        }
+    }
+
+    // start a task for every interface the kernel already has
+    pub async fn start_all(&mut self) {
+        match self.config.stub_router {
+            StubRouter::OnStartup(secs) => {
+                self.startup_stub = true;
+                self.startup_deadline =
+                    Some(tokio::time::Instant::now() + std::time::Duration::from_secs(secs as u64));
+            }
+            StubRouter::OnStartupWait => self.startup_stub = true,
+            _ => {}
+        }
+        // before any interface describes itself in the router LSA
+        self.apply_stub_router().await;
+        println!("=== Iterating over network interfaces ===");
+        for nint in datalink::interfaces() {
+            self.refresh(nint.index).await;
+        }
+        println!("=== Done iterating over network interfaces ===");
+    }
+
+    // The addresses an interface runs with, an unnumbered interface gets
+    // the primary address of the interface it borrows from as a host address
+    fn int_addrs(&self, nint: &NetworkInterface, all: &[NetworkInterface]) -> Vec<Ipv4Network> {
+        let lender = match self.config_file.interface(&nint.name).unnumbered {
+            Some(lender) => lender,
+            None => return interface::usable_ipv4_addrs(nint),
+        };
+        all.iter()
+            .find(|i| i.name == lender)
+            .and_then(|i| interface::usable_ipv4_addrs(i).first().cloned())
+            .map(|net| vec![Ipv4Network::new(net.ip(), 32).unwrap()])
+            .unwrap_or_default()
+    }
+
+    async fn start_interface(&mut self, nint: NetworkInterface, addrs: Vec<Ipv4Network>) {
+        let int_config = self.config_file.interface(&nint.name);
+        let up = link_is_up(&nint);
+        let handle_config = int_config.clone();
+        let index = nint.index;
+        let mut int = Interface::from(
+            nint,
+            addrs.clone(),
+            &self.config,
+            &int_config,
+            self.db_request_sender.clone(),
+            self.sdr_request_sender.clone(),
+        );
+        println!("Interface: {} {} mtu {}", int.name, int.addr, int.mtu);
+        let name = int.name.clone();
+        let mtu = int.mtu;
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<InterfaceCommand>(16);
+        let task = tokio::spawn(async move {
+            if int.fsm.passive || int.fsm.loopback {
+                int.run_passive(cmd_receiver).await;
+            } else {
+                int.receiver(cmd_receiver).await;
+            }
+            println!("Interface {} task stopped", int.name);
+        });
+        self.handles.insert(
+            name.clone(),
+            InterfaceHandle {
+                index,
+                up: false,
+                mtu,
+                addrs,
+                int_config: handle_config,
+                cmd_sender,
+                task,
+            },
+        );
+        self.set_link(&name, up).await;
+    }
+
+    async fn stop_interface(&mut self, name: &str) {
+        if let Some(handle) = self.handles.remove(name) {
+            println!("Interface {} removed", name);
+            // the task may already be gone, nothing left to stop then
+            let _ = handle.cmd_sender.send(InterfaceCommand::Stop).await;
+            // wait until it has withdrawn the interface from the router LSA
+            let _ = handle.task.await;
+        }
+    }
+
+    async fn send_command(&mut self, name: &str, cmd: InterfaceCommand) {
+        let failed = match self.handles.get(name) {
+            Some(handle) => handle.cmd_sender.send(cmd).await.is_err(),
+            None => false,
+        };
+        if failed {
+            eprintln!("Interface {} task is gone, forgetting it", name);
+            self.handles.remove(name);
+        }
+    }
+
+    async fn set_link(&mut self, name: &str, up: bool) {
+        let changed = match self.handles.get_mut(name) {
+            Some(handle) if handle.up != up => {
+                handle.up = up;
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            let cmd = if up {
+                InterfaceCommand::LinkUp
+            } else {
+                InterfaceCommand::LinkDown
+            };
+            self.send_command(name, cmd).await;
+        }
+    }
+
+    async fn set_mtu(&mut self, name: &str, mtu: u16) {
+        let changed = match self.handles.get_mut(name) {
+            Some(handle) if handle.mtu != mtu => {
+                handle.mtu = mtu;
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            self.send_command(name, InterfaceCommand::SetMtu(mtu)).await;
+        }
+    }
+
+    // Bring our view of the interface with this index in line with the
+    // kernel: start, stop or update its tasks
+    async fn refresh(&mut self, index: u32) {
+        self.refresh_v2(index).await;
+        self.refresh_v3(index).await;
+    }
+
+    async fn refresh_v2(&mut self, index: u32) {
+        let all = datalink::interfaces();
+        let nint = all.iter().find(|i| i.index == index).cloned();
+        let known = self
+            .handles
+            .iter()
+            .find(|(_, h)| h.index == index)
+            .map(|(name, _)| name.clone());
+        let nint = match nint {
+            Some(nint) => nint,
+            None => {
+                if let Some(name) = known {
+                    self.stop_interface(&name).await;
+                }
+                return;
+            }
+        };
+        // renamed links come back under their new name
+        if let Some(name) = known.as_ref().filter(|name| **name != nint.name) {
+            self.stop_interface(&name.clone()).await;
+        }
+        let addrs = self.int_addrs(&nint, &all);
+        // without an address there is nothing to run ospf on or to advertise
+        if addrs.is_empty() || !in_instance(&self.config_file, &nint.name) {
+            self.stop_interface(&nint.name).await;
+            return;
+        }
+        let handle = match self.handles.get_mut(&nint.name) {
+            Some(handle) => handle,
+            None => {
+                self.start_interface(nint, addrs).await;
+                return;
+            }
+        };
+        let addrs_changed = handle.addrs != addrs;
+        if addrs_changed {
+            handle.addrs = addrs.clone();
+        }
+        let name = nint.name.clone();
+        if addrs_changed {
+            self.send_command(&name, InterfaceCommand::SetAddrs(addrs))
+                .await;
+        }
+        if let Some(mtu) = interface::read_kernel_mtu(&name) {
+            self.set_mtu(&name, mtu).await;
+        }
+        self.set_link(&name, link_is_up(&nint)).await;
+    }
+
+    async fn start_interface_v3(
+        &mut self,
+        nint: NetworkInterface,
+        link_local: Ipv6Addr,
+        prefixes: Vec<Ipv6Network>,
+    ) {
+        let int_config = self.config_file.interface(&nint.name);
+        let mut int = InterfaceV3::from(
+            &nint,
+            link_local,
+            prefixes.clone(),
+            &self.config,
+            &int_config,
+            self.db_v3_request_sender.clone(),
+        );
+        println!(
+            "Interface: {} (v3) {} mtu {}",
+            int.name, int.link_local, int.mtu
+        );
+        let name = int.name.clone();
+        let mtu = int.mtu;
+        let (cmd_sender, cmd_receiver) = mpsc::channel::<InterfaceCommand>(16);
+        let task = tokio::spawn(async move {
+            if int.fsm.passive || int.fsm.loopback {
+                int.run_passive(cmd_receiver).await;
+            } else {
+                int.receiver(cmd_receiver).await;
+            }
+            println!("Interface {} (v3) task stopped", int.name);
+        });
+        self.v3_handles.insert(
+            name.clone(),
+            InterfaceHandleV3 {
+                index: nint.index,
+                up: false,
+                mtu,
+                link_local,
+                prefixes,
+                int_config,
+                cmd_sender,
+                task,
+            },
+        );
+        self.set_link_v3(&name, link_is_up(&nint)).await;
+    }
+
+    async fn stop_interface_v3(&mut self, name: &str) {
+        if let Some(handle) = self.v3_handles.remove(name) {
+            println!("Interface {} (v3) removed", name);
+            let _ = handle.cmd_sender.send(InterfaceCommand::Stop).await;
+            let _ = handle.task.await;
+        }
+    }
+
+    async fn send_command_v3(&mut self, name: &str, cmd: InterfaceCommand) {
+        let failed = match self.v3_handles.get(name) {
+            Some(handle) => handle.cmd_sender.send(cmd).await.is_err(),
+            None => false,
+        };
+        if failed {
+            eprintln!("Interface {} (v3) task is gone, forgetting it", name);
+            self.v3_handles.remove(name);
+        }
+    }
+
+    async fn set_link_v3(&mut self, name: &str, up: bool) {
+        let changed = match self.v3_handles.get_mut(name) {
+            Some(handle) if handle.up != up => {
+                handle.up = up;
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            let cmd = if up {
+                InterfaceCommand::LinkUp
+            } else {
+                InterfaceCommand::LinkDown
+            };
+            self.send_command_v3(name, cmd).await;
+        }
+    }
+
+    // OSPFv3 runs over the link-local address of the interface, loopbacks
+    // only have their prefixes advertised
+    async fn refresh_v3(&mut self, index: u32) {
+        let nint = datalink::interfaces()
+            .into_iter()
+            .find(|i| i.index == index);
+        let known = self
+            .v3_handles
+            .iter()
+            .find(|(_, h)| h.index == index)
+            .map(|(name, _)| name.clone());
+        let nint = match nint {
+            Some(nint) => nint,
+            None => {
+                if let Some(name) = known {
+                    self.stop_interface_v3(&name).await;
+                }
+                return;
+            }
+        };
+        if let Some(name) = known.as_ref().filter(|name| **name != nint.name) {
+            self.stop_interface_v3(&name.clone()).await;
+        }
+        let prefixes = interface_v3::usable_ipv6_prefixes(&nint);
+        let link_local = match interface_v3::ipv6_link_local(&nint) {
+            Some(addr) => Some(addr),
+            None if nint.is_loopback() && !prefixes.is_empty() => Some(Ipv6Addr::UNSPECIFIED),
+            None => None,
+        };
+        let link_local = match link_local {
+            Some(addr) if self.config_file.ospfv3 && in_instance(&self.config_file, &nint.name) => {
+                addr
+            }
+            _ => {
+                self.stop_interface_v3(&nint.name).await;
+                return;
+            }
+        };
+        let handle = match self.v3_handles.get_mut(&nint.name) {
+            Some(handle) => handle,
+            None => {
+                self.start_interface_v3(nint, link_local, prefixes).await;
+                return;
+            }
+        };
+        let name = nint.name.clone();
+        if handle.link_local != link_local {
+            // the socket is bound to the old address, start over
+            self.stop_interface_v3(&name).await;
+            self.start_interface_v3(nint, link_local, prefixes).await;
+            return;
+        }
+        if handle.prefixes != prefixes {
+            handle.prefixes = prefixes.clone();
+            self.send_command_v3(&name, InterfaceCommand::SetPrefixes(prefixes))
+                .await;
+        }
+        if let Some(mtu) = interface::read_kernel_mtu(&name) {
+            let changed = match self.v3_handles.get_mut(&name) {
+                Some(handle) if handle.mtu != mtu => {
+                    handle.mtu = mtu;
+                    true
+                }
+                _ => false,
+            };
+            if changed {
+                self.send_command_v3(&name, InterfaceCommand::SetMtu(mtu))
+                    .await;
+            }
+        }
+        self.set_link_v3(&name, link_is_up(&nint)).await;
+    }
+
+    // the address of a lender may have changed, bring its borrowers along
+    async fn refresh_unnumbered(&mut self) {
+        let borrowers: Vec<String> = self
+            .config_file
+            .interfaces
+            .iter()
+            .filter(|x| x.unnumbered.is_some())
+            .map(|x| x.name.clone())
+            .collect();
+        for nint in datalink::interfaces() {
+            if borrowers.contains(&nint.name) {
+                self.refresh(nint.index).await;
+            }
+        }
+    }
+
+    pub async fn handle_link_event(&mut self, event: LinkEvent) {
+        match event {
+            LinkEvent::LinkChanged {
+                index,
+                name,
+                up,
+                mtu,
+            } => {
+                println!("Link {} changed, up: {}, mtu: {:?}", name, up, mtu);
+                self.refresh(index).await;
+            }
+            LinkEvent::LinkRemoved { index, name } => {
+                println!("Link {} removed", name);
+                self.refresh(index).await;
+            }
+            LinkEvent::AddrChanged {
+                index,
+                addr,
+                prefix_len,
+                added,
+            } => {
+                println!(
+                    "Address {}/{} {} on link {}",
+                    addr,
+                    prefix_len,
+                    if added { "added" } else { "removed" },
+                    index
+                );
+                self.refresh(index).await;
+            }
+            LinkEvent::Resync => {
+                // the links we run on and the ones the kernel has now
+                let mut indexes: Vec<u32> =
+                    datalink::interfaces().iter().map(|x| x.index).collect();
+                indexes.extend(self.handles.values().map(|x| x.index));
+                indexes.extend(self.v3_handles.values().map(|x| x.index));
+                indexes.sort();
+                indexes.dedup();
+                for index in indexes {
+                    self.refresh(index).await;
+                }
+            }
+        }
+        self.refresh_unnumbered().await;
+    }
+
+    async fn query_database(&self, req: DatabaseRequestType) -> DatabaseResponse {
+        let (rpl_tx, rpl_rx) = oneshot::channel();
+        let req = DatabaseRequest {
+            request_type: req,
+            reply_channel: rpl_tx,
+        };
+        if self.db_request_sender.send(req).await.is_err() {
+            panic!("Error sending request to database");
+        }
+        match rpl_rx.await {
+            Ok(r) => r,
+            _ => panic!("Error getting response from database"),
+        }
+    }
+
+    async fn query_database_v3(&self, req: DatabaseV3RequestType) -> DatabaseV3Response {
+        let (rpl_tx, rpl_rx) = oneshot::channel();
+        let req = DatabaseV3Request {
+            request_type: req,
+            reply_channel: rpl_tx,
+        };
+        if self.db_v3_request_sender.send(req).await.is_err() {
+            panic!("Error sending request to database");
+        }
+        match rpl_rx.await {
+            Ok(r) => r,
+            _ => panic!("Error getting response from database"),
+        }
+    }
+
+    // Enter or leave stub router mode when what we want changed, both
+    // databases re-originate their router LSA
+    async fn apply_stub_router(&mut self) {
+        let wanted =
+            self.forced_stub || self.startup_stub || self.config.stub_router == StubRouter::Always;
+        if wanted == self.stub_router_active {
+            return;
+        }
+        self.stub_router_active = wanted;
+        println!(
+            "Instance {}: stub router mode {}",
+            self.config_file.name,
+            if wanted { "on" } else { "off" }
+        );
+        if let DatabaseResponse::Lsa(lsa) = self
+            .query_database(DatabaseRequestType::SetStubRouter(wanted))
+            .await
+        {
+            self.flood(vec![lsa]).await;
+        }
+        if let DatabaseV3Response::LsaList(lsas) = self
+            .query_database_v3(DatabaseV3RequestType::SetStubRouter(wanted))
+            .await
+        {
+            if !lsas.is_empty() {
+                let names: Vec<String> = self.v3_handles.keys().cloned().collect();
+                for name in names {
+                    self.send_command_v3(&name, InterfaceCommand::FloodV3(lsas.clone()))
+                        .await;
+                }
+            }
+        }
+    }
+
+    // `max-metric off` ends the startup period too, a `max-metric` in the
+    // config file stays in effect
+    async fn set_forced_stub(&mut self, on: bool) {
+        self.forced_stub = on;
+        if !on {
+            self.startup_stub = false;
+            self.startup_deadline = None;
+        }
+        self.apply_stub_router().await;
+    }
+
+    // send LSAs the database originated on its own out of every interface
+    async fn flood(&mut self, lsas: Vec<crate::lsa::Lsa>) {
+        let names: Vec<String> = self.handles.keys().cloned().collect();
+        for name in names {
+            self.send_command(&name, InterfaceCommand::Flood(lsas.clone()))
+                .await;
+        }
+    }
+
+    // Re-read the config file and apply what changed. Interfaces whose
+    // network type, area or address source changed are restarted, the others
+    // are reconfigured in place and keep their adjacencies.
+    pub async fn reload(&mut self) -> Result<(), String> {
+        let name = self.config_file.name.clone();
+        // instances are only set up at startup
+        let mut new_file = ConfigFile::load(&self.config_path)?
+            .into_iter()
+            .find(|x| x.name == name)
+            .ok_or(format!(
+                "instance {} is gone from the config, restart to remove it",
+                name
+            ))?;
+        println!(
+            "Reloading configuration of instance {} from {}",
+            name, self.config_path
+        );
+        resolve_instance(&mut new_file)?;
+        let old_config = self.config;
+        let node_info_changed = new_file.node_info != self.config_file.node_info;
+        self.config = new_file.global;
+        self.config_file = new_file;
+        let router_id_changed = self.config.router_id != old_config.router_id;
+        if router_id_changed {
+            // withdraw what we originated under the old ID while the
+            // adjacencies are still up
+            if let DatabaseResponse::LsaList(flushed) = self
+                .query_database(DatabaseRequestType::ChangeRouterId(self.config.router_id))
+                .await
+            {
+                self.flood(flushed).await;
+            }
+        }
+        if let DatabaseResponse::LsaList(lsas) = self
+            .query_database(DatabaseRequestType::ChangeConfig(self.config))
+            .await
+        {
+            if !lsas.is_empty() {
+                self.flood(lsas).await;
+            }
+        }
+        if node_info_changed {
+            let node_info = self.config_file.node_info.clone();
+            if let DatabaseResponse::LsaList(lsas) = self
+                .query_database(DatabaseRequestType::SetNodeInfo(node_info))
+                .await
+            {
+                if !lsas.is_empty() {
+                    self.flood(lsas).await;
+                }
+            }
+        }
+        self.query_database_v3(DatabaseV3RequestType::ChangeConfig(self.config))
+            .await;
+        self.reload_v3(old_config, router_id_changed).await;
+        // `max-metric` may have been added or removed
+        self.apply_stub_router().await;
+        let mut restart = vec![];
+        let mut reconfigure = vec![];
+        for (name, handle) in self.handles.iter() {
+            let new = self.config_file.interface(name);
+            if new.network_type != handle.int_config.network_type
+                || new.passive != handle.int_config.passive
+                || new.unnumbered != handle.int_config.unnumbered
+                || self.config.area_id != old_config.area_id
+            {
+                restart.push(name.clone());
+            } else {
+                reconfigure.push((name.clone(), new));
+            }
+        }
+        for (name, int_config) in reconfigure {
+            if let Some(handle) = self.handles.get_mut(&name) {
+                handle.int_config = int_config.clone();
+            }
+            let cmd = InterfaceCommand::Reconfigure(Box::new(self.config), int_config);
+            self.send_command(&name, cmd).await;
+        }
+        for name in restart {
+            self.stop_interface(&name).await;
+        }
+        if router_id_changed {
+            // neighbors know us by the old ID, form the adjacencies again
+            // and re-originate our LSAs under the new one
+            let names: Vec<String> = self.handles.keys().cloned().collect();
+            for name in names {
+                self.send_command(&name, InterfaceCommand::Restart).await;
+            }
+        }
+        // start the interfaces that were added, stop the ones that were removed
+        for nint in datalink::interfaces() {
+            self.refresh(nint.index).await;
+        }
+        Ok(())
+    }
+
+    async fn reload_v3(&mut self, old_config: Config, router_id_changed: bool) {
+        let mut restart = vec![];
+        let mut reconfigure = vec![];
+        for (name, handle) in self.v3_handles.iter() {
+            let new = self.config_file.interface(name);
+            if new.network_type != handle.int_config.network_type
+                || new.passive != handle.int_config.passive
+                || new.instance_id != handle.int_config.instance_id
+                || self.config.area_id != old_config.area_id
+            {
+                restart.push(name.clone());
+            } else {
+                reconfigure.push((name.clone(), new));
+            }
+        }
+        for (name, int_config) in reconfigure {
+            if let Some(handle) = self.v3_handles.get_mut(&name) {
+                handle.int_config = int_config.clone();
+            }
+            let cmd = InterfaceCommand::Reconfigure(Box::new(self.config), int_config);
+            self.send_command_v3(&name, cmd).await;
+        }
+        for name in restart {
+            self.stop_interface_v3(&name).await;
+        }
+        if router_id_changed {
+            let names: Vec<String> = self.v3_handles.keys().cloned().collect();
+            for name in names {
+                self.send_command_v3(&name, InterfaceCommand::Restart).await;
+            }
+        }
+    }
+
+    // Flush our LSAs, let every interface wait for them to be acknowledged
+    // and say goodbye to its neighbors, and withdraw our kernel routes
+    pub async fn shutdown(&mut self) {
+        println!("Instance {}: shutting down", self.config_file.name);
+        let flushed = match self.query_database(DatabaseRequestType::Shutdown).await {
+            DatabaseResponse::LsaList(lsas) => lsas,
+            _ => vec![],
+        };
+        let flushed_v3 = match self
+            .query_database_v3(DatabaseV3RequestType::Shutdown)
+            .await
+        {
+            DatabaseV3Response::LsaList(lsas) => lsas,
+            _ => vec![],
+        };
+        // every interface drains at the same time
+        let handles: Vec<InterfaceHandle> = self.handles.drain().map(|(_, h)| h).collect();
+        let v3_handles: Vec<InterfaceHandleV3> = self.v3_handles.drain().map(|(_, h)| h).collect();
+        for handle in handles.iter() {
+            let _ = handle
+                .cmd_sender
+                .send(InterfaceCommand::Shutdown(flushed.clone()))
+                .await;
+        }
+        for handle in v3_handles.iter() {
+            let _ = handle
+                .cmd_sender
+                .send(InterfaceCommand::ShutdownV3(flushed_v3.clone()))
+                .await;
+        }
+        for handle in handles {
+            let _ = handle.task.await;
+        }
+        for handle in v3_handles {
+            let _ = handle.task.await;
+        }
+    }
+
+    async fn query_text(&self, req: DatabaseRequestType) -> String {
+        match self.query_database(req).await {
+            DatabaseResponse::Text(text) => text,
+            _ => panic!("Unexpected response from database"),
+        }
+    }
+
+    async fn show_neighbors(&mut self) -> String {
+        let hostnames = match self
+            .query_database(DatabaseRequestType::QueryHostnames)
+            .await
+        {
+            DatabaseResponse::Hostnames(hostnames) => hostnames,
+            _ => panic!("Unexpected response from database"),
+        };
+        let mut out = format!(
+            "{:<20} {:>3} {:<10} {:<15} {}",
+            "Neighbor", "Pri", "State", "Address", "Interface"
+        );
+        let mut names: Vec<String> = self.handles.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let (rpl_tx, rpl_rx) = oneshot::channel();
+            self.send_command(&name, InterfaceCommand::QueryNeighbors(rpl_tx))
+                .await;
+            // the task may have stopped meanwhile
+            let nbrs = rpl_rx.await.unwrap_or_default();
+            for nbr in nbrs {
+                let id = match hostnames.get(&nbr.nbr_id) {
+                    Some(hostname) => hostname.clone(),
+                    None => Ipv4Addr::from(nbr.nbr_id).to_string(),
+                };
+                out.push_str(&format!(
+                    "\n{:<20} {:>3} {:<10} {:<15} {}",
+                    id,
+                    nbr.priority,
+                    format!("{:?}", nbr.state),
+                    nbr.addr,
+                    name
+                ));
+            }
+        }
+        out
+    }
+
+    async fn handle_mgmt_request(&mut self, req: MgmtRequest) {
+        let reply = match req.command {
+            MgmtCommand::Reload => match self.reload().await {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error in instance {}: {}", self.config_file.name, e),
+            },
+            MgmtCommand::MaxMetric(on) => {
+                self.set_forced_stub(on).await;
+                "ok".to_string()
+            }
+            MgmtCommand::Resync => {
+                let names: Vec<String> = self.handles.keys().cloned().collect();
+                for name in names {
+                    self.send_command(&name, InterfaceCommand::Resync).await;
+                }
+                "ok".to_string()
+            }
+            MgmtCommand::Show(what) => {
+                let text = match what {
+                    Show::Neighbors => self.show_neighbors().await,
+                    Show::Database => self.query_text(DatabaseRequestType::ShowDatabase).await,
+                    Show::Routes => self.query_text(DatabaseRequestType::ShowRoutes).await,
+                };
+                format!("instance {}:\n{}", self.config_file.name, text)
+            }
+        };
+        if req.reply_channel.send(reply).is_err() {
+            eprintln!("Error sending management reply");
+        }
+    }
+
+    pub async fn run(
+        &mut self,
+        mut events: mpsc::Receiver<LinkEvent>,
+        mut mgmt_requests: mpsc::Receiver<MgmtRequest>,
+    ) {
+        let mut hangup = signal(SignalKind::hangup()).expect("Error installing SIGHUP handler");
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Error installing SIGTERM handler");
+        let mut interrupt =
+            signal(SignalKind::interrupt()).expect("Error installing SIGINT handler");
+        loop {
+            let deadline = self.startup_deadline;
+            let startup_over = async move {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = startup_over => {
+                    self.startup_stub = false;
+                    self.startup_deadline = None;
+                    self.apply_stub_router().await;
+                }
+                event = events.recv() => match event {
+                    Some(event) => self.handle_link_event(event).await,
+                    None => return,
+                },
+                Some(req) = mgmt_requests.recv() => self.handle_mgmt_request(req).await,
+                _ = hangup.recv() => {
+                    if let Err(e) = self.reload().await {
+                        eprintln!("Error reloading configuration: {}", e);
+                    }
+                }
+                _ = terminate.recv() => return self.shutdown().await,
+                _ = interrupt.recv() => return self.shutdown().await,
+            }
+        }
+    }
+}
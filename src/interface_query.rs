@@ -1,12 +1,13 @@
 use crate::config::Config;
-use crate::database::{DatabaseRequest, DatabaseRequestType, DatabaseResponse};
+use crate::database::{DatabaseRequest, DatabaseRequestType, DatabaseResponse, RouterLsaIntDesc};
 use crate::interface::{Interface, InterfaceState};
-use crate::lsa::{LinkType, Lsa, LsaRouterLink};
+use crate::lsa::Lsa;
 use crate::neighbor::{NbrState, Neighbor};
 use crate::packets::LinkStateRequest;
-use crate::receiver::ipv4_to_bits;
 use crate::sender::{SenderRequest, SenderRequestType, SenderResponse};
+use crate::timer::Timer;
 
+use std::net::Ipv4Addr;
 use tokio::sync::oneshot;
 
 impl Interface {
@@ -59,10 +60,16 @@ impl Interface {
     }
 
     pub async fn query_multi_lsa(&self, lsr: LinkStateRequest) -> Option<Vec<Lsa>> {
-        let query_list: Vec<(u32, u32)> = lsr
+        let query_list: Vec<(u8, u32, u32)> = lsr
             .requests
             .iter()
-            .map(|x| (x.link_state_id, x.advertising_router))
+            .map(|x| {
+                (
+                    x.link_state_type as u8,
+                    x.link_state_id,
+                    x.advertising_router,
+                )
+            })
             .collect();
         let res_list = match self
             .query_database(DatabaseRequestType::QueryMultiLsa(query_list))
@@ -80,54 +87,147 @@ impl Interface {
     }
 
     pub async fn query_gen_router_lsa(&self) {
-        assert!(self.state != InterfaceState::Down);
-        let mut link = LsaRouterLink::new(self.int_output_cost as u16);
-
-        if (self.state != InterfaceState::Waiting)
-            && (self.designated_router == self.addr
-                || self.neighbor_list[self.get_neighbor_index(self.designated_router).unwrap()]
-                    .state
-                    == NbrState::Full)
-        {
-            link.link_type = LinkType::Transit as u8;
-            link.link_id = ipv4_to_bits(self.designated_router);
-            link.link_data = ipv4_to_bits(self.addr);
-        } else {
-            link.link_type = LinkType::Stub as u8;
-            link.link_id = ipv4_to_bits(self.addr) & ipv4_to_bits(self.mask);
-            link.link_data = ipv4_to_bits(self.mask);
-        };
         match self
-            .query_database(DatabaseRequestType::GenRouterLsa(link, self.addr))
+            .query_database(DatabaseRequestType::GenRouterLsa(
+                self.router_lsa_desc(),
+                self.name.clone(),
+            ))
             .await
         {
-            DatabaseResponse::Lsa(lsa) => self.flood_lsu(vec![lsa]).await,
-            DatabaseResponse::UpdateDone => {}
+            DatabaseResponse::LsaList(lsas) if !lsas.is_empty() => self.flood_lsu(lsas).await,
+            DatabaseResponse::LsaList(_) => {}
             _ => panic!("Error getting general router LSA from database"),
         };
     }
 
-    pub async fn query_gen_network_lsa(&self) {
-        let nbr_ip_list: Vec<std::net::Ipv4Addr> = self
+    // everything the database needs to describe this interface in the router LSA
+    pub fn router_lsa_desc(&self) -> RouterLsaIntDesc {
+        let full_nbrs: Vec<(u32, Ipv4Addr)> = self
+            .fsm
             .neighbor_list
             .iter()
             .filter(|x| x.state == NbrState::Full)
-            .map(|x| x.nbr_ip)
+            .map(|x| (x.nbr_id, x.nbr_ip))
             .collect();
-        if nbr_ip_list.len() == 0 {
+        // transit if fully adjacent to the DR, or if we are the DR
+        // and fully adjacent to at least one other router
+        let transit = match self.fsm.state {
+            InterfaceState::DR => !full_nbrs.is_empty(),
+            InterfaceState::BDR | InterfaceState::DROther => full_nbrs
+                .iter()
+                .any(|(_, ip)| *ip == self.fsm.designated_router),
+            _ => false,
+        };
+        RouterLsaIntDesc {
+            int_type: self.fsm.int_type,
+            state: self.fsm.state,
+            area_id: self.area_id,
+            addr: self.addr,
+            mask: self.mask,
+            secondary_addrs: self.secondary_addrs.clone(),
+            unnumbered: self.unnumbered,
+            ifindex: self.ifindex,
+            cost: self.int_output_cost as u16,
+            passive: self.fsm.passive,
+            dr: self.fsm.designated_router,
+            transit,
+            full_nbrs,
+            prefix_sid: self.prefix_sid,
+            topology_costs: self.topology_costs.clone(),
+            link_attrs: self.link_attrs,
+        }
+    }
+
+    // The network LSA lists the DR and every router fully adjacent to it,
+    // it's withdrawn once there is no adjacency left
+    pub async fn query_gen_network_lsa(&mut self) {
+        let nbr_id_list: Vec<u32> = self
+            .fsm
+            .neighbor_list
+            .iter()
+            .filter(|x| x.state == NbrState::Full)
+            .map(|x| x.nbr_id)
+            .collect();
+        if nbr_id_list.is_empty() {
+            self.query_flush_network_lsa().await;
             return;
         }
+        let mut attached_routers = vec![self.query_config().await.router_id];
+        attached_routers.extend(nbr_id_list);
         match self
             .query_database(DatabaseRequestType::GenNetworkLsa(
                 self.addr,
                 self.mask,
-                nbr_ip_list,
+                attached_routers,
             ))
             .await
         {
-            DatabaseResponse::Lsa(lsa) => self.flood_lsu(vec![lsa]).await,
+            DatabaseResponse::Lsa(lsa) => {
+                // the new instance replaces a flush still waiting for acks
+                if let Some((flushed, _)) = self.flushed_network_lsa.take() {
+                    for nbr in self.fsm.neighbor_list.iter_mut() {
+                        nbr.lsa_retransmission_list.retain(|x| !flushed.same_ids(x));
+                    }
+                }
+                self.flood_lsu(vec![lsa]).await
+            }
             DatabaseResponse::UpdateDone => {}
             _ => panic!("Error getting general network LSA from database"),
         };
     }
+
+    // The MaxAge copy goes on the retransmission lists of the adjacent
+    // neighbors, the database drops it once they all acknowledged it
+    pub async fn query_flush_network_lsa(&mut self) {
+        let lsa = match self
+            .query_database(DatabaseRequestType::FlushNetworkLsa(self.addr))
+            .await
+        {
+            DatabaseResponse::Lsa(lsa) => lsa,
+            DatabaseResponse::NotFound => return,
+            _ => panic!("Error flushing network LSA from database"),
+        };
+        self.flood_lsu(vec![lsa.clone()]).await;
+        for nbr in self.fsm.neighbor_list.iter_mut() {
+            if matches!(
+                nbr.state,
+                NbrState::Exchange | NbrState::Loading | NbrState::Full
+            ) {
+                nbr.lsa_retransmission_list.push(lsa.get_hdr().clone());
+            }
+        }
+        let mut rxmt_timer = Timer::new(self.rxmt_interval);
+        rxmt_timer.start();
+        self.flushed_network_lsa = Some((lsa, rxmt_timer));
+    }
+
+    // Retransmit the flushed network LSA to whoever hasn't acknowledged it,
+    // and remove it from the database once nobody is left
+    pub async fn check_flushed_network_lsa(&mut self) {
+        let Some((lsa, rxmt_timer)) = self.flushed_network_lsa.as_mut() else {
+            return;
+        };
+        let pending: Vec<Ipv4Addr> = self
+            .fsm
+            .neighbor_list
+            .iter()
+            .filter(|x| x.lsa_retransmission_list.iter().any(|h| lsa.same_ids(h)))
+            .map(|x| x.nbr_ip)
+            .collect();
+        if pending.is_empty() {
+            let hdr = lsa.get_hdr().clone();
+            self.flushed_network_lsa = None;
+            self.query_database(DatabaseRequestType::RemoveLsa(hdr))
+                .await;
+            return;
+        }
+        if !rxmt_timer.is_expired() {
+            return;
+        }
+        rxmt_timer.start();
+        let lsa = lsa.clone();
+        for nbr_ip in pending {
+            self.send_lsu(nbr_ip, vec![lsa.clone()]).await;
+        }
+    }
 }
@@ -3,11 +3,79 @@ use std::net::Ipv4Addr;
 use crate::interface::Interface;
 use crate::lsa::{Lsa, LsaHeader};
 use crate::neighbor::NbrState;
-use crate::packets::DBDFlag;
+use crate::packets::{
+    DBDFlag, LlsBlock, LlsTlv, DBD_FIXED_LEN, IPV4_HDR_LEN, LLS_EO_LR, LSA_HDR_LEN, LSU_FIXED_LEN,
+    OPTION_L, OSPF_HDR_LEN,
+};
 use crate::receiver::ipv4_to_bits;
 use crate::sender::{SenderRequestType, SenderResponse};
 
 impl Interface {
+    // the largest OSPF packet (header included) that fits in one ip datagram
+    pub fn max_ospf_packet_len(&self) -> usize {
+        (self.mtu as usize).saturating_sub(IPV4_HDR_LEN)
+    }
+
+    // how many LSA headers fit in a single DBD packet, next to its LLS block
+    pub fn max_dbd_lsa_hdrs(&self) -> usize {
+        let lls_len = self.lls_block().encode().len();
+        (self
+            .max_ospf_packet_len()
+            .saturating_sub(OSPF_HDR_LEN + DBD_FIXED_LEN + lls_len)
+            / LSA_HDR_LEN)
+            .max(1)
+    }
+
+    // appended to our hellos and DBDs, it tells the neighbors we can
+    // resync our databases out of band (RFC 4811)
+    pub fn lls_block(&self) -> LlsBlock {
+        LlsBlock::new(vec![LlsTlv::ext_options(LLS_EO_LR)])
+    }
+
+    // group LSAs into as few LSU packets as the MTU allows, an LSA that
+    // can't fit even alone is sent by itself and fragmented by the sender
+    pub fn split_lsas(&self, lsas: Vec<Lsa>) -> Vec<Vec<Lsa>> {
+        let budget = self
+            .max_ospf_packet_len()
+            .saturating_sub(OSPF_HDR_LEN + LSU_FIXED_LEN);
+        let mut packets = vec![];
+        let mut cur = vec![];
+        let mut cur_len = 0;
+        for lsa in lsas {
+            let len = lsa.encode().len();
+            if !cur.is_empty() && cur_len + len > budget {
+                packets.push(std::mem::take(&mut cur));
+                cur_len = 0;
+            }
+            cur_len += len;
+            cur.push(lsa);
+        }
+        if !cur.is_empty() {
+            packets.push(cur);
+        }
+        packets
+    }
+
+    // with GTSM on, our packets leave with the highest TTL so the
+    // neighbors can tell they weren't forwarded (RFC 5082)
+    pub fn ttl(&self) -> u8 {
+        if self.ttl_security.is_some() {
+            255
+        } else {
+            1
+        }
+    }
+
+    // The neighbor of an unnumbered link is usually not on any of our
+    // subnets, so talk to it through AllSPFRouters instead
+    pub fn unicast_dst(&self, ip: Ipv4Addr) -> Ipv4Addr {
+        if self.unnumbered {
+            Ipv4Addr::new(224, 0, 0, 5)
+        } else {
+            ip
+        }
+    }
+
     pub async fn send_hello(&self) {
         let my_config = self.query_config().await;
         // send hello packet
@@ -17,28 +85,36 @@ impl Interface {
                 packet_type: crate::packets::PacketType::Hello as u8,
                 packet_length: 0,
                 router_id: my_config.router_id,
-                area_id: my_config.area_id,
+                area_id: self.area_id,
                 checksum: 0,
                 auth_type: 0,
                 auth: 0,
             },
-            network_mask: my_config.network_mask,
-            hello_interval: my_config.hello_interval as u16,
-            options: my_config.options,
-            router_priority: my_config.router_priority,
-            router_dead_interval: my_config.router_dead_interval,
-            designated_router: ipv4_to_bits(self.designated_router),
-            backup_designated_router: ipv4_to_bits(self.backup_designated_router),
+            // unnumbered point-to-point links have no mask (RFC 2328 9.5)
+            network_mask: if self.unnumbered {
+                0
+            } else {
+                ipv4_to_bits(self.mask)
+            },
+            hello_interval: self.hello_interval as u16,
+            options: my_config.options | OPTION_L,
+            router_priority: self.fsm.router_priority,
+            router_dead_interval: self.dead_interval,
+            designated_router: ipv4_to_bits(self.fsm.designated_router),
+            backup_designated_router: ipv4_to_bits(self.fsm.backup_designated_router),
             neighbors: vec![],
+            lls: Some(self.lls_block()),
         };
-        for nbr in self.neighbor_list.iter() {
-            hello_packet.neighbors.push(ipv4_to_bits(nbr.nbr_ip));
+        for nbr in self.fsm.neighbor_list.iter() {
+            hello_packet.neighbors.push(nbr.nbr_id);
         }
         match self
             .query_sender(SenderRequestType::SendOSPFPacket(
                 crate::packets::OSPFPacket::Hello(hello_packet),
                 (self.addr, Ipv4Addr::new(224, 0, 0, 5)),
                 self.pnet_interface.clone(),
+                self.mtu,
+                self.ttl(),
             ))
             .await
         {
@@ -66,17 +142,20 @@ impl Interface {
                 auth_type: 0,
                 auth: 0,
             },
-            interface_mtu: my_config.default_mtu,
-            options: my_config.options,
+            interface_mtu: self.mtu,
+            options: my_config.options | OPTION_L,
             flags,
             dbd_seq_num,
             lsa_hdrs,
+            lls: Some(self.lls_block()),
         };
         match self
             .query_sender(SenderRequestType::SendOSPFPacket(
                 crate::packets::OSPFPacket::DBDescription(dbd_packet),
-                (self.addr, ip),
+                (self.addr, self.unicast_dst(ip)),
                 self.pnet_interface.clone(),
+                self.mtu,
+                self.ttl(),
             ))
             .await
         {
@@ -87,20 +166,19 @@ impl Interface {
 
     pub async fn send_dbd_if_need(&mut self) {
         let sdr_clone = self.clone();
-        for nbr in self.neighbor_list.iter_mut() {
-            if nbr.state == NbrState::ExStart && nbr.exstart_rxmt_timer.is_expired() {
+        for nbr in self.fsm.neighbor_list.iter_mut() {
+            if nbr.sync_state() == NbrState::ExStart && nbr.exstart_rxmt_timer.is_expired() {
+                let flags = nbr.dbd_flags(DBDFlag::get_all_set());
                 sdr_clone
-                    .send_dbd_packet(
-                        nbr.nbr_ip,
-                        DBDFlag::get_all_set().to_byte(),
-                        nbr.dd_seq_number,
-                        vec![],
-                    )
+                    .send_dbd_packet(nbr.nbr_ip, flags, nbr.dd_seq_number, vec![])
                     .await;
-                nbr.last_sent_dbd =
-                    Some((DBDFlag::get_all_set().to_byte(), nbr.dd_seq_number, vec![]));
+                nbr.last_sent_dbd = Some((flags, nbr.dd_seq_number, vec![]));
                 nbr.exstart_rxmt_timer.start();
-            } else if nbr.state == NbrState::Exchange && nbr.mst_exch_timer.is_expired() {
+            } else if nbr.sync_state() == NbrState::Exchange
+                // only the master retransmits
+                && nbr.mst_exch_timer.is_up()
+                && nbr.mst_exch_timer.is_expired()
+            {
                 let (flags, seq, lsa_hdrs) = nbr.last_sent_dbd.clone().unwrap();
                 sdr_clone
                     .send_dbd_packet(nbr.nbr_ip, flags, seq, lsa_hdrs)
@@ -113,9 +191,9 @@ impl Interface {
     pub async fn send_lsr_if_need(&mut self) {
         use crate::packets::{LinkStateRequest, LinkStateRequestItem};
         let sdr_clone = self.clone();
-        for nbr in self.neighbor_list.iter_mut() {
+        for nbr in self.fsm.neighbor_list.iter_mut() {
             // only send when loading
-            if nbr.state != NbrState::Loading {
+            if nbr.sync_state() != NbrState::Loading {
                 continue;
             }
             if !nbr.lsr_rxmt_timer.is_up() {
@@ -150,8 +228,10 @@ impl Interface {
             match sdr_clone
                 .query_sender(SenderRequestType::SendOSPFPacket(
                     crate::packets::OSPFPacket::LinkStateRequest(lsr_packet),
-                    (sdr_clone.addr, nbr.nbr_ip),
+                    (sdr_clone.addr, sdr_clone.unicast_dst(nbr.nbr_ip)),
                     sdr_clone.pnet_interface.clone(),
+                    sdr_clone.mtu,
+                    sdr_clone.ttl(),
                 ))
                 .await
             {
@@ -163,6 +243,12 @@ impl Interface {
     }
 
     pub async fn send_lsu(&self, ip: Ipv4Addr, lsas: Vec<Lsa>) {
+        for lsas in self.split_lsas(lsas) {
+            self.send_lsu_packet(ip, lsas).await;
+        }
+    }
+
+    async fn send_lsu_packet(&self, ip: Ipv4Addr, lsas: Vec<Lsa>) {
         let my_config = self.query_config().await;
         let lsu = crate::packets::LinkStateUpdate {
             header: crate::packets::Header {
@@ -181,8 +267,10 @@ impl Interface {
         match self
             .query_sender(SenderRequestType::SendOSPFPacket(
                 crate::packets::OSPFPacket::LinkStateUpdate(lsu),
-                (self.addr, ip),
+                (self.addr, self.unicast_dst(ip)),
                 self.pnet_interface.clone(),
+                self.mtu,
+                self.ttl(),
             ))
             .await
         {
@@ -197,6 +285,14 @@ impl Interface {
     }
 
     pub async fn send_lsack(&self, ip: Ipv4Addr, lsas: Vec<LsaHeader>) {
+        let max_hdrs =
+            (self.max_ospf_packet_len().saturating_sub(OSPF_HDR_LEN) / LSA_HDR_LEN).max(1);
+        for lsas in lsas.chunks(max_hdrs) {
+            self.send_lsack_packet(ip, lsas.to_vec()).await;
+        }
+    }
+
+    async fn send_lsack_packet(&self, ip: Ipv4Addr, lsas: Vec<LsaHeader>) {
         let my_config = self.query_config().await;
         let lsack = crate::packets::LinkStateAcknowledgment {
             header: crate::packets::Header {
@@ -214,8 +310,10 @@ impl Interface {
         match self
             .query_sender(SenderRequestType::SendOSPFPacket(
                 crate::packets::OSPFPacket::LinkStateAcknowledgment(lsack),
-                (self.addr, ip),
+                (self.addr, self.unicast_dst(ip)),
                 self.pnet_interface.clone(),
+                self.mtu,
+                self.ttl(),
             ))
             .await
         {
@@ -0,0 +1,432 @@
+// An OSPFv3 interface (RFC 5340 4.1.2). It runs the same interface and
+// neighbor state machines as OSPFv2, but neighbors, the DR and the BDR are
+// all identified by router ID, and packets go over a raw IPv6 socket.
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use pnet::datalink::NetworkInterface;
+use pnet::ipnetwork::{IpNetwork, Ipv6Network};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{Config, InterfaceConfig};
+use crate::database_v3::{
+    DatabaseV3Request, DatabaseV3RequestType, DatabaseV3Response, InterfaceDescV3, NeighborDescV3,
+};
+use crate::interface::{
+    read_kernel_mtu, Drain, InterfaceCommand, InterfaceState, InterfaceType, SHUTDOWN_ACK_TIMEOUT,
+};
+use crate::interface_fsm::{InterfaceFsm, OspfInterface};
+use crate::lsa_v3::{LsaHeaderV3, LsaV3};
+use crate::neighbor::{NbrState, Neighbor};
+use crate::packets_v3::DBDescriptionV3;
+use crate::socket_v3::OspfV3Socket;
+use crate::timer::Timer;
+
+pub type NeighborV3 = Neighbor<LsaHeaderV3, DBDescriptionV3, Ipv6Addr>;
+
+#[derive(Debug, Clone)]
+pub struct InterfaceV3 {
+    pub name: String,
+    // the DR and BDR are router IDs
+    pub fsm: InterfaceFsm<LsaHeaderV3, DBDescriptionV3, Ipv6Addr>,
+    // the ifindex, advertised as our interface ID
+    pub interface_id: u32,
+    pub instance_id: u8,
+    pub link_local: Ipv6Addr,
+    // global and unique local prefixes of the link
+    pub prefixes: Vec<Ipv6Network>,
+    pub area_id: u32,
+    pub hello_interval: u32,
+    pub dead_interval: u32,
+    pub inf_transit_delay: u32,
+    pub int_output_cost: u32,
+    pub rxmt_interval: u32,
+    pub mtu: u16,
+    pub mtu_ignore: bool,
+    // GTSM (RFC 5082), the hop count is only used for IPv4 virtual links
+    pub ttl_security: Option<u8>,
+    pub socket: Option<Arc<OspfV3Socket>>,
+    // listening to AllDRouters, only while DR or BDR
+    pub joined_all_d: bool,
+    pub db_request_sender: mpsc::Sender<DatabaseV3Request>,
+    pub last_nbr_state: HashMap<u32, NbrState>,
+    pub drain: Option<Drain<LsaV3>>,
+}
+
+pub fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+pub fn ipv6_link_local(nint: &NetworkInterface) -> Option<Ipv6Addr> {
+    nint.ips.iter().find_map(|ip| match ip {
+        IpNetwork::V6(net) if is_link_local(&net.ip()) => Some(net.ip()),
+        _ => None,
+    })
+}
+
+// ipv6 prefixes worth advertising
+pub fn usable_ipv6_prefixes(nint: &NetworkInterface) -> Vec<Ipv6Network> {
+    nint.ips
+        .iter()
+        .filter_map(|ip| match ip {
+            IpNetwork::V6(net) if !is_link_local(&net.ip()) && !net.ip().is_loopback() => {
+                Some(*net)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+impl InterfaceV3 {
+    pub fn from(
+        nint: &NetworkInterface,
+        link_local: Ipv6Addr,
+        prefixes: Vec<Ipv6Network>,
+        config: &Config,
+        int_config: &InterfaceConfig,
+        db_req_sender: mpsc::Sender<DatabaseV3Request>,
+    ) -> Self {
+        // broadcast and NBMA networks have to be asked for with network-type
+        let int_type = int_config.network_type.unwrap_or(InterfaceType::P2P);
+        let hello_interval = int_config.hello_interval.unwrap_or(config.hello_interval);
+        let dead_interval = int_config.dead_interval.unwrap_or(config.dead_interval);
+        InterfaceV3 {
+            name: nint.name.clone(),
+            fsm: InterfaceFsm::new(
+                int_type,
+                nint.is_loopback(),
+                int_config.passive,
+                int_config.router_priority.unwrap_or(config.router_priority),
+                hello_interval,
+                dead_interval,
+            ),
+            interface_id: nint.index,
+            instance_id: int_config.instance_id.unwrap_or(0),
+            link_local,
+            prefixes,
+            area_id: config.area_id,
+            hello_interval,
+            dead_interval,
+            inf_transit_delay: config.inf_transit_delay,
+            int_output_cost: int_config.cost.unwrap_or(1) as u32,
+            rxmt_interval: config.rxmt_interval,
+            mtu: read_kernel_mtu(&nint.name).unwrap_or(config.default_mtu),
+            mtu_ignore: int_config.mtu_ignore,
+            ttl_security: int_config.ttl_security,
+            socket: None,
+            joined_all_d: false,
+            db_request_sender: db_req_sender,
+            last_nbr_state: HashMap::new(),
+            drain: None,
+        }
+    }
+
+    pub async fn query_database(&self, req: DatabaseV3RequestType) -> DatabaseV3Response {
+        let (rpl_tx, rpl_rx) = oneshot::channel();
+        let req = DatabaseV3Request {
+            request_type: req,
+            reply_channel: rpl_tx,
+        };
+        if self.db_request_sender.send(req).await.is_err() {
+            panic!("Error sending request to database");
+        }
+        match rpl_rx.await {
+            Ok(r) => r,
+            _ => panic!("Error getting response from database"),
+        }
+    }
+
+    pub async fn query_config(&self) -> Config {
+        match self
+            .query_database(DatabaseV3RequestType::QueryConfig)
+            .await
+        {
+            DatabaseV3Response::Config(c) => c,
+            _ => panic!("Error getting config from database"),
+        }
+    }
+
+    // everything the database needs to originate our LSAs for this link
+    pub fn interface_desc(&self) -> InterfaceDescV3 {
+        let nbrs: Vec<NeighborDescV3> = self
+            .fsm
+            .neighbor_list
+            .iter()
+            .filter(|x| x.state.have_two_way_comm())
+            .map(|x| NeighborDescV3 {
+                router_id: x.nbr_id,
+                interface_id: x.nbr_interface_id,
+                link_local: x.nbr_ip,
+                full: x.state == NbrState::Full,
+            })
+            .collect();
+        let transit = match self.fsm.state {
+            InterfaceState::DR => nbrs.iter().any(|x| x.full),
+            InterfaceState::BDR | InterfaceState::DROther => nbrs
+                .iter()
+                .any(|x| x.full && Ipv4Addr::from(x.router_id) == self.fsm.designated_router),
+            _ => false,
+        };
+        InterfaceDescV3 {
+            int_type: self.fsm.int_type,
+            state: self.fsm.state,
+            area_id: self.area_id,
+            interface_id: self.interface_id,
+            link_local: self.link_local,
+            prefixes: self.prefixes.clone(),
+            cost: self.int_output_cost as u16,
+            priority: self.fsm.router_priority,
+            passive: self.fsm.passive,
+            dr: self.fsm.designated_router,
+            transit,
+            nbrs,
+        }
+    }
+
+    // tell the database how the interface looks now and flood what it
+    // re-originated, Link-LSAs of other links stay off this one
+    pub async fn update_db(&self) {
+        let lsas = match self
+            .query_database(DatabaseV3RequestType::UpdateInterface(
+                self.name.clone(),
+                self.interface_desc(),
+            ))
+            .await
+        {
+            DatabaseV3Response::LsaList(lsas) => lsas,
+            _ => panic!("Error updating interface in database"),
+        };
+        self.flood_own(lsas).await;
+    }
+
+    // link-scope LSAs of other links stay off this one
+    fn for_this_link(&self, lsas: Vec<LsaV3>) -> Vec<LsaV3> {
+        lsas.into_iter()
+            .filter(|x| {
+                !x.get_hdr().link_local_scope() || x.get_hdr().link_state_id == self.interface_id
+            })
+            .collect()
+    }
+
+    pub async fn flood_own(&self, lsas: Vec<LsaV3>) {
+        let lsas = self.for_this_link(lsas);
+        if !lsas.is_empty() && !self.fsm.passive && self.fsm.state != InterfaceState::Down {
+            self.flood_lsu(lsas).await;
+        }
+    }
+
+    // Same as for OSPFv2, flood the flushed LSAs and wait for the adjacent
+    // neighbors to acknowledge them
+    async fn start_drain(&mut self, lsas: Vec<LsaV3>) -> bool {
+        if self.fsm.passive || self.fsm.state == InterfaceState::Down {
+            return false;
+        }
+        let lsas = self.for_this_link(lsas);
+        self.flood_lsu(lsas.clone()).await;
+        let hdrs: Vec<LsaHeaderV3> = lsas.iter().map(|x| x.get_hdr().clone()).collect();
+        for nbr in self.fsm.neighbor_list.iter_mut() {
+            if matches!(
+                nbr.state,
+                NbrState::Exchange | NbrState::Loading | NbrState::Full
+            ) {
+                nbr.lsa_retransmission_list = hdrs.clone();
+            }
+        }
+        let mut rxmt_timer = Timer::new(self.rxmt_interval);
+        rxmt_timer.start();
+        self.drain = Some(Drain {
+            lsas,
+            deadline: std::time::Instant::now() + SHUTDOWN_ACK_TIMEOUT,
+            rxmt_timer,
+        });
+        true
+    }
+
+    pub async fn check_drain(&mut self) -> bool {
+        let drain = match self.drain.as_mut() {
+            Some(drain) => drain,
+            None => return false,
+        };
+        let pending: Vec<(Ipv6Addr, Vec<LsaV3>)> = self
+            .fsm
+            .neighbor_list
+            .iter()
+            .filter(|x| !x.lsa_retransmission_list.is_empty())
+            .map(|nbr| {
+                let lsas = drain
+                    .lsas
+                    .iter()
+                    .filter(|l| nbr.lsa_retransmission_list.iter().any(|h| l.same_ids(h)))
+                    .cloned()
+                    .collect();
+                (nbr.nbr_ip, lsas)
+            })
+            .collect();
+        if pending.is_empty() || std::time::Instant::now() >= drain.deadline {
+            return true;
+        }
+        if !drain.rxmt_timer.is_expired() {
+            return false;
+        }
+        drain.rxmt_timer.start();
+        for (dst, lsas) in pending {
+            self.send_lsu(dst, lsas).await;
+        }
+        false
+    }
+
+    pub async fn say_goodbye(&mut self) {
+        self.fsm.neighbor_list.clear();
+        self.fsm.designated_router = Ipv4Addr::from(0);
+        self.fsm.backup_designated_router = Ipv4Addr::from(0);
+        self.send_hello().await;
+        println!("Interface {} (v3) shut down", self.name);
+    }
+
+    // Whether a neighbor came or went, or changed state. Returns true if the
+    // 2-way status of any neighbor changed, which calls for a new election.
+    pub async fn check_nbr_change(&mut self) -> bool {
+        // dead neighbors are forgotten
+        self.fsm.neighbor_list.retain(|x| x.state != NbrState::Down);
+        let now: HashMap<u32, NbrState> = self
+            .fsm
+            .neighbor_list
+            .iter()
+            .map(|x| (x.nbr_id, x.state))
+            .collect();
+        if now == self.last_nbr_state {
+            return false;
+        }
+        let two_way_changed = self.last_nbr_state.keys().chain(now.keys()).any(|id| {
+            let was = self
+                .last_nbr_state
+                .get(id)
+                .is_some_and(|x| x.have_two_way_comm());
+            let is = now.get(id).is_some_and(|x| x.have_two_way_comm());
+            was != is
+        });
+        self.last_nbr_state = now;
+        self.update_db().await;
+        two_way_changed
+    }
+
+    pub fn find_neighbor(&self, router_id: u32) -> Option<usize> {
+        self.fsm
+            .neighbor_list
+            .iter()
+            .position(|n| n.nbr_id == router_id)
+    }
+
+    // only the DR and BDR listen to AllDRouters
+    pub fn update_all_d_membership(&mut self) {
+        let wanted = matches!(self.fsm.state, InterfaceState::DR | InterfaceState::BDR);
+        if wanted == self.joined_all_d {
+            return;
+        }
+        if let Some(socket) = self.socket.as_ref() {
+            match socket.set_membership(crate::packets_v3::ALL_D_ROUTERS_V6, wanted) {
+                Ok(()) => self.joined_all_d = wanted,
+                Err(e) => eprintln!(
+                    "Error changing AllDRouters membership on {}: {}",
+                    self.name, e
+                ),
+            }
+        }
+    }
+
+    // the commands that are not about the interface state machine, IPv4
+    // addresses and OSPFv2 LSAs are not ours
+    async fn apply_command(&mut self, cmd: InterfaceCommand) -> bool {
+        match cmd {
+            InterfaceCommand::SetMtu(mtu) => self.mtu = mtu,
+            InterfaceCommand::SetAddrs(_)
+            | InterfaceCommand::Flood(_)
+            | InterfaceCommand::Shutdown(_)
+            | InterfaceCommand::Resync
+            | InterfaceCommand::QueryNeighbors(_) => {}
+            InterfaceCommand::ShutdownV3(lsas) => return self.start_drain(lsas).await,
+            InterfaceCommand::FloodV3(lsas) => {
+                if self.fsm.state != InterfaceState::Down {
+                    self.flood_own(lsas).await;
+                }
+            }
+            InterfaceCommand::SetPrefixes(prefixes) => {
+                self.prefixes = prefixes;
+                if self.fsm.state != InterfaceState::Down {
+                    self.update_db().await;
+                }
+            }
+            InterfaceCommand::Reconfigure(config, int_config) => {
+                self.reconfigure(&config, &int_config).await;
+            }
+            InterfaceCommand::LinkUp
+            | InterfaceCommand::LinkDown
+            | InterfaceCommand::Restart
+            | InterfaceCommand::Stop => {
+                // handled by the shared state machine
+            }
+        }
+        true
+    }
+
+    async fn reconfigure(&mut self, config: &Config, int_config: &InterfaceConfig) {
+        let hello_interval = int_config.hello_interval.unwrap_or(config.hello_interval);
+        let dead_interval = int_config.dead_interval.unwrap_or(config.dead_interval);
+        let router_priority = int_config.router_priority.unwrap_or(config.router_priority);
+        self.hello_interval = hello_interval;
+        self.dead_interval = dead_interval;
+        self.rxmt_interval = config.rxmt_interval;
+        self.fsm
+            .set_intervals(hello_interval, dead_interval, config.rxmt_interval);
+        self.inf_transit_delay = config.inf_transit_delay;
+        self.mtu_ignore = int_config.mtu_ignore;
+        self.int_output_cost = int_config.cost.unwrap_or(1) as u32;
+        self.ttl_security = int_config.ttl_security;
+        self.apply_hop_limit();
+        if !self.set_priority(router_priority).await && self.fsm.state != InterfaceState::Down {
+            // the cost may have changed
+            self.update_db().await;
+        }
+    }
+}
+
+impl OspfInterface for InterfaceV3 {
+    type Hdr = LsaHeaderV3;
+    type Dbd = DBDescriptionV3;
+    type Addr = Ipv6Addr;
+
+    fn fsm(&self) -> &InterfaceFsm<LsaHeaderV3, DBDescriptionV3, Ipv6Addr> {
+        &self.fsm
+    }
+
+    fn fsm_mut(&mut self) -> &mut InterfaceFsm<LsaHeaderV3, DBDescriptionV3, Ipv6Addr> {
+        &mut self.fsm
+    }
+
+    fn log_name(&self) -> String {
+        format!("Interface {} (v3)", self.name)
+    }
+
+    // addresses are router IDs in OSPFv3
+    async fn election_ids(&self) -> (u32, Ipv4Addr) {
+        let router_id = self.query_config().await.router_id;
+        (router_id, Ipv4Addr::from(router_id))
+    }
+
+    async fn hello(&self) {
+        self.send_hello().await;
+    }
+
+    fn clear_nbr_state(&mut self) {
+        self.last_nbr_state.clear();
+    }
+
+    async fn event_done(&mut self, _before: InterfaceState, _elected: Option<bool>) {
+        self.update_db().await;
+    }
+
+    async fn handle_family_command(&mut self, cmd: InterfaceCommand) -> bool {
+        self.apply_command(cmd).await
+    }
+}
@@ -0,0 +1,228 @@
+use std::net::Ipv6Addr;
+
+use crate::interface_v3::InterfaceV3;
+use crate::lsa_v3::{LsaHeaderV3, LsaV3, LSA_V3_HDR_LEN};
+use crate::neighbor::NbrState;
+use crate::packets::{DBDFlag, PacketType};
+use crate::packets_v3::*;
+use crate::receiver::ipv4_to_bits;
+
+impl InterfaceV3 {
+    // the largest OSPF packet (header included) that fits in one ipv6 packet
+    pub fn max_ospf_packet_len(&self) -> usize {
+        (self.mtu as usize).saturating_sub(IPV6_HDR_LEN)
+    }
+
+    // with GTSM on, our packets leave with the highest hop limit so the
+    // neighbors can tell they weren't forwarded (RFC 5082)
+    pub fn hop_limit(&self) -> u8 {
+        if self.ttl_security.is_some() {
+            255
+        } else {
+            1
+        }
+    }
+
+    pub fn apply_hop_limit(&self) {
+        if let Some(socket) = self.socket.as_ref() {
+            if let Err(e) = socket.set_hop_limit(self.hop_limit()) {
+                eprintln!("Error setting hop limit on {}: {}", self.name, e);
+            }
+        }
+    }
+
+    pub fn max_dbd_lsa_hdrs(&self) -> usize {
+        (self
+            .max_ospf_packet_len()
+            .saturating_sub(OSPF_V3_HDR_LEN + DBD_V3_FIXED_LEN)
+            / LSA_V3_HDR_LEN)
+            .max(1)
+    }
+
+    pub fn split_lsas(&self, lsas: Vec<LsaV3>) -> Vec<Vec<LsaV3>> {
+        let budget = self
+            .max_ospf_packet_len()
+            .saturating_sub(OSPF_V3_HDR_LEN + LSU_V3_FIXED_LEN);
+        let mut packets = vec![];
+        let mut cur = vec![];
+        let mut cur_len = 0;
+        for lsa in lsas {
+            let len = lsa.encode().len();
+            if !cur.is_empty() && cur_len + len > budget {
+                packets.push(std::mem::take(&mut cur));
+                cur_len = 0;
+            }
+            cur_len += len;
+            cur.push(lsa);
+        }
+        if !cur.is_empty() {
+            packets.push(cur);
+        }
+        packets
+    }
+
+    pub async fn make_header(&self, packet_type: PacketType) -> HeaderV3 {
+        let my_config = self.query_config().await;
+        HeaderV3 {
+            version: 3,
+            packet_type: packet_type as u8,
+            packet_length: 0,
+            router_id: my_config.router_id,
+            area_id: self.area_id,
+            checksum: 0,
+            instance_id: self.instance_id,
+            reserved: 0,
+        }
+    }
+
+    pub fn send_packet(&self, mut packet: OSPFv3Packet, dst: Ipv6Addr) {
+        let socket = match self.socket.as_ref() {
+            Some(socket) => socket,
+            None => return,
+        };
+        packet.set_packet_length();
+        if let Err(e) = socket.send_to(&packet.encode(), dst) {
+            eprintln!(
+                "Error sending ospfv3 type {} packet to {} on {}: {}",
+                packet.get_hdr().packet_type,
+                dst,
+                self.name,
+                e
+            );
+        }
+    }
+
+    pub async fn send_hello(&self) {
+        let my_config = self.query_config().await;
+        let options = crate::packets_v3::OPTION_V3_V6
+            | crate::packets_v3::OPTION_V3_R
+            | if my_config.options & crate::packets::OPTION_E != 0 {
+                OPTION_V3_E
+            } else {
+                0
+            };
+        let hello = HelloV3 {
+            header: self.make_header(PacketType::Hello).await,
+            interface_id: self.interface_id,
+            router_priority: self.fsm.router_priority,
+            options_hi: 0,
+            options,
+            hello_interval: self.hello_interval as u16,
+            router_dead_interval: self.dead_interval as u16,
+            designated_router: ipv4_to_bits(self.fsm.designated_router),
+            backup_designated_router: ipv4_to_bits(self.fsm.backup_designated_router),
+            neighbors: self.fsm.neighbor_list.iter().map(|x| x.nbr_id).collect(),
+        };
+        self.send_packet(OSPFv3Packet::Hello(hello), ALL_SPF_ROUTERS_V6);
+    }
+
+    pub async fn send_dbd_packet(
+        &self,
+        dst: Ipv6Addr,
+        flags: u8,
+        dbd_seq_num: u32,
+        lsa_hdrs: Vec<LsaHeaderV3>,
+    ) {
+        let dbd = DBDescriptionV3 {
+            header: self.make_header(PacketType::DBD).await,
+            reserved: 0,
+            options_hi: 0,
+            options: OPTION_V3_V6 | OPTION_V3_R,
+            interface_mtu: self.mtu,
+            reserved2: 0,
+            flags,
+            dbd_seq_num,
+            lsa_hdrs,
+        };
+        self.send_packet(OSPFv3Packet::DBDescription(dbd), dst);
+    }
+
+    pub async fn send_dbd_if_need(&mut self) {
+        let sdr_clone = self.clone();
+        for nbr in self.fsm.neighbor_list.iter_mut() {
+            if nbr.state == NbrState::ExStart && nbr.exstart_rxmt_timer.is_expired() {
+                let flags = DBDFlag::get_all_set().to_byte();
+                sdr_clone
+                    .send_dbd_packet(nbr.nbr_ip, flags, nbr.dd_seq_number, vec![])
+                    .await;
+                nbr.last_sent_dbd = Some((flags, nbr.dd_seq_number, vec![]));
+                nbr.exstart_rxmt_timer.start();
+            } else if nbr.state == NbrState::Exchange
+                && !nbr.nbr_is_master
+                && nbr.mst_exch_timer.is_up()
+                && nbr.mst_exch_timer.is_expired()
+            {
+                if let Some((flags, seq, lsa_hdrs)) = nbr.last_sent_dbd.clone() {
+                    sdr_clone
+                        .send_dbd_packet(nbr.nbr_ip, flags, seq, lsa_hdrs)
+                        .await;
+                }
+                nbr.mst_exch_timer.start();
+            }
+        }
+    }
+
+    pub async fn send_lsr_if_need(&mut self) {
+        let sdr_clone = self.clone();
+        for nbr in self.fsm.neighbor_list.iter_mut() {
+            if nbr.state != NbrState::Loading {
+                continue;
+            }
+            if !nbr.lsr_rxmt_timer.is_up() {
+                nbr.lsr_rxmt_timer.start_imm();
+            }
+            if !nbr.lsr_rxmt_timer.is_expired() {
+                continue;
+            }
+            let max_items = (sdr_clone
+                .max_ospf_packet_len()
+                .saturating_sub(OSPF_V3_HDR_LEN)
+                / 12)
+                .max(1);
+            let requests = nbr
+                .link_state_req_list
+                .iter()
+                .take(max_items)
+                .map(|x| LinkStateRequestItemV3 {
+                    reserved: 0,
+                    ls_type: x.ls_type,
+                    link_state_id: x.link_state_id,
+                    advertising_router: x.advertising_router,
+                })
+                .collect();
+            let lsr = LinkStateRequestV3 {
+                header: sdr_clone.make_header(PacketType::LSR).await,
+                requests,
+            };
+            sdr_clone.send_packet(OSPFv3Packet::LinkStateRequest(lsr), nbr.nbr_ip);
+            nbr.lsr_rxmt_timer.start();
+        }
+    }
+
+    pub async fn send_lsu(&self, dst: Ipv6Addr, lsas: Vec<LsaV3>) {
+        for lsas in self.split_lsas(lsas) {
+            let lsu = LinkStateUpdateV3 {
+                header: self.make_header(PacketType::LSU).await,
+                num_lsa: lsas.len() as u32,
+                lsas,
+            };
+            self.send_packet(OSPFv3Packet::LinkStateUpdate(lsu), dst);
+        }
+    }
+
+    pub async fn flood_lsu(&self, lsas: Vec<LsaV3>) {
+        self.send_lsu(ALL_SPF_ROUTERS_V6, lsas).await;
+    }
+
+    pub async fn send_lsack(&self, dst: Ipv6Addr, lsas: Vec<LsaHeaderV3>) {
+        let max_hdrs =
+            (self.max_ospf_packet_len().saturating_sub(OSPF_V3_HDR_LEN) / LSA_V3_HDR_LEN).max(1);
+        for lsas in lsas.chunks(max_hdrs) {
+            let lsack = LinkStateAcknowledgmentV3 {
+                header: self.make_header(PacketType::LSAck).await,
+                lsas: lsas.to_vec(),
+            };
+            self.send_packet(OSPFv3Packet::LinkStateAcknowledgment(lsack), dst);
+        }
+    }
+}
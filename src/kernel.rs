@@ -0,0 +1,306 @@
+// Keep the kernel routing table in line with the computed routes, routes
+// are programmed with iproute2 and tagged with the ospf protocol number.
+// MPLS routes go through rtnetlink, iproute2 builds often lack MPLS support.
+use std::ffi::CString;
+use std::net::IpAddr;
+use std::process::Command;
+
+use crate::netlink;
+
+// RTPROT_OSPF from linux/rtnetlink.h
+pub const RTPROT_OSPF: u8 = 188;
+
+// Fast reroute backups sit behind the primary route with a worse metric,
+// the kernel falls back to them as soon as the primary route is removed
+// with its interface, or skipped because its carrier is down when
+// net.ipv4.conf.*.ignore_routes_with_linkdown is set
+pub const BACKUP_ROUTE_METRIC: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelRoute {
+    // ipv4 or ipv6, iproute2 picks the family from the addresses
+    pub dest: IpAddr,
+    pub prefix_len: u32,
+    pub next_hop: IpAddr,
+    pub dev: String,
+    // the next hop is not on any subnet of the outgoing interface
+    pub onlink: bool,
+    // routing table, 0 for the main table
+    pub table: u32,
+    // the kernel prefers the lowest, backup paths use BACKUP_ROUTE_METRIC
+    pub metric: u32,
+    // MPLS labels pushed onto the packets, outermost first
+    pub labels: Vec<u32>,
+}
+
+// A route of the MPLS forwarding table, matching on the top label
+#[derive(Debug, Clone, PartialEq)]
+pub struct MplsRoute {
+    pub label: u32,
+    // what the top label is swapped for, popped if empty
+    pub out_labels: Vec<u32>,
+    // None to pop the label and deliver the packet locally
+    pub next_hop: Option<IpAddr>,
+    pub dev: String,
+}
+
+fn run_ip(args: &[String]) -> Result<(), String> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|e| format!("cannot run ip: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+impl KernelRoute {
+    fn prefix(&self) -> String {
+        format!("{}/{}", self.dest, self.prefix_len)
+    }
+
+    // a route replaces the one for the same prefix, table and metric
+    fn key(&self) -> (String, u32, u32) {
+        (self.prefix(), self.table, self.metric)
+    }
+
+    pub fn install(&self) -> Result<(), String> {
+        let mut args = vec!["route".to_string(), "replace".to_string(), self.prefix()];
+        if !self.labels.is_empty() {
+            args.push("encap".to_string());
+            args.push("mpls".to_string());
+            args.push(label_stack(&self.labels));
+        }
+        args.extend([
+            "via".to_string(),
+            self.next_hop.to_string(),
+            "dev".to_string(),
+            self.dev.clone(),
+        ]);
+        if self.onlink {
+            args.push("onlink".to_string());
+        }
+        args.push("proto".to_string());
+        args.push(RTPROT_OSPF.to_string());
+        args.extend(self.table_args());
+        run_ip(&args)
+    }
+
+    pub fn remove(&self) -> Result<(), String> {
+        let mut args = vec![
+            "route".to_string(),
+            "del".to_string(),
+            self.prefix(),
+            "proto".to_string(),
+            RTPROT_OSPF.to_string(),
+        ];
+        args.extend(self.table_args());
+        run_ip(&args)
+    }
+
+    // the metric and table tell routes to the same prefix apart
+    fn table_args(&self) -> Vec<String> {
+        let mut args = vec!["metric".to_string(), self.metric.to_string()];
+        if self.table != 0 {
+            args.push("table".to_string());
+            args.push(self.table.to_string());
+        }
+        args
+    }
+}
+
+fn label_stack(labels: &[u32]) -> String {
+    let labels: Vec<String> = labels.iter().map(|x| x.to_string()).collect();
+    labels.join("/")
+}
+
+// label stack entries, the last one with the bottom of stack bit
+fn mpls_label_stack(labels: &[u32]) -> Vec<u8> {
+    let last = labels.len().saturating_sub(1);
+    labels
+        .iter()
+        .enumerate()
+        .flat_map(|(i, label)| {
+            let bos = if i == last { 1 << 8 } else { 0 };
+            ((label << 12) | bos).to_be_bytes()
+        })
+        .collect()
+}
+
+fn ifindex(dev: &str) -> Result<u32, String> {
+    let name = CString::new(dev).map_err(|e| e.to_string())?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(format!("no such device {}", dev)),
+        index => Ok(index),
+    }
+}
+
+impl MplsRoute {
+    // the rtmsg and attributes describing the route, a removal only needs
+    // the label
+    fn netlink_body(&self, with_next_hop: bool) -> Result<Vec<u8>, String> {
+        let mut body = vec![
+            libc::AF_MPLS as u8,
+            // the label is all of the destination
+            20,
+            0,
+            0,
+            libc::RT_TABLE_MAIN,
+            RTPROT_OSPF,
+            libc::RT_SCOPE_UNIVERSE,
+            libc::RTN_UNICAST,
+        ];
+        body.extend(0u32.to_ne_bytes());
+        netlink::push_attr(&mut body, libc::RTA_DST, &mpls_label_stack(&[self.label]));
+        if !with_next_hop {
+            return Ok(body);
+        }
+        if !self.out_labels.is_empty() {
+            netlink::push_attr(
+                &mut body,
+                libc::RTA_NEWDST,
+                &mpls_label_stack(&self.out_labels),
+            );
+        }
+        if let Some(next_hop) = self.next_hop {
+            let via = match next_hop {
+                IpAddr::V4(addr) => {
+                    let mut via = (libc::AF_INET as u16).to_ne_bytes().to_vec();
+                    via.extend(addr.octets());
+                    via
+                }
+                IpAddr::V6(addr) => {
+                    let mut via = (libc::AF_INET6 as u16).to_ne_bytes().to_vec();
+                    via.extend(addr.octets());
+                    via
+                }
+            };
+            netlink::push_attr(&mut body, libc::RTA_VIA, &via);
+        }
+        netlink::push_attr(&mut body, libc::RTA_OIF, &ifindex(&self.dev)?.to_ne_bytes());
+        Ok(body)
+    }
+
+    pub fn install(&self) -> Result<(), String> {
+        let flags = (libc::NLM_F_CREATE | libc::NLM_F_REPLACE) as u16;
+        netlink::request(libc::RTM_NEWROUTE, flags, &self.netlink_body(true)?)
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn remove(&self) -> Result<(), String> {
+        netlink::request(libc::RTM_DELROUTE, 0, &self.netlink_body(false)?)
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Let the kernel take labelled packets in on `devs` and size its label
+// table for labels up to `max_label`, needs the mpls_router module
+pub fn enable_mpls(max_label: u32, devs: &[String]) -> Result<(), String> {
+    let write = |name: &str, value: String| -> Result<(), String> {
+        let path = format!("/proc/sys/net/mpls/{}", name);
+        let current =
+            std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        if current.trim() == value {
+            return Ok(());
+        }
+        std::fs::write(&path, value).map_err(|e| format!("cannot write {}: {}", path, e))
+    };
+    let current: u32 = std::fs::read_to_string("/proc/sys/net/mpls/platform_labels")
+        .map_err(|e| format!("MPLS is not available, load mpls_router: {}", e))?
+        .trim()
+        .parse()
+        .unwrap_or(0);
+    if current <= max_label {
+        write("platform_labels", (max_label + 1).to_string())?;
+    }
+    for dev in devs {
+        write(&format!("conf/{}/input", dev), "1".to_string())?;
+    }
+    Ok(())
+}
+
+// the routing table of a VRF device, from `ip -d link show`
+pub fn vrf_table(vrf: &str) -> Result<u32, String> {
+    let output = Command::new("ip")
+        .args(["-d", "link", "show", "dev", vrf])
+        .output()
+        .map_err(|e| format!("cannot run ip: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let text = String::from_utf8_lossy(&output.stdout).to_string();
+    let words: Vec<&str> = text.split_whitespace().collect();
+    words
+        .windows(3)
+        .find(|w| w[0] == "vrf" && w[1] == "table")
+        .and_then(|w| w[2].parse().ok())
+        .ok_or(format!("{} is not a VRF device", vrf))
+}
+
+// Withdraw the routes that are gone and program the new or changed ones,
+// `installed` is left describing what the kernel has
+pub fn sync_routes(installed: &mut Vec<KernelRoute>, wanted: Vec<KernelRoute>) {
+    for route in installed.iter() {
+        if wanted.iter().any(|r| r.key() == route.key()) {
+            continue;
+        }
+        if let Err(e) = route.remove() {
+            eprintln!("Error removing route {}: {}", route.prefix(), e);
+        }
+    }
+    installed.retain(|r| wanted.iter().any(|w| w.key() == r.key()));
+    for route in wanted {
+        if installed.contains(&route) {
+            continue;
+        }
+        match route.install() {
+            Ok(()) => {
+                println!(
+                    "Installed route {} via {} dev {}",
+                    route.prefix(),
+                    route.next_hop,
+                    route.dev
+                );
+                installed.retain(|r| r.key() != route.key());
+                installed.push(route);
+            }
+            Err(e) => eprintln!("Error installing route {}: {}", route.prefix(), e),
+        }
+    }
+}
+
+// Same as sync_routes for the MPLS table, where a label has a single route
+pub fn sync_mpls_routes(installed: &mut Vec<MplsRoute>, wanted: Vec<MplsRoute>) {
+    for route in installed.iter() {
+        if wanted.iter().any(|r| r.label == route.label) {
+            continue;
+        }
+        if let Err(e) = route.remove() {
+            eprintln!("Error removing MPLS route {}: {}", route.label, e);
+        }
+    }
+    installed.retain(|r| wanted.iter().any(|w| w.label == r.label));
+    for route in wanted {
+        if installed.contains(&route) {
+            continue;
+        }
+        match route.install() {
+            Ok(()) => {
+                println!(
+                    "Installed MPLS route {} {} dev {}",
+                    route.label,
+                    if route.out_labels.is_empty() {
+                        "pop".to_string()
+                    } else {
+                        format!("swap {}", label_stack(&route.out_labels))
+                    },
+                    route.dev
+                );
+                installed.retain(|r| r.label != route.label);
+                installed.push(route);
+            }
+            Err(e) => eprintln!("Error installing MPLS route {}: {}", route.label, e),
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use encoding_derive::Encoding;
+use nom::combinator::complete;
+use nom::multi::many0;
 use nom_derive::*;
 
 #[derive(Debug, Clone, NomBE, PartialEq, Encoding)]
@@ -28,8 +30,17 @@ pub enum LsaType {
     LsaSumnet,
     LsaSumasb,
     LsaAsexternal,
+    // opaque LSAs (RFC 5250), flooded on a link, in the area or in the AS
+    LsaOpaqueLink = 9,
+    LsaOpaqueArea,
+    LsaOpaqueAs,
 }
 
+// flags of the router LSA, in the high byte of LsaRouter::flags
+pub const ROUTER_FLAG_B: u16 = 0x0100;
+pub const ROUTER_FLAG_E: u16 = 0x0200;
+pub const ROUTER_FLAG_V: u16 = 0x0400;
+
 #[repr(u8)]
 pub enum LinkType {
     P2P = 1,
@@ -45,6 +56,8 @@ pub enum Lsa {
     LsaSumnet(LsaSum),
     LsaSumasb(LsaSum),
     LsaAsexternal(LsaAsexternal),
+    // of any flooding scope
+    LsaOpaque(LsaOpaque),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -62,6 +75,7 @@ impl Lsa {
             Lsa::LsaSumnet(lsa) => &lsa.header,
             Lsa::LsaSumasb(lsa) => &lsa.header,
             Lsa::LsaAsexternal(lsa) => &lsa.header,
+            Lsa::LsaOpaque(lsa) => &lsa.header,
         }
     }
 
@@ -72,6 +86,7 @@ impl Lsa {
             Lsa::LsaSumnet(lsa) => &mut lsa.header,
             Lsa::LsaSumasb(lsa) => &mut lsa.header,
             Lsa::LsaAsexternal(lsa) => &mut lsa.header,
+            Lsa::LsaOpaque(lsa) => &mut lsa.header,
         }
     }
 
@@ -158,6 +173,10 @@ impl<'a> Parse<&'a [u8]> for Lsa {
                 let (input, lsa) = LsaAsexternal::parse(input)?;
                 Ok((input, Lsa::LsaAsexternal(lsa)))
             }
+            9..=11 => {
+                let (input, lsa) = LsaOpaque::parse(input)?;
+                Ok((input, Lsa::LsaOpaque(lsa)))
+            }
             _ => Err(nom::Err::Error(nom::error::make_error(
                 input,
                 nom::error::ErrorKind::Tag,
@@ -174,6 +193,7 @@ impl Lsa {
             Lsa::LsaSumnet(lsa) => lsa.encode(),
             Lsa::LsaSumasb(lsa) => lsa.encode(),
             Lsa::LsaAsexternal(lsa) => lsa.encode(),
+            Lsa::LsaOpaque(lsa) => lsa.encode(),
         }
     }
 
@@ -212,6 +232,18 @@ pub struct LsaRouterLink {
 }
 
 impl LsaRouterLink {
+    // the cost of the link in a topology (RFC 4915 3.2), None if the link
+    // is not part of it
+    pub fn topology_metric(&self, mt_id: u8) -> Option<u16> {
+        if mt_id == 0 {
+            return Some(self.metric);
+        }
+        self.tos_list
+            .iter()
+            .find(|x| x.tos == mt_id)
+            .map(|x| x.metric)
+    }
+
     pub fn new(metric: u16) -> Self {
         LsaRouterLink {
             link_id: 0,
@@ -248,6 +280,21 @@ pub struct LsaSum {
     pub header: LsaHeader,
     pub network_mask: u32,
     pub metric: u32,
+    // the metrics in the other topologies, MT-ID in the high byte
+    #[nom(Count = "(header.length as usize).saturating_sub(28) / 4")]
+    pub tos_list: Vec<u32>,
+}
+
+impl LsaSum {
+    pub fn topology_metric(&self, mt_id: u8) -> Option<u32> {
+        if mt_id == 0 {
+            return Some(self.metric & 0xFF_FFFF);
+        }
+        self.tos_list
+            .iter()
+            .find(|x| (*x >> 24) as u8 == mt_id)
+            .map(|x| x & 0xFF_FFFF)
+    }
 }
 
 #[derive(Debug, Clone, NomBE, Encoding)]
@@ -258,9 +305,74 @@ pub struct LsaAsexternal {
     pub metric: u32,
     pub forwarding_address: u32,
     pub external_route_tag: u32,
+    #[nom(Count = "(header.length as usize).saturating_sub(36) / 12")]
+    pub tos_list: Vec<LsaAsexternalTos>,
+}
+
+// an external route in another topology, the E bit and the MT-ID are in
+// the high byte of the metric
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaAsexternalTos {
+    pub metric: u32,
+    pub forwarding_address: u32,
+    pub external_route_tag: u32,
+}
+
+// The link state ID of an opaque LSA is its opaque type in the high byte
+// and an ID picked by the originator, the body is up to the application
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaOpaque {
+    #[nom(
+        Verify = "(LsaType::LsaOpaqueLink as u8..=LsaType::LsaOpaqueAs as u8).contains(&header.ls_type)"
+    )]
+    pub header: LsaHeader,
+    #[nom(Count = "(header.length as usize).saturating_sub(20)")]
+    pub body: Vec<u8>,
+}
+
+impl LsaOpaque {
+    pub fn opaque_type(&self) -> u8 {
+        (self.header.link_state_id >> 24) as u8
+    }
+
+    pub fn tlvs(&self) -> Vec<OpaqueTlv> {
+        OpaqueTlv::parse_all(&self.body)
+    }
+}
+
+// The TLVs most opaque LSAs are made of, sub-TLVs have the same layout
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct OpaqueTlv {
+    pub tlv_type: u16,
+    // of the value, not counting the padding
+    pub length: u16,
+    // padded to a 32-bit boundary
+    #[nom(Count = "(length as usize).div_ceil(4) * 4")]
+    pub value: Vec<u8>,
+}
+
+impl OpaqueTlv {
+    pub fn new(tlv_type: u16, mut value: Vec<u8>) -> Self {
+        let length = value.len() as u16;
+        value.resize(value.len().div_ceil(4) * 4, 0);
+        OpaqueTlv {
+            tlv_type,
+            length,
+            value,
+        }
+    }
+
+    // a truncated TLV ends the list
+    pub fn parse_all(input: &[u8]) -> Vec<OpaqueTlv> {
+        many0(complete(OpaqueTlv::parse))(input).map_or(vec![], |(_, tlvs)| tlvs)
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value[..self.length as usize]
+    }
 }
 
-fn fletcher16_checksum(data: Vec<u8>, len: u32, offset: u32) -> u16 {
+pub fn fletcher16_checksum(data: Vec<u8>, len: u32, offset: u32) -> u16 {
     let mut c0 = 0i32;
     let mut c1 = 0i32;
     for idx in 0..len {
@@ -304,3 +416,71 @@ fn fletcher16_checksum(data: Vec<u8>, len: u32, offset: u32) -> u16 {
 
     ((x << 8) | y) as u16
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opaque_tlv_padding() {
+        let tlv = OpaqueTlv::new(7, b"r1".to_vec());
+        assert_eq!(tlv.length, 2);
+        assert_eq!(tlv.value(), b"r1");
+        let bytes = tlv.encode();
+        assert_eq!(bytes, vec![0, 7, 0, 2, b'r', b'1', 0, 0]);
+        let tlvs = OpaqueTlv::parse_all(&[bytes.clone(), bytes].concat());
+        assert_eq!(tlvs.len(), 2);
+        assert_eq!(tlvs[1].value(), b"r1");
+    }
+
+    #[test]
+    fn truncated_opaque_tlv_ends_the_list() {
+        let mut bytes = OpaqueTlv::new(1, vec![1, 2, 3, 4]).encode();
+        bytes.extend(OpaqueTlv::new(2, vec![5, 6, 7, 8]).encode());
+        // a length running past the end drops that TLV only
+        bytes[10..12].copy_from_slice(&8u16.to_be_bytes());
+        let tlvs = OpaqueTlv::parse_all(&bytes);
+        assert_eq!(tlvs.len(), 1);
+        assert_eq!(tlvs[0].value(), [1, 2, 3, 4]);
+        // padding missing at the very end
+        assert_eq!(OpaqueTlv::parse_all(&bytes[..6]).len(), 0);
+        assert!(OpaqueTlv::parse_all(&[]).is_empty());
+    }
+
+    #[test]
+    fn opaque_lsa_round_trip() {
+        let body = [
+            OpaqueTlv::new(1, vec![0, 0, 0, 1]).encode(),
+            OpaqueTlv::new(7, b"r1".to_vec()).encode(),
+        ]
+        .concat();
+        let mut lsa = Lsa::LsaOpaque(LsaOpaque {
+            header: LsaHeader {
+                age: 0,
+                options: 0x02,
+                ls_type: LsaType::LsaOpaqueArea as u8,
+                link_state_id: 4 << 24,
+                advertising_router: 0x0101_0101,
+                sequence_number: 0x8000_0001,
+                checksum: 0,
+                length: 0,
+            },
+            body: body.clone(),
+        });
+        lsa.set_checksum_length();
+        let bytes = lsa.encode();
+        assert_eq!(lsa.get_hdr().length as usize, 20 + body.len());
+        let (rest, parsed) = Lsa::parse(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.get_hdr(), lsa.get_hdr());
+        match parsed {
+            Lsa::LsaOpaque(opaque) => {
+                assert_eq!(opaque.opaque_type(), 4);
+                assert_eq!(opaque.tlvs().len(), 2);
+            }
+            other => panic!("expected an opaque LSA, got {:?}", other),
+        }
+        // the body is cut short
+        assert!(Lsa::parse(&bytes[..bytes.len() - 4]).is_err());
+    }
+}
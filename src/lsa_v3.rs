@@ -0,0 +1,548 @@
+// LSA formats of OSPFv3 (RFC 5340 A.4). Addresses are gone from the router
+// and network LSAs, prefixes are carried by the Link and Intra-Area-Prefix LSAs.
+use encoding_derive::Encoding;
+use nom_derive::*;
+use pnet::ipnetwork::Ipv6Network;
+use std::net::Ipv6Addr;
+
+use crate::lsa::{fletcher16_checksum, LsaCmpResult};
+
+pub const LSA_V3_HDR_LEN: usize = 20;
+
+#[derive(Debug, Clone, NomBE, PartialEq, Encoding)]
+pub struct LsaHeaderV3 {
+    pub age: u16,
+    pub ls_type: u16,
+    pub link_state_id: u32,
+    pub advertising_router: u32,
+    pub sequence_number: u32,
+    pub checksum: u16,
+    pub length: u16,
+}
+
+impl LsaHeaderV3 {
+    pub fn same_ids(&self, oth_hdr: &LsaHeaderV3) -> bool {
+        self.ls_type == oth_hdr.ls_type
+            && self.link_state_id == oth_hdr.link_state_id
+            && self.advertising_router == oth_hdr.advertising_router
+    }
+
+    // only flooded on the link it was received on (RFC 5340 A.4.2.1)
+    pub fn link_local_scope(&self) -> bool {
+        self.ls_type & LSA_V3_SCOPE_MASK == 0
+    }
+}
+
+// the S1/S2 bits of the LS type, 00 is link-local, 01 area and 10 AS scope
+pub const LSA_V3_SCOPE_MASK: u16 = 0x6000;
+
+#[repr(u16)]
+pub enum LsaTypeV3 {
+    Router = 0x2001,
+    Network = 0x2002,
+    InterAreaPrefix = 0x2003,
+    InterAreaRouter = 0x2004,
+    Link = 0x0008,
+    IntraAreaPrefix = 0x2009,
+}
+
+// flags of the router LSA
+pub const ROUTER_V3_FLAG_B: u8 = 0x01;
+pub const ROUTER_V3_FLAG_E: u8 = 0x02;
+pub const ROUTER_V3_FLAG_V: u8 = 0x04;
+
+// prefix options (RFC 5340 A.4.1.1)
+pub const PREFIX_OPTION_NU: u8 = 0x01;
+pub const PREFIX_OPTION_LA: u8 = 0x02;
+
+#[repr(u8)]
+pub enum LinkTypeV3 {
+    P2P = 1,
+    Transit,
+    Virtual = 4,
+}
+
+#[derive(Debug, Clone)]
+pub enum LsaV3 {
+    Router(LsaRouterV3),
+    Network(LsaNetworkV3),
+    InterAreaPrefix(LsaInterAreaPrefix),
+    InterAreaRouter(LsaInterAreaRouter),
+    Link(LsaLink),
+    IntraAreaPrefix(LsaIntraAreaPrefix),
+    // AS-external and unknown types, flooded but not used for routing
+    Other(LsaOtherV3),
+}
+
+impl LsaV3 {
+    pub fn get_hdr(&self) -> &LsaHeaderV3 {
+        match self {
+            LsaV3::Router(lsa) => &lsa.header,
+            LsaV3::Network(lsa) => &lsa.header,
+            LsaV3::InterAreaPrefix(lsa) => &lsa.header,
+            LsaV3::InterAreaRouter(lsa) => &lsa.header,
+            LsaV3::Link(lsa) => &lsa.header,
+            LsaV3::IntraAreaPrefix(lsa) => &lsa.header,
+            LsaV3::Other(lsa) => &lsa.header,
+        }
+    }
+
+    pub fn get_mut_hdr(&mut self) -> &mut LsaHeaderV3 {
+        match self {
+            LsaV3::Router(lsa) => &mut lsa.header,
+            LsaV3::Network(lsa) => &mut lsa.header,
+            LsaV3::InterAreaPrefix(lsa) => &mut lsa.header,
+            LsaV3::InterAreaRouter(lsa) => &mut lsa.header,
+            LsaV3::Link(lsa) => &mut lsa.header,
+            LsaV3::IntraAreaPrefix(lsa) => &mut lsa.header,
+            LsaV3::Other(lsa) => &mut lsa.header,
+        }
+    }
+
+    // same rules as OSPFv2 (RFC 2328 13.1)
+    pub fn cmp_with(&self, other: &LsaV3) -> LsaCmpResult {
+        let my_hdr = self.get_hdr();
+        let oth_hdr = other.get_hdr();
+        assert!(my_hdr.same_ids(oth_hdr));
+        if my_hdr.sequence_number != oth_hdr.sequence_number {
+            if (my_hdr.sequence_number as i32) > (oth_hdr.sequence_number as i32) {
+                LsaCmpResult::Newer
+            } else {
+                LsaCmpResult::Older
+            }
+        } else if my_hdr.checksum != oth_hdr.checksum {
+            if my_hdr.checksum > oth_hdr.checksum {
+                LsaCmpResult::Newer
+            } else {
+                LsaCmpResult::Older
+            }
+        } else if my_hdr.age == oth_hdr.age {
+            LsaCmpResult::Same
+        } else if my_hdr.age == crate::config::MAX_AGE as u16 {
+            LsaCmpResult::Newer
+        } else if oth_hdr.age == crate::config::MAX_AGE as u16 {
+            LsaCmpResult::Older
+        } else if (my_hdr.age as i32 - oth_hdr.age as i32).unsigned_abs()
+            > crate::config::MAX_AGE_DIFF
+        {
+            if my_hdr.age < oth_hdr.age {
+                LsaCmpResult::Newer
+            } else {
+                LsaCmpResult::Older
+            }
+        } else {
+            LsaCmpResult::Same
+        }
+    }
+
+    pub fn same_ids(&self, oth_hdr: &LsaHeaderV3) -> bool {
+        self.get_hdr().same_ids(oth_hdr)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            LsaV3::Router(lsa) => lsa.encode(),
+            LsaV3::Network(lsa) => lsa.encode(),
+            LsaV3::InterAreaPrefix(lsa) => lsa.encode(),
+            LsaV3::InterAreaRouter(lsa) => lsa.encode(),
+            LsaV3::Link(lsa) => lsa.encode(),
+            LsaV3::IntraAreaPrefix(lsa) => lsa.encode(),
+            LsaV3::Other(lsa) => lsa.encode(),
+        }
+    }
+
+    pub fn set_checksum_length(&mut self) {
+        let buf = self.encode();
+        self.get_mut_hdr().length = buf.len() as u16;
+        self.get_mut_hdr().checksum = 0;
+        // the age is not covered by the checksum
+        let buf = self.encode()[2..].to_vec();
+        let len = buf.len();
+        let sum = fletcher16_checksum(buf, len as u32, 14);
+        self.get_mut_hdr().checksum = sum;
+    }
+}
+
+impl<'a> Parse<&'a [u8]> for LsaV3 {
+    fn parse(input: &'a [u8]) -> nom::IResult<&'a [u8], Self> {
+        if input.len() < LSA_V3_HDR_LEN {
+            return Err(nom::Err::Error(nom::error::make_error(
+                input,
+                nom::error::ErrorKind::Eof,
+            )));
+        }
+        // the counts inside an LSA can't make it read into the next one
+        let length = u16::from_be_bytes([input[18], input[19]]) as usize;
+        if length < LSA_V3_HDR_LEN || length > input.len() {
+            return Err(nom::Err::Error(nom::error::make_error(
+                input,
+                nom::error::ErrorKind::LengthValue,
+            )));
+        }
+        let (input, rest) = input.split_at(length);
+        let ls_type = u16::from_be_bytes([input[2], input[3]]);
+        let lsa = match ls_type {
+            x if x == LsaTypeV3::Router as u16 => LsaV3::Router(LsaRouterV3::parse(input)?.1),
+            x if x == LsaTypeV3::Network as u16 => LsaV3::Network(LsaNetworkV3::parse(input)?.1),
+            x if x == LsaTypeV3::InterAreaPrefix as u16 => {
+                LsaV3::InterAreaPrefix(LsaInterAreaPrefix::parse(input)?.1)
+            }
+            x if x == LsaTypeV3::InterAreaRouter as u16 => {
+                LsaV3::InterAreaRouter(LsaInterAreaRouter::parse(input)?.1)
+            }
+            x if x == LsaTypeV3::Link as u16 => LsaV3::Link(LsaLink::parse(input)?.1),
+            x if x == LsaTypeV3::IntraAreaPrefix as u16 => {
+                LsaV3::IntraAreaPrefix(LsaIntraAreaPrefix::parse(input)?.1)
+            }
+            _ => LsaV3::Other(LsaOtherV3::parse(input)?.1),
+        };
+        Ok((rest, lsa))
+    }
+}
+
+// An address prefix, only as many 32 bit words as the prefix length needs
+// are carried (RFC 5340 A.4.1). The third field is the metric in
+// Intra-Area-Prefix LSAs and reserved everywhere else.
+#[derive(Debug, Clone, PartialEq, NomBE, Encoding)]
+pub struct PrefixV3 {
+    pub prefix_length: u8,
+    pub prefix_options: u8,
+    pub metric: u16,
+    #[nom(Count = "(prefix_length as usize + 31) / 32")]
+    pub address: Vec<u32>,
+}
+
+impl PrefixV3 {
+    pub fn from_network(net: &Ipv6Network, metric: u16) -> Self {
+        let octets = net.network().octets();
+        let words = (net.prefix() as usize).div_ceil(32);
+        PrefixV3 {
+            prefix_length: net.prefix(),
+            prefix_options: 0,
+            metric,
+            address: (0..words)
+                .map(|i| u32::from_be_bytes(octets[i * 4..i * 4 + 4].try_into().unwrap()))
+                .collect(),
+        }
+    }
+
+    pub fn to_network(&self) -> Option<Ipv6Network> {
+        let mut octets = [0u8; 16];
+        for (i, word) in self.address.iter().take(4).enumerate() {
+            octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        let net = Ipv6Network::new(Ipv6Addr::from(octets), self.prefix_length).ok()?;
+        // drop host bits a sloppy originator may have left set
+        Ipv6Network::new(net.network(), net.prefix()).ok()
+    }
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaRouterV3 {
+    #[nom(Verify = "header.ls_type == LsaTypeV3::Router as u16 && header.length >= 24")]
+    pub header: LsaHeaderV3,
+    pub flags: u8,
+    pub options_hi: u8,
+    pub options: u16,
+    #[nom(Count = "(header.length as usize - 24) / 16")]
+    pub links: Vec<LsaRouterLinkV3>,
+}
+
+#[derive(Debug, Clone, PartialEq, NomBE, Encoding)]
+pub struct LsaRouterLinkV3 {
+    pub link_type: u8,
+    pub reserved: u8,
+    pub metric: u16,
+    pub interface_id: u32,
+    pub nbr_interface_id: u32,
+    pub nbr_router_id: u32,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaNetworkV3 {
+    #[nom(Verify = "header.ls_type == LsaTypeV3::Network as u16 && header.length >= 24")]
+    pub header: LsaHeaderV3,
+    pub reserved: u8,
+    pub options_hi: u8,
+    pub options: u16,
+    #[nom(Count = "(header.length as usize - 24) / 4")]
+    pub attached_routers: Vec<u32>,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaInterAreaPrefix {
+    #[nom(Verify = "header.ls_type == LsaTypeV3::InterAreaPrefix as u16 && header.length >= 28")]
+    pub header: LsaHeaderV3,
+    pub reserved: u8,
+    // the metric is 24 bits long
+    pub metric_hi: u8,
+    pub metric: u16,
+    pub prefix: PrefixV3,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaInterAreaRouter {
+    #[nom(Verify = "header.ls_type == LsaTypeV3::InterAreaRouter as u16")]
+    pub header: LsaHeaderV3,
+    pub reserved: u8,
+    pub options_hi: u8,
+    pub options: u16,
+    pub reserved2: u8,
+    pub metric_hi: u8,
+    pub metric: u16,
+    pub dest_router_id: u32,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaLink {
+    #[nom(Verify = "header.ls_type == LsaTypeV3::Link as u16")]
+    pub header: LsaHeaderV3,
+    pub router_priority: u8,
+    pub options_hi: u8,
+    pub options: u16,
+    #[nom(Count = "4")]
+    pub link_local_addr: Vec<u32>,
+    // a prefix takes at least 4 bytes
+    #[nom(Verify = "(*num_prefixes as usize) * 4 <= (header.length as usize).saturating_sub(44)")]
+    pub num_prefixes: u32,
+    #[nom(Count = "num_prefixes")]
+    pub prefixes: Vec<PrefixV3>,
+}
+
+impl LsaLink {
+    pub fn link_local(&self) -> Ipv6Addr {
+        let mut octets = [0u8; 16];
+        for (i, word) in self.link_local_addr.iter().take(4).enumerate() {
+            octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        Ipv6Addr::from(octets)
+    }
+}
+
+pub fn ipv6_to_words(addr: Ipv6Addr) -> Vec<u32> {
+    addr.octets()
+        .chunks(4)
+        .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+        .collect()
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaIntraAreaPrefix {
+    #[nom(Verify = "header.ls_type == LsaTypeV3::IntraAreaPrefix as u16")]
+    pub header: LsaHeaderV3,
+    #[nom(Verify = "(*num_prefixes as usize) * 4 <= (header.length as usize).saturating_sub(32)")]
+    pub num_prefixes: u16,
+    // the router or network LSA the prefixes are attached to
+    pub ref_ls_type: u16,
+    pub ref_link_state_id: u32,
+    pub ref_advertising_router: u32,
+    #[nom(Count = "num_prefixes")]
+    pub prefixes: Vec<PrefixV3>,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LsaOtherV3 {
+    #[nom(Verify = "header.length as usize >= LSA_V3_HDR_LEN")]
+    pub header: LsaHeaderV3,
+    #[nom(Count = "header.length as usize - LSA_V3_HDR_LEN")]
+    pub body: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdr(ls_type: LsaTypeV3) -> LsaHeaderV3 {
+        LsaHeaderV3 {
+            age: 1,
+            ls_type: ls_type as u16,
+            link_state_id: 5,
+            advertising_router: 0x0101_0101,
+            sequence_number: 0x8000_0001,
+            checksum: 0,
+            length: 0,
+        }
+    }
+
+    fn net(s: &str) -> Ipv6Network {
+        s.parse().unwrap()
+    }
+
+    fn round_trip(mut lsa: LsaV3) -> (LsaV3, Vec<u8>) {
+        lsa.set_checksum_length();
+        let bytes = lsa.encode();
+        assert_eq!(lsa.get_hdr().length as usize, bytes.len());
+        let (rest, parsed) = LsaV3::parse(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.get_hdr(), lsa.get_hdr());
+        assert_eq!(parsed.encode(), bytes);
+        (parsed, bytes)
+    }
+
+    #[test]
+    fn prefix_words() {
+        for (s, words) in [
+            ("::/0", 0),
+            ("2001:db8::/32", 1),
+            ("2001:db8:1::/64", 2),
+            ("fd00::2/128", 4),
+        ] {
+            let prefix = PrefixV3::from_network(&net(s), 10);
+            assert_eq!(prefix.address.len(), words);
+            assert_eq!(prefix.to_network(), Some(net(s)));
+            let bytes = prefix.encode();
+            assert_eq!(bytes.len(), 4 + words * 4);
+            assert_eq!(PrefixV3::parse(&bytes).unwrap().1, prefix);
+        }
+        // host bits are cleared
+        let prefix = PrefixV3::from_network(&net("fd00::2/128"), 0);
+        let sloppy = PrefixV3 {
+            prefix_length: 64,
+            ..prefix
+        };
+        assert_eq!(sloppy.to_network(), Some(net("fd00::/64")));
+    }
+
+    #[test]
+    fn router_and_network_round_trip() {
+        let link = LsaRouterLinkV3 {
+            link_type: LinkTypeV3::P2P as u8,
+            reserved: 0,
+            metric: 10,
+            interface_id: 2,
+            nbr_interface_id: 3,
+            nbr_router_id: 0x0202_0202,
+        };
+        let (parsed, bytes) = round_trip(LsaV3::Router(LsaRouterV3 {
+            header: hdr(LsaTypeV3::Router),
+            flags: ROUTER_V3_FLAG_E,
+            options_hi: 0,
+            options: 0x13,
+            links: vec![link.clone(), link.clone()],
+        }));
+        assert_eq!(bytes.len(), 24 + 2 * 16);
+        match parsed {
+            LsaV3::Router(r) => assert_eq!(r.links, vec![link.clone(), link]),
+            other => panic!("expected a router LSA, got {:?}", other),
+        }
+        let (parsed, _) = round_trip(LsaV3::Network(LsaNetworkV3 {
+            header: hdr(LsaTypeV3::Network),
+            reserved: 0,
+            options_hi: 0,
+            options: 0x13,
+            attached_routers: vec![1, 2, 3],
+        }));
+        match parsed {
+            LsaV3::Network(n) => assert_eq!(n.attached_routers, vec![1, 2, 3]),
+            other => panic!("expected a network LSA, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn link_and_intra_area_prefix_round_trip() {
+        let ll: Ipv6Addr = "fe80::1".parse().unwrap();
+        let prefixes = vec![
+            PrefixV3::from_network(&net("2001:db8:1::/64"), 0),
+            PrefixV3::from_network(&net("fd00::1/128"), 0),
+        ];
+        let (parsed, _) = round_trip(LsaV3::Link(LsaLink {
+            header: hdr(LsaTypeV3::Link),
+            router_priority: 1,
+            options_hi: 0,
+            options: 0x13,
+            link_local_addr: ipv6_to_words(ll),
+            num_prefixes: 2,
+            prefixes: prefixes.clone(),
+        }));
+        match parsed {
+            LsaV3::Link(l) => {
+                assert_eq!(l.link_local(), ll);
+                assert_eq!(l.prefixes, prefixes);
+            }
+            other => panic!("expected a link LSA, got {:?}", other),
+        }
+        let (parsed, _) = round_trip(LsaV3::IntraAreaPrefix(LsaIntraAreaPrefix {
+            header: hdr(LsaTypeV3::IntraAreaPrefix),
+            num_prefixes: 2,
+            ref_ls_type: LsaTypeV3::Router as u16,
+            ref_link_state_id: 0,
+            ref_advertising_router: 0x0101_0101,
+            prefixes: prefixes.clone(),
+        }));
+        match parsed {
+            LsaV3::IntraAreaPrefix(p) => assert_eq!(p.prefixes, prefixes),
+            other => panic!("expected an intra-area-prefix LSA, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_type_kept_as_is() {
+        let mut header = hdr(LsaTypeV3::Router);
+        header.ls_type = 0x400A;
+        let (parsed, _) = round_trip(LsaV3::Other(LsaOtherV3 {
+            header,
+            body: vec![1, 2, 3, 4],
+        }));
+        assert!(matches!(parsed, LsaV3::Other(o) if o.body == vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn malformed_lengths() {
+        let mut lsa = LsaV3::Network(LsaNetworkV3 {
+            header: hdr(LsaTypeV3::Network),
+            reserved: 0,
+            options_hi: 0,
+            options: 0,
+            attached_routers: vec![1, 2],
+        });
+        lsa.set_checksum_length();
+        let bytes = lsa.encode();
+        // shorter than a header
+        assert!(LsaV3::parse(&bytes[..LSA_V3_HDR_LEN - 1]).is_err());
+        // a length past the end of the input
+        assert!(LsaV3::parse(&bytes[..bytes.len() - 4]).is_err());
+        // a length shorter than the header
+        let mut bad = bytes.clone();
+        bad[18..20].copy_from_slice(&16u16.to_be_bytes());
+        assert!(LsaV3::parse(&bad).is_err());
+        // a length shorter than the network LSA's fixed part
+        bad[18..20].copy_from_slice(&22u16.to_be_bytes());
+        assert!(LsaV3::parse(&bad).is_err());
+        // a length cutting off the last router only reads up to it
+        let mut short = bytes.clone();
+        short[18..20].copy_from_slice(&28u16.to_be_bytes());
+        let (rest, parsed) = LsaV3::parse(&short).unwrap();
+        assert_eq!(rest.len(), 4);
+        assert!(matches!(parsed, LsaV3::Network(n) if n.attached_routers == vec![1]));
+    }
+
+    #[test]
+    fn prefix_counts_past_the_length() {
+        let mut lsa = LsaV3::Link(LsaLink {
+            header: hdr(LsaTypeV3::Link),
+            router_priority: 1,
+            options_hi: 0,
+            options: 0,
+            link_local_addr: ipv6_to_words("fe80::1".parse().unwrap()),
+            num_prefixes: 1,
+            prefixes: vec![PrefixV3::from_network(&net("2001:db8::/64"), 0)],
+        });
+        lsa.set_checksum_length();
+        let mut bytes = lsa.encode();
+        bytes[40..44].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(LsaV3::parse(&bytes).is_err());
+
+        let mut lsa = LsaV3::IntraAreaPrefix(LsaIntraAreaPrefix {
+            header: hdr(LsaTypeV3::IntraAreaPrefix),
+            num_prefixes: 1,
+            ref_ls_type: LsaTypeV3::Router as u16,
+            ref_link_state_id: 0,
+            ref_advertising_router: 0,
+            prefixes: vec![PrefixV3::from_network(&net("2001:db8::/64"), 0)],
+        });
+        lsa.set_checksum_length();
+        let mut bytes = lsa.encode();
+        bytes[20..22].copy_from_slice(&3u16.to_be_bytes());
+        assert!(LsaV3::parse(&bytes).is_err());
+    }
+}
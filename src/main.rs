@@ -1,78 +1,129 @@
 mod config;
 mod database;
+mod database_v3;
+mod election;
+mod flex_algo;
+mod frr;
 mod interface;
+mod interface_fsm;
+mod interface_manager;
 mod interface_query;
 mod interface_send;
+mod interface_v3;
+mod interface_v3_send;
+mod kernel;
 mod lsa;
+mod lsa_v3;
+mod mgmt;
 mod neighbor;
+mod netlink;
+mod opaque;
 mod packets;
+mod packets_v3;
 mod receiver;
+mod receiver_v3;
 mod route;
+mod route_v3;
+mod router_info;
 mod sender;
+mod socket_v3;
+mod sr;
 mod timer;
 
-use pnet::datalink;
+use std::net::Ipv4Addr;
 use tokio;
 use tokio::sync::mpsc;
 
-use crate::interface::Interface;
+use crate::interface_manager::InterfaceManager;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let ints = datalink::interfaces();
-    let mut my_ints: Vec<Interface> = Vec::new();
-    let my_config = config::Config::new();
+// Everything one OSPF instance needs: its databases, its packet sender and
+// the interface manager driving them
+async fn run_instance(
+    config_path: String,
+    config_file: config::ConfigFile,
+    link_events: mpsc::Receiver<netlink::LinkEvent>,
+    mgmt_requests: mpsc::Receiver<mgmt::MgmtRequest>,
+) {
+    let name = config_file.name.clone();
+    let my_config = config_file.global;
     let (db_mpsc_sdr, db_mpsc_rcvr) = mpsc::channel::<database::DatabaseRequest>(10);
+    let (db_v3_mpsc_sdr, db_v3_mpsc_rcvr) = mpsc::channel::<database_v3::DatabaseV3Request>(10);
     let (sdr_mpsc_sdr, sdr_mpsc_rcvr) = mpsc::channel::<sender::SenderRequest>(10);
-    println!("=== Iterating over network interfaces ===");
-    for i in ints {
-        // Skip loopback interfaces
-        if i.is_loopback() {
-            continue;
-        }
-        my_ints.push(Interface::from(
-            i,
-            &my_config,
-            db_mpsc_sdr.clone(),
-            sdr_mpsc_sdr.clone(),
-        ));
-        println!(
-            "Interface: {} {}",
-            my_ints.last().unwrap().name,
-            my_ints.last().unwrap().addr
-        );
-    }
-    println!("=== Done iterating over network interfaces ===");
 
-    let mut my_database =
-        crate::database::LinkStateDatabase::from(my_config, db_mpsc_rcvr, my_ints.clone());
+    let mut my_database = crate::database::LinkStateDatabase::from(my_config, db_mpsc_rcvr);
+    my_database.node_info = config_file.node_info.clone();
+
+    let mut my_database_v3 = database_v3::LinkStateDatabaseV3::from(my_config, db_v3_mpsc_rcvr);
 
     let mut my_sender = crate::sender::OSPFPacketSender {
         request_channel: sdr_mpsc_rcvr,
+        ip_identification: 0,
     };
 
     tokio::spawn(async move {
         my_database.database_thread().await;
         println!("Database thread stopped");
     });
+    tokio::spawn(async move {
+        my_database_v3.database_thread().await;
+        println!("OSPFv3 database thread stopped");
+    });
     tokio::spawn(async move {
         my_sender.sender_thread().await;
         println!("Sender thread stopped");
     });
-    for i in my_ints {
-        tokio::spawn(async move {
-            i.clone().receiver().await;
-            println!("Interface {} receiver thread stopped", i.name);
-        });
+
+    let mut manager = InterfaceManager::new(
+        &config_path,
+        config_file,
+        db_mpsc_sdr,
+        db_v3_mpsc_sdr,
+        sdr_mpsc_sdr,
+    );
+    manager.start_all().await;
+    manager.run(link_events, mgmt_requests).await;
+    println!("Instance {} stopped", name);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config_path = std::env::args()
+        .nth(1)
+        .unwrap_or(config::DEFAULT_CONFIG_PATH.to_string());
+    let mut instances =
+        config::ConfigFile::load_or_default(&config_path).expect("Error loading config file");
+    for config_file in instances.iter_mut() {
+        interface_manager::resolve_instance(config_file)
+            .map_err(|e| format!("instance {}: {}", config_file.name, e))?;
+        println!(
+            "Instance {}: router ID {}, vrf {:?}, table {}",
+            config_file.name,
+            Ipv4Addr::from(config_file.global.router_id),
+            config_file.vrf,
+            config_file.global.route_table
+        );
     }
-    // tokio::spawn(async move {
-    //     my_ints[0].receiver().await;
-    //     println!("Receiver thread stopped");
-    // });
 
-    // loop {
-    //     my_ints[0].send_hello().await;
-    //     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-    // }
-    loop {}
+    // subscribe before the first scan so no change in between is missed
+    let link_events =
+        netlink::start_monitor(instances.len()).expect("Error opening netlink socket");
+    let mgmt_requests = mgmt::start_listener(config::DEFAULT_MGMT_SOCKET_PATH, instances.len())
+        .expect("Error opening management socket");
+    let mut tasks = vec![];
+    for ((config_file, link_events), mgmt_requests) in
+        instances.into_iter().zip(link_events).zip(mgmt_requests)
+    {
+        tasks.push(tokio::spawn(run_instance(
+            config_path.clone(),
+            config_file,
+            link_events,
+            mgmt_requests,
+        )));
+    }
+    for task in tasks {
+        task.await?;
+    }
+    let _ = std::fs::remove_file(config::DEFAULT_MGMT_SOCKET_PATH);
+    println!("All instances stopped");
+    Ok(())
 }
@@ -0,0 +1,122 @@
+// Management socket, every line written to it is one command and gets a
+// reply from the interface manager, e.g. `echo reload | nc -U <socket>`
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug, Clone)]
+pub enum MgmtCommand {
+    Reload,
+    // force stub router mode on, or end it (RFC 6987)
+    MaxMetric(bool),
+    // resync the databases of the Full adjacencies out of band (RFC 4811)
+    Resync,
+    // print state, routers go by their advertised hostnames
+    Show(Show),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Show {
+    Neighbors,
+    Database,
+    Routes,
+}
+
+#[derive(Debug)]
+pub struct MgmtRequest {
+    pub command: MgmtCommand,
+    pub reply_channel: oneshot::Sender<String>,
+}
+
+pub fn parse_command(line: &str) -> Result<MgmtCommand, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["reload"] => Ok(MgmtCommand::Reload),
+        ["max-metric", "on"] => Ok(MgmtCommand::MaxMetric(true)),
+        ["max-metric", "off"] => Ok(MgmtCommand::MaxMetric(false)),
+        ["resync"] => Ok(MgmtCommand::Resync),
+        ["show", "neighbors"] => Ok(MgmtCommand::Show(Show::Neighbors)),
+        ["show", "database"] => Ok(MgmtCommand::Show(Show::Database)),
+        ["show", "routes"] => Ok(MgmtCommand::Show(Show::Routes)),
+        _ => Err(format!("unknown command '{}'", line.trim())),
+    }
+}
+
+// Pass the command to every instance, the reply is "ok" unless one of
+// them reports something else. What they show is put one after the other.
+async fn dispatch(command: MgmtCommand, instances: &[mpsc::Sender<MgmtRequest>]) -> Option<String> {
+    let mut replies = vec![];
+    for requests in instances {
+        let (rpl_tx, rpl_rx) = oneshot::channel();
+        let req = MgmtRequest {
+            command: command.clone(),
+            reply_channel: rpl_tx,
+        };
+        requests.send(req).await.ok()?;
+        replies.push(
+            rpl_rx
+                .await
+                .unwrap_or_else(|_| "error: no reply".to_string()),
+        );
+    }
+    if let MgmtCommand::Show(_) = command {
+        return Some(replies.join("\n"));
+    }
+    replies.retain(|x| x != "ok");
+    if replies.is_empty() {
+        return Some("ok".to_string());
+    }
+    Some(replies.join("; "))
+}
+
+async fn handle_client(stream: UnixStream, instances: Vec<mpsc::Sender<MgmtRequest>>) {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match parse_command(&line) {
+            Ok(command) => match dispatch(command, &instances).await {
+                Some(reply) => reply,
+                None => return,
+            },
+            Err(e) => format!("error: {}", e),
+        };
+        if write_half
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+// Listen on the unix socket at `path`, requests are sent to each of the
+// returned channels, one per instance
+pub fn start_listener(
+    path: &str,
+    instances: usize,
+) -> std::io::Result<Vec<mpsc::Receiver<MgmtRequest>>> {
+    // a socket file left behind by a previous run would make bind fail
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    let (txs, rxs): (Vec<_>, Vec<_>) = (0..instances)
+        .map(|_| mpsc::channel::<MgmtRequest>(10))
+        .unzip();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, txs.clone()));
+                }
+                Err(e) => eprintln!("Error accepting management connection: {}", e),
+            }
+        }
+    });
+    Ok(rxs)
+}
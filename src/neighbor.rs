@@ -1,10 +1,14 @@
+use crate::interface::{InterfaceState, InterfaceType};
 use crate::lsa::LsaHeader;
-use crate::packets::{DBDescription, Hello};
+use crate::packets::{DBDFlag, DBDescription, Hello, LLS_EO_LR};
 use crate::timer::Timer;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
+// The neighbor state machine is shared by OSPFv2 and OSPFv3, which only
+// differ in the LSA headers and DBD packets exchanged, and in the address
+// a neighbor is reached at
 #[derive(Debug, Clone)]
-pub struct Neighbor {
+pub struct Neighbor<H = LsaHeader, D = DBDescription, A = Ipv4Addr> {
     pub state: NbrState,
     pub inactivity_timer: Timer,
     pub exstart_rxmt_timer: Timer,
@@ -12,17 +16,49 @@ pub struct Neighbor {
     pub lsr_rxmt_timer: Timer,
     pub nbr_is_master: bool,
     pub dd_seq_number: u32,
-    pub last_rcv_dbd: Option<DBDescription>,
-    pub last_sent_dbd: Option<(u8, u32, Vec<LsaHeader>)>,
+    pub last_rcv_dbd: Option<D>,
+    pub last_sent_dbd: Option<(u8, u32, Vec<H>)>,
     pub nbr_id: u32,
     pub nbr_pri: u8,
-    pub nbr_ip: Ipv4Addr,
+    // the interface address for OSPFv2, the link-local address for OSPFv3
+    pub nbr_ip: A,
+    // the neighbor's interface ID, OSPFv3 only (RFC 5340 4.1.3)
+    pub nbr_interface_id: u32,
     pub nbr_options: u8,
     pub nbr_dr: Ipv4Addr,
     pub nbr_bdr: Ipv4Addr,
-    pub lsa_retransmission_list: Vec<LsaHeader>,
-    pub db_summary_list: Vec<LsaHeader>,
-    pub link_state_req_list: Vec<LsaHeader>,
+    // extended options from the neighbor's LLS block (RFC 5613)
+    pub nbr_lls_options: u32,
+    // where an out-of-band resync stands, the neighbor stays Full
+    // meanwhile (RFC 4811)
+    pub oob_resync: Option<NbrState>,
+    pub lsa_retransmission_list: Vec<H>,
+    pub db_summary_list: Vec<H>,
+    pub link_state_req_list: Vec<H>,
+    // DBDs rejected because the neighbor's mtu is larger than ours
+    pub mtu_mismatch_count: u32,
+    pub int_view: IntView,
+}
+
+// The parts of the interface state a neighbor needs to decide whether
+// an adjacency should be formed, kept in sync by the interface
+#[derive(Debug, Clone, Copy)]
+pub struct IntView {
+    pub int_type: InterfaceType,
+    pub int_state: InterfaceState,
+    pub dr: Ipv4Addr,
+    pub bdr: Ipv4Addr,
+}
+
+impl Default for IntView {
+    fn default() -> Self {
+        IntView {
+            int_type: InterfaceType::Broadcast,
+            int_state: InterfaceState::Down,
+            dr: Ipv4Addr::new(0, 0, 0, 0),
+            bdr: Ipv4Addr::new(0, 0, 0, 0),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -70,6 +106,8 @@ pub enum NbrEvent {
     HelloReceived,
     OneWayReceived,
     TwoWayReceived,
+    // resync the database with a Full neighbor out of band
+    OobResync,
     NegotiationDone,
     ExchangeDone,
     BadLSReq,
@@ -83,7 +121,49 @@ pub enum NbrEvent {
 }
 
 impl Neighbor {
-    pub fn new(hello_packet: Hello, inactivity_interval: u32, rxmt_interval: u32) -> Neighbor {
+    pub fn new(
+        hello_packet: Hello,
+        src_ip: Ipv4Addr,
+        inactivity_interval: u32,
+        rxmt_interval: u32,
+    ) -> Neighbor {
+        let mut nbr = Neighbor::create(
+            hello_packet.header.router_id,
+            src_ip,
+            inactivity_interval,
+            rxmt_interval,
+        );
+        nbr.nbr_pri = hello_packet.router_priority;
+        nbr.nbr_options = hello_packet.options;
+        nbr.nbr_dr = Ipv4Addr::from(hello_packet.designated_router);
+        nbr.nbr_bdr = Ipv4Addr::from(hello_packet.backup_designated_router);
+        nbr.nbr_lls_options = hello_packet.lls.map_or(0, |x| x.ext_options());
+        nbr
+    }
+}
+
+// What a neighbor's address has to offer the state machines
+pub trait NbrAddr: Copy + PartialEq + std::fmt::Display + std::fmt::Debug {
+    // the address a router is named by in the DR and BDR fields, its
+    // interface address in OSPFv2 and its router ID in OSPFv3
+    fn dr_id(self, router_id: u32) -> Ipv4Addr;
+}
+
+impl NbrAddr for Ipv4Addr {
+    fn dr_id(self, _router_id: u32) -> Ipv4Addr {
+        self
+    }
+}
+
+impl NbrAddr for Ipv6Addr {
+    fn dr_id(self, router_id: u32) -> Ipv4Addr {
+        Ipv4Addr::from(router_id)
+    }
+}
+
+impl<H, D, A: NbrAddr> Neighbor<H, D, A> {
+    // a neighbor we know nothing about yet besides its ids
+    pub fn create(nbr_id: u32, nbr_ip: A, inactivity_interval: u32, rxmt_interval: u32) -> Self {
         Neighbor {
             state: NbrState::Down,
             inactivity_timer: Timer::new(inactivity_interval),
@@ -94,38 +174,20 @@ impl Neighbor {
             dd_seq_number: 0,
             last_rcv_dbd: None,
             last_sent_dbd: None,
-            nbr_id: 0,
-            nbr_pri: hello_packet.router_priority,
-            nbr_ip: Ipv4Addr::from(hello_packet.header.router_id),
-            nbr_options: hello_packet.options,
-            nbr_dr: Ipv4Addr::from(hello_packet.designated_router),
-            nbr_bdr: Ipv4Addr::from(hello_packet.backup_designated_router),
-            lsa_retransmission_list: Vec::new(),
-            db_summary_list: Vec::new(),
-            link_state_req_list: Vec::new(),
-        }
-    }
-
-    pub fn default() -> Self {
-        Neighbor {
-            state: NbrState::Down,
-            inactivity_timer: Timer::new(0),
-            exstart_rxmt_timer: Timer::new(0),
-            mst_exch_timer: Timer::new(0),
-            lsr_rxmt_timer: Timer::new(0),
-            nbr_is_master: false,
-            dd_seq_number: 0,
-            last_rcv_dbd: None,
-            last_sent_dbd: None,
-            nbr_id: 0,
+            nbr_id,
             nbr_pri: 0,
-            nbr_ip: Ipv4Addr::new(0, 0, 0, 0),
+            nbr_ip,
+            nbr_interface_id: 0,
             nbr_options: 0,
-            nbr_dr: Ipv4Addr::new(0, 0, 0, 0),
-            nbr_bdr: Ipv4Addr::new(0, 0, 0, 0),
+            nbr_dr: Ipv4Addr::from(0),
+            nbr_bdr: Ipv4Addr::from(0),
+            nbr_lls_options: 0,
+            oob_resync: None,
             lsa_retransmission_list: Vec::new(),
             db_summary_list: Vec::new(),
             link_state_req_list: Vec::new(),
+            mtu_mismatch_count: 0,
+            int_view: IntView::default(),
         }
     }
 
@@ -136,9 +198,41 @@ impl Neighbor {
         }
     }
 
-    fn decide_adjacency(&mut self) -> bool {
-        // TODO
-        true
+    // RFC 2328 10.4, adjacencies are only formed on point-to-point like
+    // networks, or when either side is the DR or BDR
+    fn decide_adjacency(&self) -> bool {
+        let view = &self.int_view;
+        let me = self.dr_id();
+        match view.int_type {
+            InterfaceType::P2P | InterfaceType::P2MP | InterfaceType::Virtual => true,
+            _ => {
+                view.int_state == InterfaceState::DR
+                    || view.int_state == InterfaceState::BDR
+                    || me == view.dr
+                    || me == view.bdr
+            }
+        }
+    }
+
+    // how the neighbor shows up in the DR and BDR fields
+    pub fn dr_id(&self) -> Ipv4Addr {
+        self.nbr_ip.dr_id(self.nbr_id)
+    }
+
+    // the state driving the database exchange, which is the resync's
+    // while one is going on
+    pub fn sync_state(&self) -> NbrState {
+        self.oob_resync.unwrap_or(self.state)
+    }
+
+    pub fn lr_capable(&self) -> bool {
+        self.nbr_lls_options & LLS_EO_LR != 0
+    }
+
+    // every DBD sent during an out-of-band resync has the R bit set
+    pub fn dbd_flags(&self, mut flag: DBDFlag) -> u8 {
+        flag.resync = self.oob_resync.is_some();
+        flag.to_byte()
     }
 
     fn clear_lsa_lists(&mut self) {
@@ -148,6 +242,7 @@ impl Neighbor {
     }
 
     fn begin_exstart(&mut self) {
+        self.oob_resync = None;
         self.state = NbrState::ExStart;
         // set dd_seq_number to some unique number like time elapsed
         self.dd_seq_number = self.inactivity_timer.elapsed() as u32;
@@ -156,6 +251,9 @@ impl Neighbor {
     }
 
     pub fn handle_event(&mut self, event: NbrEvent) {
+        if self.oob_resync.is_some() && self.handle_resync_event(event) {
+            return;
+        }
         let before = self.state;
         if event != NbrEvent::HelloReceived && event != NbrEvent::TwoWayReceived {
             println!("Neighbor {} received event {:?}", self.nbr_ip, event);
@@ -193,6 +291,18 @@ impl Neighbor {
                     self.state = NbrState::TwoWay;
                 }
             }
+            NbrEvent::OobResync => {
+                // only a Full adjacency can be resynced out of band
+                if self.state != NbrState::Full {
+                    return;
+                }
+                self.oob_resync = Some(NbrState::ExStart);
+                self.db_summary_list.clear();
+                self.link_state_req_list.clear();
+                self.dd_seq_number = self.inactivity_timer.elapsed() as u32;
+                self.exstart_rxmt_timer.start_imm();
+                println!("Neighbor {} starting out-of-band resync", self.nbr_ip);
+            }
             NbrEvent::NegotiationDone => {
                 assert!(self.state == NbrState::ExStart);
                 self.state = NbrState::Exchange;
@@ -222,14 +332,10 @@ impl Neighbor {
                         // if not, state is TwoWay
                         self.state = NbrState::TwoWay;
                     }
-                } else {
-                    if self.decide_adjacency() {
-                        // maintain and do nothing
-                    } else {
-                        // if not, state is TwoWay and clear 3 list of LSA
-                        self.state = NbrState::TwoWay;
-                        self.clear_lsa_lists();
-                    }
+                } else if self.state.higher_than_two_way() && !self.decide_adjacency() {
+                    // adjacency no longer wanted, state is TwoWay and clear 3 list of LSA
+                    self.state = NbrState::TwoWay;
+                    self.clear_lsa_lists();
                 }
             }
             NbrEvent::SeqNumberMismatch | NbrEvent::BadLSReq => {
@@ -240,16 +346,19 @@ impl Neighbor {
                 self.state = NbrState::Exchange;
             }
             NbrEvent::KillNbr => {
+                self.oob_resync = None;
                 self.state = NbrState::Down;
                 // clear 3 list of LSA
                 self.clear_lsa_lists();
             }
             NbrEvent::InactivityTimer => {
+                self.oob_resync = None;
                 self.state = NbrState::Down;
                 // clear 3 list of LSA
                 self.clear_lsa_lists();
             }
             NbrEvent::LLDown => {
+                self.oob_resync = None;
                 self.state = NbrState::Down;
                 // clear 3 list of LSA
                 self.clear_lsa_lists();
@@ -262,4 +371,45 @@ impl Neighbor {
             );
         }
     }
+
+    // The exchange steps of an out-of-band resync move the resync along
+    // instead of the neighbor, returns false for the events it leaves to
+    // the regular state machine
+    fn handle_resync_event(&mut self, event: NbrEvent) -> bool {
+        let before = self.oob_resync;
+        match event {
+            NbrEvent::NegotiationDone => {
+                assert!(self.oob_resync == Some(NbrState::ExStart));
+                self.oob_resync = Some(NbrState::Exchange);
+            }
+            NbrEvent::ExchangeDone => {
+                assert!(self.oob_resync == Some(NbrState::Exchange));
+                if self.link_state_req_list.is_empty() {
+                    self.oob_resync = None;
+                } else {
+                    self.oob_resync = Some(NbrState::Loading);
+                }
+            }
+            NbrEvent::LoadingDone => {
+                assert!(self.oob_resync == Some(NbrState::Loading));
+                self.oob_resync = None;
+            }
+            _ => return false,
+        }
+        println!(
+            "Neighbor {} received event {:?} during out-of-band resync",
+            self.nbr_ip, event
+        );
+        match self.oob_resync {
+            None => println!("Neighbor {} out-of-band resync done", self.nbr_ip),
+            Some(phase) if before != self.oob_resync => println!(
+                "Neighbor {} resync moved from {:?} to {:?}",
+                self.nbr_ip,
+                before.unwrap(),
+                phase
+            ),
+            _ => {}
+        }
+        true
+    }
 }
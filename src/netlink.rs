@@ -0,0 +1,286 @@
+// Watch the kernel for link and address changes through a rtnetlink socket,
+// and send it the requests iproute2 can't be used for
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone)]
+pub enum LinkEvent {
+    // a link was added or changed, `up` means administratively up with carrier
+    LinkChanged {
+        index: u32,
+        name: String,
+        up: bool,
+        mtu: Option<u32>,
+    },
+    LinkRemoved {
+        index: u32,
+        name: String,
+    },
+    // an ipv4 or ipv6 address was added to or removed from a link
+    AddrChanged {
+        index: u32,
+        addr: IpAddr,
+        prefix_len: u8,
+        added: bool,
+    },
+    // events were lost, everything has to be looked at again
+    Resync,
+}
+
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+const RECV_BUFFER_SIZE: usize = 65536;
+
+fn nla_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_ne_bytes([buf[at], buf[at + 1]])
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+// iterate over the (type, payload) route attributes of a message
+fn parse_attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = vec![];
+    while buf.len() >= 4 {
+        let len = read_u16(buf, 0) as usize;
+        let attr_type = read_u16(buf, 2);
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        attrs.push((attr_type, &buf[4..len]));
+        buf = &buf[std::cmp::min(nla_align(len), buf.len())..];
+    }
+    attrs
+}
+
+fn parse_link(msg_type: u16, body: &[u8]) -> Option<LinkEvent> {
+    if body.len() < IFINFOMSG_LEN {
+        return None;
+    }
+    let index = read_u32(body, 4);
+    let flags = read_u32(body, 8);
+    let mut name = String::new();
+    let mut mtu = None;
+    for (attr_type, data) in parse_attrs(&body[IFINFOMSG_LEN..]) {
+        match attr_type {
+            libc::IFLA_IFNAME => {
+                name = String::from_utf8_lossy(data)
+                    .trim_end_matches('\0')
+                    .to_string();
+            }
+            libc::IFLA_MTU if data.len() >= 4 => mtu = Some(read_u32(data, 0)),
+            _ => {}
+        }
+    }
+    if msg_type == libc::RTM_DELLINK {
+        return Some(LinkEvent::LinkRemoved { index, name });
+    }
+    let up = flags & libc::IFF_UP as u32 != 0 && flags & libc::IFF_RUNNING as u32 != 0;
+    Some(LinkEvent::LinkChanged {
+        index,
+        name,
+        up,
+        mtu,
+    })
+}
+
+fn parse_addr(msg_type: u16, body: &[u8]) -> Option<LinkEvent> {
+    if body.len() < IFADDRMSG_LEN {
+        return None;
+    }
+    let family = body[0] as libc::c_int;
+    let prefix_len = body[1];
+    let index = read_u32(body, 4);
+    let mut local = None;
+    let mut address = None;
+    for (attr_type, data) in parse_attrs(&body[IFADDRMSG_LEN..]) {
+        let addr = match family {
+            libc::AF_INET if data.len() >= 4 => {
+                IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+            }
+            libc::AF_INET6 if data.len() >= 16 => {
+                IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&data[..16]).unwrap()))
+            }
+            _ => continue,
+        };
+        match attr_type {
+            libc::IFA_LOCAL => local = Some(addr),
+            libc::IFA_ADDRESS => address = Some(addr),
+            _ => {}
+        }
+    }
+    // on point-to-point links IFA_ADDRESS is the peer, IFA_LOCAL is ours
+    let addr = local.or(address)?;
+    Some(LinkEvent::AddrChanged {
+        index,
+        addr,
+        prefix_len,
+        added: msg_type == libc::RTM_NEWADDR,
+    })
+}
+
+fn parse_messages(buf: &[u8]) -> Vec<LinkEvent> {
+    let mut events = vec![];
+    let mut at = 0;
+    while at + NLMSG_HDR_LEN <= buf.len() {
+        let len = read_u32(buf, at) as usize;
+        let msg_type = read_u16(buf, at + 4);
+        if len < NLMSG_HDR_LEN || at + len > buf.len() {
+            break;
+        }
+        let body = &buf[at + NLMSG_HDR_LEN..at + len];
+        let event = match msg_type {
+            libc::RTM_NEWLINK | libc::RTM_DELLINK => parse_link(msg_type, body),
+            libc::RTM_NEWADDR | libc::RTM_DELADDR => parse_addr(msg_type, body),
+            _ => None,
+        };
+        events.extend(event);
+        at += nla_align(len);
+    }
+    events
+}
+
+// a rtnetlink socket listening to `groups`, none for requests
+fn open_socket(groups: u32) -> std::io::Result<libc::c_int> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        );
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = groups;
+        if libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) < 0
+        {
+            let err = std::io::Error::last_os_error();
+            libc::close(fd);
+            return Err(err);
+        }
+        Ok(fd)
+    }
+}
+
+// Start watching link and address changes, every event is sent to each of
+// the returned channels, one per subscriber. The socket is read from a
+// dedicated thread since reads block, it stops when every subscriber is
+// gone or the socket fails.
+pub fn start_monitor(subscribers: usize) -> std::io::Result<Vec<mpsc::Receiver<LinkEvent>>> {
+    let fd = open_socket(
+        (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32,
+    )?;
+    let (mut txs, rxs): (Vec<_>, Vec<_>) = (0..subscribers)
+        .map(|_| mpsc::channel::<LinkEvent>(64))
+        .unzip();
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let len =
+                unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            let events = if len < 0 {
+                let err = std::io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    // the socket overflowed and we missed events
+                    Some(libc::ENOBUFS) => {
+                        eprintln!("Netlink socket overflowed, rescanning interfaces");
+                        vec![LinkEvent::Resync]
+                    }
+                    _ => {
+                        eprintln!("Error while reading netlink socket, stopping: {}", err);
+                        break;
+                    }
+                }
+            } else {
+                parse_messages(&buf[..len as usize])
+            };
+            // a stopped instance drops out, the others keep their events
+            for event in events.iter() {
+                txs.retain(|tx| tx.blocking_send(event.clone()).is_ok());
+            }
+            if txs.is_empty() {
+                break;
+            }
+        }
+        unsafe { libc::close(fd) };
+    });
+    Ok(rxs)
+}
+
+// append a route attribute to a message body
+pub fn push_attr(body: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    body.extend(((4 + data.len()) as u16).to_ne_bytes());
+    body.extend(attr_type.to_ne_bytes());
+    body.extend(data);
+    body.resize(nla_align(body.len()), 0);
+}
+
+// the errno of the kernel's acknowledgement, 0 for success
+fn read_ack(buf: &[u8]) -> Option<i32> {
+    let mut at = 0;
+    while at + NLMSG_HDR_LEN <= buf.len() {
+        let len = read_u32(buf, at) as usize;
+        let msg_type = read_u16(buf, at + 4);
+        if len < NLMSG_HDR_LEN || at + len > buf.len() {
+            break;
+        }
+        if msg_type == libc::NLMSG_ERROR as u16 && len >= NLMSG_HDR_LEN + 4 {
+            return Some(-(read_u32(buf, at + NLMSG_HDR_LEN) as i32));
+        }
+        at += nla_align(len);
+    }
+    None
+}
+
+// Send one request and wait for the kernel to acknowledge it
+pub fn request(msg_type: u16, flags: u16, body: &[u8]) -> std::io::Result<()> {
+    let fd = open_socket(0)?;
+    let flags = flags | (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+    let mut msg = vec![];
+    msg.extend(((NLMSG_HDR_LEN + body.len()) as u32).to_ne_bytes());
+    msg.extend(msg_type.to_ne_bytes());
+    msg.extend(flags.to_ne_bytes());
+    // sequence number and port ID, the kernel fills in ours
+    msg.extend(1u32.to_ne_bytes());
+    msg.extend(0u32.to_ne_bytes());
+    msg.extend(body);
+    let result = (|| {
+        let sent = unsafe { libc::send(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if sent < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let len =
+                unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if len < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            match read_ack(&buf[..len as usize]) {
+                Some(0) => return Ok(()),
+                Some(errno) => return Err(std::io::Error::from_raw_os_error(errno)),
+                None => continue,
+            }
+        }
+    })();
+    unsafe { libc::close(fd) };
+    result
+}
@@ -0,0 +1,260 @@
+// The area scope opaque LSAs we originate (RFC 5250): the router
+// information LSA (RFC 7770), one extended prefix LSA and an extended link
+// LSA per link (RFC 7684). The router information LSA always describes
+// the router itself, segment routing and the flexible algorithms add their
+// TLVs to them and an LSA nobody has anything for is not originated.
+use crate::database::LinkStateDatabase;
+use crate::interface::InterfaceType;
+use crate::lsa::{Lsa, LsaHeader, LsaOpaque, LsaType, OpaqueTlv};
+use crate::receiver::ipv4_to_bits;
+use crate::sr::ALGORITHM_SPF;
+
+// opaque types of the LSAs we originate
+pub const OPAQUE_ROUTER_INFO: u8 = 4;
+pub const OPAQUE_EXT_PREFIX: u8 = 7;
+pub const OPAQUE_EXT_LINK: u8 = 8;
+
+// the algorithms a router computes paths for (RFC 8665 3.1)
+pub const RI_TLV_SR_ALGORITHM: u16 = 8;
+// the one top level TLV of the extended link LSA
+const TLV_EXT_LINK: u16 = 1;
+
+pub fn encode_tlvs(tlvs: &[OpaqueTlv]) -> Vec<u8> {
+    tlvs.iter().flat_map(|x| x.encode()).collect()
+}
+
+pub fn read_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+// A link of our router LSA, as an extended link TLV describes it
+#[derive(Debug)]
+pub struct ExtLink {
+    // interface name and neighbor ID, 0 for a transit network
+    pub key: (String, u32),
+    pub link_type: u8,
+    pub link_id: u32,
+    pub link_data: u32,
+    // the fully adjacent neighbors reached over it
+    pub nbrs: Vec<u32>,
+}
+
+impl LinkStateDatabase {
+    // the non-stub links of our router LSA, sorted by interface name
+    fn ext_links(&self) -> Vec<ExtLink> {
+        let mut names: Vec<&String> = self.each_int_link.keys().collect();
+        names.sort();
+        let mut links = vec![];
+        for name in names {
+            let desc = &self.each_int_link[name];
+            if desc.passive || desc.full_nbrs.is_empty() {
+                continue;
+            }
+            let link_data = if desc.unnumbered {
+                desc.ifindex
+            } else {
+                ipv4_to_bits(desc.addr)
+            };
+            match desc.int_type {
+                InterfaceType::Broadcast | InterfaceType::NBMA if desc.transit => {
+                    links.push(ExtLink {
+                        key: (name.clone(), 0),
+                        link_type: 2,
+                        link_id: ipv4_to_bits(desc.dr),
+                        link_data,
+                        nbrs: desc.full_nbrs.iter().map(|(id, _)| *id).collect(),
+                    });
+                }
+                InterfaceType::P2P | InterfaceType::P2MP => {
+                    for (id, _) in desc.full_nbrs.iter() {
+                        links.push(ExtLink {
+                            key: (name.clone(), *id),
+                            link_type: 1,
+                            link_id: *id,
+                            link_data,
+                            nbrs: vec![*id],
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        links
+    }
+
+    // Number the links for the link state IDs of their extended link LSAs,
+    // a link keeps its number as long as it's there
+    fn allocate_ext_link_ids(&mut self, links: &[ExtLink]) {
+        self.ext_link_ids
+            .retain(|key, _| links.iter().any(|x| x.key == *key));
+        for link in links {
+            if self.ext_link_ids.contains_key(&link.key) {
+                continue;
+            }
+            let id = (0..).find(|i| !self.ext_link_ids.values().any(|x| x == i));
+            self.ext_link_ids.insert(link.key.clone(), id.unwrap());
+        }
+    }
+
+    fn router_info_tlvs(&self) -> Vec<OpaqueTlv> {
+        let mut tlvs = self.node_router_info_tlvs();
+        let mut algorithms = vec![ALGORITHM_SPF];
+        algorithms.extend(self.global_config.flex_algos().map(|x| x.id));
+        if self.global_config.segment_routing.is_some() || algorithms.len() > 1 {
+            tlvs.push(OpaqueTlv::new(RI_TLV_SR_ALGORITHM, algorithms));
+        }
+        tlvs.extend(self.sr_router_info_tlvs());
+        tlvs.extend(self.flex_algo_router_info_tlvs());
+        tlvs
+    }
+
+    // link state IDs and bodies of the opaque LSAs for the current config
+    // and adjacencies
+    fn opaque_lsa_bodies(&self, links: &[ExtLink]) -> Vec<(u32, Vec<u8>)> {
+        if self.each_int_link.is_empty() {
+            return vec![];
+        }
+        let lsid = |opaque_type: u8, id: u32| ((opaque_type as u32) << 24) | id;
+        let mut bodies = vec![(
+            lsid(OPAQUE_ROUTER_INFO, 0),
+            encode_tlvs(&self.router_info_tlvs()),
+        )];
+        let prefixes = self.sr_ext_prefix_tlvs();
+        if !prefixes.is_empty() {
+            bodies.push((lsid(OPAQUE_EXT_PREFIX, 0), encode_tlvs(&prefixes)));
+        }
+        for link in links {
+            let desc = &self.each_int_link[&link.key.0];
+            let mut subs = self.adj_sid_sub_tlvs(link);
+            subs.extend(self.link_attrs_sub_tlv(desc.link_attrs));
+            if subs.is_empty() {
+                continue;
+            }
+            let mut value = vec![link.link_type, 0, 0, 0];
+            value.extend(link.link_id.to_be_bytes());
+            value.extend(link.link_data.to_be_bytes());
+            value.extend(encode_tlvs(&subs));
+            bodies.push((
+                lsid(OPAQUE_EXT_LINK, self.ext_link_ids[&link.key]),
+                encode_tlvs(&[OpaqueTlv::new(TLV_EXT_LINK, value)]),
+            ));
+        }
+        bodies
+    }
+
+    // Bring our opaque LSAs in line with the config and the adjacencies,
+    // the new instances and the flushed ones are returned to be flooded
+    pub fn originate_opaque_lsas(&mut self) -> Vec<Lsa> {
+        self.allocate_adj_sids();
+        let links = self.ext_links();
+        self.allocate_ext_link_ids(&links);
+        let router_id = self.global_config.router_id;
+        let wanted = self.opaque_lsa_bodies(&links);
+        let mut changed = vec![];
+        let ours = |lsa: &Lsa| {
+            let hdr = lsa.get_hdr();
+            hdr.ls_type == LsaType::LsaOpaqueArea as u8
+                && hdr.advertising_router == router_id
+                && matches!(
+                    (hdr.link_state_id >> 24) as u8,
+                    OPAQUE_ROUTER_INFO | OPAQUE_EXT_PREFIX | OPAQUE_EXT_LINK
+                )
+        };
+        // premature aging for the ones we no longer originate
+        let (stale, kept): (Vec<Lsa>, Vec<Lsa>) = self.lsa_list.drain(..).partition(|x| {
+            ours(x)
+                && !wanted
+                    .iter()
+                    .any(|(id, _)| *id == x.get_hdr().link_state_id)
+        });
+        self.lsa_list = kept;
+        for mut lsa in stale {
+            lsa.get_mut_hdr().age = crate::config::MAX_AGE as u16;
+            changed.push(lsa);
+        }
+        for (link_state_id, body) in wanted {
+            let old = self
+                .lsa_list
+                .iter()
+                .position(|x| ours(x) && x.get_hdr().link_state_id == link_state_id);
+            if let Some(Lsa::LsaOpaque(old)) = old.map(|i| &self.lsa_list[i]) {
+                if old.body == body && old.header.age < crate::config::MAX_AGE as u16 {
+                    continue;
+                }
+            }
+            let mut lsa = Lsa::LsaOpaque(LsaOpaque {
+                header: LsaHeader {
+                    age: 0,
+                    options: 0x02,
+                    ls_type: LsaType::LsaOpaqueArea as u8,
+                    link_state_id,
+                    advertising_router: router_id,
+                    sequence_number: self.cur_lsa_seq_num as u32,
+                    checksum: 0,
+                    length: 0,
+                },
+                body,
+            });
+            lsa.set_checksum_length();
+            self.cur_lsa_seq_num += 1;
+            match old {
+                Some(i) => self.lsa_list[i] = lsa.clone(),
+                None => self.lsa_list.push(lsa.clone()),
+            }
+            println!("Opaque LSA generated: {:?}", lsa.get_hdr());
+            changed.push(lsa);
+        }
+        changed
+    }
+
+    // the area scope opaque LSAs still in effect
+    pub fn area_opaque_lsas(&self) -> impl Iterator<Item = &LsaOpaque> {
+        self.lsa_list.iter().filter_map(|lsa| match lsa {
+            Lsa::LsaOpaque(l)
+                if l.header.ls_type == LsaType::LsaOpaqueArea as u8
+                    && l.header.age < crate::config::MAX_AGE as u16 =>
+            {
+                Some(l)
+            }
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use nom_derive::Parse;
+
+    // an area scope opaque LSA made of these TLVs, through the wire format
+    pub fn opaque_lsa(opaque_type: u8, adv_router: u32, tlvs: &[OpaqueTlv]) -> LsaOpaque {
+        let mut lsa = Lsa::LsaOpaque(LsaOpaque {
+            header: LsaHeader {
+                age: 0,
+                options: 0x02,
+                ls_type: LsaType::LsaOpaqueArea as u8,
+                link_state_id: (opaque_type as u32) << 24,
+                advertising_router: adv_router,
+                sequence_number: 0x8000_0001,
+                checksum: 0,
+                length: 0,
+            },
+            body: encode_tlvs(tlvs),
+        });
+        lsa.set_checksum_length();
+        match Lsa::parse(&lsa.encode()) {
+            Ok((_, Lsa::LsaOpaque(lsa))) => lsa,
+            other => panic!("expected an opaque LSA, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sr_algorithm_tlv() {
+        let tlv = OpaqueTlv::new(RI_TLV_SR_ALGORITHM, vec![ALGORITHM_SPF, 128, 129]);
+        let lsa = opaque_lsa(OPAQUE_ROUTER_INFO, 1, &[tlv]);
+        let tlvs = lsa.tlvs();
+        assert_eq!(tlvs.len(), 1);
+        assert_eq!(tlvs[0].value(), [ALGORITHM_SPF, 128, 129]);
+        assert_eq!(lsa.header.length as usize, 20 + 8);
+    }
+}
@@ -1,9 +1,33 @@
 use crate::lsa::*;
 use encoding_derive::Encoding;
-use nom::combinator::peek;
+use nom::bytes::streaming::take;
+use nom::combinator::{complete, peek};
+use nom::multi::many0;
 use nom::number::streaming::be_u16;
 use nom_derive::*;
 
+// sizes used when fitting packets into the interface MTU
+pub const IPV4_HDR_LEN: usize = 20;
+pub const OSPF_HDR_LEN: usize = 24;
+pub const LSA_HDR_LEN: usize = 20;
+pub const HELLO_FIXED_LEN: usize = 20;
+pub const DBD_FIXED_LEN: usize = 8;
+pub const LSU_FIXED_LEN: usize = 4;
+
+// bits of the options field
+pub const OPTION_E: u8 = 0x02;
+pub const OPTION_N: u8 = 0x08;
+pub const OPTION_L: u8 = 0x10;
+pub const OPTION_O: u8 = 0x40;
+
+// LLS TLV types (RFC 5613)
+pub const LLS_TLV_EXT_OPTIONS: u16 = 1;
+pub const LLS_TLV_CRYPTO_AUTH: u16 = 2;
+
+// bits of the LLS extended options
+pub const LLS_EO_LR: u32 = 0x01;
+pub const LLS_EO_RS: u32 = 0x02;
+
 #[derive(Debug, Clone)]
 pub enum OSPFPacket {
     Hello(Hello),
@@ -75,32 +99,27 @@ impl OSPFPacket {
         }
     }
 
+    // the LLS block trails the packet, it is not covered by the packet
+    // length nor by the checksum
+    fn lls_len(&self) -> usize {
+        let lls = match self {
+            OSPFPacket::Hello(hello) => hello.lls.as_ref(),
+            OSPFPacket::DBDescription(dbd) => dbd.lls.as_ref(),
+            _ => None,
+        };
+        lls.map_or(0, |x| x.encode().len())
+    }
+
     pub fn set_packet_length(&mut self) {
-        // not right
-        let len = self.encode_bincode().len() as u16;
-        // println!("{:?}\n{:?}", self, self.encode_bincode());
+        let len = (self.encode_bincode().len() - self.lls_len()) as u16;
         self.get_mut_hdr().packet_length = len;
     }
 
     pub fn set_checksum(&mut self) {
         self.get_mut_hdr().checksum = 0;
-        let bytes = match self {
-            OSPFPacket::Hello(hello) => hello.encode(),
-            OSPFPacket::DBDescription(dbd) => dbd.encode(),
-            OSPFPacket::LinkStateRequest(lsr) => lsr.encode(),
-            OSPFPacket::LinkStateUpdate(lsu) => lsu.encode(),
-            OSPFPacket::LinkStateAcknowledgment(lsa) => lsa.encode(),
-        };
-        let mut sum = 0u32;
-        for i in 0..bytes.len() / 2 {
-            sum += u16::from_be_bytes([bytes[i * 2], bytes[i * 2 + 1]]) as u32;
-            sum = (sum & 0xffff) + (sum >> 16);
-        }
-        if bytes.len() % 2 != 0 {
-            sum += u16::from_be_bytes([bytes[bytes.len() - 1], 0]) as u32;
-            sum = (sum & 0xffff) + (sum >> 16);
-        }
-        self.get_mut_hdr().checksum = !sum as u16;
+        let mut bytes = self.encode_bincode();
+        bytes.truncate(bytes.len() - self.lls_len());
+        self.get_mut_hdr().checksum = ip_checksum(&bytes);
     }
 
     pub fn encode_bincode(&self) -> Vec<u8> {
@@ -114,6 +133,15 @@ impl OSPFPacket {
     }
 }
 
+pub fn ip_checksum(bytes: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in bytes.chunks(2) {
+        sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
 #[derive(Debug, Clone, NomBE, Encoding)]
 pub struct Header {
     pub version: u8,
@@ -145,7 +173,12 @@ pub struct Hello {
     pub router_dead_interval: u32,
     pub designated_router: u32,
     pub backup_designated_router: u32,
+    #[nom(
+        Count = "(header.packet_length as usize).saturating_sub(OSPF_HDR_LEN + HELLO_FIXED_LEN) / 4"
+    )]
     pub neighbors: Vec<u32>,
+    #[nom(Parse = "{ |i| parse_lls(i, options) }")]
+    pub lls: Option<LlsBlock>,
 }
 
 #[derive(Debug, Clone, NomBE, Encoding)]
@@ -155,11 +188,18 @@ pub struct DBDescription {
     pub options: u8,
     pub flags: u8,
     pub dbd_seq_num: u32,
+    #[nom(
+        Count = "(header.packet_length as usize).saturating_sub(OSPF_HDR_LEN + DBD_FIXED_LEN) / LSA_HDR_LEN"
+    )]
     pub lsa_hdrs: Vec<LsaHeader>,
+    #[nom(Parse = "{ |i| parse_lls(i, options) }")]
+    pub lls: Option<LlsBlock>,
 }
 
 #[derive(Debug, Clone)]
 pub struct DBDFlag {
+    // out-of-band resync in progress (RFC 4811)
+    pub resync: bool,
     pub init: bool,
     pub more: bool,
     pub masterslave: bool,
@@ -170,13 +210,15 @@ impl DBDescription {
         DBDFlag::from_byte(self.flags)
     }
     pub fn all_flag_set(&self) -> bool {
-        return (self.flags & 0b1110_0000) == 0b1110_0000;
+        let flag = self.get_flag();
+        flag.init && flag.more && flag.masterslave
     }
 }
 
 impl DBDFlag {
     pub fn new(init: bool, more: bool, masterslave: bool) -> Self {
         DBDFlag {
+            resync: false,
             init,
             more,
             masterslave,
@@ -187,6 +229,7 @@ impl DBDFlag {
     }
     pub fn from_byte(byte: u8) -> Self {
         DBDFlag {
+            resync: (byte & 0b0000_1000) != 0,
             init: (byte & 0b0000_0100) != 0,
             more: (byte & 0b0000_0010) != 0,
             masterslave: (byte & 0b0000_0001) != 0,
@@ -194,6 +237,9 @@ impl DBDFlag {
     }
     pub fn to_byte(&self) -> u8 {
         let mut byte = 0;
+        if self.resync {
+            byte |= 0b0000_1000;
+        }
         if self.init {
             byte |= 0b0000_0100;
         }
@@ -233,3 +279,283 @@ pub struct LinkStateAcknowledgment {
     pub header: Header,
     pub lsas: Vec<LsaHeader>,
 }
+
+// RFC 5613 link-local signaling data, appended to hellos and DBDs that
+// have the L bit set in their options
+#[derive(Debug, Clone, Encoding)]
+pub struct LlsBlock {
+    pub checksum: u16,
+    // in 32-bit words, this header included
+    pub length: u16,
+    pub tlvs: Vec<LlsTlv>,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LlsTlv {
+    pub tlv_type: u16,
+    // of the value, not counting the padding
+    pub length: u16,
+    // padded to a 32-bit boundary
+    #[nom(Count = "(length as usize).div_ceil(4) * 4")]
+    pub value: Vec<u8>,
+}
+
+impl<'a> Parse<&'a [u8]> for LlsBlock {
+    fn parse(input: &'a [u8]) -> nom::IResult<&'a [u8], Self> {
+        let (input, checksum) = be_u16(input)?;
+        let (input, length) = be_u16(input)?;
+        let (input, body) = take((length as usize * 4).saturating_sub(4))(input)?;
+        let (_, tlvs) = many0(complete(LlsTlv::parse))(body)?;
+        Ok((
+            input,
+            LlsBlock {
+                checksum,
+                length,
+                tlvs,
+            },
+        ))
+    }
+}
+
+// A missing or damaged LLS block is ignored, the packet itself is still
+// good (RFC 5613 2.2)
+fn parse_lls(input: &[u8], options: u8) -> nom::IResult<&[u8], Option<LlsBlock>> {
+    if options & OPTION_L == 0 {
+        return Ok((input, None));
+    }
+    match LlsBlock::parse(input) {
+        Ok((rest, lls)) if lls.is_valid() => Ok((rest, Some(lls))),
+        _ => {
+            println!("Ignoring malformed LLS block");
+            Ok((input, None))
+        }
+    }
+}
+
+impl LlsBlock {
+    pub fn new(tlvs: Vec<LlsTlv>) -> Self {
+        let mut lls = LlsBlock {
+            checksum: 0,
+            length: 0,
+            tlvs,
+        };
+        lls.length = (lls.encode().len() / 4) as u16;
+        lls.checksum = ip_checksum(&lls.encode());
+        lls
+    }
+
+    // Cryptographic authentication of the block (RFC 5613 2.5) is not
+    // supported, we don't run OSPF authentication and have no key to check
+    // it with. The block is kept so the receiver can tell it was signed.
+    pub fn is_authenticated(&self) -> bool {
+        self.tlvs.iter().any(|x| x.tlv_type == LLS_TLV_CRYPTO_AUTH)
+    }
+
+    fn is_valid(&self) -> bool {
+        ip_checksum(&self.encode()) == 0
+    }
+
+    pub fn ext_options(&self) -> u32 {
+        self.tlvs
+            .iter()
+            .find(|x| x.tlv_type == LLS_TLV_EXT_OPTIONS && x.value.len() >= 4)
+            .map_or(0, |x| {
+                u32::from_be_bytes([x.value[0], x.value[1], x.value[2], x.value[3]])
+            })
+    }
+}
+
+impl LlsTlv {
+    pub fn ext_options(bits: u32) -> Self {
+        LlsTlv {
+            tlv_type: LLS_TLV_EXT_OPTIONS,
+            length: 4,
+            value: bits.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(packet_type: PacketType) -> Header {
+        Header {
+            version: 2,
+            packet_type: packet_type as u8,
+            packet_length: 0,
+            router_id: 0x0101_0101,
+            area_id: 0,
+            checksum: 0,
+            auth_type: 0,
+            auth: 0,
+        }
+    }
+
+    fn hello(neighbors: Vec<u32>, lls: Option<LlsBlock>) -> OSPFPacket {
+        let mut packet = OSPFPacket::Hello(Hello {
+            header: header(PacketType::Hello),
+            network_mask: 0xFFFF_FF00,
+            hello_interval: 10,
+            options: OPTION_E | if lls.is_some() { OPTION_L } else { 0 },
+            router_priority: 1,
+            router_dead_interval: 40,
+            designated_router: 0,
+            backup_designated_router: 0,
+            neighbors,
+            lls,
+        });
+        packet.set_packet_length();
+        packet.set_checksum();
+        packet
+    }
+
+    fn parse_hello(bytes: &[u8]) -> Hello {
+        match OSPFPacket::parse(bytes) {
+            Ok((_, OSPFPacket::Hello(hello))) => hello,
+            other => panic!("expected a hello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let packet = hello(vec![0x0202_0202, 0x0303_0303], None);
+        let bytes = packet.encode_bincode();
+        assert_eq!(bytes.len(), OSPF_HDR_LEN + HELLO_FIXED_LEN + 8);
+        assert_eq!(ip_checksum(&bytes), 0);
+        let parsed = parse_hello(&bytes);
+        assert_eq!(parsed.neighbors, vec![0x0202_0202, 0x0303_0303]);
+        assert!(parsed.lls.is_none());
+    }
+
+    #[test]
+    fn hello_length_shorter_than_fixed_part() {
+        let mut bytes = hello(vec![], None).encode_bincode();
+        // a packet length that doesn't even cover the fixed part has no neighbors
+        bytes[2..4].copy_from_slice(&(OSPF_HDR_LEN as u16).to_be_bytes());
+        assert!(parse_hello(&bytes).neighbors.is_empty());
+    }
+
+    #[test]
+    fn lls_round_trip() {
+        let lls = LlsBlock::new(vec![LlsTlv::ext_options(LLS_EO_LR | LLS_EO_RS)]);
+        assert_eq!(lls.length, 3);
+        let packet = hello(vec![0x0202_0202], Some(lls));
+        let bytes = packet.encode_bincode();
+        // the LLS block is not counted in the packet length
+        assert_eq!(packet.get_hdr().packet_length as usize, bytes.len() - 12);
+        let parsed = parse_hello(&bytes);
+        assert_eq!(parsed.neighbors, vec![0x0202_0202]);
+        assert_eq!(parsed.lls.unwrap().ext_options(), LLS_EO_LR | LLS_EO_RS);
+    }
+
+    #[test]
+    fn lls_tlv_padding() {
+        let tlv = LlsTlv {
+            tlv_type: 99,
+            length: 5,
+            value: vec![1, 2, 3, 4, 5, 0, 0, 0],
+        };
+        let lls = LlsBlock::new(vec![tlv, LlsTlv::ext_options(LLS_EO_LR)]);
+        let bytes = lls.encode();
+        assert_eq!(bytes.len(), 4 + 12 + 8);
+        let (rest, parsed) = LlsBlock::parse(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.tlvs.len(), 2);
+        assert_eq!(parsed.tlvs[0].value[..5], [1, 2, 3, 4, 5]);
+        assert_eq!(parsed.ext_options(), LLS_EO_LR);
+    }
+
+    #[test]
+    fn malformed_lls_is_ignored() {
+        let lls = LlsBlock::new(vec![LlsTlv::ext_options(LLS_EO_LR)]);
+        let bytes = hello(vec![0x0202_0202], Some(lls)).encode_bincode();
+        let lls_at = bytes.len() - 12;
+
+        // bad checksum
+        let mut bad = bytes.clone();
+        bad[lls_at] ^= 0xFF;
+        let parsed = parse_hello(&bad);
+        assert_eq!(parsed.neighbors, vec![0x0202_0202]);
+        assert!(parsed.lls.is_none());
+
+        // longer than what is left of the packet
+        let mut bad = bytes.clone();
+        bad[lls_at + 2..lls_at + 4].copy_from_slice(&8u16.to_be_bytes());
+        assert!(parse_hello(&bad).lls.is_none());
+
+        // cut off
+        assert!(parse_hello(&bytes[..lls_at + 6]).lls.is_none());
+
+        // the L bit set without a block
+        assert!(parse_hello(&bytes[..lls_at]).lls.is_none());
+    }
+
+    #[test]
+    fn authenticated_lls_is_kept() {
+        let auth = LlsTlv {
+            tlv_type: LLS_TLV_CRYPTO_AUTH,
+            length: 20,
+            value: vec![0; 20],
+        };
+        let lls = LlsBlock::new(vec![LlsTlv::ext_options(LLS_EO_LR), auth]);
+        let parsed = parse_hello(&hello(vec![], Some(lls)).encode_bincode());
+        let lls = parsed.lls.unwrap();
+        assert!(lls.is_authenticated());
+        assert_eq!(lls.tlvs[1].value.len(), 20);
+    }
+
+    #[test]
+    fn lls_tlv_longer_than_block_ends_the_list() {
+        let mut lls = LlsBlock::new(vec![LlsTlv::ext_options(LLS_EO_LR)]);
+        lls.tlvs[0].length = 12;
+        lls.checksum = 0;
+        lls.checksum = ip_checksum(&lls.encode());
+        let (_, parsed) = LlsBlock::parse(&lls.encode()).unwrap();
+        assert!(parsed.tlvs.is_empty());
+        assert_eq!(parsed.ext_options(), 0);
+    }
+
+    #[test]
+    fn dbd_round_trip() {
+        let lsa_hdr = LsaHeader {
+            age: 1,
+            options: OPTION_E,
+            ls_type: LsaType::LsaRouter as u8,
+            link_state_id: 0x0101_0101,
+            advertising_router: 0x0101_0101,
+            sequence_number: 0x8000_0001,
+            checksum: 0x1234,
+            length: 36,
+        };
+        let mut packet = OSPFPacket::DBDescription(DBDescription {
+            header: header(PacketType::DBD),
+            interface_mtu: 1500,
+            options: OPTION_E | OPTION_L,
+            flags: DBDFlag::new(false, true, true).to_byte(),
+            dbd_seq_num: 7,
+            lsa_hdrs: vec![lsa_hdr.clone(), lsa_hdr.clone()],
+            lls: Some(LlsBlock::new(vec![LlsTlv::ext_options(LLS_EO_RS)])),
+        });
+        packet.set_packet_length();
+        let bytes = packet.encode_bincode();
+        let dbd = match OSPFPacket::parse(&bytes) {
+            Ok((_, OSPFPacket::DBDescription(dbd))) => dbd,
+            other => panic!("expected a DBD, got {:?}", other),
+        };
+        assert_eq!(dbd.lsa_hdrs, vec![lsa_hdr.clone(), lsa_hdr]);
+        let flag = dbd.get_flag();
+        assert!(!flag.init && flag.more && flag.masterslave && !flag.resync);
+        assert_eq!(dbd.lls.unwrap().ext_options(), LLS_EO_RS);
+    }
+
+    #[test]
+    fn wrong_version_or_type() {
+        let mut bytes = hello(vec![], None).encode_bincode();
+        bytes[0] = 3;
+        assert!(OSPFPacket::parse(&bytes).is_err());
+        bytes[0] = 2;
+        bytes[1] = 6;
+        assert!(OSPFPacket::parse(&bytes).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+// OSPFv3 packet formats (RFC 5340 A.3). The checksum is computed and
+// verified by the kernel through IPV6_CHECKSUM, and there is no
+// authentication in the header.
+use crate::lsa_v3::*;
+use encoding_derive::Encoding;
+use nom::combinator::peek;
+use nom::number::streaming::be_u16;
+use nom_derive::*;
+use std::net::Ipv6Addr;
+
+pub const IPV6_HDR_LEN: usize = 40;
+pub const OSPF_V3_HDR_LEN: usize = 16;
+pub const DBD_V3_FIXED_LEN: usize = 12;
+pub const LSU_V3_FIXED_LEN: usize = 4;
+// where the checksum sits in the header, for IPV6_CHECKSUM
+pub const OSPF_V3_CHECKSUM_OFFSET: i32 = 12;
+
+pub const ALL_SPF_ROUTERS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 5);
+pub const ALL_D_ROUTERS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 6);
+
+// bits of the options field (RFC 5340 A.2)
+pub const OPTION_V3_V6: u16 = 0x0001;
+pub const OPTION_V3_E: u16 = 0x0002;
+pub const OPTION_V3_R: u16 = 0x0010;
+
+#[derive(Debug, Clone)]
+pub enum OSPFv3Packet {
+    Hello(HelloV3),
+    DBDescription(DBDescriptionV3),
+    LinkStateRequest(LinkStateRequestV3),
+    LinkStateUpdate(LinkStateUpdateV3),
+    LinkStateAcknowledgment(LinkStateAcknowledgmentV3),
+}
+
+impl<'a> Parse<&'a [u8]> for OSPFv3Packet {
+    fn parse(input: &'a [u8]) -> nom::IResult<&'a [u8], Self> {
+        let (_, wd) = peek(be_u16)(input)?;
+        let b0 = (wd >> 8) as u8;
+        let b1 = (wd & 0xff) as u8;
+        if b0 != 3 {
+            return Err(nom::Err::Error(nom::error::make_error(
+                input,
+                nom::error::ErrorKind::Tag,
+            )));
+        }
+        match b1 {
+            1 => {
+                let (input, hello) = HelloV3::parse(input)?;
+                Ok((input, OSPFv3Packet::Hello(hello)))
+            }
+            2 => {
+                let (input, dbd) = DBDescriptionV3::parse(input)?;
+                Ok((input, OSPFv3Packet::DBDescription(dbd)))
+            }
+            3 => {
+                let (input, lsr) = LinkStateRequestV3::parse(input)?;
+                Ok((input, OSPFv3Packet::LinkStateRequest(lsr)))
+            }
+            4 => {
+                let (input, lsu) = LinkStateUpdateV3::parse(input)?;
+                Ok((input, OSPFv3Packet::LinkStateUpdate(lsu)))
+            }
+            5 => {
+                let (input, lsack) = LinkStateAcknowledgmentV3::parse(input)?;
+                Ok((input, OSPFv3Packet::LinkStateAcknowledgment(lsack)))
+            }
+            _ => Err(nom::Err::Error(nom::error::make_error(
+                input,
+                nom::error::ErrorKind::Tag,
+            ))),
+        }
+    }
+}
+
+impl OSPFv3Packet {
+    pub fn get_hdr(&self) -> &HeaderV3 {
+        match self {
+            OSPFv3Packet::Hello(hello) => &hello.header,
+            OSPFv3Packet::DBDescription(dbd) => &dbd.header,
+            OSPFv3Packet::LinkStateRequest(lsr) => &lsr.header,
+            OSPFv3Packet::LinkStateUpdate(lsu) => &lsu.header,
+            OSPFv3Packet::LinkStateAcknowledgment(lsack) => &lsack.header,
+        }
+    }
+
+    pub fn get_mut_hdr(&mut self) -> &mut HeaderV3 {
+        match self {
+            OSPFv3Packet::Hello(hello) => &mut hello.header,
+            OSPFv3Packet::DBDescription(dbd) => &mut dbd.header,
+            OSPFv3Packet::LinkStateRequest(lsr) => &mut lsr.header,
+            OSPFv3Packet::LinkStateUpdate(lsu) => &mut lsu.header,
+            OSPFv3Packet::LinkStateAcknowledgment(lsack) => &mut lsack.header,
+        }
+    }
+
+    pub fn set_packet_length(&mut self) {
+        let len = self.encode().len() as u16;
+        self.get_mut_hdr().packet_length = len;
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            OSPFv3Packet::Hello(hello) => hello.encode(),
+            OSPFv3Packet::DBDescription(dbd) => dbd.encode(),
+            OSPFv3Packet::LinkStateRequest(lsr) => lsr.encode(),
+            OSPFv3Packet::LinkStateUpdate(lsu) => lsu.encode(),
+            OSPFv3Packet::LinkStateAcknowledgment(lsack) => lsack.encode(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct HeaderV3 {
+    pub version: u8,
+    pub packet_type: u8,
+    pub packet_length: u16,
+    pub router_id: u32,
+    pub area_id: u32,
+    pub checksum: u16,
+    pub instance_id: u8,
+    pub reserved: u8,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct HelloV3 {
+    pub header: HeaderV3,
+    pub interface_id: u32,
+    pub router_priority: u8,
+    // the options are 24 bits long
+    pub options_hi: u8,
+    pub options: u16,
+    pub hello_interval: u16,
+    pub router_dead_interval: u16,
+    // DR and BDR are identified by router ID
+    pub designated_router: u32,
+    pub backup_designated_router: u32,
+    pub neighbors: Vec<u32>,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct DBDescriptionV3 {
+    pub header: HeaderV3,
+    pub reserved: u8,
+    pub options_hi: u8,
+    pub options: u16,
+    pub interface_mtu: u16,
+    pub reserved2: u8,
+    pub flags: u8,
+    pub dbd_seq_num: u32,
+    pub lsa_hdrs: Vec<LsaHeaderV3>,
+}
+
+impl DBDescriptionV3 {
+    pub fn get_flag(&self) -> crate::packets::DBDFlag {
+        crate::packets::DBDFlag::from_byte(self.flags)
+    }
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LinkStateRequestV3 {
+    pub header: HeaderV3,
+    pub requests: Vec<LinkStateRequestItemV3>,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LinkStateRequestItemV3 {
+    pub reserved: u16,
+    pub ls_type: u16,
+    pub link_state_id: u32,
+    pub advertising_router: u32,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LinkStateUpdateV3 {
+    pub header: HeaderV3,
+    pub num_lsa: u32,
+    #[nom(Count = "num_lsa")]
+    pub lsas: Vec<LsaV3>,
+}
+
+#[derive(Debug, Clone, NomBE, Encoding)]
+pub struct LinkStateAcknowledgmentV3 {
+    pub header: HeaderV3,
+    pub lsas: Vec<LsaHeaderV3>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(packet_type: u8) -> HeaderV3 {
+        HeaderV3 {
+            version: 3,
+            packet_type,
+            packet_length: 0,
+            router_id: 0x0101_0101,
+            area_id: 0,
+            checksum: 0,
+            instance_id: 0,
+            reserved: 0,
+        }
+    }
+
+    fn lsa_hdr(link_state_id: u32) -> LsaHeaderV3 {
+        LsaHeaderV3 {
+            age: 1,
+            ls_type: LsaTypeV3::Router as u16,
+            link_state_id,
+            advertising_router: 0x0101_0101,
+            sequence_number: 0x8000_0001,
+            checksum: 0x1234,
+            length: 24,
+        }
+    }
+
+    fn round_trip(mut packet: OSPFv3Packet) -> OSPFv3Packet {
+        packet.set_packet_length();
+        let bytes = packet.encode();
+        assert_eq!(packet.get_hdr().packet_length as usize, bytes.len());
+        let (rest, parsed) = OSPFv3Packet::parse(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.encode(), bytes);
+        parsed
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let parsed = round_trip(OSPFv3Packet::Hello(HelloV3 {
+            header: header(1),
+            interface_id: 2,
+            router_priority: 1,
+            options_hi: 0,
+            options: OPTION_V3_V6 | OPTION_V3_E | OPTION_V3_R,
+            hello_interval: 10,
+            router_dead_interval: 40,
+            designated_router: 0x0101_0101,
+            backup_designated_router: 0,
+            neighbors: vec![0x0202_0202],
+        }));
+        match parsed {
+            OSPFv3Packet::Hello(hello) => {
+                assert_eq!(hello.interface_id, 2);
+                assert_eq!(hello.neighbors, vec![0x0202_0202]);
+            }
+            other => panic!("expected a hello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dbd_and_lsack_round_trip() {
+        let parsed = round_trip(OSPFv3Packet::DBDescription(DBDescriptionV3 {
+            header: header(2),
+            reserved: 0,
+            options_hi: 0,
+            options: OPTION_V3_V6,
+            interface_mtu: 1500,
+            reserved2: 0,
+            flags: 0b111,
+            dbd_seq_num: 9,
+            lsa_hdrs: vec![lsa_hdr(1), lsa_hdr(2)],
+        }));
+        match parsed {
+            OSPFv3Packet::DBDescription(dbd) => {
+                assert!(dbd.get_flag().init);
+                assert_eq!(dbd.lsa_hdrs, vec![lsa_hdr(1), lsa_hdr(2)]);
+            }
+            other => panic!("expected a DBD, got {:?}", other),
+        }
+        let parsed = round_trip(OSPFv3Packet::LinkStateAcknowledgment(
+            LinkStateAcknowledgmentV3 {
+                header: header(5),
+                lsas: vec![lsa_hdr(3)],
+            },
+        ));
+        assert!(matches!(parsed,
+            OSPFv3Packet::LinkStateAcknowledgment(ack) if ack.lsas == vec![lsa_hdr(3)]));
+    }
+
+    #[test]
+    fn lsr_round_trip() {
+        let item = LinkStateRequestItemV3 {
+            reserved: 0,
+            ls_type: LsaTypeV3::Network as u16,
+            link_state_id: 4,
+            advertising_router: 0x0202_0202,
+        };
+        let parsed = round_trip(OSPFv3Packet::LinkStateRequest(LinkStateRequestV3 {
+            header: header(3),
+            requests: vec![item.clone(), item],
+        }));
+        match parsed {
+            OSPFv3Packet::LinkStateRequest(lsr) => {
+                assert_eq!(lsr.requests.len(), 2);
+                assert_eq!(lsr.requests[1].link_state_id, 4);
+            }
+            other => panic!("expected an LSR, got {:?}", other),
+        }
+    }
+
+    fn lsu(num_lsa: u32) -> Vec<u8> {
+        let mut lsa = LsaV3::Network(LsaNetworkV3 {
+            header: LsaHeaderV3 {
+                ls_type: LsaTypeV3::Network as u16,
+                ..lsa_hdr(2)
+            },
+            reserved: 0,
+            options_hi: 0,
+            options: OPTION_V3_V6,
+            attached_routers: vec![0x0101_0101, 0x0202_0202],
+        });
+        lsa.set_checksum_length();
+        let mut packet = OSPFv3Packet::LinkStateUpdate(LinkStateUpdateV3 {
+            header: header(4),
+            num_lsa,
+            lsas: vec![lsa.clone(), lsa],
+        });
+        packet.set_packet_length();
+        packet.encode()
+    }
+
+    #[test]
+    fn lsu_round_trip() {
+        let bytes = lsu(2);
+        match OSPFv3Packet::parse(&bytes) {
+            Ok((rest, OSPFv3Packet::LinkStateUpdate(lsu))) => {
+                assert!(rest.is_empty());
+                assert_eq!(lsu.lsas.len(), 2);
+                assert_eq!(lsu.lsas[1].get_hdr().link_state_id, 2);
+            }
+            other => panic!("expected an LSU, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_lsu() {
+        // more LSAs announced than carried
+        assert!(OSPFv3Packet::parse(&lsu(3)).is_err());
+        // the last LSA cut off
+        let bytes = lsu(2);
+        assert!(OSPFv3Packet::parse(&bytes[..bytes.len() - 4]).is_err());
+        // not OSPFv3
+        let mut bytes = lsu(2);
+        bytes[0] = 2;
+        assert!(OSPFv3Packet::parse(&bytes).is_err());
+    }
+}
@@ -3,21 +3,63 @@ use std::net::Ipv4Addr;
 
 use nom_derive::Parse;
 use pnet::datalink;
+use pnet::ipnetwork::Ipv4Network;
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet::packet::Packet;
+use tokio::sync::mpsc;
 
 use crate::config::*;
 use crate::database::{DatabaseRequestType, DatabaseResponse};
-use crate::interface::{Interface, InterfaceEvent, InterfaceState};
-use crate::lsa::LsaCmpResult;
+use crate::interface::{
+    HelloMismatch, Interface, InterfaceCommand, InterfaceEvent, InterfaceState, InterfaceType,
+    IpFragment,
+};
+use crate::interface_fsm::OspfInterface;
+use crate::lsa::{LsaCmpResult, LsaType};
 use crate::neighbor::{NbrEvent, NbrState, Neighbor};
-use crate::packets::{DBDFlag, OSPFPacket};
+use crate::packets::{DBDFlag, OSPFPacket, LLS_EO_RS, OPTION_E, OPTION_N, OPTION_O};
+
+const MAX_PENDING_DATAGRAMS: usize = 64;
+// how long the fragments of a datagram wait for the rest, like the
+// kernel's ipfrag_time
+const REASSEMBLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+// frames read but not yet handled by the interface task
+const MAX_PENDING_FRAMES: usize = 64;
+const RECV_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(200);
 
 pub fn ipv4_to_bits(ip: Ipv4Addr) -> u32 {
     u32::from_be_bytes(ip.octets())
 }
 
+// Reads block, so the frames of an interface are read on a dedicated
+// thread and handed to its task, which stays free for timers and commands.
+// The thread ends once the task drops the receiver.
+fn spawn_frame_reader(
+    name: String,
+    mut rx: Box<dyn datalink::DataLinkReceiver>,
+) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, frames) = mpsc::channel(MAX_PENDING_FRAMES);
+    std::thread::spawn(move || loop {
+        match rx.next() {
+            Ok(frame) => {
+                if tx.blocking_send(frame.to_vec()).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                if tx.is_closed() {
+                    return;
+                }
+                if e.kind() != std::io::ErrorKind::TimedOut {
+                    eprintln!("Error while reading new packet on {}: {}", name, e);
+                }
+            }
+        }
+    });
+    frames
+}
+
 impl Interface {
     // Check if the received packet's target ip is the same as
     // the interface's ip or the OSPF multicast address
@@ -28,30 +70,115 @@ impl Interface {
         (ip == addr) || (ip == ospf_multicast)
     }
 
-    pub async fn receiver(&mut self) {
-        let (_, mut rx) = match datalink::channel(&self.pnet_interface, Default::default()) {
-            Ok(datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
+    // GTSM (RFC 5082): a packet that crossed more hops than allowed, or a
+    // unicast one from off our subnets, was likely injected from afar
+    fn gtsm_accepts(&self, packet: &Ipv4Packet) -> bool {
+        let hops = match self.ttl_security {
+            Some(hops) => hops,
+            None => return true,
+        };
+        if self.fsm.int_type == InterfaceType::Virtual {
+            return packet.get_ttl() >= 255 - hops;
+        }
+        if packet.get_ttl() < 255 {
+            return false;
+        }
+        if packet.get_destination() != self.addr || self.unnumbered {
+            return true;
+        }
+        let src = packet.get_source();
+        Ipv4Network::with_netmask(self.addr, self.mask).is_ok_and(|x| x.contains(src))
+            || self.secondary_addrs.iter().any(|x| x.contains(src))
+    }
+
+    // Collect ip fragments until the whole OSPF packet has arrived
+    fn reassemble(&mut self, packet: &Ipv4Packet) -> Option<Vec<u8>> {
+        let more = packet.get_flags() & Ipv4Flags::MoreFragments != 0;
+        let offset = packet.get_fragment_offset() as usize * 8;
+        if !more && offset == 0 {
+            return Some(packet.payload().to_vec());
+        }
+        // drop half-received datagrams that waited too long or pile up
+        self.frag_buffer.retain(|_, frags| {
+            frags
+                .iter()
+                .all(|f| f.arrived.elapsed() < REASSEMBLY_TIMEOUT)
+        });
+        if self.frag_buffer.len() >= MAX_PENDING_DATAGRAMS {
+            self.frag_buffer.clear();
+        }
+        let key = (packet.get_source(), packet.get_identification());
+        let frags = self.frag_buffer.entry(key).or_default();
+        if !frags.iter().any(|f| f.offset == offset) {
+            frags.push(IpFragment {
+                offset,
+                more,
+                data: packet.payload().to_vec(),
+                arrived: std::time::Instant::now(),
+            });
+            frags.sort_by_key(|f| f.offset);
+        }
+        // complete once the fragments are contiguous up to the last one
+        let mut expected = 0;
+        for f in frags.iter() {
+            if f.offset != expected {
+                return None;
+            }
+            expected += f.data.len();
+        }
+        if frags.last().unwrap().more {
+            return None;
+        }
+        let frags = self.frag_buffer.remove(&key).unwrap();
+        Some(frags.into_iter().flat_map(|f| f.data).collect())
+    }
+
+    pub async fn receiver(&mut self, mut commands: mpsc::Receiver<InterfaceCommand>) {
+        // the reader thread checks now and then whether we are still there
+        let config = datalink::Config {
+            read_timeout: Some(RECV_TIMEOUT),
+            ..Default::default()
+        };
+        let rx = match datalink::channel(&self.pnet_interface, config) {
+            Ok(datalink::Channel::Ethernet(_, rx)) => rx,
             Ok(_) => panic!("Unhandled channel type"),
             Err(e) => panic!("An error occurred when creating the channel: {}", e),
         };
-        self.handle_event(InterfaceEvent::InterfaceUp).await;
+        let mut frames = spawn_frame_reader(self.name.clone(), rx);
         loop {
-            // check if any timer is expired
-            self.check_timers().await;
-            // check if need to resend dbd
-            self.send_dbd_if_need().await;
-            // check if there's any nbr change
-            if self.check_nbr_change().await {
-                self.handle_event(InterfaceEvent::NeighborChange).await;
-            }
-            self.update_nbr_states().await;
-            // send LSR packet if needed
-            self.send_lsr_if_need().await;
-            print!(".");
-            // flush
-            std::io::stdout().flush().unwrap();
-            match rx.next() {
-                Ok(packet) => {
+            if !self.poll_commands(&mut commands).await {
+                return;
+            }
+            if self.check_drain().await {
+                self.say_goodbye().await;
+                return;
+            }
+            if self.fsm.state != InterfaceState::Down {
+                // check if any timer is expired
+                self.check_timers().await;
+                // check if need to resend dbd
+                self.send_dbd_if_need().await;
+                // check if there's any nbr change
+                if self.check_nbr_change().await {
+                    self.handle_event(InterfaceEvent::NeighborChange).await;
+                }
+                self.update_nbr_states().await;
+                // send LSR packet if needed
+                self.send_lsr_if_need().await;
+            }
+            self.check_flushed_network_lsa().await;
+            // wake up regularly even without packets to run timers and commands
+            match tokio::time::timeout(RECV_TIMEOUT, frames.recv()).await {
+                Err(_) => {}
+                Ok(None) => {
+                    eprintln!("Packet reader on {} stopped", self.name);
+                    return;
+                }
+                Ok(Some(_)) if self.fsm.state == InterfaceState::Down => {}
+                Ok(Some(packet)) => {
+                    print!(".");
+                    // flush
+                    std::io::stdout().flush().unwrap();
                     let payload_offset = 14usize;
                     let packet = match Ipv4Packet::new(&packet[payload_offset..]) {
                         Some(packet) => packet,
@@ -66,11 +193,22 @@ impl Interface {
                     {
                         continue;
                     }
-                    if packet.get_flags() != 0 {
-                        println!("Received OSPF packet with flags = {}", packet.get_flags());
+                    if !self.gtsm_accepts(&packet) {
+                        println!(
+                            "Dropping packet from {} on {}: ttl {} fails ttl security",
+                            packet.get_source(),
+                            self.name,
+                            packet.get_ttl()
+                        );
+                        continue;
                     }
                     let src_ip = packet.get_source();
-                    let packet = match OSPFPacket::parse(packet.payload()) {
+                    let payload = match self.reassemble(&packet) {
+                        Some(payload) => payload,
+                        // wait for the remaining fragments
+                        None => continue,
+                    };
+                    let packet = match OSPFPacket::parse(&payload) {
                         Ok((_, packet)) => packet,
                         Err(e) => {
                             eprintln!("Error while parsing ospf packet: {}", e);
@@ -83,37 +221,86 @@ impl Interface {
                     if packet.get_hdr().packet_type > 1 {
                         println!("Received OSPF packet");
                     }
-                    self.handle_packet(packet).await;
-                }
-                Err(e) => {
-                    eprintln!("Error while reading new packet: {}", e);
+                    self.handle_packet(packet, src_ip).await;
                 }
             }
         }
     }
 
-    pub async fn handle_packet(&mut self, packet: OSPFPacket) {
+    pub async fn handle_packet(&mut self, packet: OSPFPacket, src_ip: Ipv4Addr) {
+        if self.fsm.passive {
+            return;
+        }
         // Handle packet
         match packet {
             OSPFPacket::Hello(hello) => {
-                self.handle_hello(hello).await;
+                self.handle_hello(hello, src_ip).await;
             }
             OSPFPacket::DBDescription(dbd) => {
-                self.handle_dbd(dbd).await;
+                self.handle_dbd(dbd, src_ip).await;
             }
             OSPFPacket::LinkStateRequest(lsr) => {
-                self.handle_lsr(lsr).await;
+                self.handle_lsr(lsr, src_ip).await;
             }
             OSPFPacket::LinkStateUpdate(lsu) => {
-                self.handle_lsu(lsu).await;
+                self.handle_lsu(lsu, src_ip).await;
             }
-            OSPFPacket::LinkStateAcknowledgment(lsa) => {
-                println!("Received LinkStateAcknowledgment packet");
+            OSPFPacket::LinkStateAcknowledgment(lsack) => {
+                self.handle_lsack(lsack, src_ip);
             }
         }
     }
 
-    pub async fn handle_hello(&mut self, hello: crate::packets::Hello) {
+    // acknowledged LSAs no longer need to be retransmitted (RFC 2328 13.7)
+    pub fn handle_lsack(
+        &mut self,
+        lsack: crate::packets::LinkStateAcknowledgment,
+        src_ip: Ipv4Addr,
+    ) {
+        if let Some(idx) = self.find_neighbor(src_ip, lsack.header.router_id) {
+            self.fsm.neighbor_list[idx]
+                .lsa_retransmission_list
+                .retain(|x| !lsack.lsas.iter().any(|h| h.same_ids(x)));
+        }
+    }
+
+    // Check the hello parameters that must match ours (RFC 2328 10.5)
+    fn check_hello_params(
+        &self,
+        hello: &crate::packets::Hello,
+        my_config: &Config,
+    ) -> Option<HelloMismatch> {
+        let options = my_config.options;
+        // our own hellos are filtered by source address already
+        if hello.header.router_id == my_config.router_id {
+            return Some(HelloMismatch::DuplicateRouterId);
+        }
+        if hello.header.area_id != self.area_id {
+            return Some(HelloMismatch::AreaId);
+        }
+        // the mask is only meaningful on multi-access networks
+        if self.fsm.int_type != InterfaceType::P2P
+            && self.fsm.int_type != InterfaceType::Virtual
+            && hello.network_mask != ipv4_to_bits(self.mask)
+        {
+            return Some(HelloMismatch::NetworkMask);
+        }
+        if hello.hello_interval as u32 != self.hello_interval {
+            return Some(HelloMismatch::HelloInterval);
+        }
+        if hello.router_dead_interval != self.dead_interval {
+            return Some(HelloMismatch::DeadInterval);
+        }
+        if (hello.options ^ options) & OPTION_E != 0 {
+            return Some(HelloMismatch::ExternalRoutingCapability);
+        }
+        if (hello.options ^ options) & OPTION_N != 0 {
+            return Some(HelloMismatch::NssaCapability);
+        }
+        None
+    }
+
+    pub async fn handle_hello(&mut self, hello: crate::packets::Hello, src_ip: Ipv4Addr) {
         // println!("Received Hello packet: {:?}", hello);
         // print!(
         //     "Received Hello packet from {}, it's neighbor = ",
@@ -124,33 +311,64 @@ impl Interface {
         // }
         // println!();
 
-        // TODO: check 'E' bit, check if hello settings same
-        // TODO: for some network, ip = src_ip, for others, ip = router_id
-        let router_id = Ipv4Addr::from(hello.header.router_id);
-        if self.get_neighbor_index(router_id).is_none() {
-            self.neighbor_list.push(Neighbor::new(
-                hello.clone(),
-                self.dead_interval,
-                self.rxmt_interval,
-            ));
+        let my_config = self.query_config().await;
+        if let Some(mismatch) = self.check_hello_params(&hello, &my_config) {
+            *self.hello_drop_counts.entry(mismatch).or_insert(0) += 1;
+            println!(
+                "Dropping hello from {} on {}: {:?} mismatch",
+                Ipv4Addr::from(hello.header.router_id),
+                self.name,
+                mismatch
+            );
+            return;
         }
+        let sender_nbr_idx = match self.find_neighbor(src_ip, hello.header.router_id) {
+            Some(idx) => idx,
+            None => {
+                self.fsm.neighbor_list.push(Neighbor::new(
+                    hello.clone(),
+                    src_ip,
+                    self.dead_interval,
+                    self.rxmt_interval,
+                ));
+                self.fsm.neighbor_list.len() - 1
+            }
+        };
 
-        let self_clone = self.clone();
-
-        let sender_nbr_idx = self.get_neighbor_index(router_id).unwrap();
-        let sender_nbr = &mut self.neighbor_list[sender_nbr_idx];
-        sender_nbr.nbr_ip = Ipv4Addr::from(hello.header.router_id);
+        let int_view = self.fsm.int_view();
+        let sender_nbr = &mut self.fsm.neighbor_list[sender_nbr_idx];
+        sender_nbr.int_view = int_view;
+        sender_nbr.nbr_ip = src_ip;
         sender_nbr.nbr_id = hello.header.router_id;
         let prev_neighbor_dr = sender_nbr.nbr_dr;
         sender_nbr.nbr_dr = Ipv4Addr::from(hello.designated_router);
         let prev_neighbor_bdr = sender_nbr.nbr_bdr;
         sender_nbr.nbr_bdr = Ipv4Addr::from(hello.backup_designated_router);
         sender_nbr.nbr_pri = hello.router_priority;
+        let lls_options = match hello.lls.as_ref() {
+            // we have no keys to check an authenticated block with, its
+            // options can't be trusted (RFC 5613 2.5)
+            Some(lls) if lls.is_authenticated() => {
+                if sender_nbr.state == NbrState::Down {
+                    eprintln!(
+                        "Ignoring the LLS options of {}, LLS authentication is not supported",
+                        Ipv4Addr::from(hello.header.router_id)
+                    );
+                }
+                0
+            }
+            Some(lls) => lls.ext_options(),
+            None => 0,
+        };
+        sender_nbr.nbr_lls_options = lls_options;
         sender_nbr.handle_event(NbrEvent::HelloReceived);
-        let my_config = self_clone.query_config().await;
         // if self is in hello's neighbor list, 2-way receive, else 1-way receive
         if hello.neighbors.contains(&my_config.router_id) {
             sender_nbr.handle_event(NbrEvent::TwoWayReceived);
+        } else if lls_options & LLS_EO_RS != 0 && sender_nbr.state == NbrState::Full {
+            // a restarting neighbor doesn't know us yet, keep the adjacency
+            // until it resyncs with us (RFC 4812)
+            return;
         } else {
             // println!("1-way receive, neighbor = {:?}", hello.neighbors);
             sender_nbr.handle_event(NbrEvent::OneWayReceived);
@@ -160,7 +378,7 @@ impl Interface {
         // if the neighbor declares itself as DR or BDR when interface is Waiting, interface call BackupSeen event
         // if the neighbor's DR or BDR declare status changes, interface call NeighborChange event
         if (sender_nbr.nbr_dr == sender_nbr.nbr_ip || sender_nbr.nbr_bdr == sender_nbr.nbr_ip)
-            && self.state == InterfaceState::Waiting
+            && self.fsm.state == InterfaceState::Waiting
         {
             self.handle_event(InterfaceEvent::BackupSeen).await;
         } else if ((prev_neighbor_dr == sender_nbr.nbr_ip)
@@ -172,7 +390,7 @@ impl Interface {
         }
     }
 
-    pub async fn handle_dbd(&mut self, dbd: crate::packets::DBDescription) {
+    pub async fn handle_dbd(&mut self, dbd: crate::packets::DBDescription, src_ip: Ipv4Addr) {
         println!(
             "Received DBDescription packet from {}, flags {:#?}, seq_num {}",
             Ipv4Addr::from(dbd.header.router_id),
@@ -183,12 +401,21 @@ impl Interface {
         let self_clone = self.clone();
 
         let router_id = Ipv4Addr::from(dbd.header.router_id);
-        if self.get_neighbor_index(router_id).is_none() {
+        let sender_nbr_idx = match self.find_neighbor(src_ip, dbd.header.router_id) {
+            Some(idx) => idx,
             // get a dbd not in nbr list, drop it
+            None => return,
+        };
+        let sender_nbr = &mut self.fsm.neighbor_list[sender_nbr_idx];
+        // a DBD announcing a larger mtu than we can receive is rejected (RFC 2328 10.6)
+        if !self_clone.mtu_ignore && dbd.interface_mtu > self_clone.mtu {
+            sender_nbr.mtu_mismatch_count += 1;
+            println!(
+                "Adjacency problem with neighbor {} on {}: MTU mismatch (neighbor {}, ours {}), DBD rejected",
+                sender_nbr.nbr_ip, self_clone.name, dbd.interface_mtu, self_clone.mtu
+            );
             return;
         }
-        let sender_nbr_idx = self.get_neighbor_index(router_id).unwrap();
-        let sender_nbr = &mut self.neighbor_list[sender_nbr_idx];
         // check if dbd packet is duplicate
         let dbd_duped = (!sender_nbr.last_rcv_dbd.is_none())
             && (dbd.dbd_seq_num == sender_nbr.last_rcv_dbd.as_ref().unwrap().dbd_seq_num);
@@ -206,9 +433,27 @@ impl Interface {
             }
         }
 
+        // the R bit asks for, or belongs to, an out-of-band resync of a
+        // Full adjacency (RFC 4811)
+        let flag = sender_nbr.last_rcv_dbd.as_ref().unwrap().get_flag();
+        if sender_nbr.oob_resync.is_some() && !flag.resync {
+            sender_nbr.handle_event(NbrEvent::SeqNumberMismatch);
+            return;
+        }
+        if sender_nbr.oob_resync.is_none() && flag.resync {
+            if sender_nbr.state != NbrState::Full {
+                return;
+            }
+            if flag.init {
+                sender_nbr.handle_event(NbrEvent::OobResync);
+            } else if !dbd_duped {
+                return;
+            }
+        }
+
         let dbd = sender_nbr.last_rcv_dbd.as_ref().unwrap();
         let mut dbd_accepted = false;
-        match sender_nbr.state {
+        match sender_nbr.sync_state() {
             NbrState::Down | NbrState::Attempt | NbrState::TwoWay => {
                 // packet should be refused
                 return;
@@ -259,14 +504,18 @@ impl Interface {
                     DatabaseResponse::LsaList(lsas) => lsas,
                     _ => panic!("Unexpected response"),
                 };
-                sender_nbr
-                    .db_summary_list
-                    .extend(all_lsas.iter().map(|x| x.get_hdr().clone()));
+                // opaque LSAs only go to neighbors that understand them (RFC 5250 3.1)
+                let opaque_capable = sender_nbr.nbr_options & OPTION_O != 0;
+                sender_nbr.db_summary_list.extend(
+                    all_lsas
+                        .iter()
+                        .map(|x| x.get_hdr().clone())
+                        .filter(|x| opaque_capable || x.ls_type < LsaType::LsaOpaqueLink as u8),
+                );
                 // now negotiation is done
                 if sender_nbr.nbr_is_master {
                     // if i am slave, send the first packet
-                    let max_transmit_size =
-                        (self_clone.query_config().await.default_mtu as usize - 100) / (20);
+                    let max_transmit_size = self_clone.max_dbd_lsa_hdrs();
                     let real_size =
                         std::cmp::min(max_transmit_size, sender_nbr.db_summary_list.len());
                     let lsa_hdrs = if real_size > 0 {
@@ -279,13 +528,13 @@ impl Interface {
                     self_clone
                         .send_dbd_packet(
                             sender_nbr.nbr_ip,
-                            DBDFlag::new(false, more, false).to_byte(),
+                            sender_nbr.dbd_flags(DBDFlag::new(false, more, false)),
                             sender_nbr.dd_seq_number,
                             lsa_hdrs.clone(),
                         )
                         .await;
                     sender_nbr.last_sent_dbd = Some((
-                        DBDFlag::new(false, more, false).to_byte(),
+                        sender_nbr.dbd_flags(DBDFlag::new(false, more, false)),
                         sender_nbr.dd_seq_number,
                         lsa_hdrs,
                     ));
@@ -352,6 +601,10 @@ impl Interface {
         let dbd = sender_nbr.last_rcv_dbd.as_ref().unwrap();
         if dbd_accepted {
             for lsahdr in dbd.lsa_hdrs.iter() {
+                // link scope opaque LSAs are not kept, see handle_lsu
+                if lsahdr.ls_type == LsaType::LsaOpaqueLink as u8 {
+                    continue;
+                }
                 // if lsa is not in database summary list, add it
                 if self_clone.query_by_lsa_hdr(lsahdr.clone()).await.is_none() {
                     sender_nbr.link_state_req_list.push(lsahdr.clone());
@@ -367,8 +620,7 @@ impl Interface {
                 // if i am slave, set seq & retransmit
                 sender_nbr.dd_seq_number = dbd.dbd_seq_num;
                 // TODO: test this feature
-                let max_transmit_size =
-                    (self_clone.query_config().await.default_mtu as usize - 100) / (20);
+                let max_transmit_size = self_clone.max_dbd_lsa_hdrs();
                 let real_size = std::cmp::min(max_transmit_size, sender_nbr.db_summary_list.len());
                 let is_last = sender_nbr.db_summary_list.len() == real_size;
                 let lsa_hdrs;
@@ -380,7 +632,7 @@ impl Interface {
                     sender_nbr.db_summary_list.clear();
                 }
                 let sending_flags =
-                    DBDFlag::new(false, !is_last, !sender_nbr.nbr_is_master).to_byte();
+                    sender_nbr.dbd_flags(DBDFlag::new(false, !is_last, !sender_nbr.nbr_is_master));
                 self_clone
                     .send_dbd_packet(
                         sender_nbr.nbr_ip,
@@ -403,8 +655,7 @@ impl Interface {
                     sender_nbr.handle_event(NbrEvent::ExchangeDone);
                 } else {
                     // else, send a new DBD packet
-                    let max_transmit_size =
-                        (self_clone.query_config().await.default_mtu as usize - 100) / (20);
+                    let max_transmit_size = self_clone.max_dbd_lsa_hdrs();
                     let real_size =
                         std::cmp::min(max_transmit_size, sender_nbr.db_summary_list.len());
                     let is_last = sender_nbr.db_summary_list.len() == real_size;
@@ -417,8 +668,11 @@ impl Interface {
                         lsa_hdrs = vec![];
                         sender_nbr.db_summary_list.clear();
                     }
-                    let sending_flags =
-                        DBDFlag::new(false, !is_last, !sender_nbr.nbr_is_master).to_byte();
+                    let sending_flags = sender_nbr.dbd_flags(DBDFlag::new(
+                        false,
+                        !is_last,
+                        !sender_nbr.nbr_is_master,
+                    ));
                     self_clone
                         .send_dbd_packet(
                             sender_nbr.nbr_ip,
@@ -435,18 +689,18 @@ impl Interface {
         }
     }
 
-    pub async fn handle_lsr(&mut self, lsr: crate::packets::LinkStateRequest) {
+    pub async fn handle_lsr(&mut self, lsr: crate::packets::LinkStateRequest, src_ip: Ipv4Addr) {
         println!("Received LinkStateRequest packet: {:?}", lsr);
 
         let query_int = self.clone();
 
-        let router_id = Ipv4Addr::from(lsr.header.router_id);
-        if self.get_neighbor_index(router_id).is_none() {
+        let sender_nbr_idx = match self.find_neighbor(src_ip, lsr.header.router_id) {
+            Some(idx) => idx,
             // get a lsr not in nbr list, drop it
-            return;
-        }
-        let sender_nbr_idx = self.get_neighbor_index(router_id).unwrap();
-        let sender_nbr = &mut self.neighbor_list[sender_nbr_idx];
+            None => return,
+        };
+        let sender_nbr = &mut self.fsm.neighbor_list[sender_nbr_idx];
+        let nbr_ip = sender_nbr.nbr_ip;
 
         if !(sender_nbr.state == NbrState::Exchange
             || sender_nbr.state == NbrState::Loading
@@ -460,7 +714,7 @@ impl Interface {
             Some(lsas) => {
                 // TODO: send LinkStateUpdate packet
                 println!("Sending requested LSAs: {:?}", lsas);
-                self.send_lsu(router_id, lsas).await;
+                query_int.send_lsu(nbr_ip, lsas).await;
             }
             None => {
                 // if not all requested LSAs are found, drop the packet & call BadLSReq event
@@ -469,7 +723,7 @@ impl Interface {
         }
     }
 
-    pub async fn handle_lsu(&mut self, lsu: crate::packets::LinkStateUpdate) {
+    pub async fn handle_lsu(&mut self, lsu: crate::packets::LinkStateUpdate, src_ip: Ipv4Addr) {
         print!(
             "Received LinkStateUpdate packet from {}, LSAs:",
             Ipv4Addr::from(lsu.header.router_id)
@@ -486,28 +740,37 @@ impl Interface {
 
         let self_clone = self.clone();
 
-        let router_id = Ipv4Addr::from(lsu.header.router_id);
-        if self.get_neighbor_index(router_id).is_none() {
-            // get a lsr not in nbr list, drop it
-            return;
-        }
-        let sender_nbr_idx = self.get_neighbor_index(router_id).unwrap();
-        let sender_nbr = &mut self.neighbor_list[sender_nbr_idx];
+        let sender_nbr_idx = match self.find_neighbor(src_ip, lsu.header.router_id) {
+            Some(idx) => idx,
+            // get a lsu not in nbr list, drop it
+            None => return,
+        };
+        let my_router_id = self_clone.query_config().await.router_id;
+        let sender_nbr = &mut self.fsm.neighbor_list[sender_nbr_idx];
+        let nbr_ip = sender_nbr.nbr_ip;
 
         for lsa in lsu.lsas.iter() {
             let hdr = lsa.get_hdr();
             // TODO: 1. check the checksum
             // 2. check ls_type
-            if hdr.ls_type == 0 || hdr.ls_type > 5 {
+            if !matches!(hdr.ls_type, 1..=5 | 9..=11) {
                 // ls_type is invalid, drop the packet
                 continue;
             }
+            // the area LSDB has no room for link scope opaque LSAs and we have
+            // no use for them, they are acknowledged so the neighbor stops
+            // sending them but neither kept nor flooded
+            if hdr.ls_type == LsaType::LsaOpaqueLink as u8 {
+                sender_nbr.link_state_req_list.retain(|x| !lsa.same_ids(x));
+                self_clone.send_lsack(nbr_ip, vec![hdr.clone()]).await;
+                continue;
+            }
             let mut lsdb_ver = self_clone.query_by_lsa_hdr(hdr.clone()).await;
             // TODO: 3. if is AS-external-LSA (type-5) and i am in stub area, drop the packet
             // 4. if LS age is equal to MaxAge and lsdb doesn't have this LSA
             if hdr.age == MAX_AGE as u16 && lsdb_ver.is_none() {
                 // send a LSAck to Ack to this LSA
-                self_clone.send_lsack(router_id, vec![hdr.clone()]).await;
+                self_clone.send_lsack(nbr_ip, vec![hdr.clone()]).await;
                 // drop the packet
                 continue;
             }
@@ -532,7 +795,7 @@ impl Interface {
                             .lsa_retransmission_list
                             .retain(|x| !lsa.same_ids(x));
                         // 7.b. send LSAck
-                        self_clone.send_lsack(router_id, vec![hdr.clone()]).await;
+                        self_clone.send_lsack(nbr_ip, vec![hdr.clone()]).await;
                         continue;
                     }
                     LsaCmpResult::Newer => {
@@ -554,9 +817,7 @@ impl Interface {
                             continue;
                         }
                         // directly send a LSU to update
-                        self_clone
-                            .send_lsu(router_id, vec![lsdb_ver.unwrap()])
-                            .await;
+                        self_clone.send_lsu(nbr_ip, vec![lsdb_ver.unwrap()]).await;
                         continue;
                     }
                 }
@@ -571,16 +832,33 @@ impl Interface {
                 sender_nbr
                     .lsa_retransmission_list
                     .retain(|x| !lsa.same_ids(x));
+                // f. if is self-originated, we re-originate or flush it instead
+                // of keeping the received instance
+                if hdr.advertising_router == my_router_id {
+                    self_clone.send_lsack(nbr_ip, vec![hdr.clone()]).await;
+                    if let DatabaseResponse::Lsa(ours) = self_clone
+                        .query_database(DatabaseRequestType::SelfOriginatedLsa(lsa.clone()))
+                        .await
+                    {
+                        self_clone.flood_lsu(vec![ours]).await;
+                    }
+                    continue;
+                }
                 // d. add or update the LSA
-                self_clone
+                let reply = self_clone
                     .query_database(DatabaseRequestType::AddOrUpdateLsa(lsa.clone()))
                     .await;
                 // e. send LSAck
-                self_clone.send_lsack(router_id, vec![hdr.clone()]).await;
-                // f. if is self-originated
-                // TODO
+                self_clone.send_lsack(nbr_ip, vec![hdr.clone()]).await;
+                // a network LSA of ours under another router ID, the database
+                // flushed it
+                if let DatabaseResponse::Lsa(ours) = reply {
+                    self_clone.flood_lsu(vec![ours]).await;
+                }
             }
-            if sender_nbr.state == NbrState::Loading && sender_nbr.link_state_req_list.len() == 0 {
+            if sender_nbr.sync_state() == NbrState::Loading
+                && sender_nbr.link_state_req_list.len() == 0
+            {
                 // if all requested LSAs are received, call LoadingDone event
                 sender_nbr.handle_event(NbrEvent::LoadingDone);
             }
@@ -0,0 +1,492 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use nom_derive::Parse;
+use tokio::sync::mpsc;
+
+use crate::config::*;
+use crate::database_v3::{DatabaseV3RequestType, DatabaseV3Response};
+use crate::interface::{HelloMismatch, InterfaceCommand, InterfaceEvent, InterfaceState};
+use crate::interface_fsm::OspfInterface;
+use crate::interface_v3::{InterfaceV3, NeighborV3};
+use crate::lsa::LsaCmpResult;
+use crate::lsa_v3::LsaHeaderV3;
+use crate::neighbor::{NbrEvent, NbrState};
+use crate::packets::DBDFlag;
+use crate::packets_v3::*;
+use crate::socket_v3::OspfV3Socket;
+
+const RECV_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(200);
+const RECV_BUFFER_SIZE: usize = 65536;
+// packets read but not yet handled by the interface task
+const MAX_PENDING_PACKETS: usize = 64;
+
+// A packet with its source and hop limit
+type ReceivedPacket = (Vec<u8>, Ipv6Addr, Option<u8>);
+
+// Like the OSPFv2 frames, packets are read on a dedicated thread since
+// reads block. The thread ends once the task drops the receiver.
+fn spawn_packet_reader(name: String, socket: Arc<OspfV3Socket>) -> mpsc::Receiver<ReceivedPacket> {
+    let (tx, packets) = mpsc::channel(MAX_PENDING_PACKETS);
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, src, hop_limit)) => {
+                    if tx
+                        .blocking_send((buf[..len].to_vec(), src, hop_limit))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    if tx.is_closed() {
+                        return;
+                    }
+                    if e.kind() != std::io::ErrorKind::WouldBlock
+                        && e.kind() != std::io::ErrorKind::TimedOut
+                    {
+                        eprintln!("Error while reading ospfv3 packet on {}: {}", name, e);
+                    }
+                }
+            }
+        }
+    });
+    packets
+}
+
+impl InterfaceV3 {
+    pub async fn receiver(&mut self, mut commands: mpsc::Receiver<InterfaceCommand>) {
+        let mut packets = match OspfV3Socket::open(
+            &self.name,
+            self.interface_id,
+            self.link_local,
+            RECV_TIMEOUT,
+        ) {
+            Ok(socket) => {
+                let socket = Arc::new(socket);
+                self.socket = Some(socket.clone());
+                self.apply_hop_limit();
+                spawn_packet_reader(self.name.clone(), socket)
+            }
+            Err(e) => {
+                eprintln!("Error opening ospfv3 socket on {}: {}", self.name, e);
+                // keep taking commands so the manager can stop us
+                return self.run_passive(commands).await;
+            }
+        };
+        loop {
+            if !self.poll_commands(&mut commands).await {
+                return;
+            }
+            if self.check_drain().await {
+                self.say_goodbye().await;
+                return;
+            }
+            if self.fsm.state != InterfaceState::Down {
+                self.check_timers().await;
+                self.send_dbd_if_need().await;
+                if self.check_nbr_change().await {
+                    self.handle_event(InterfaceEvent::NeighborChange).await;
+                }
+                self.send_lsr_if_need().await;
+                self.update_all_d_membership();
+            }
+            // wake up regularly even without packets to run timers and commands
+            match tokio::time::timeout(RECV_TIMEOUT, packets.recv()).await {
+                Err(_) => {}
+                Ok(None) => {
+                    eprintln!("Packet reader on {} stopped", self.name);
+                    return;
+                }
+                Ok(Some(_)) if self.fsm.state == InterfaceState::Down => {}
+                Ok(Some((buf, src, hop_limit))) => {
+                    if src == self.link_local {
+                        continue;
+                    }
+                    if !self.gtsm_accepts(hop_limit) {
+                        println!(
+                            "Dropping packet from {} on {}: hop limit {:?} fails ttl security",
+                            src, self.name, hop_limit
+                        );
+                        continue;
+                    }
+                    let packet = match OSPFv3Packet::parse(&buf) {
+                        Ok((_, packet)) => packet,
+                        Err(e) => {
+                            eprintln!("Error while parsing ospfv3 packet: {}", e);
+                            continue;
+                        }
+                    };
+                    // several instances can share a link (RFC 5340 2.4)
+                    if packet.get_hdr().instance_id != self.instance_id {
+                        continue;
+                    }
+                    self.handle_packet(packet, src).await;
+                }
+            }
+        }
+    }
+
+    // GTSM (RFC 5082): OSPFv3 neighbors are all on the link, so anything
+    // that lost hop limit on the way was forwarded to us
+    fn gtsm_accepts(&self, hop_limit: Option<u8>) -> bool {
+        self.ttl_security.is_none() || hop_limit == Some(255)
+    }
+
+    pub async fn handle_packet(&mut self, packet: OSPFv3Packet, src: Ipv6Addr) {
+        if self.fsm.passive {
+            return;
+        }
+        match packet {
+            OSPFv3Packet::Hello(hello) => self.handle_hello(hello, src).await,
+            OSPFv3Packet::DBDescription(dbd) => self.handle_dbd(dbd).await,
+            OSPFv3Packet::LinkStateRequest(lsr) => self.handle_lsr(lsr).await,
+            OSPFv3Packet::LinkStateUpdate(lsu) => self.handle_lsu(lsu).await,
+            OSPFv3Packet::LinkStateAcknowledgment(lsack) => self.handle_lsack(lsack),
+        }
+    }
+
+    // RFC 5340 4.2.2.1, there is no network mask to compare
+    fn check_hello_params(&self, hello: &HelloV3, my_config: &Config) -> Option<HelloMismatch> {
+        if hello.header.router_id == my_config.router_id {
+            return Some(HelloMismatch::DuplicateRouterId);
+        }
+        if hello.header.area_id != self.area_id {
+            return Some(HelloMismatch::AreaId);
+        }
+        if hello.hello_interval as u32 != self.hello_interval {
+            return Some(HelloMismatch::HelloInterval);
+        }
+        if hello.router_dead_interval as u32 != self.dead_interval {
+            return Some(HelloMismatch::DeadInterval);
+        }
+        let our_e = my_config.options & crate::packets::OPTION_E != 0;
+        if (hello.options & OPTION_V3_E != 0) != our_e {
+            return Some(HelloMismatch::ExternalRoutingCapability);
+        }
+        None
+    }
+
+    pub async fn handle_hello(&mut self, hello: HelloV3, src: Ipv6Addr) {
+        let my_config = self.query_config().await;
+        if let Some(mismatch) = self.check_hello_params(&hello, &my_config) {
+            println!(
+                "Dropping hello from {} on {}: {:?} mismatch",
+                Ipv4Addr::from(hello.header.router_id),
+                self.name,
+                mismatch
+            );
+            return;
+        }
+        let router_id = hello.header.router_id;
+        let idx = match self.find_neighbor(router_id) {
+            Some(idx) => idx,
+            None => {
+                self.fsm.neighbor_list.push(NeighborV3::create(
+                    router_id,
+                    src,
+                    self.dead_interval,
+                    self.rxmt_interval,
+                ));
+                self.fsm.neighbor_list.len() - 1
+            }
+        };
+        let int_view = self.fsm.int_view();
+        let nbr = &mut self.fsm.neighbor_list[idx];
+        nbr.int_view = int_view;
+        // the link-local address can change, the router ID is what identifies
+        nbr.nbr_ip = src;
+        nbr.nbr_interface_id = hello.interface_id;
+        let prev_dr = nbr.nbr_dr;
+        let prev_bdr = nbr.nbr_bdr;
+        nbr.nbr_dr = Ipv4Addr::from(hello.designated_router);
+        nbr.nbr_bdr = Ipv4Addr::from(hello.backup_designated_router);
+        nbr.nbr_pri = hello.router_priority;
+        nbr.nbr_options = hello.options as u8;
+        nbr.handle_event(NbrEvent::HelloReceived);
+        if hello.neighbors.contains(&my_config.router_id) {
+            nbr.handle_event(NbrEvent::TwoWayReceived);
+        } else {
+            nbr.handle_event(NbrEvent::OneWayReceived);
+            return;
+        }
+        let me = nbr.dr_id();
+        if (nbr.nbr_dr == me || nbr.nbr_bdr == me) && self.fsm.state == InterfaceState::Waiting {
+            self.handle_event(InterfaceEvent::BackupSeen).await;
+        } else if ((prev_dr == me) ^ (nbr.nbr_dr == me)) || ((prev_bdr == me) ^ (nbr.nbr_bdr == me))
+        {
+            self.handle_event(InterfaceEvent::NeighborChange).await;
+        }
+    }
+
+    // send the next slice of the database summary list
+    async fn send_next_dbd(&self, nbr: &mut NeighborV3, master: bool) {
+        let n = std::cmp::min(self.max_dbd_lsa_hdrs(), nbr.db_summary_list.len());
+        let lsa_hdrs: Vec<LsaHeaderV3> = nbr.db_summary_list.drain(..n).collect();
+        let more = !nbr.db_summary_list.is_empty();
+        let flags = DBDFlag::new(false, more, master).to_byte();
+        self.send_dbd_packet(nbr.nbr_ip, flags, nbr.dd_seq_number, lsa_hdrs.clone())
+            .await;
+        nbr.last_sent_dbd = Some((flags, nbr.dd_seq_number, lsa_hdrs));
+    }
+
+    pub async fn handle_dbd(&mut self, dbd: DBDescriptionV3) {
+        let my_router_id = self.query_config().await.router_id;
+        let idx = match self.find_neighbor(dbd.header.router_id) {
+            Some(idx) => idx,
+            None => return,
+        };
+        if !self.mtu_ignore && dbd.interface_mtu > self.mtu {
+            println!(
+                "Adjacency problem with neighbor {} on {}: MTU mismatch (neighbor {}, ours {}), DBD rejected",
+                Ipv4Addr::from(dbd.header.router_id),
+                self.name,
+                dbd.interface_mtu,
+                self.mtu
+            );
+            return;
+        }
+        let self_clone = self.clone();
+        let nbr = &mut self.fsm.neighbor_list[idx];
+        let duped = nbr
+            .last_rcv_dbd
+            .as_ref()
+            .is_some_and(|x| x.dbd_seq_num == dbd.dbd_seq_num && x.flags == dbd.flags);
+        if nbr.state == NbrState::Init {
+            nbr.handle_event(NbrEvent::TwoWayReceived);
+        }
+        let flags = dbd.get_flag();
+        let options = dbd.options as u8;
+        match nbr.state {
+            NbrState::ExStart => {
+                if flags.init && flags.more && flags.masterslave && nbr.nbr_id > my_router_id {
+                    nbr.nbr_is_master = true;
+                    nbr.dd_seq_number = dbd.dbd_seq_num;
+                } else if !flags.init
+                    && !flags.masterslave
+                    && dbd.dbd_seq_num == nbr.dd_seq_number
+                    && nbr.nbr_id < my_router_id
+                {
+                    nbr.nbr_is_master = false;
+                } else {
+                    return;
+                }
+                nbr.nbr_options = options;
+                nbr.handle_event(NbrEvent::NegotiationDone);
+                let all_lsas = match self_clone
+                    .query_database(DatabaseV3RequestType::QueryAllLsa(self_clone.name.clone()))
+                    .await
+                {
+                    DatabaseV3Response::LsaList(lsas) => lsas,
+                    _ => panic!("Unexpected response"),
+                };
+                nbr.db_summary_list = all_lsas.iter().map(|x| x.get_hdr().clone()).collect();
+                if nbr.nbr_is_master {
+                    // as the slave, answer the master's initial DBD
+                    nbr.last_rcv_dbd = Some(dbd);
+                    self_clone.send_next_dbd(nbr, false).await;
+                    return;
+                }
+            }
+            NbrState::Exchange => {
+                if duped {
+                    // the slave answers a retransmission with its last DBD
+                    if nbr.nbr_is_master {
+                        if let Some((flags, seq, hdrs)) = nbr.last_sent_dbd.clone() {
+                            self_clone
+                                .send_dbd_packet(nbr.nbr_ip, flags, seq, hdrs)
+                                .await;
+                        }
+                    }
+                    return;
+                }
+                let expected = if nbr.nbr_is_master {
+                    nbr.dd_seq_number.wrapping_add(1)
+                } else {
+                    nbr.dd_seq_number
+                };
+                if flags.masterslave != nbr.nbr_is_master
+                    || flags.init
+                    || options != nbr.nbr_options
+                    || dbd.dbd_seq_num != expected
+                {
+                    nbr.handle_event(NbrEvent::SeqNumberMismatch);
+                    return;
+                }
+            }
+            NbrState::Loading | NbrState::Full => {
+                if !duped {
+                    nbr.handle_event(NbrEvent::SeqNumberMismatch);
+                } else if nbr.nbr_is_master {
+                    if let Some((flags, seq, hdrs)) = nbr.last_sent_dbd.clone() {
+                        self_clone
+                            .send_dbd_packet(nbr.nbr_ip, flags, seq, hdrs)
+                            .await;
+                    }
+                }
+                return;
+            }
+            _ => return,
+        }
+        // the packet is accepted, request what we don't have or have older
+        for hdr in dbd.lsa_hdrs.iter() {
+            let ours = match self_clone
+                .query_database(DatabaseV3RequestType::QueryLsaByHdr(
+                    self_clone.name.clone(),
+                    hdr.clone(),
+                ))
+                .await
+            {
+                DatabaseV3Response::Lsa(l) => Some(l),
+                _ => None,
+            };
+            let wanted = match ours {
+                Some(l) => (l.get_hdr().sequence_number as i32) < (hdr.sequence_number as i32),
+                None => true,
+            };
+            if wanted && !nbr.link_state_req_list.iter().any(|x| x.same_ids(hdr)) {
+                nbr.link_state_req_list.push(hdr.clone());
+            }
+        }
+        let nbr_more = flags.more;
+        nbr.last_rcv_dbd = Some(dbd);
+        if nbr.nbr_is_master {
+            nbr.dd_seq_number = nbr.dd_seq_number.wrapping_add(1);
+            self_clone.send_next_dbd(nbr, false).await;
+            let we_more = nbr.last_sent_dbd.as_ref().is_some_and(|x| x.0 & 0b010 != 0);
+            if !we_more && !nbr_more {
+                nbr.handle_event(NbrEvent::ExchangeDone);
+            }
+        } else {
+            let we_more = nbr.last_sent_dbd.as_ref().is_some_and(|x| x.0 & 0b010 != 0);
+            if !we_more && !nbr_more {
+                nbr.mst_exch_timer.stop();
+                nbr.handle_event(NbrEvent::ExchangeDone);
+            } else {
+                nbr.dd_seq_number = nbr.dd_seq_number.wrapping_add(1);
+                self_clone.send_next_dbd(nbr, true).await;
+                nbr.mst_exch_timer.start();
+            }
+        }
+    }
+
+    pub async fn handle_lsr(&mut self, lsr: LinkStateRequestV3) {
+        let idx = match self.find_neighbor(lsr.header.router_id) {
+            Some(idx) => idx,
+            None => return,
+        };
+        if !matches!(
+            self.fsm.neighbor_list[idx].state,
+            NbrState::Exchange | NbrState::Loading | NbrState::Full
+        ) {
+            return;
+        }
+        let queries: Vec<(u16, u32, u32)> = lsr
+            .requests
+            .iter()
+            .map(|x| (x.ls_type, x.link_state_id, x.advertising_router))
+            .collect();
+        let lsas = match self
+            .query_database(DatabaseV3RequestType::QueryMultiLsa(
+                self.name.clone(),
+                queries,
+            ))
+            .await
+        {
+            DatabaseV3Response::LsaList(l) => l,
+            _ => panic!("Error getting LSA list from database"),
+        };
+        if lsas.len() == lsr.requests.len() {
+            let dst = self.fsm.neighbor_list[idx].nbr_ip;
+            self.send_lsu(dst, lsas).await;
+        } else {
+            self.fsm.neighbor_list[idx].handle_event(NbrEvent::BadLSReq);
+        }
+    }
+
+    // Flooding procedure of RFC 2328 13 with the LSA scopes of RFC 5340 4.5
+    pub async fn handle_lsu(&mut self, lsu: LinkStateUpdateV3) {
+        let router_id = lsu.header.router_id;
+        let idx = match self.find_neighbor(router_id) {
+            Some(idx) => idx,
+            None => return,
+        };
+        if !matches!(
+            self.fsm.neighbor_list[idx].state,
+            NbrState::Exchange | NbrState::Loading | NbrState::Full
+        ) {
+            return;
+        }
+        let my_router_id = self.query_config().await.router_id;
+        let self_clone = self.clone();
+        let name = self.name.clone();
+        let nbr = &mut self.fsm.neighbor_list[idx];
+        let mut acks = vec![];
+        for lsa in lsu.lsas.iter() {
+            let hdr = lsa.get_hdr().clone();
+            let ours = match self_clone
+                .query_database(DatabaseV3RequestType::QueryLsaByHdr(
+                    name.clone(),
+                    hdr.clone(),
+                ))
+                .await
+            {
+                DatabaseV3Response::Lsa(l) => Some(l),
+                _ => None,
+            };
+            if hdr.age == MAX_AGE as u16 && ours.is_none() {
+                acks.push(hdr);
+                continue;
+            }
+            match ours.map(|x| (x.cmp_with(lsa), x)) {
+                Some((LsaCmpResult::Same, _)) => {
+                    nbr.lsa_retransmission_list.retain(|x| !x.same_ids(&hdr));
+                    acks.push(hdr);
+                    continue;
+                }
+                Some((LsaCmpResult::Newer, ours)) => {
+                    if nbr.link_state_req_list.iter().any(|x| x.same_ids(&hdr)) {
+                        nbr.handle_event(NbrEvent::BadLSReq);
+                        return;
+                    }
+                    // send our newer copy back
+                    self_clone.send_lsu(nbr.nbr_ip, vec![ours]).await;
+                    continue;
+                }
+                _ => {}
+            }
+            nbr.link_state_req_list.retain(|x| !x.same_ids(&hdr));
+            nbr.lsa_retransmission_list.retain(|x| !x.same_ids(&hdr));
+            self_clone.flood_lsu(vec![lsa.clone()]).await;
+            acks.push(hdr.clone());
+            let reply = if hdr.advertising_router == my_router_id {
+                DatabaseV3RequestType::SelfOriginatedLsa(name.clone(), lsa.clone())
+            } else {
+                DatabaseV3RequestType::AddOrUpdateLsa(name.clone(), lsa.clone())
+            };
+            match self_clone.query_database(reply).await {
+                DatabaseV3Response::Lsa(l) => self_clone.flood_own(vec![l]).await,
+                DatabaseV3Response::LsaList(l) => self_clone.flood_own(l).await,
+                _ => {}
+            }
+        }
+        if !acks.is_empty() {
+            self_clone.send_lsack(nbr.nbr_ip, acks).await;
+        }
+        if nbr.state == NbrState::Loading && nbr.link_state_req_list.is_empty() {
+            nbr.handle_event(NbrEvent::LoadingDone);
+        }
+    }
+
+    pub fn handle_lsack(&mut self, lsack: LinkStateAcknowledgmentV3) {
+        if let Some(idx) = self.find_neighbor(lsack.header.router_id) {
+            let nbr = &mut self.fsm.neighbor_list[idx];
+            for hdr in lsack.lsas.iter() {
+                nbr.lsa_retransmission_list
+                    .retain(|x: &LsaHeaderV3| !x.same_ids(hdr));
+            }
+        }
+    }
+}
@@ -1,4 +1,9 @@
+use crate::config::{FastReroute, FlexAlgo, Topology};
 use crate::database::LinkStateDatabase;
+use crate::flex_algo::FlexAlgoGraph;
+use crate::frr::Backup;
+use crate::interface::InterfaceType;
+use crate::kernel::{self, KernelRoute, MplsRoute, BACKUP_ROUTE_METRIC};
 use crate::lsa::{LinkType, Lsa, LsaNetwork, LsaRouter};
 use crate::route;
 use std::collections::HashMap;
@@ -9,24 +14,49 @@ use std::net::Ipv4Addr;
 pub struct RouteEntry {
     pub dest_id: Ipv4Addr,
     pub mask: Ipv4Addr,
+    // 0.0.0.0 if the destination is directly attached
     pub next_hop: Ipv4Addr,
     pub metric: u32,
     pub int_addr: Ipv4Addr,
+    pub int_name: String,
+    // the next hop is reachable although it's on none of our subnets
+    pub onlink: bool,
+    // where to go when the next hop fails, with fast reroute on
+    pub backup: Option<Backup>,
+}
+
+// where to send packets for the first node on the path to a destination
+#[derive(Debug, Clone)]
+pub struct NextHop {
+    pub addr: Ipv4Addr,
+    pub int_name: String,
+    pub int_addr: Ipv4Addr,
+    pub onlink: bool,
 }
 
 #[derive(Debug)]
 pub struct RouteTable {
+    // the topology the routes are for (RFC 4915), 0 for the default one
+    pub mt_id: u8,
+    // the flexible algorithm they are for, 0 for plain SPF
+    pub algorithm: u8,
+    pub flex_algo: Option<FlexAlgoGraph>,
     pub entries: Vec<RouteEntry>,
-    nodes: HashMap<Ipv4Addr, Node>,
-    prevs: HashMap<Ipv4Addr, Ipv4Addr>,
-    edges: HashMap<Ipv4Addr, Vec<Edge>>,
+    // what we have programmed into the kernel
+    pub installed: Vec<KernelRoute>,
+    pub installed_mpls: Vec<MplsRoute>,
+    pub nodes: HashMap<Ipv4Addr, Node>,
+    pub prevs: HashMap<Ipv4Addr, Ipv4Addr>,
+    pub edges: HashMap<Ipv4Addr, Vec<Edge>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
-struct Node {
+pub struct Node {
     pub id: Ipv4Addr,
     pub mask: Ipv4Addr,
     pub dis: u32,
+    // described by a router LSA, not a destination network
+    pub router: bool,
 }
 
 impl std::cmp::PartialOrd for Node {
@@ -43,14 +73,35 @@ pub struct Edge {
 
 impl RouteTable {
     pub fn new() -> Self {
+        RouteTable::for_topology(0)
+    }
+
+    pub fn for_topology(mt_id: u8) -> Self {
         RouteTable {
+            mt_id,
+            algorithm: 0,
+            flex_algo: None,
             entries: vec![],
+            installed: vec![],
+            installed_mpls: vec![],
             nodes: HashMap::new(),
             prevs: HashMap::new(),
             edges: HashMap::new(),
         }
     }
 
+    pub fn for_flex_algo(algorithm: u8) -> Self {
+        RouteTable {
+            algorithm,
+            ..RouteTable::for_topology(0)
+        }
+    }
+
+    // the default topology with plain SPF
+    pub fn is_default(&self) -> bool {
+        self.mt_id == 0 && self.algorithm == 0
+    }
+
     pub fn clear_graph(&mut self) {
         self.nodes.clear();
         self.edges.clear();
@@ -67,7 +118,75 @@ impl RouteTable {
 }
 
 impl LinkStateDatabase {
+    // Every topology and flexible algorithm has its own graph, SPF and
+    // kernel table, its route table is swapped in while its routes are
+    // computed
     pub fn update_route_table(&mut self) {
+        self.update_topology_routes();
+        let topologies: Vec<Topology> = self.global_config.topologies().collect();
+        let gone: Vec<u8> = self
+            .topology_tables
+            .keys()
+            .filter(|id| !topologies.iter().any(|x| x.id == **id))
+            .cloned()
+            .collect();
+        for id in gone {
+            if let Some(mut table) = self.topology_tables.remove(&id) {
+                kernel::sync_routes(&mut table.installed, vec![]);
+            }
+        }
+        for topology in topologies {
+            let mut table = self
+                .topology_tables
+                .remove(&topology.id)
+                .unwrap_or_else(|| RouteTable::for_topology(topology.id));
+            std::mem::swap(&mut self.route_table, &mut table);
+            self.update_topology_routes();
+            std::mem::swap(&mut self.route_table, &mut table);
+            self.topology_tables.insert(topology.id, table);
+        }
+        let algos: Vec<FlexAlgo> = self.global_config.flex_algos().collect();
+        self.flex_algo_tables.retain(|id, table| {
+            let keep = algos.iter().any(|x| x.id == *id);
+            if !keep {
+                kernel::sync_routes(&mut table.installed, vec![]);
+            }
+            keep
+        });
+        for algo in algos {
+            let mut table = self
+                .flex_algo_tables
+                .remove(&algo.id)
+                .unwrap_or_else(|| RouteTable::for_flex_algo(algo.id));
+            table.flex_algo = self.flex_algo_graph(algo.id);
+            std::mem::swap(&mut self.route_table, &mut table);
+            self.update_topology_routes();
+            std::mem::swap(&mut self.route_table, &mut table);
+            self.flex_algo_tables.insert(algo.id, table);
+        }
+    }
+
+    fn update_topology_routes(&mut self) {
+        let mt_id = self.route_table.mt_id;
+        let algorithm = self.route_table.algorithm;
+        let kernel_table = match (mt_id, algorithm) {
+            (0, 0) => self.global_config.route_table,
+            (0, _) => match self.global_config.flex_algo(algorithm) {
+                Some(algo) => algo.table,
+                None => return,
+            },
+            _ => match self.global_config.topology(mt_id) {
+                Some(topology) => topology.table,
+                None => return,
+            },
+        };
+        // no usable definition, the algorithm's routes are withdrawn
+        if algorithm != 0 && self.route_table.flex_algo.is_none() {
+            self.route_table.clear_graph();
+            self.route_table.entries.clear();
+            kernel::sync_routes(&mut self.route_table.installed, vec![]);
+            return;
+        }
         // println!("Updating route table, current lsa list:");
         // for lsa in self.lsa_list.iter() {
         //     println!("{:?}", lsa);
@@ -75,8 +194,13 @@ impl LinkStateDatabase {
         self.route_table.clear_graph();
         // kinda ugly, but for passing the borrow checker
         for lsa in self.lsa_list.clone().iter().cloned() {
+            // flushed LSAs no longer describe the topology
+            if lsa.get_hdr().age >= crate::config::MAX_AGE as u16 {
+                continue;
+            }
             match lsa {
-                Lsa::LsaRouter(l) => {
+                // routers outside the algorithm are left out of its graph
+                Lsa::LsaRouter(l) if self.route_table.includes(l.header.advertising_router) => {
                     self.update_route_table_manage_router_lsa(l);
                 }
                 Lsa::LsaNetwork(l) => {
@@ -90,7 +214,8 @@ impl LinkStateDatabase {
 
         for lsa in self.lsa_list.iter() {
             match lsa {
-                Lsa::LsaSumnet(l) => {
+                // inter-area routes are not computed per algorithm
+                Lsa::LsaSumnet(l) if algorithm == 0 => {
                     // self-originated
                     if l.header.advertising_router == self.global_config.router_id {
                         continue;
@@ -108,18 +233,24 @@ impl LinkStateDatabase {
                     if adv_rtr.dis == u32::MAX {
                         continue;
                     }
+                    let metric = match l.topology_metric(mt_id) {
+                        Some(metric) => metric,
+                        // not advertised in this topology
+                        None => continue,
+                    };
                     self.route_table.nodes.insert(
                         Ipv4Addr::from(l.header.link_state_id),
                         Node {
                             id: Ipv4Addr::from(l.header.link_state_id),
                             mask: Ipv4Addr::from(l.network_mask),
                             dis: adv_rtr.dis,
+                            router: false,
                         },
                     );
                     self.route_table.put_or_update_edge(
                         adv_rtr.id,
                         Ipv4Addr::from(l.header.link_state_id),
-                        l.metric as u32,
+                        metric,
                     );
                     self.route_table
                         .prevs
@@ -130,8 +261,22 @@ impl LinkStateDatabase {
         }
         // TODO: construct external routes
 
+        // repairs and labels are only computed for the default topology
+        let default = self.route_table.is_default();
+        let mut frr = (self.global_config.fast_reroute != FastReroute::Off && default)
+            .then(|| self.frr_context());
+        let sr = match self.global_config.segment_routing {
+            Some(_) if default => Some(self.sr_context()),
+            _ => None,
+        };
+
         // construct route table
-        self.route_table.entries.clear();
+        let mut entries = vec![];
+        let mut wanted = vec![];
+        let mut wanted_mpls = match &sr {
+            Some(ctx) => self.sr_local_routes(ctx),
+            None => vec![],
+        };
         for node in self.route_table.nodes.values() {
             if node.dis == u32::MAX || node.id == Ipv4Addr::from(self.global_config.router_id) {
                 // println!(
@@ -149,27 +294,191 @@ impl LinkStateDatabase {
                 cur_node = *prev_node;
             }
             // println!("node: {:?}, next_hop: {:?}", node, next_hop);
-            // get interface address
-            let int_addr = self
-                .int_list
-                .iter()
-                .find(|x| x.addr == cur_node || x.addr & node.mask == node.id)
-                .unwrap()
-                .addr;
-            self.route_table.entries.push(RouteEntry {
+            let hop = match self.resolve_next_hop(next_hop) {
+                Some(hop) => hop,
+                // the interface went away since the LSA was originated
+                None => continue,
+            };
+            // only paths through another router can be repaired
+            let backup = match &mut frr {
+                Some(ctx) if hop.addr != Ipv4Addr::from(0) => {
+                    self.find_backup(ctx, node.id, next_hop, &hop.int_name)
+                }
+                _ => None,
+            };
+            // directly attached networks are already in the kernel table
+            if !node.router && hop.addr != Ipv4Addr::from(0) {
+                let binding = match &sr {
+                    Some(ctx) => self.sr_binding(ctx, node, next_hop),
+                    None => None,
+                };
+                if let Some(binding) = &binding {
+                    println!(
+                        "Label binding for {}/{}: in {} out {}",
+                        node.id,
+                        u32::from(node.mask).count_ones(),
+                        binding.in_label,
+                        binding
+                            .out_label
+                            .map_or("implicit-null".to_string(), |x| x.to_string())
+                    );
+                    wanted_mpls.push(binding.mpls_route(&hop));
+                }
+                wanted.push(KernelRoute {
+                    dest: node.id.into(),
+                    prefix_len: u32::from(node.mask).count_ones(),
+                    next_hop: hop.addr.into(),
+                    dev: hop.int_name.clone(),
+                    onlink: hop.onlink,
+                    table: kernel_table,
+                    metric: 0,
+                    labels: binding.and_then(|x| x.out_label).into_iter().collect(),
+                });
+                if let Some(Backup::Lfa { hop: alt, .. }) = &backup {
+                    wanted.push(KernelRoute {
+                        dest: node.id.into(),
+                        prefix_len: u32::from(node.mask).count_ones(),
+                        next_hop: alt.addr.into(),
+                        dev: alt.int_name.clone(),
+                        onlink: alt.onlink,
+                        table: kernel_table,
+                        metric: BACKUP_ROUTE_METRIC,
+                        labels: vec![],
+                    });
+                }
+            }
+            entries.push(RouteEntry {
                 dest_id: node.id,
                 mask: node.mask,
-                next_hop,
+                next_hop: hop.addr,
                 metric: node.dis,
-                int_addr,
+                int_addr: hop.int_addr,
+                int_name: hop.int_name,
+                onlink: hop.onlink,
+                backup,
             });
         }
+        self.route_table.entries = entries;
+        if frr.is_some() {
+            self.report_frr_coverage();
+        }
+        kernel::sync_routes(&mut self.route_table.installed, wanted);
+        if mt_id != 0 {
+            println!(
+                "topology {} route table: {:?}",
+                mt_id, self.route_table.entries
+            );
+            return;
+        }
+        if algorithm != 0 {
+            println!(
+                "flex-algo {} route table: {:?}",
+                algorithm, self.route_table.entries
+            );
+            return;
+        }
+        kernel::sync_mpls_routes(&mut self.route_table.installed_mpls, wanted_mpls);
 
         println!("route table: {:?}", self.route_table.entries);
 
         println!("Route table updated");
     }
 
+    fn report_frr_coverage(&self) {
+        let mut repairable = 0;
+        let mut covered = 0;
+        for entry in self.route_table.entries.iter() {
+            if entry.next_hop == Ipv4Addr::from(0) {
+                continue;
+            }
+            repairable += 1;
+            let dest = format!("{}/{}", entry.dest_id, u32::from(entry.mask).count_ones());
+            match &entry.backup {
+                Some(backup) => {
+                    covered += 1;
+                    println!("Fast reroute for {}: {}", dest, backup);
+                }
+                None => println!("Fast reroute for {}: unprotected", dest),
+            }
+        }
+        println!(
+            "Fast reroute covers {} of {} destinations",
+            covered, repairable
+        );
+    }
+
+    // Find the outgoing interface and next hop address for the first node
+    // on a path (RFC 2328 16.1.1), only interfaces that are up are described
+    pub fn resolve_next_hop(&self, hop: Ipv4Addr) -> Option<NextHop> {
+        let node = self.route_table.nodes.get(&hop)?;
+        let mut names: Vec<&String> = self.each_int_link.keys().collect();
+        names.sort();
+        if !node.router {
+            // a network attached to one of our interfaces
+            let name = names.into_iter().find(|name| {
+                let desc = &self.each_int_link[*name];
+                // a borrowed address belongs to the interface lending it
+                let own = !desc.unnumbered
+                    && (desc.addr == hop
+                        || desc.addr & node.mask == hop
+                        || desc
+                            .secondary_addrs
+                            .iter()
+                            .any(|a| a.ip() & node.mask == hop));
+                own || desc.full_nbrs.iter().any(|(_, ip)| *ip == hop)
+            })?;
+            return Some(NextHop {
+                addr: Ipv4Addr::from(0),
+                int_name: name.clone(),
+                int_addr: self.each_int_link[name].addr,
+                onlink: false,
+            });
+        }
+        // a neighbor on a point-to-point link, we know its address from its hellos
+        for name in names.iter() {
+            let desc = &self.each_int_link[*name];
+            if !matches!(desc.int_type, InterfaceType::P2P | InterfaceType::P2MP) {
+                continue;
+            }
+            if let Some((_, ip)) = desc.full_nbrs.iter().find(|(id, _)| *id == u32::from(hop)) {
+                return Some(NextHop {
+                    addr: *ip,
+                    int_name: (*name).clone(),
+                    int_addr: desc.addr,
+                    onlink: desc.unnumbered || *ip & desc.mask != desc.addr & desc.mask,
+                });
+            }
+        }
+        // a neighbor on a transit network, its address is the link data
+        // of its transit link
+        let lsa = self.lsa_list.iter().find_map(|lsa| match lsa {
+            Lsa::LsaRouter(l) if l.header.link_state_id == u32::from(hop) => Some(l),
+            _ => None,
+        })?;
+        for link in lsa.links.iter() {
+            if link.link_type != LinkType::Transit as u8 {
+                continue;
+            }
+            let ip = Ipv4Addr::from(link.link_data);
+            let name = names.iter().find(|name| {
+                let desc = &self.each_int_link[**name];
+                matches!(
+                    desc.int_type,
+                    InterfaceType::Broadcast | InterfaceType::NBMA
+                ) && ip & desc.mask == desc.addr & desc.mask
+            });
+            if let Some(name) = name {
+                return Some(NextHop {
+                    addr: ip,
+                    int_name: (*name).clone(),
+                    int_addr: self.each_int_link[*name].addr,
+                    onlink: false,
+                });
+            }
+        }
+        None
+    }
+
     fn update_route_table_manage_router_lsa(&mut self, lsa: LsaRouter) {
         self.route_table.nodes.insert(
             Ipv4Addr::from(lsa.header.link_state_id),
@@ -177,15 +486,22 @@ impl LinkStateDatabase {
                 id: Ipv4Addr::from(lsa.header.link_state_id),
                 mask: Ipv4Addr::from(0),
                 dis: u32::MAX,
+                router: true,
             },
         );
         // println!("Router LSA: {:?}", lsa);
         for link in lsa.links.iter() {
-            if link.link_type == LinkType::P2P as u8 {
+            // links without a metric in this topology or algorithm are not
+            // part of it
+            let metric = match self.link_metric(lsa.header.advertising_router, link) {
+                Some(metric) => metric,
+                None => continue,
+            };
+            if link.link_type == LinkType::P2P as u8 || link.link_type == LinkType::Virtual as u8 {
                 self.route_table.put_or_update_edge(
                     Ipv4Addr::from(lsa.header.link_state_id),
                     Ipv4Addr::from(link.link_id),
-                    link.metric as u32,
+                    metric,
                 );
             } else if link.link_type == LinkType::Transit as u8 {
                 // println!("Transit link: {:?}", link);
@@ -208,13 +524,13 @@ impl LinkStateDatabase {
 
                 let nlsa = self.get_network_lsa(link.link_id).unwrap();
                 for rtr_id in nlsa.attached_routers {
-                    if rtr_id == lsa.header.link_state_id {
+                    if rtr_id == lsa.header.link_state_id || !self.route_table.includes(rtr_id) {
                         continue;
                     }
                     self.route_table.put_or_update_edge(
                         Ipv4Addr::from(lsa.header.link_state_id),
                         Ipv4Addr::from(rtr_id),
-                        link.metric as u32,
+                        metric,
                     );
                 }
             } else if link.link_type == LinkType::Stub as u8 {
@@ -224,12 +540,13 @@ impl LinkStateDatabase {
                         id: Ipv4Addr::from(link.link_id),
                         mask: Ipv4Addr::from(link.link_data),
                         dis: u32::MAX,
+                        router: false,
                     },
                 );
                 self.route_table.put_or_update_edge(
                     Ipv4Addr::from(lsa.header.link_state_id),
                     Ipv4Addr::from(link.link_id),
-                    link.metric as u32,
+                    metric,
                 );
             } else {
                 unimplemented!();
@@ -245,6 +562,7 @@ impl LinkStateDatabase {
                 id: net_node_id,
                 mask: Ipv4Addr::from(lsa.network_mask),
                 dis: u32::MAX,
+                router: false,
             },
         );
         for rtr_id in lsa.attached_routers {
@@ -257,11 +575,15 @@ impl LinkStateDatabase {
         // for ed in self.route_table.edges.iter() {
         //     println!("{} => {:?}", ed.0, ed.1);
         // }
-        self.route_table
+        match self
+            .route_table
             .nodes
             .get_mut(&Ipv4Addr::from(self.global_config.router_id))
-            .unwrap()
-            .dis = 0;
+        {
+            Some(root) => root.dis = 0,
+            // we have no router LSA (yet), nothing is reachable
+            None => return,
+        }
         let mut q: Vec<Node> = self.route_table.nodes.values().cloned().collect();
         for node in q.iter() {
             self.route_table.prevs.insert(node.id, Ipv4Addr::from(0));
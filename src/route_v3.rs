@@ -0,0 +1,326 @@
+// OSPFv3 route calculation (RFC 5340 4.8). The shortest path tree is built
+// from router and network LSAs only, the prefixes are then attached to the
+// vertices from the Intra-Area-Prefix and Inter-Area-Prefix LSAs.
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+
+use pnet::ipnetwork::Ipv6Network;
+
+use crate::database_v3::LinkStateDatabaseV3;
+use crate::kernel::{self, KernelRoute};
+use crate::lsa_v3::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VertexV3 {
+    Router(u32),
+    // advertising router (the DR) and its interface ID
+    Network(u32, u32),
+}
+
+// the outgoing interface, and the link-local address of the next router
+// unless the destination is on an attached link
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextHopV3 {
+    pub int_name: String,
+    pub addr: Option<Ipv6Addr>,
+}
+
+#[derive(Debug)]
+pub struct RouteEntryV3 {
+    pub dest: Ipv6Network,
+    pub metric: u32,
+    pub next_hop: NextHopV3,
+}
+
+#[derive(Debug)]
+pub struct RouteTableV3 {
+    pub entries: Vec<RouteEntryV3>,
+    // what we have programmed into the kernel
+    pub installed: Vec<KernelRoute>,
+}
+
+impl RouteTableV3 {
+    pub fn new() -> Self {
+        RouteTableV3 {
+            entries: vec![],
+            installed: vec![],
+        }
+    }
+}
+
+// one step away from a vertex: the next vertex, the cost, and the
+// interface ID of the link when leaving from a router
+struct EdgeV3 {
+    dst: VertexV3,
+    dis: u32,
+    interface_id: u32,
+}
+
+impl LinkStateDatabaseV3 {
+    fn router_lsa(&self, router_id: u32) -> Option<&LsaRouterV3> {
+        self.lsa_list.iter().find_map(|x| match x {
+            LsaV3::Router(l)
+                if l.header.advertising_router == router_id
+                    && l.header.age < crate::config::MAX_AGE as u16 =>
+            {
+                Some(l)
+            }
+            _ => None,
+        })
+    }
+
+    fn network_lsa(&self, dr: u32, interface_id: u32) -> Option<&LsaNetworkV3> {
+        self.lsa_list.iter().find_map(|x| match x {
+            LsaV3::Network(l)
+                if l.header.advertising_router == dr
+                    && l.header.link_state_id == interface_id
+                    && l.header.age < crate::config::MAX_AGE as u16 =>
+            {
+                Some(l)
+            }
+            _ => None,
+        })
+    }
+
+    // the links of a vertex that are described from both ends (RFC 2328 16.1 (2)b)
+    fn edges(&self, vertex: VertexV3) -> Vec<EdgeV3> {
+        let mut edges = vec![];
+        match vertex {
+            VertexV3::Router(id) => {
+                let lsa = match self.router_lsa(id) {
+                    Some(lsa) => lsa,
+                    None => return edges,
+                };
+                for link in lsa.links.iter() {
+                    let (dst, back) = if link.link_type == LinkTypeV3::Transit as u8 {
+                        let dst = VertexV3::Network(link.nbr_router_id, link.nbr_interface_id);
+                        let back = self
+                            .network_lsa(link.nbr_router_id, link.nbr_interface_id)
+                            .is_some_and(|n| n.attached_routers.contains(&id));
+                        (dst, back)
+                    } else {
+                        let dst = VertexV3::Router(link.nbr_router_id);
+                        let back = self.router_lsa(link.nbr_router_id).is_some_and(|r| {
+                            r.links
+                                .iter()
+                                .any(|x| x.link_type == link.link_type && x.nbr_router_id == id)
+                        });
+                        (dst, back)
+                    };
+                    if back {
+                        edges.push(EdgeV3 {
+                            dst,
+                            dis: link.metric as u32,
+                            interface_id: link.interface_id,
+                        });
+                    }
+                }
+            }
+            VertexV3::Network(dr, interface_id) => {
+                if let Some(lsa) = self.network_lsa(dr, interface_id) {
+                    for rtr in lsa.attached_routers.iter() {
+                        if self.router_lsa(*rtr).is_some() {
+                            edges.push(EdgeV3 {
+                                dst: VertexV3::Router(*rtr),
+                                dis: 0,
+                                interface_id: 0,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        edges
+    }
+
+    // the link-local address of a router on the link of one of our
+    // interfaces, from its Link-LSA or else from its hellos
+    fn nbr_link_local(&self, int_name: &str, router_id: u32) -> Option<Ipv6Addr> {
+        let from_lsa = self.link_lsas.get(int_name).and_then(|list| {
+            list.iter().find_map(|x| match x {
+                LsaV3::Link(l) if l.header.advertising_router == router_id => Some(l.link_local()),
+                _ => None,
+            })
+        });
+        from_lsa.or_else(|| {
+            self.each_int
+                .get(int_name)?
+                .nbrs
+                .iter()
+                .find(|x| x.router_id == router_id)
+                .map(|x| x.link_local)
+        })
+    }
+
+    fn int_by_id(&self, interface_id: u32) -> Option<String> {
+        self.each_int
+            .iter()
+            .find(|(_, x)| x.interface_id == interface_id)
+            .map(|(name, _)| name.clone())
+    }
+
+    // Next hop towards `dst` reached from `parent` (RFC 2328 16.1.1)
+    fn next_hop(
+        &self,
+        parent: VertexV3,
+        parent_hop: Option<&NextHopV3>,
+        edge: &EdgeV3,
+    ) -> Option<NextHopV3> {
+        let root = VertexV3::Router(self.global_config.router_id);
+        if parent == root {
+            let int_name = self.int_by_id(edge.interface_id)?;
+            let addr = match edge.dst {
+                VertexV3::Router(id) => Some(self.nbr_link_local(&int_name, id)?),
+                VertexV3::Network(..) => None,
+            };
+            return Some(NextHopV3 { int_name, addr });
+        }
+        let parent_hop = parent_hop?.clone();
+        match (parent, edge.dst) {
+            // a router on a network attached to us
+            (VertexV3::Network(..), VertexV3::Router(id)) if parent_hop.addr.is_none() => {
+                let addr = self.nbr_link_local(&parent_hop.int_name, id)?;
+                Some(NextHopV3 {
+                    int_name: parent_hop.int_name,
+                    addr: Some(addr),
+                })
+            }
+            _ => Some(parent_hop),
+        }
+    }
+
+    fn run_spf(&self) -> HashMap<VertexV3, (u32, Option<NextHopV3>)> {
+        let root = VertexV3::Router(self.global_config.router_id);
+        let mut done: HashMap<VertexV3, (u32, Option<NextHopV3>)> = HashMap::new();
+        let mut candidates: HashMap<VertexV3, (u32, Option<NextHopV3>)> = HashMap::new();
+        if self.router_lsa(self.global_config.router_id).is_none() {
+            return done;
+        }
+        candidates.insert(root, (0, None));
+        while let Some(v) = candidates
+            .iter()
+            .min_by_key(|(_, (dis, _))| *dis)
+            .map(|(v, _)| *v)
+        {
+            let Some((dis, hop)) = candidates.remove(&v) else {
+                break;
+            };
+            done.insert(v, (dis, hop.clone()));
+            for edge in self.edges(v) {
+                if done.contains_key(&edge.dst) {
+                    continue;
+                }
+                let alt = dis + edge.dis;
+                if candidates
+                    .get(&edge.dst)
+                    .is_some_and(|(old, _)| *old <= alt)
+                {
+                    continue;
+                }
+                let next = match self.next_hop(v, hop.as_ref(), &edge) {
+                    Some(next) => next,
+                    // no interface or neighbor address to reach it through yet
+                    None => continue,
+                };
+                candidates.insert(edge.dst, (alt, Some(next)));
+            }
+        }
+        done
+    }
+
+    pub fn update_route_table(&mut self) {
+        let tree = self.run_spf();
+        let mut best: HashMap<Ipv6Network, (u32, NextHopV3)> = HashMap::new();
+        let mut add = |net: Ipv6Network, metric: u32, hop: &NextHopV3| {
+            if best.get(&net).is_some_and(|(m, _)| *m <= metric) {
+                return;
+            }
+            best.insert(net, (metric, hop.clone()));
+        };
+        for lsa in self.lsa_list.iter() {
+            if lsa.get_hdr().age >= crate::config::MAX_AGE as u16 {
+                continue;
+            }
+            match lsa {
+                LsaV3::IntraAreaPrefix(l) => {
+                    let vertex = if l.ref_ls_type == LsaTypeV3::Network as u16 {
+                        VertexV3::Network(l.ref_advertising_router, l.ref_link_state_id)
+                    } else {
+                        VertexV3::Router(l.ref_advertising_router)
+                    };
+                    // our own prefixes are on attached links
+                    let (dis, hop) = match tree.get(&vertex) {
+                        Some((dis, Some(hop))) => (*dis, hop),
+                        _ => continue,
+                    };
+                    for prefix in l.prefixes.iter() {
+                        if prefix.prefix_options & PREFIX_OPTION_NU != 0 {
+                            continue;
+                        }
+                        if let Some(net) = prefix.to_network() {
+                            add(net, dis + prefix.metric as u32, hop);
+                        }
+                    }
+                }
+                LsaV3::InterAreaPrefix(l) => {
+                    if l.header.advertising_router == self.global_config.router_id {
+                        continue;
+                    }
+                    let vertex = VertexV3::Router(l.header.advertising_router);
+                    let (dis, hop) = match tree.get(&vertex) {
+                        Some((dis, Some(hop))) => (*dis, hop),
+                        _ => continue,
+                    };
+                    let metric = ((l.metric_hi as u32) << 16) | l.metric as u32;
+                    if metric >= crate::config::LS_INFINITY {
+                        continue;
+                    }
+                    if let Some(net) = l.prefix.to_network() {
+                        add(net, dis + metric, hop);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // prefixes of our own interfaces are already in the kernel table
+        let own: Vec<Ipv6Network> = self
+            .each_int
+            .values()
+            .flat_map(|x| {
+                x.prefixes
+                    .iter()
+                    .filter_map(|p| Ipv6Network::new(p.network(), p.prefix()).ok())
+            })
+            .collect();
+        let mut entries = vec![];
+        let mut wanted = vec![];
+        for (dest, (metric, hop)) in best {
+            if let Some(addr) = hop.addr.filter(|_| !own.contains(&dest)) {
+                wanted.push(KernelRoute {
+                    dest: dest.network().into(),
+                    prefix_len: dest.prefix() as u32,
+                    next_hop: addr.into(),
+                    dev: hop.int_name.clone(),
+                    onlink: false,
+                    table: self.global_config.route_table,
+                    metric: 0,
+                    labels: vec![],
+                });
+            }
+            entries.push(RouteEntryV3 {
+                dest,
+                metric,
+                next_hop: hop,
+            });
+        }
+        self.route_table.entries = entries;
+        kernel::sync_routes(&mut self.route_table.installed, wanted);
+        println!("ipv6 route table:");
+        for entry in self.route_table.entries.iter() {
+            println!(
+                "  {} metric {} dev {} via {:?}",
+                entry.dest, entry.metric, entry.next_hop.int_name, entry.next_hop.addr
+            );
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// The router information LSA (RFC 7770) tells what a router is: its
+// capabilities, its node admin tags (RFC 7777) and its hostname (RFC 5642).
+// Segment routing and the flexible algorithms add their own TLVs, see
+// opaque.rs. The hostnames are used for the management socket's output.
+use crate::database::LinkStateDatabase;
+use crate::lsa::{Lsa, LsaOpaque, OpaqueTlv};
+use crate::opaque::{read_u32, OPAQUE_ROUTER_INFO};
+use crate::route::RouteTable;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+
+const RI_TLV_INFO_CAPABILITIES: u16 = 1;
+const RI_TLV_HOSTNAME: u16 = 7;
+const RI_TLV_NODE_ADMIN_TAG: u16 = 10;
+
+// informational capability bits, numbered from the most significant one
+const CAPABILITY_NAMES: [&str; 6] = [
+    "graceful-restart",
+    "graceful-restart-helper",
+    "stub-router",
+    "traffic-engineering",
+    "p2p-over-lan",
+    "experimental-te",
+];
+const CAP_STUB_ROUTER: u32 = 1 << 29;
+const CAP_P2P_OVER_LAN: u32 = 1 << 27;
+
+// What a router's information LSA says about it
+#[derive(Debug, Clone, Default)]
+pub struct RouterInfo {
+    pub capabilities: u32,
+    pub hostname: Option<String>,
+    pub tags: Vec<u32>,
+}
+
+impl std::fmt::Display for RouterInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let caps: Vec<&str> = CAPABILITY_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.capabilities & (1 << (31 - i)) != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "capabilities [{}]", caps.join(" "))?;
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self.tags.iter().map(|x| x.to_string()).collect();
+            write!(f, " tags [{}]", tags.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_router_info(lsa: &LsaOpaque) -> Option<RouterInfo> {
+    if lsa.opaque_type() != OPAQUE_ROUTER_INFO {
+        return None;
+    }
+    let mut info = RouterInfo::default();
+    for tlv in lsa.tlvs() {
+        let value = tlv.value();
+        match tlv.tlv_type {
+            RI_TLV_INFO_CAPABILITIES if value.len() >= 4 => info.capabilities = read_u32(value),
+            RI_TLV_HOSTNAME if !value.is_empty() => {
+                let name = String::from_utf8_lossy(value);
+                info.hostname = Some(name.trim_end_matches('\0').to_string());
+            }
+            RI_TLV_NODE_ADMIN_TAG => info.tags.extend(value.chunks_exact(4).map(read_u32)),
+            _ => {}
+        }
+    }
+    Some(info)
+}
+
+fn system_hostname() -> Option<String> {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    let name = name.trim();
+    (!name.is_empty() && name.len() <= 255).then(|| name.to_string())
+}
+
+fn ls_type_name(ls_type: u8) -> String {
+    match ls_type {
+        1 => "router".to_string(),
+        2 => "network".to_string(),
+        3 => "summary".to_string(),
+        4 => "asbr-summary".to_string(),
+        5 => "external".to_string(),
+        9 => "opaque-link".to_string(),
+        10 => "opaque-area".to_string(),
+        11 => "opaque-as".to_string(),
+        _ => format!("type-{}", ls_type),
+    }
+}
+
+impl LinkStateDatabase {
+    pub fn node_router_info_tlvs(&self) -> Vec<OpaqueTlv> {
+        let capabilities = CAP_STUB_ROUTER | CAP_P2P_OVER_LAN;
+        let mut tlvs = vec![OpaqueTlv::new(
+            RI_TLV_INFO_CAPABILITIES,
+            capabilities.to_be_bytes().to_vec(),
+        )];
+        let hostname = self.node_info.hostname.clone().or_else(system_hostname);
+        if let Some(name) = hostname {
+            tlvs.push(OpaqueTlv::new(RI_TLV_HOSTNAME, name.into_bytes()));
+        }
+        if !self.node_info.tags.is_empty() {
+            let tags = self.node_info.tags.iter().flat_map(|x| x.to_be_bytes());
+            tlvs.push(OpaqueTlv::new(RI_TLV_NODE_ADMIN_TAG, tags.collect()));
+        }
+        tlvs
+    }
+
+    // keep what the router information LSAs say, by advertising router
+    pub fn update_router_infos(&mut self) {
+        let infos: HashMap<u32, RouterInfo> = self
+            .area_opaque_lsas()
+            .filter_map(|lsa| Some((lsa.header.advertising_router, parse_router_info(lsa)?)))
+            .collect();
+        self.router_infos = infos;
+    }
+
+    // the hostname of a router when it advertises one, its ID otherwise
+    pub fn router_name(&self, router_id: u32) -> String {
+        match self
+            .router_infos
+            .get(&router_id)
+            .and_then(|x| x.hostname.as_ref())
+        {
+            Some(name) => name.clone(),
+            None => Ipv4Addr::from(router_id).to_string(),
+        }
+    }
+
+    pub fn hostnames(&self) -> HashMap<u32, String> {
+        self.router_infos
+            .iter()
+            .filter_map(|(id, info)| Some((*id, info.hostname.clone()?)))
+            .collect()
+    }
+
+    pub fn show_database(&self) -> String {
+        let mut out = format!(
+            "{:<13} {:<15} {:<20} {:>4} {:<10} {}\n",
+            "Type", "Link ID", "ADV Router", "Age", "Seq#", "Checksum"
+        );
+        let mut hdrs: Vec<_> = self.lsa_list.iter().map(Lsa::get_hdr).collect();
+        hdrs.sort_by_key(|x| (x.ls_type, x.link_state_id, x.advertising_router));
+        for hdr in hdrs {
+            // router LSAs are named after their router
+            let link_id = match hdr.ls_type {
+                1 => self.router_name(hdr.link_state_id),
+                _ => Ipv4Addr::from(hdr.link_state_id).to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{:<13} {:<15} {:<20} {:>4} {:#010x} {:#06x}",
+                ls_type_name(hdr.ls_type),
+                link_id,
+                self.router_name(hdr.advertising_router),
+                hdr.age,
+                hdr.sequence_number,
+                hdr.checksum
+            );
+        }
+        let mut ids: Vec<&u32> = self.router_infos.keys().collect();
+        ids.sort();
+        for id in ids {
+            let _ = writeln!(
+                out,
+                "router {} ({}): {}",
+                self.router_name(*id),
+                Ipv4Addr::from(*id),
+                self.router_infos[id]
+            );
+        }
+        out.trim_end().to_string()
+    }
+
+    fn show_route_table(&self, out: &mut String, table: &RouteTable) {
+        let mut entries: Vec<_> = table.entries.iter().collect();
+        entries.sort_by_key(|x| (x.mask == Ipv4Addr::from(0), x.dest_id, x.mask));
+        for entry in entries {
+            // routes to routers have no mask
+            let dest = match entry.mask {
+                mask if mask == Ipv4Addr::from(0) => {
+                    format!("router {}", self.router_name(u32::from(entry.dest_id)))
+                }
+                mask => format!("{}/{}", entry.dest_id, u32::from(mask).count_ones()),
+            };
+            let via = match entry.next_hop {
+                hop if hop == Ipv4Addr::from(0) => "directly attached".to_string(),
+                hop if entry.onlink => format!("via {} onlink", hop),
+                hop => format!("via {}", hop),
+            };
+            let _ = writeln!(
+                out,
+                "{} {} dev {} ({}) metric {}",
+                dest, via, entry.int_name, entry.int_addr, entry.metric
+            );
+        }
+    }
+
+    pub fn show_routes(&self) -> String {
+        let mut out = String::new();
+        self.show_route_table(&mut out, &self.route_table);
+        let mut topologies: Vec<_> = self.topology_tables.iter().collect();
+        topologies.sort_by_key(|(id, _)| **id);
+        for (id, table) in topologies {
+            let _ = writeln!(out, "topology {}:", id);
+            self.show_route_table(&mut out, table);
+        }
+        let mut algos: Vec<_> = self.flex_algo_tables.iter().collect();
+        algos.sort_by_key(|(id, _)| **id);
+        for (id, table) in algos {
+            let _ = writeln!(out, "flex-algo {}:", id);
+            self.show_route_table(&mut out, table);
+        }
+        out.trim_end().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opaque::tests::opaque_lsa;
+    use crate::opaque::OPAQUE_EXT_LINK;
+
+    #[test]
+    fn router_info_round_trip() {
+        let tlvs = [
+            OpaqueTlv::new(
+                RI_TLV_INFO_CAPABILITIES,
+                (CAP_STUB_ROUTER | CAP_P2P_OVER_LAN).to_be_bytes().to_vec(),
+            ),
+            OpaqueTlv::new(RI_TLV_HOSTNAME, b"r1\0".to_vec()),
+            OpaqueTlv::new(
+                RI_TLV_NODE_ADMIN_TAG,
+                [7u32, 9].map(u32::to_be_bytes).concat(),
+            ),
+        ];
+        let info = parse_router_info(&opaque_lsa(OPAQUE_ROUTER_INFO, 1, &tlvs)).unwrap();
+        assert_eq!(info.capabilities, CAP_STUB_ROUTER | CAP_P2P_OVER_LAN);
+        assert_eq!(info.hostname.as_deref(), Some("r1"));
+        assert_eq!(info.tags, vec![7, 9]);
+        assert_eq!(
+            info.to_string(),
+            "capabilities [stub-router p2p-over-lan] tags [7 9]"
+        );
+        assert!(parse_router_info(&opaque_lsa(OPAQUE_EXT_LINK, 1, &tlvs)).is_none());
+    }
+
+    #[test]
+    fn malformed_router_info() {
+        let tlvs = [
+            // too short for the capability bits
+            OpaqueTlv::new(RI_TLV_INFO_CAPABILITIES, vec![0xFF, 0xFF]),
+            OpaqueTlv::new(RI_TLV_HOSTNAME, vec![]),
+            // the trailing partial tag is dropped
+            OpaqueTlv::new(RI_TLV_NODE_ADMIN_TAG, vec![0, 0, 0, 7, 0, 0]),
+        ];
+        let info = parse_router_info(&opaque_lsa(OPAQUE_ROUTER_INFO, 1, &tlvs)).unwrap();
+        assert_eq!(info.capabilities, 0);
+        assert_eq!(info.hostname, None);
+        assert_eq!(info.tags, vec![7]);
+    }
+}
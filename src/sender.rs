@@ -1,14 +1,16 @@
 use pnet::datalink::NetworkInterface;
-use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::ipv4::{Ipv4Flags, MutableIpv4Packet};
 use std::net::Ipv4Addr;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 
-use crate::packets::OSPFPacket;
+use crate::packets::{OSPFPacket, IPV4_HDR_LEN};
 
 #[derive(Debug)]
 pub struct OSPFPacketSender {
     pub request_channel: mpsc::Receiver<SenderRequest>,
+    // identification field for the next ip datagram we build
+    pub ip_identification: u16,
 }
 
 #[derive(Debug)]
@@ -19,7 +21,8 @@ pub struct SenderRequest {
 
 #[derive(Debug)]
 pub enum SenderRequestType {
-    SendOSPFPacket(OSPFPacket, (Ipv4Addr, Ipv4Addr), NetworkInterface),
+    // packet, (src, dst), outgoing interface, interface mtu, ttl
+    SendOSPFPacket(OSPFPacket, (Ipv4Addr, Ipv4Addr), NetworkInterface, u16, u8),
 }
 
 #[derive(Debug)]
@@ -28,7 +31,21 @@ pub enum SenderResponse {
     Failed,
 }
 
-const SENDER_BUFFER_SIZE: usize = 200;
+fn bind_to_device(fd: libc::c_int, name: &str) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
 
 impl OSPFPacketSender {
     pub async fn sender_thread(&mut self) {
@@ -42,23 +59,16 @@ impl OSPFPacketSender {
 
     async fn handle_request(&mut self, req: SenderRequestType) -> SenderResponse {
         match req {
-            SenderRequestType::SendOSPFPacket(mut packet, (src_ip, dst_ip), interface) => {
+            SenderRequestType::SendOSPFPacket(
+                mut packet,
+                (src_ip, dst_ip),
+                interface,
+                mtu,
+                ttl,
+            ) => {
                 packet.set_packet_length();
                 packet.set_checksum();
                 let encoded = packet.encode_bincode();
-                assert!(encoded.len() < SENDER_BUFFER_SIZE);
-                let mut buffer = [0u8; SENDER_BUFFER_SIZE];
-                let mut ipv4_packet = MutableIpv4Packet::new(&mut buffer).unwrap();
-                ipv4_packet.set_version(4);
-                ipv4_packet.set_header_length(5);
-                ipv4_packet.set_total_length(encoded.len() as u16 + 20);
-                ipv4_packet.set_ttl(1);
-                ipv4_packet
-                    .set_next_level_protocol(pnet::packet::ip::IpNextHeaderProtocols::OspfigP);
-                ipv4_packet.set_source(src_ip);
-                ipv4_packet.set_destination(dst_ip);
-                ipv4_packet.set_checksum(pnet::packet::ipv4::checksum(&ipv4_packet.to_immutable()));
-                ipv4_packet.set_payload(&encoded);
 
                 let (mut tx, _) = pnet::transport::transport_channel(
                     1024,
@@ -67,16 +77,55 @@ impl OSPFPacketSender {
                     ),
                 )
                 .unwrap();
+                // unnumbered interfaces share their source address, so the
+                // route lookup alone can't tell which link to send on
+                if bind_to_device(tx.socket.fd, &interface.name).is_err() {
+                    eprintln!("Error binding socket to interface {}", interface.name);
+                    return SenderResponse::Failed;
+                }
                 println!(
                     "Sending ospf type {} packet to {:?}",
                     packet.get_hdr().packet_type,
                     dst_ip
                 );
 
-                match tx.send_to(ipv4_packet, std::net::IpAddr::V4(dst_ip)) {
-                    Ok(_) => SenderResponse::PacketSent,
-                    Err(_) => SenderResponse::Failed,
+                // fragment offsets are counted in 8 bytes units, a tiny mtu
+                // still lets at least one unit through
+                let max_frag_len = ((mtu as usize).saturating_sub(IPV4_HDR_LEN) & !7).max(8);
+                let identification = self.ip_identification;
+                self.ip_identification = self.ip_identification.wrapping_add(1);
+                let mut offset = 0;
+                while offset < encoded.len() {
+                    let frag_len = std::cmp::min(max_frag_len, encoded.len() - offset);
+                    let more = offset + frag_len < encoded.len();
+                    let mut buffer = vec![0u8; IPV4_HDR_LEN + frag_len];
+                    let mut ipv4_packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+                    ipv4_packet.set_version(4);
+                    ipv4_packet.set_header_length(5);
+                    ipv4_packet.set_total_length((IPV4_HDR_LEN + frag_len) as u16);
+                    ipv4_packet.set_identification(identification);
+                    if more {
+                        ipv4_packet.set_flags(Ipv4Flags::MoreFragments);
+                    }
+                    ipv4_packet.set_fragment_offset((offset / 8) as u16);
+                    ipv4_packet.set_ttl(ttl);
+                    ipv4_packet
+                        .set_next_level_protocol(pnet::packet::ip::IpNextHeaderProtocols::OspfigP);
+                    ipv4_packet.set_source(src_ip);
+                    ipv4_packet.set_destination(dst_ip);
+                    ipv4_packet
+                        .set_checksum(pnet::packet::ipv4::checksum(&ipv4_packet.to_immutable()));
+                    ipv4_packet.set_payload(&encoded[offset..offset + frag_len]);
+
+                    if tx
+                        .send_to(ipv4_packet, std::net::IpAddr::V4(dst_ip))
+                        .is_err()
+                    {
+                        return SenderResponse::Failed;
+                    }
+                    offset += frag_len;
                 }
+                SenderResponse::PacketSent
             }
         }
     }
@@ -0,0 +1,194 @@
+// Raw IPv6 socket carrying OSPFv3 on one link. The kernel fills in and
+// verifies the packet checksum, and sources everything from our link-local
+// address.
+use std::net::Ipv6Addr;
+
+use crate::packets_v3::{ALL_SPF_ROUTERS_V6, OSPF_V3_CHECKSUM_OFFSET};
+
+// IPPROTO_OSPFIGP
+const OSPF_PROTOCOL: libc::c_int = 89;
+
+#[derive(Debug)]
+pub struct OspfV3Socket {
+    fd: libc::c_int,
+    ifindex: u32,
+}
+
+fn check(ret: libc::c_int) -> std::io::Result<()> {
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn sockaddr(addr: Ipv6Addr, ifindex: u32) -> libc::sockaddr_in6 {
+    let mut sa: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sa.sin6_addr.s6_addr = addr.octets();
+    sa.sin6_scope_id = ifindex;
+    sa
+}
+
+impl OspfV3Socket {
+    pub fn open(
+        name: &str,
+        ifindex: u32,
+        link_local: Ipv6Addr,
+        timeout: std::time::Duration,
+    ) -> std::io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_INET6,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                OSPF_PROTOCOL,
+            )
+        };
+        check(fd)?;
+        // closed on drop from here on
+        let socket = OspfV3Socket { fd, ifindex };
+        socket.set_opt(
+            libc::IPPROTO_IPV6,
+            libc::IPV6_CHECKSUM,
+            &OSPF_V3_CHECKSUM_OFFSET,
+        )?;
+        check(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                name.as_ptr() as *const libc::c_void,
+                name.len() as libc::socklen_t,
+            )
+        })?;
+        socket.set_opt(
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_IF,
+            &(ifindex as libc::c_int),
+        )?;
+        socket.set_hop_limit(1)?;
+        // for GTSM, the hop limit comes with every packet we read
+        socket.set_opt(
+            libc::IPPROTO_IPV6,
+            libc::IPV6_RECVHOPLIMIT,
+            &(1 as libc::c_int),
+        )?;
+        socket.set_opt(
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_LOOP,
+            &(0 as libc::c_int),
+        )?;
+        let tv = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        socket.set_opt(libc::SOL_SOCKET, libc::SO_RCVTIMEO, &tv)?;
+        let sa = sockaddr(link_local, ifindex);
+        check(unsafe {
+            libc::bind(
+                fd,
+                &sa as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        })?;
+        socket.set_membership(ALL_SPF_ROUTERS_V6, true)?;
+        Ok(socket)
+    }
+
+    fn set_opt<T>(&self, level: libc::c_int, name: libc::c_int, value: &T) -> std::io::Result<()> {
+        check(unsafe {
+            libc::setsockopt(
+                self.fd,
+                level,
+                name,
+                value as *const T as *const libc::c_void,
+                std::mem::size_of::<T>() as libc::socklen_t,
+            )
+        })
+    }
+
+    // the hop limit of the packets we send, multicast or not
+    pub fn set_hop_limit(&self, hops: u8) -> std::io::Result<()> {
+        let hops = hops as libc::c_int;
+        self.set_opt(libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS, &hops)?;
+        self.set_opt(libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, &hops)
+    }
+
+    pub fn set_membership(&self, group: Ipv6Addr, join: bool) -> std::io::Result<()> {
+        let mreq = libc::ipv6_mreq {
+            ipv6mr_multiaddr: libc::in6_addr {
+                s6_addr: group.octets(),
+            },
+            ipv6mr_interface: self.ifindex,
+        };
+        let opt = if join {
+            libc::IPV6_ADD_MEMBERSHIP
+        } else {
+            libc::IPV6_DROP_MEMBERSHIP
+        };
+        self.set_opt(libc::IPPROTO_IPV6, opt, &mreq)
+    }
+
+    pub fn send_to(&self, buf: &[u8], dst: Ipv6Addr) -> std::io::Result<()> {
+        let sa = sockaddr(dst, self.ifindex);
+        let ret = unsafe {
+            libc::sendto(
+                self.fd,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+                &sa as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // blocks until a packet arrives or the receive timeout expires, raw
+    // IPv6 sockets deliver the payload without the IPv6 header, its hop
+    // limit is returned along with the source
+    pub fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, Ipv6Addr, Option<u8>)> {
+        let mut sa: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // room for the one IPV6_HOPLIMIT control message
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = &mut sa as *mut libc::sockaddr_in6 as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        let ret = unsafe { libc::recvmsg(self.fd, &mut msg, 0) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut hop_limit = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let hdr = unsafe { &*cmsg };
+            if hdr.cmsg_level == libc::IPPROTO_IPV6 && hdr.cmsg_type == libc::IPV6_HOPLIMIT {
+                let value =
+                    unsafe { (libc::CMSG_DATA(cmsg) as *const libc::c_int).read_unaligned() };
+                hop_limit = Some(value as u8);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        Ok((
+            ret as usize,
+            Ipv6Addr::from(sa.sin6_addr.s6_addr),
+            hop_limit,
+        ))
+    }
+}
+
+impl Drop for OspfV3Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
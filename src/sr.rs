@@ -0,0 +1,467 @@
+// Segment routing with the MPLS data plane (RFC 8665). Our label blocks go
+// in the router information LSA (RFC 7770), prefix and adjacency SIDs in
+// extended prefix and extended link opaque LSAs (RFC 7684), see opaque.rs
+// for their origination. Every router
+// with a path to an SR prefix binds the prefix to a label of its own SRGB
+// and swaps it for the one of the next hop.
+use crate::config::LabelRange;
+use crate::database::LinkStateDatabase;
+use crate::interface::InterfaceState;
+use crate::kernel;
+use crate::kernel::MplsRoute;
+use crate::lsa::{LsaOpaque, OpaqueTlv};
+use crate::opaque::{read_u32, ExtLink, OPAQUE_EXT_PREFIX, OPAQUE_ROUTER_INFO};
+use crate::receiver::ipv4_to_bits;
+use crate::route::{NextHop, Node};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+// router information TLVs and their sub-TLVs
+const RI_TLV_SID_LABEL_RANGE: u16 = 9;
+const RI_TLV_SR_LOCAL_BLOCK: u16 = 14;
+const SUB_TLV_SID_LABEL: u16 = 1;
+
+const TLV_EXT_PREFIX: u16 = 1;
+const SUB_TLV_PREFIX_SID: u16 = 2;
+const SUB_TLV_ADJ_SID: u16 = 2;
+const SUB_TLV_LAN_ADJ_SID: u16 = 3;
+
+const ROUTE_TYPE_INTRA_AREA: u8 = 1;
+// the prefix identifies the router (extended prefix TLV flags)
+const EXT_PREFIX_FLAG_N: u8 = 0x40;
+// prefix SID flags
+const PREFIX_SID_FLAG_NP: u8 = 0x40;
+const PREFIX_SID_FLAG_V: u8 = 0x08;
+const PREFIX_SID_FLAG_L: u8 = 0x04;
+// adjacency SID flags, our labels are local values
+const ADJ_SID_FLAG_V: u8 = 0x40;
+const ADJ_SID_FLAG_L: u8 = 0x20;
+pub const ALGORITHM_SPF: u8 = 0;
+
+// A prefix SID found in an extended prefix LSA, only indexes into the
+// SRGB are supported
+#[derive(Debug, Clone)]
+pub struct PrefixSidEntry {
+    pub prefix: Ipv4Addr,
+    pub prefix_len: u32,
+    pub adv_router: u32,
+    pub index: u32,
+    pub no_php: bool,
+}
+
+// What the other routers advertise, gathered once per route calculation
+#[derive(Debug)]
+pub struct SrContext {
+    pub srgbs: HashMap<u32, LabelRange>,
+    pub prefix_sids: Vec<PrefixSidEntry>,
+}
+
+// The labels of a prefix, the outgoing label is implicit null when the
+// next hop is the last one and expects us to pop
+#[derive(Debug)]
+pub struct LabelBinding {
+    pub in_label: u32,
+    pub out_label: Option<u32>,
+}
+
+fn label_bytes(label: u32) -> [u8; 3] {
+    let b = label.to_be_bytes();
+    [b[1], b[2], b[3]]
+}
+
+fn read_u24(b: &[u8]) -> u32 {
+    u32::from_be_bytes([0, b[0], b[1], b[2]])
+}
+
+// the SID/Label range and SR local block TLVs
+fn label_range_tlv(tlv_type: u16, range: LabelRange) -> OpaqueTlv {
+    let mut value = label_bytes(range.size).to_vec();
+    value.push(0);
+    value.extend(OpaqueTlv::new(SUB_TLV_SID_LABEL, label_bytes(range.start).to_vec()).encode());
+    OpaqueTlv::new(tlv_type, value)
+}
+
+fn parse_label_range(tlv: &OpaqueTlv) -> Option<LabelRange> {
+    let value = tlv.value();
+    if value.len() < 4 {
+        return None;
+    }
+    let sid = OpaqueTlv::parse_all(&value[4..])
+        .into_iter()
+        .find(|x| x.tlv_type == SUB_TLV_SID_LABEL && x.length == 3)?;
+    Some(LabelRange {
+        start: read_u24(sid.value()) & 0xF_FFFF,
+        size: read_u24(value),
+    })
+}
+
+// the first SRGB range of a router information LSA
+pub fn parse_srgb(lsa: &LsaOpaque) -> Option<LabelRange> {
+    if lsa.opaque_type() != OPAQUE_ROUTER_INFO {
+        return None;
+    }
+    lsa.tlvs()
+        .iter()
+        .find(|x| x.tlv_type == RI_TLV_SID_LABEL_RANGE)
+        .and_then(parse_label_range)
+}
+
+pub fn parse_prefix_sids(lsa: &LsaOpaque) -> Vec<PrefixSidEntry> {
+    let mut sids = vec![];
+    if lsa.opaque_type() != OPAQUE_EXT_PREFIX {
+        return sids;
+    }
+    for tlv in lsa.tlvs().iter().filter(|x| x.tlv_type == TLV_EXT_PREFIX) {
+        let value = tlv.value();
+        if value.len() < 4 || value[2] != 0 || value[1] > 32 {
+            continue;
+        }
+        let prefix_len = value[1] as u32;
+        let addr_len = (prefix_len as usize).div_ceil(32) * 4;
+        if value.len() < 4 + addr_len {
+            continue;
+        }
+        let prefix = match addr_len {
+            0 => Ipv4Addr::from(0),
+            _ => Ipv4Addr::from(read_u32(&value[4..])),
+        };
+        for sub in OpaqueTlv::parse_all(&value[4 + addr_len..]) {
+            let v = sub.value();
+            // only the default topology and algorithm, with an index
+            if sub.tlv_type != SUB_TLV_PREFIX_SID
+                || v.len() != 8
+                || v[0] & (PREFIX_SID_FLAG_V | PREFIX_SID_FLAG_L) != 0
+                || v[2] != 0
+                || v[3] != ALGORITHM_SPF
+            {
+                continue;
+            }
+            sids.push(PrefixSidEntry {
+                prefix,
+                prefix_len,
+                adv_router: lsa.header.advertising_router,
+                index: read_u32(&v[4..]),
+                no_php: v[0] & PREFIX_SID_FLAG_NP != 0,
+            });
+        }
+    }
+    sids
+}
+
+impl LinkStateDatabase {
+    // Let the kernel take our labels in on our interfaces, only when segment
+    // routing gets enabled or the interfaces change
+    pub fn update_mpls(&mut self) {
+        let wanted = self.global_config.segment_routing.map(|sr| {
+            let max_label =
+                std::cmp::max(sr.srgb.start + sr.srgb.size, sr.srlb.start + sr.srlb.size) - 1;
+            let mut devs: Vec<String> = self.each_int_link.keys().cloned().collect();
+            devs.sort();
+            (max_label, devs)
+        });
+        if wanted == self.mpls_setup {
+            return;
+        }
+        if let Some((max_label, devs)) = &wanted {
+            if let Err(e) = kernel::enable_mpls(*max_label, devs) {
+                eprintln!("Error enabling MPLS: {}", e);
+            }
+        }
+        self.mpls_setup = wanted;
+    }
+
+    // Give an SRLB label to every full adjacency, labels stay with their
+    // adjacency as long as it's up
+    pub fn allocate_adj_sids(&mut self) {
+        let srlb = match self.global_config.segment_routing {
+            Some(sr) => sr.srlb,
+            None => {
+                self.adj_sids.clear();
+                return;
+            }
+        };
+        let links = &self.each_int_link;
+        self.adj_sids.retain(|(name, nbr_id), label| {
+            *label >= srlb.start
+                && *label < srlb.start + srlb.size
+                && links
+                    .get(name)
+                    .is_some_and(|x| x.full_nbrs.iter().any(|(id, _)| id == nbr_id))
+        });
+        let mut names: Vec<&String> = self.each_int_link.keys().collect();
+        names.sort();
+        for name in names {
+            for (nbr_id, _) in self.each_int_link[name].full_nbrs.iter() {
+                let key = (name.clone(), *nbr_id);
+                if self.adj_sids.contains_key(&key) {
+                    continue;
+                }
+                let label = (srlb.start..srlb.start + srlb.size)
+                    .find(|l| !self.adj_sids.values().any(|x| x == l));
+                match label {
+                    Some(label) => {
+                        self.adj_sids.insert(key, label);
+                    }
+                    None => eprintln!("SR local block exhausted, no adjacency SID left"),
+                }
+            }
+        }
+    }
+
+    // our label blocks, for the router information LSA
+    pub fn sr_router_info_tlvs(&self) -> Vec<OpaqueTlv> {
+        match self.global_config.segment_routing {
+            Some(sr) => vec![
+                label_range_tlv(RI_TLV_SID_LABEL_RANGE, sr.srgb),
+                label_range_tlv(RI_TLV_SR_LOCAL_BLOCK, sr.srlb),
+            ],
+            None => vec![],
+        }
+    }
+
+    // an extended prefix TLV for every interface with a prefix SID
+    pub fn sr_ext_prefix_tlvs(&self) -> Vec<OpaqueTlv> {
+        if self.global_config.segment_routing.is_none() {
+            return vec![];
+        }
+        let mut names: Vec<&String> = self.each_int_link.keys().collect();
+        names.sort();
+        let mut prefixes = vec![];
+        for name in names {
+            let desc = &self.each_int_link[name];
+            let sid = match desc.prefix_sid {
+                Some(sid) if !desc.unnumbered => sid,
+                _ => continue,
+            };
+            let (prefix, prefix_len, flags) = if desc.state == InterfaceState::Loopback {
+                (desc.addr, 32, EXT_PREFIX_FLAG_N)
+            } else {
+                let mask = ipv4_to_bits(desc.mask);
+                (
+                    Ipv4Addr::from(ipv4_to_bits(desc.addr) & mask),
+                    mask.count_ones() as u8,
+                    0,
+                )
+            };
+            let sid_flags = if sid.no_php { PREFIX_SID_FLAG_NP } else { 0 };
+            let mut prefix_sid = vec![sid_flags, 0, 0, ALGORITHM_SPF];
+            prefix_sid.extend(sid.index.to_be_bytes());
+            let mut value = vec![ROUTE_TYPE_INTRA_AREA, prefix_len, 0, flags];
+            if prefix_len > 0 {
+                value.extend(prefix.octets());
+            }
+            value.extend(OpaqueTlv::new(SUB_TLV_PREFIX_SID, prefix_sid).encode());
+            prefixes.push(OpaqueTlv::new(TLV_EXT_PREFIX, value));
+        }
+        prefixes
+    }
+
+    // the adjacency SIDs of a link, LAN adjacency SIDs name the neighbor
+    pub fn adj_sid_sub_tlvs(&self, link: &ExtLink) -> Vec<OpaqueTlv> {
+        let flags = ADJ_SID_FLAG_V | ADJ_SID_FLAG_L;
+        let mut subs = vec![];
+        for id in link.nbrs.iter() {
+            let label = match self.adj_sids.get(&(link.key.0.clone(), *id)) {
+                Some(label) => label_bytes(*label),
+                None => continue,
+            };
+            let mut v = vec![flags, 0, 0, 0];
+            if link.key.1 == 0 {
+                v.extend(id.to_be_bytes());
+                v.extend(label);
+                subs.push(OpaqueTlv::new(SUB_TLV_LAN_ADJ_SID, v));
+            } else {
+                v.extend(label);
+                subs.push(OpaqueTlv::new(SUB_TLV_ADJ_SID, v));
+            }
+        }
+        subs
+    }
+
+    pub fn sr_context(&self) -> SrContext {
+        let mut ctx = SrContext {
+            srgbs: HashMap::new(),
+            prefix_sids: vec![],
+        };
+        for lsa in self.area_opaque_lsas() {
+            if let Some(srgb) = parse_srgb(lsa) {
+                ctx.srgbs.insert(lsa.header.advertising_router, srgb);
+            }
+            ctx.prefix_sids.extend(parse_prefix_sids(lsa));
+        }
+        ctx
+    }
+
+    // Labels for a prefix we route through `nbr`, the SID has to come
+    // from the router our path to the prefix ends at
+    pub fn sr_binding(&self, ctx: &SrContext, node: &Node, nbr: Ipv4Addr) -> Option<LabelBinding> {
+        let srgb = self.global_config.segment_routing?.srgb;
+        let origin = u32::from(*self.route_table.prevs.get(&node.id)?);
+        let sid = ctx.prefix_sids.iter().find(|x| {
+            x.prefix == node.id
+                && x.prefix_len == u32::from(node.mask).count_ones()
+                && x.adv_router == origin
+        })?;
+        let label =
+            |range: &LabelRange| (sid.index < range.size).then_some(range.start + sid.index);
+        let in_label = label(&srgb)?;
+        let out_label = if u32::from(nbr) == origin && !sid.no_php {
+            None
+        } else {
+            Some(label(ctx.srgbs.get(&u32::from(nbr))?)?)
+        };
+        Some(LabelBinding {
+            in_label,
+            out_label,
+        })
+    }
+
+    // Our own prefix SIDs end here, adjacency SIDs are popped and sent
+    // to the neighbor
+    pub fn sr_local_routes(&self, ctx: &SrContext) -> Vec<MplsRoute> {
+        let sr = match self.global_config.segment_routing {
+            Some(sr) => sr,
+            None => return vec![],
+        };
+        let mut routes = vec![];
+        for sid in ctx
+            .prefix_sids
+            .iter()
+            .filter(|x| x.adv_router == self.global_config.router_id && x.index < sr.srgb.size)
+        {
+            routes.push(MplsRoute {
+                label: sr.srgb.start + sid.index,
+                out_labels: vec![],
+                next_hop: None,
+                dev: "lo".to_string(),
+            });
+        }
+        for ((name, nbr_id), label) in self.adj_sids.iter() {
+            let nbr = self
+                .each_int_link
+                .get(name)
+                .and_then(|x| x.full_nbrs.iter().find(|(id, _)| id == nbr_id));
+            if let Some((_, ip)) = nbr {
+                routes.push(MplsRoute {
+                    label: *label,
+                    out_labels: vec![],
+                    next_hop: Some((*ip).into()),
+                    dev: name.clone(),
+                });
+            }
+        }
+        routes
+    }
+}
+
+impl LabelBinding {
+    pub fn mpls_route(&self, hop: &NextHop) -> MplsRoute {
+        MplsRoute {
+            label: self.in_label,
+            out_labels: self.out_label.into_iter().collect(),
+            next_hop: Some(hop.addr.into()),
+            dev: hop.int_name.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opaque::tests::opaque_lsa;
+
+    fn ext_prefix_tlv(prefix: Ipv4Addr, prefix_len: u8, subs: &[OpaqueTlv]) -> OpaqueTlv {
+        let mut value = vec![ROUTE_TYPE_INTRA_AREA, prefix_len, 0, EXT_PREFIX_FLAG_N];
+        value.extend(ipv4_to_bits(prefix).to_be_bytes());
+        value.extend(crate::opaque::encode_tlvs(subs));
+        OpaqueTlv::new(TLV_EXT_PREFIX, value)
+    }
+
+    fn prefix_sid_tlv(flags: u8, algorithm: u8, index: u32) -> OpaqueTlv {
+        let mut value = vec![flags, 0, 0, algorithm];
+        value.extend(index.to_be_bytes());
+        OpaqueTlv::new(SUB_TLV_PREFIX_SID, value)
+    }
+
+    #[test]
+    fn label_range_round_trip() {
+        let range = LabelRange {
+            start: 16000,
+            size: 8000,
+        };
+        let tlv = label_range_tlv(RI_TLV_SID_LABEL_RANGE, range);
+        assert_eq!(tlv.length, 4 + 8);
+        assert_eq!(parse_label_range(&tlv), Some(range));
+        let lsa = opaque_lsa(
+            OPAQUE_ROUTER_INFO,
+            1,
+            &[label_range_tlv(RI_TLV_SR_LOCAL_BLOCK, range), tlv],
+        );
+        assert_eq!(parse_srgb(&lsa), Some(range));
+        // not a router information LSA
+        let lsa = opaque_lsa(OPAQUE_EXT_PREFIX, 1, &lsa.tlvs());
+        assert_eq!(parse_srgb(&lsa), None);
+    }
+
+    #[test]
+    fn malformed_label_range() {
+        let range = LabelRange {
+            start: 16000,
+            size: 8000,
+        };
+        let tlv = label_range_tlv(RI_TLV_SID_LABEL_RANGE, range);
+        // too short for the range size
+        let short = OpaqueTlv::new(RI_TLV_SID_LABEL_RANGE, tlv.value()[..3].to_vec());
+        assert_eq!(parse_label_range(&short), None);
+        // the SID/label sub-TLV has to be a 3 byte label
+        let mut value = tlv.value()[..4].to_vec();
+        value.extend(OpaqueTlv::new(SUB_TLV_SID_LABEL, vec![0, 0, 0x3E, 0x80]).encode());
+        let index = OpaqueTlv::new(RI_TLV_SID_LABEL_RANGE, value);
+        assert_eq!(parse_label_range(&index), None);
+        // cut off sub-TLV
+        let cut = OpaqueTlv::new(RI_TLV_SID_LABEL_RANGE, tlv.value()[..9].to_vec());
+        assert_eq!(parse_label_range(&cut), None);
+    }
+
+    #[test]
+    fn prefix_sid_round_trip() {
+        let prefix = Ipv4Addr::new(10, 0, 0, 1);
+        let lsa = opaque_lsa(
+            OPAQUE_EXT_PREFIX,
+            0x0101_0101,
+            &[ext_prefix_tlv(
+                prefix,
+                32,
+                &[prefix_sid_tlv(PREFIX_SID_FLAG_NP, ALGORITHM_SPF, 7)],
+            )],
+        );
+        let sids = parse_prefix_sids(&lsa);
+        assert_eq!(sids.len(), 1);
+        assert_eq!(sids[0].prefix, prefix);
+        assert_eq!(sids[0].prefix_len, 32);
+        assert_eq!(sids[0].adv_router, 0x0101_0101);
+        assert_eq!(sids[0].index, 7);
+        assert!(sids[0].no_php);
+    }
+
+    #[test]
+    fn unusable_prefix_sids() {
+        let prefix = Ipv4Addr::new(10, 0, 0, 1);
+        let sids = |tlvs: &[OpaqueTlv]| parse_prefix_sids(&opaque_lsa(OPAQUE_EXT_PREFIX, 1, tlvs));
+        // labels instead of indexes, and other algorithms
+        let subs = [
+            prefix_sid_tlv(PREFIX_SID_FLAG_V | PREFIX_SID_FLAG_L, ALGORITHM_SPF, 7),
+            prefix_sid_tlv(0, 128, 7),
+        ];
+        assert!(sids(&[ext_prefix_tlv(prefix, 32, &subs)]).is_empty());
+        // a prefix length past 32
+        assert!(sids(&[ext_prefix_tlv(prefix, 33, &[prefix_sid_tlv(0, 0, 7)])]).is_empty());
+        // the prefix is missing
+        let tlv = OpaqueTlv::new(TLV_EXT_PREFIX, vec![ROUTE_TYPE_INTRA_AREA, 32, 0, 0]);
+        assert!(sids(&[tlv]).is_empty());
+        // a sub-TLV of the wrong length
+        let mut sub = prefix_sid_tlv(0, ALGORITHM_SPF, 7);
+        sub.length = 7;
+        assert!(sids(&[ext_prefix_tlv(prefix, 32, &[sub])]).is_empty());
+    }
+}
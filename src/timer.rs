@@ -25,6 +25,11 @@ impl Timer {
         self.active = true;
     }
 
+    // a running timer keeps its start time and expires after the new interval
+    pub fn set_interval(&mut self, interval: u32) {
+        self.interval = interval;
+    }
+
     pub fn stop(&mut self) {
         self.active = false;
     }